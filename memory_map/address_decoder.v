@@ -0,0 +1,56 @@
+// Generated from memory_map.txt by `patch memory-map`. Don't edit.
+//
+// Address decoder for the remapped memory map. Each select is
+// high while the 68000's address is in its region.
+
+module address_decoder (
+    input  wire [23:1] addr,
+    output wire        ram_sel,
+    output wire        rom_sel,
+    output wire        debug_sel,
+    output wire        scsi_sel,
+    output wire        scc_read_sel,
+    output wire        scc_write_sel,
+    output wire        iwm_sel,
+    output wire        via_sel,
+    output wire        floppy_mailbox_sel,
+    output wire        scsi_mailbox_sel,
+    output wire        via_mailbox_sel
+);
+
+    wire [23:0] a = {addr, 1'b0};
+
+    // RAM, up to the ROM
+    assign ram_sel = a < 24'hf80000;
+
+    // SE FDHD ROM
+    assign rom_sel = a >= 24'hf80000 && a < 24'hfc0000;
+
+    // Debug hooks
+    assign debug_sel = a >= 24'hfc0000 && a < 24'hfc0008;
+
+    // NCR 5380 SCSI controller
+    assign scsi_sel = a >= 24'hfc1000 && a < 24'hfc1280;
+
+    // Z8530 SCC, reads
+    assign scc_read_sel = a >= 24'hfc2000 && a < 24'hfc3000;
+
+    // Z8530 SCC, writes
+    assign scc_write_sel = a >= 24'hfc3000 && a < 24'hfc4000;
+
+    // IWM floppy controller
+    assign iwm_sel = a >= 24'hfc4000 && a < 24'hfc6000;
+
+    // 6522 VIA
+    assign via_sel = a >= 24'hfc6000 && a < 24'hfc8000;
+
+    // Paravirtual floppy driver (patch rom --floppy-mailbox)
+    assign floppy_mailbox_sel = a >= 24'hfc8000 && a < 24'hfc8010;
+
+    // Paravirtual SCSI Manager (patch rom --scsi-mailbox)
+    assign scsi_mailbox_sel = a >= 24'hfc8010 && a < 24'hfc8020;
+
+    // Paravirtual VIA functions (patch rom --via-mailbox)
+    assign via_mailbox_sel = a >= 24'hfc8020 && a < 24'hfc8040;
+
+endmodule
@@ -0,0 +1,64 @@
+-- Generated from memory_map.txt by `patch memory-map`. Don't edit.
+--
+-- Address decoder for the remapped memory map. Each select is
+-- high while the 68000's address is in its region.
+
+library ieee;
+use ieee.std_logic_1164.all;
+use ieee.numeric_std.all;
+
+entity address_decoder is
+    port (
+        addr               : in  unsigned(23 downto 1);
+        ram_sel            : out std_logic;
+        rom_sel            : out std_logic;
+        debug_sel          : out std_logic;
+        scsi_sel           : out std_logic;
+        scc_read_sel       : out std_logic;
+        scc_write_sel      : out std_logic;
+        iwm_sel            : out std_logic;
+        via_sel            : out std_logic;
+        floppy_mailbox_sel : out std_logic;
+        scsi_mailbox_sel   : out std_logic;
+        via_mailbox_sel    : out std_logic
+    );
+end entity address_decoder;
+
+architecture rtl of address_decoder is
+    signal a : unsigned(23 downto 0);
+begin
+    a <= addr & '0';
+
+    -- RAM, up to the ROM
+    ram_sel <= '1' when a < x"f80000" else '0';
+
+    -- SE FDHD ROM
+    rom_sel <= '1' when a >= x"f80000" and a < x"fc0000" else '0';
+
+    -- Debug hooks
+    debug_sel <= '1' when a >= x"fc0000" and a < x"fc0008" else '0';
+
+    -- NCR 5380 SCSI controller
+    scsi_sel <= '1' when a >= x"fc1000" and a < x"fc1280" else '0';
+
+    -- Z8530 SCC, reads
+    scc_read_sel <= '1' when a >= x"fc2000" and a < x"fc3000" else '0';
+
+    -- Z8530 SCC, writes
+    scc_write_sel <= '1' when a >= x"fc3000" and a < x"fc4000" else '0';
+
+    -- IWM floppy controller
+    iwm_sel <= '1' when a >= x"fc4000" and a < x"fc6000" else '0';
+
+    -- 6522 VIA
+    via_sel <= '1' when a >= x"fc6000" and a < x"fc8000" else '0';
+
+    -- Paravirtual floppy driver (patch rom --floppy-mailbox)
+    floppy_mailbox_sel <= '1' when a >= x"fc8000" and a < x"fc8010" else '0';
+
+    -- Paravirtual SCSI Manager (patch rom --scsi-mailbox)
+    scsi_mailbox_sel <= '1' when a >= x"fc8010" and a < x"fc8020" else '0';
+
+    -- Paravirtual VIA functions (patch rom --via-mailbox)
+    via_mailbox_sel <= '1' when a >= x"fc8020" and a < x"fc8040" else '0';
+end architecture rtl;
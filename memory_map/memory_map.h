@@ -0,0 +1,68 @@
+/* Generated from memory_map.txt by `patch memory-map`. Don't edit. */
+
+#ifndef MEMORY_MAP_H
+#define MEMORY_MAP_H
+
+/* RAM, up to the ROM */
+#define RAM_START 0x000000UL
+#define RAM_END   0xf80000UL
+#define RAM_SIZE  0xf80000UL
+
+/* SE FDHD ROM */
+#define ROM_START      0xf80000UL
+#define ROM_END        0xfc0000UL
+#define ROM_SIZE       0x040000UL
+#define ROM_MOVED_FROM 0x400000UL
+
+/* Debug hooks */
+#define DEBUG_START      0xfc0000UL
+#define DEBUG_END        0xfc0008UL
+#define DEBUG_SIZE       0x000008UL
+#define DEBUG_MOVED_FROM 0xf80000UL
+
+/* NCR 5380 SCSI controller */
+#define SCSI_START      0xfc1000UL
+#define SCSI_END        0xfc1280UL
+#define SCSI_SIZE       0x000280UL
+#define SCSI_MOVED_FROM 0x5ff000UL
+
+/* Z8530 SCC, reads */
+#define SCC_READ_START      0xfc2000UL
+#define SCC_READ_END        0xfc3000UL
+#define SCC_READ_SIZE       0x001000UL
+#define SCC_READ_MOVED_FROM 0x9ff000UL
+
+/* Z8530 SCC, writes */
+#define SCC_WRITE_START      0xfc3000UL
+#define SCC_WRITE_END        0xfc4000UL
+#define SCC_WRITE_SIZE       0x001000UL
+#define SCC_WRITE_MOVED_FROM 0xbff000UL
+
+/* IWM floppy controller */
+#define IWM_START      0xfc4000UL
+#define IWM_END        0xfc6000UL
+#define IWM_SIZE       0x002000UL
+#define IWM_MOVED_FROM 0xdfe000UL
+
+/* 6522 VIA */
+#define VIA_START      0xfc6000UL
+#define VIA_END        0xfc8000UL
+#define VIA_SIZE       0x002000UL
+#define VIA_MOVED_FROM 0xefe000UL
+
+/* Paravirtual floppy driver (patch rom --floppy-mailbox) */
+#define FLOPPY_MAILBOX_START 0xfc8000UL
+#define FLOPPY_MAILBOX_END   0xfc8010UL
+#define FLOPPY_MAILBOX_SIZE  0x000010UL
+
+/* Paravirtual SCSI Manager (patch rom --scsi-mailbox) */
+#define SCSI_MAILBOX_START 0xfc8010UL
+#define SCSI_MAILBOX_END   0xfc8020UL
+#define SCSI_MAILBOX_SIZE  0x000010UL
+
+/* Paravirtual VIA functions (patch rom --via-mailbox) */
+#define VIA_MAILBOX_START 0xfc8020UL
+#define VIA_MAILBOX_END   0xfc8040UL
+#define VIA_MAILBOX_SIZE  0x000020UL
+
+#endif /* MEMORY_MAP_H */
@@ -0,0 +1,102 @@
+// Generated from memory_map.txt by `patch memory-map`. Don't edit.
+
+// RAM, up to the ROM
+pub const RAM_START: u32 = 0x000000;
+pub const RAM_END: u32 = 0xf80000;
+pub const RAM_SIZE: u32 = 0xf80000;
+pub const RAM_START_HEX: &str = "0x000000";
+pub const RAM_END_HEX: &str = "0xf80000";
+
+// SE FDHD ROM
+pub const ROM_START: u32 = 0xf80000;
+pub const ROM_END: u32 = 0xfc0000;
+pub const ROM_SIZE: u32 = 0x040000;
+pub const ROM_MOVED_FROM: u32 = 0x400000;
+pub const ROM_START_HEX: &str = "0xf80000";
+pub const ROM_END_HEX: &str = "0xfc0000";
+
+// Debug hooks
+pub const DEBUG_START: u32 = 0xfc0000;
+pub const DEBUG_END: u32 = 0xfc0008;
+pub const DEBUG_SIZE: u32 = 0x000008;
+pub const DEBUG_MOVED_FROM: u32 = 0xf80000;
+pub const DEBUG_START_HEX: &str = "0xfc0000";
+pub const DEBUG_END_HEX: &str = "0xfc0008";
+
+// NCR 5380 SCSI controller
+pub const SCSI_START: u32 = 0xfc1000;
+pub const SCSI_END: u32 = 0xfc1280;
+pub const SCSI_SIZE: u32 = 0x000280;
+pub const SCSI_MOVED_FROM: u32 = 0x5ff000;
+pub const SCSI_START_HEX: &str = "0xfc1000";
+pub const SCSI_END_HEX: &str = "0xfc1280";
+
+// Z8530 SCC, reads
+pub const SCC_READ_START: u32 = 0xfc2000;
+pub const SCC_READ_END: u32 = 0xfc3000;
+pub const SCC_READ_SIZE: u32 = 0x001000;
+pub const SCC_READ_MOVED_FROM: u32 = 0x9ff000;
+pub const SCC_READ_START_HEX: &str = "0xfc2000";
+pub const SCC_READ_END_HEX: &str = "0xfc3000";
+
+// Z8530 SCC, writes
+pub const SCC_WRITE_START: u32 = 0xfc3000;
+pub const SCC_WRITE_END: u32 = 0xfc4000;
+pub const SCC_WRITE_SIZE: u32 = 0x001000;
+pub const SCC_WRITE_MOVED_FROM: u32 = 0xbff000;
+pub const SCC_WRITE_START_HEX: &str = "0xfc3000";
+pub const SCC_WRITE_END_HEX: &str = "0xfc4000";
+
+// IWM floppy controller
+pub const IWM_START: u32 = 0xfc4000;
+pub const IWM_END: u32 = 0xfc6000;
+pub const IWM_SIZE: u32 = 0x002000;
+pub const IWM_MOVED_FROM: u32 = 0xdfe000;
+pub const IWM_START_HEX: &str = "0xfc4000";
+pub const IWM_END_HEX: &str = "0xfc6000";
+
+// 6522 VIA
+pub const VIA_START: u32 = 0xfc6000;
+pub const VIA_END: u32 = 0xfc8000;
+pub const VIA_SIZE: u32 = 0x002000;
+pub const VIA_MOVED_FROM: u32 = 0xefe000;
+pub const VIA_START_HEX: &str = "0xfc6000";
+pub const VIA_END_HEX: &str = "0xfc8000";
+
+// Paravirtual floppy driver (patch rom --floppy-mailbox)
+pub const FLOPPY_MAILBOX_START: u32 = 0xfc8000;
+pub const FLOPPY_MAILBOX_END: u32 = 0xfc8010;
+pub const FLOPPY_MAILBOX_SIZE: u32 = 0x000010;
+pub const FLOPPY_MAILBOX_START_HEX: &str = "0xfc8000";
+pub const FLOPPY_MAILBOX_END_HEX: &str = "0xfc8010";
+
+// Paravirtual SCSI Manager (patch rom --scsi-mailbox)
+pub const SCSI_MAILBOX_START: u32 = 0xfc8010;
+pub const SCSI_MAILBOX_END: u32 = 0xfc8020;
+pub const SCSI_MAILBOX_SIZE: u32 = 0x000010;
+pub const SCSI_MAILBOX_START_HEX: &str = "0xfc8010";
+pub const SCSI_MAILBOX_END_HEX: &str = "0xfc8020";
+
+// Paravirtual VIA functions (patch rom --via-mailbox)
+pub const VIA_MAILBOX_START: u32 = 0xfc8020;
+pub const VIA_MAILBOX_END: u32 = 0xfc8040;
+pub const VIA_MAILBOX_SIZE: u32 = 0x000020;
+pub const VIA_MAILBOX_START_HEX: &str = "0xfc8020";
+pub const VIA_MAILBOX_END_HEX: &str = "0xfc8040";
+
+// The SE's hardware, moved: name, start and end.
+pub const IO_REGIONS: [(&str, u32, u32); 6] = [
+    ("DEBUG", DEBUG_START, DEBUG_END),
+    ("SCSI", SCSI_START, SCSI_END),
+    ("SCC_READ", SCC_READ_START, SCC_READ_END),
+    ("SCC_WRITE", SCC_WRITE_START, SCC_WRITE_END),
+    ("IWM", IWM_START, IWM_END),
+    ("VIA", VIA_START, VIA_END),
+];
+
+// The paravirtual devices' mailboxes, if patched in: name, start and end.
+pub const MAILBOXES: [(&str, u32, u32); 3] = [
+    ("FLOPPY_MAILBOX", FLOPPY_MAILBOX_START, FLOPPY_MAILBOX_END),
+    ("SCSI_MAILBOX", SCSI_MAILBOX_START, SCSI_MAILBOX_END),
+    ("VIA_MAILBOX", VIA_MAILBOX_START, VIA_MAILBOX_END),
+];
@@ -0,0 +1,409 @@
+//
+// 68000 disassembler
+//
+// Motorola syntax, for the emulator's traces and failure reports, and
+// the patch tool's reports. A-line traps are shown as "aline #$xxxx",
+// matching the Ghidra hack in the README.
+//
+
+struct Reader<F: Fn(u32) -> u16> {
+    pc: u32,
+    read_word: F,
+}
+
+impl<F: Fn(u32) -> u16> Reader<F> {
+    fn word(&mut self) -> u16 {
+        let w = (self.read_word)(self.pc);
+        self.pc = self.pc.wrapping_add(2);
+        w
+    }
+
+    fn long(&mut self) -> u32 {
+        let hi = self.word() as u32;
+        (hi << 16) | self.word() as u32
+    }
+}
+
+fn size_suffix(bits: u16) -> Option<&'static str> {
+    match bits & 3 {
+        0 => Some(".b"),
+        1 => Some(".w"),
+        2 => Some(".l"),
+        _ => None,
+    }
+}
+
+fn signed_hex(value: i32) -> String {
+    if value < 0 {
+        format!("-${:x}", -(value as i64))
+    } else {
+        format!("${:x}", value)
+    }
+}
+
+const CONDITIONS: [&str; 16] = [
+    "t", "f", "hi", "ls", "cc", "cs", "ne", "eq", "vc", "vs", "pl", "mi", "ge", "lt", "gt", "le",
+];
+
+// Decode an effective address. 'size' is the operand size in bytes,
+// for immediates.
+fn ea<F: Fn(u32) -> u16>(r: &mut Reader<F>, mode: u16, reg: u16, size: u32) -> String {
+    match mode & 7 {
+        0 => format!("d{}", reg),
+        1 => format!("a{}", reg),
+        2 => format!("(a{})", reg),
+        3 => format!("(a{})+", reg),
+        4 => format!("-(a{})", reg),
+        5 => format!("{}(a{})", signed_hex(r.word() as i16 as i32), reg),
+        6 => index(r, &format!("a{}", reg), 0),
+        _ => match reg {
+            0 => format!("(${:x}).w", r.word() as i16 as i32 as u32 & 0xffffff),
+            1 => format!("(${:x}).l", r.long()),
+            2 => {
+                let base = r.pc;
+                let disp = r.word() as i16 as i32;
+                format!("${:x}(pc)", base.wrapping_add(disp as u32) & 0xffffff)
+            }
+            3 => {
+                let base = r.pc;
+                index(r, "pc", base)
+            }
+            4 => match size {
+                1 => format!("#${:x}", r.word() & 0xff),
+                2 => format!("#${:x}", r.word()),
+                _ => format!("#${:x}", r.long()),
+            },
+            _ => "?".to_string(),
+        },
+    }
+}
+
+fn index<F: Fn(u32) -> u16>(r: &mut Reader<F>, base: &str, pc: u32) -> String {
+    let ext = r.word();
+    let kind = if ext & 0x8000 != 0 { "a" } else { "d" };
+    let size = if ext & 0x0800 != 0 { "l" } else { "w" };
+    let disp = ext as i8 as i32;
+    let reg = (ext >> 12) & 7;
+    if base == "pc" {
+        format!(
+            "${:x}(pc,{}{}.{})",
+            pc.wrapping_add(disp as u32) & 0xffffff,
+            kind,
+            reg,
+            size
+        )
+    } else {
+        format!("{}({},{}{}.{})", signed_hex(disp), base, kind, reg, size)
+    }
+}
+
+fn reg_list(mask: u16, reversed: bool) -> String {
+    let mut regs = Vec::new();
+    for i in 0..16 {
+        let bit = if reversed { 15 - i } else { i };
+        if mask & (1 << bit) != 0 {
+            regs.push(if i < 8 {
+                format!("d{}", i)
+            } else {
+                format!("a{}", i - 8)
+            });
+        }
+    }
+    regs.join("/")
+}
+
+// Disassemble the instruction at 'pc', returning the text and the
+// instruction length in bytes.
+pub fn disassemble(pc: u32, read_word: impl Fn(u32) -> u16) -> (String, u32) {
+    let mut r = Reader { pc, read_word };
+    let op = r.word();
+    let text = decode(&mut r, op).unwrap_or_else(|| format!("dc.w ${:04x}", op));
+    (text, r.pc.wrapping_sub(pc))
+}
+
+fn decode<F: Fn(u32) -> u16>(r: &mut Reader<F>, op: u16) -> Option<String> {
+    let mode = (op >> 3) & 7;
+    let reg = op & 7;
+    let reg9 = (op >> 9) & 7;
+    let size_bits = (op >> 6) & 3;
+    let bytes = |bits: u16| 1u32 << (bits & 3);
+
+    Some(match op >> 12 {
+        0x0 => {
+            if op & 0x0100 != 0 {
+                if mode == 1 {
+                    let disp = signed_hex(r.word() as i16 as i32);
+                    let (sz, to_mem) = match (op >> 6) & 3 {
+                        0 => ("w", false),
+                        1 => ("l", false),
+                        2 => ("w", true),
+                        _ => ("l", true),
+                    };
+                    return Some(if to_mem {
+                        format!("movep.{} d{},{}(a{})", sz, reg9, disp, reg)
+                    } else {
+                        format!("movep.{} {}(a{}),d{}", sz, disp, reg, reg9)
+                    });
+                }
+                let name = ["btst", "bchg", "bclr", "bset"][size_bits as usize];
+                return Some(format!("{} d{},{}", name, reg9, ea(r, mode, reg, 1)));
+            }
+            if op & 0x0f00 == 0x0800 {
+                let name = ["btst", "bchg", "bclr", "bset"][size_bits as usize];
+                let bit = r.word() & 0xff;
+                return Some(format!("{} #{},{}", name, bit, ea(r, mode, reg, 1)));
+            }
+            let name = match reg9 {
+                0 => "ori",
+                1 => "andi",
+                2 => "subi",
+                3 => "addi",
+                5 => "eori",
+                6 => "cmpi",
+                _ => return None,
+            };
+            if op & 0x3f == 0x3c {
+                return match size_bits {
+                    0 => Some(format!("{} #${:x},ccr", name, r.word() & 0xff)),
+                    1 => Some(format!("{} #${:x},sr", name, r.word())),
+                    _ => None,
+                };
+            }
+            let sz = size_suffix(size_bits)?;
+            let imm = ea(r, 7, 4, bytes(size_bits));
+            format!("{}{} {},{}", name, sz, imm, ea(r, mode, reg, 0))
+        }
+        0x1..=0x3 => {
+            let (sz, n) = match op >> 12 {
+                1 => (".b", 1),
+                2 => (".l", 4),
+                _ => (".w", 2),
+            };
+            let src = ea(r, mode, reg, n);
+            let dst_mode = (op >> 6) & 7;
+            if dst_mode == 1 {
+                format!("movea{} {},a{}", sz, src, reg9)
+            } else {
+                format!("move{} {},{}", sz, src, ea(r, dst_mode, reg9, n))
+            }
+        }
+        0x4 => decode_4(r, op)?,
+        0x5 => {
+            if size_bits == 3 {
+                let cond = CONDITIONS[((op >> 8) & 0xf) as usize];
+                if mode == 1 {
+                    let base = r.pc;
+                    let disp = r.word() as i16 as i32;
+                    format!(
+                        "db{} d{},${:x}",
+                        cond,
+                        reg,
+                        base.wrapping_add(disp as u32) & 0xffffff
+                    )
+                } else {
+                    format!("s{} {}", cond, ea(r, mode, reg, 1))
+                }
+            } else {
+                let data = if reg9 == 0 { 8 } else { reg9 };
+                let name = if op & 0x0100 != 0 { "subq" } else { "addq" };
+                format!(
+                    "{}{} #{},{}",
+                    name,
+                    size_suffix(size_bits)?,
+                    data,
+                    ea(r, mode, reg, 0)
+                )
+            }
+        }
+        0x6 => {
+            let base = r.pc;
+            let mut disp = op as i8 as i32;
+            let mut sz = ".s";
+            if disp == 0 {
+                disp = r.word() as i16 as i32;
+                sz = "";
+            }
+            let name = match (op >> 8) & 0xf {
+                0 => "bra".to_string(),
+                1 => "bsr".to_string(),
+                c => format!("b{}", CONDITIONS[c as usize]),
+            };
+            format!(
+                "{}{} ${:x}",
+                name,
+                sz,
+                base.wrapping_add(disp as u32) & 0xffffff
+            )
+        }
+        0x7 => format!("moveq #{},d{}", signed_hex(op as i8 as i32), reg9),
+        0x8 => match (size_bits, op & 0x0100 != 0) {
+            (3, false) => format!("divu.w {},d{}", ea(r, mode, reg, 2), reg9),
+            (3, true) => format!("divs.w {},d{}", ea(r, mode, reg, 2), reg9),
+            (0, true) if mode < 2 => bcd_form("sbcd", op),
+            _ => logic_form(r, "or", op)?,
+        },
+        0x9 | 0xd => {
+            let name = if op >> 12 == 0x9 { "sub" } else { "add" };
+            match (size_bits, op & 0x0100 != 0) {
+                (3, false) => format!("{}a.w {},a{}", name, ea(r, mode, reg, 2), reg9),
+                (3, true) => format!("{}a.l {},a{}", name, ea(r, mode, reg, 4), reg9),
+                (_, true) if mode < 2 => {
+                    let sz = size_suffix(size_bits)?;
+                    if mode == 0 {
+                        format!("{}x{} d{},d{}", name, sz, reg, reg9)
+                    } else {
+                        format!("{}x{} -(a{}),-(a{})", name, sz, reg, reg9)
+                    }
+                }
+                _ => logic_form(r, name, op)?,
+            }
+        }
+        0xa => format!("aline #${:04x}", op),
+        0xb => match (size_bits, op & 0x0100 != 0) {
+            (3, false) => format!("cmpa.w {},a{}", ea(r, mode, reg, 2), reg9),
+            (3, true) => format!("cmpa.l {},a{}", ea(r, mode, reg, 4), reg9),
+            (_, false) => format!(
+                "cmp{} {},d{}",
+                size_suffix(size_bits)?,
+                ea(r, mode, reg, bytes(size_bits)),
+                reg9
+            ),
+            (_, true) if mode == 1 => {
+                format!("cmpm{} (a{})+,(a{})+", size_suffix(size_bits)?, reg, reg9)
+            }
+            _ => logic_form(r, "eor", op)?,
+        },
+        0xc => match ((op >> 6) & 7, mode) {
+            (3, _) => format!("mulu.w {},d{}", ea(r, mode, reg, 2), reg9),
+            (7, _) => format!("muls.w {},d{}", ea(r, mode, reg, 2), reg9),
+            (4, 0) | (4, 1) => bcd_form("abcd", op),
+            (5, 0) => format!("exg d{},d{}", reg9, reg),
+            (5, 1) => format!("exg a{},a{}", reg9, reg),
+            (6, 1) => format!("exg d{},a{}", reg9, reg),
+            _ => logic_form(r, "and", op)?,
+        },
+        0xe => {
+            let names = ["as", "ls", "rox", "ro"];
+            let dir = if op & 0x0100 != 0 { "l" } else { "r" };
+            if size_bits == 3 {
+                let name = names[((op >> 9) & 3) as usize];
+                format!("{}{}.w {}", name, dir, ea(r, mode, reg, 2))
+            } else {
+                let name = names[((op >> 3) & 3) as usize];
+                let count = if op & 0x0020 != 0 {
+                    format!("d{}", reg9)
+                } else {
+                    format!("#{}", if reg9 == 0 { 8 } else { reg9 })
+                };
+                format!(
+                    "{}{}{} {},d{}",
+                    name,
+                    dir,
+                    size_suffix(size_bits)?,
+                    count,
+                    reg
+                )
+            }
+        }
+        _ => format!("fline #${:04x}", op),
+    })
+}
+
+fn bcd_form(name: &str, op: u16) -> String {
+    let (rx, ry) = ((op >> 9) & 7, op & 7);
+    if op & 0x0008 != 0 {
+        format!("{} -(a{}),-(a{})", name, ry, rx)
+    } else {
+        format!("{} d{},d{}", name, ry, rx)
+    }
+}
+
+// The common "<ea>,Dn" / "Dn,<ea>" forms of AND, OR, EOR, ADD, SUB.
+fn logic_form<F: Fn(u32) -> u16>(r: &mut Reader<F>, name: &str, op: u16) -> Option<String> {
+    let size_bits = (op >> 6) & 3;
+    let sz = size_suffix(size_bits)?;
+    let reg9 = (op >> 9) & 7;
+    let operand = ea(r, (op >> 3) & 7, op & 7, 1 << size_bits);
+    Some(if op & 0x0100 != 0 {
+        format!("{}{} d{},{}", name, sz, reg9, operand)
+    } else {
+        format!("{}{} {},d{}", name, sz, operand, reg9)
+    })
+}
+
+fn decode_4<F: Fn(u32) -> u16>(r: &mut Reader<F>, op: u16) -> Option<String> {
+    let mode = (op >> 3) & 7;
+    let reg = op & 7;
+    let reg9 = (op >> 9) & 7;
+
+    if op & 0x01c0 == 0x01c0 {
+        return Some(format!("lea {},a{}", ea(r, mode, reg, 4), reg9));
+    }
+    if op & 0x01c0 == 0x0180 {
+        return Some(format!("chk.w {},d{}", ea(r, mode, reg, 2), reg9));
+    }
+
+    let simple = match op {
+        0x4afc => Some("illegal"),
+        0x4e70 => Some("reset"),
+        0x4e71 => Some("nop"),
+        0x4e73 => Some("rte"),
+        0x4e75 => Some("rts"),
+        0x4e76 => Some("trapv"),
+        0x4e77 => Some("rtr"),
+        _ => None,
+    };
+    if let Some(name) = simple {
+        return Some(name.to_string());
+    }
+
+    Some(match op & 0xfff8 {
+        0x4e40 | 0x4e48 => format!("trap #{}", op & 0xf),
+        0x4e50 => format!("link a{},#{}", reg, signed_hex(r.word() as i16 as i32)),
+        0x4e58 => format!("unlk a{}", reg),
+        0x4e60 => format!("move.l a{},usp", reg),
+        0x4e68 => format!("move.l usp,a{}", reg),
+        0x4840 => format!("swap d{}", reg),
+        0x4880 => format!("ext.w d{}", reg),
+        0x48c0 => format!("ext.l d{}", reg),
+        _ if op == 0x4e72 => format!("stop #${:04x}", r.word()),
+        _ => match op & 0xffc0 {
+            0x40c0 => format!("move.w sr,{}", ea(r, mode, reg, 2)),
+            0x44c0 => format!("move.w {},ccr", ea(r, mode, reg, 2)),
+            0x46c0 => format!("move.w {},sr", ea(r, mode, reg, 2)),
+            0x4800 => format!("nbcd {}", ea(r, mode, reg, 1)),
+            0x4840 => format!("pea {}", ea(r, mode, reg, 4)),
+            0x4ac0 => format!("tas {}", ea(r, mode, reg, 1)),
+            0x4e80 => format!("jsr {}", ea(r, mode, reg, 4)),
+            0x4ec0 => format!("jmp {}", ea(r, mode, reg, 4)),
+            0x4880 | 0x48c0 | 0x4c80 | 0x4cc0 => {
+                let sz = if op & 0x0040 != 0 { "l" } else { "w" };
+                let mask = r.word();
+                if op & 0x0400 != 0 {
+                    let src = ea(r, mode, reg, 0);
+                    format!("movem.{} {},{}", sz, src, reg_list(mask, false))
+                } else {
+                    let dst = ea(r, mode, reg, 0);
+                    format!("movem.{} {},{}", sz, reg_list(mask, mode == 4), dst)
+                }
+            }
+            _ => {
+                let name = match op & 0xff00 {
+                    0x4000 => "negx",
+                    0x4200 => "clr",
+                    0x4400 => "neg",
+                    0x4600 => "not",
+                    0x4a00 => "tst",
+                    _ => return None,
+                };
+                let size_bits = (op >> 6) & 3;
+                format!(
+                    "{}{} {}",
+                    name,
+                    size_suffix(size_bits)?,
+                    ea(r, mode, reg, 0)
+                )
+            }
+        },
+    })
+}
@@ -0,0 +1,50 @@
+//
+// DiskCopy 4.2 images
+//
+// Disk images may be raw, or wrapped in DiskCopy 4.2's header, which
+// has the data's size and a checksum over it. This finds the data in
+// an image and keeps the checksum up to date, for the emulator's disks
+// and the patch tool's HFS code.
+//
+
+// DiskCopy 4.2 header fields.
+const HEADER_SIZE: usize = 0x54;
+const DATA_SIZE: usize = 0x40;
+const TAG_SIZE: usize = 0x44;
+pub const DATA_CHECKSUM: usize = 0x48;
+const MAGIC: usize = 0x52;
+
+fn read_long(data: &[u8], addr: usize) -> u32 {
+    u32::from_be_bytes(data[addr..addr + 4].try_into().unwrap())
+}
+
+// If the image is in DiskCopy 4.2 format, the offset and size of the
+// data within it.
+pub fn data(image: &[u8]) -> Option<(usize, usize)> {
+    if image.len() < HEADER_SIZE || image[MAGIC..MAGIC + 2] != [0x01, 0x00] {
+        return None;
+    }
+    let data_size = read_long(image, DATA_SIZE) as usize;
+    let tag_size = read_long(image, TAG_SIZE) as usize;
+    if HEADER_SIZE + data_size + tag_size != image.len() {
+        return None;
+    }
+    Some((HEADER_SIZE, data_size))
+}
+
+// DiskCopy's checksum: add each word, rotating right after each.
+pub fn checksum(data: &[u8]) -> u32 {
+    data.chunks(2).fold(0, |sum, word| {
+        sum.wrapping_add(u16::from_be_bytes([word[0], word[1]]) as u32)
+            .rotate_right(1)
+    })
+}
+
+// Bring a DiskCopy 4.2 image's data checksum up to date after
+// patching. Raw images are left alone.
+pub fn update_checksum(image: &mut [u8]) {
+    if let Some((offset, size)) = data(image) {
+        let checksum = checksum(&image[offset..offset + size]);
+        image[DATA_CHECKSUM..DATA_CHECKSUM + 4].copy_from_slice(&checksum.to_be_bytes());
+    }
+}
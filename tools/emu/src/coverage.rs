@@ -0,0 +1,261 @@
+//
+// Code coverage
+//
+// Records which parts of the ROM get executed, and which subroutines
+// are reached by calls and computed jumps without anything in the ROM
+// referring to them statically, so that we can see how they're really
+// reached.
+//
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::cpu::VEC_AUTOVECTOR_BASE;
+use crate::machine::{Machine, ROM_BASE, ROM_SIZE};
+use crate::symbols::Symbols;
+
+fn in_rom(addr: u32) -> bool {
+    (ROM_BASE..ROM_BASE + ROM_SIZE).contains(&addr)
+}
+
+fn read_word(rom: &[u8], offset: usize) -> u16 {
+    ((rom[offset] as u16) << 8) | rom[offset + 1] as u16
+}
+
+fn read_long(rom: &[u8], offset: usize) -> u32 {
+    ((read_word(rom, offset) as u32) << 16) | read_word(rom, offset + 2) as u32
+}
+
+// Calls, and jumps whose target is computed at run time (through an
+// address register or a PC-relative index), which is how jump tables
+// are implemented.
+fn is_call_or_computed_jump(opcode: u16) -> bool {
+    if opcode & 0xff00 == 0x6100 {
+        // BSR
+        return true;
+    }
+    if opcode & 0xffc0 == 0x4e80 {
+        // JSR
+        return true;
+    }
+    if opcode & 0xffc0 == 0x4ec0 {
+        // JMP, other than absolute and d16(PC).
+        let mode = (opcode >> 3) & 7;
+        let reg = opcode & 7;
+        return mode != 7 || reg == 3;
+    }
+    false
+}
+
+pub struct Coverage {
+    // One flag per ROM byte, set for every byte of each executed
+    // instruction.
+    executed: Vec<bool>,
+    // Targets of calls and computed jumps, with the PCs that reached
+    // them.
+    targets: BTreeMap<u32, BTreeSet<u32>>,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage {
+            executed: vec![false; ROM_SIZE as usize],
+            targets: BTreeMap::new(),
+        }
+    }
+
+    // Harness observer.
+    pub fn observe(&mut self, m: &Machine, pc: u32, vector: Option<u8>) {
+        // Taking an interrupt or sitting in STOP doesn't execute
+        // anything.
+        let interrupted = matches!(vector, Some(v) if (VEC_AUTOVECTOR_BASE..VEC_AUTOVECTOR_BASE + 8).contains(&v));
+        if interrupted || m.cpu.stopped || !in_rom(pc) {
+            return;
+        }
+
+        let offset = (pc - ROM_BASE) as usize;
+        if !self.executed[offset] {
+            let (_, len) = disasm::disassemble(pc, |addr| m.mem.peek_word(addr));
+            let end = (offset + len as usize).min(ROM_SIZE as usize);
+            self.executed[offset..end]
+                .iter_mut()
+                .for_each(|b| *b = true);
+        }
+
+        if vector.is_none() && is_call_or_computed_jump(read_word(m.mem.rom(), offset)) {
+            self.targets.entry(m.cpu.pc).or_default().insert(pc);
+        }
+    }
+
+    // Contiguous executed ranges of ROM offsets, end exclusive.
+    fn ranges(&self) -> Vec<(usize, usize)> {
+        let mut ranges = Vec::new();
+        let mut start = None;
+        for (offset, executed) in self.executed.iter().chain(&[false]).enumerate() {
+            match (start, *executed) {
+                (None, true) => start = Some(offset),
+                (Some(s), false) => {
+                    ranges.push((s, offset));
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        ranges
+    }
+
+    // Bitmap of executed ROM bytes, most significant bit first.
+    pub fn write_bitmap(&self, path: &Path) -> anyhow::Result<()> {
+        let bitmap = self
+            .executed
+            .chunks(8)
+            .map(|bits| bits.iter().fold(0u8, |acc, bit| (acc << 1) | *bit as u8))
+            .collect::<Vec<_>>();
+        fs::write(path, bitmap).with_context(|| format!("Couldn't write {}", path.display()))?;
+        Ok(())
+    }
+
+    // Called or jumped-to ROM addresses that nothing in the ROM refers
+    // to, with the PCs that reached them.
+    pub fn unreferenced(&self, rom: &[u8]) -> Vec<(u32, &BTreeSet<u32>)> {
+        let references = static_references(rom);
+        self.targets
+            .iter()
+            .filter(|(target, _)| in_rom(**target) && !references.contains(target))
+            .map(|(target, from)| (*target, from))
+            .collect()
+    }
+
+    pub fn print_report(&self, rom: &[u8], symbols: &Symbols) {
+        let executed = self.executed.iter().filter(|b| **b).count();
+        println!(
+            "Executed {} of {} ROM bytes ({:.1}%) in {} ranges",
+            executed,
+            ROM_SIZE,
+            100.0 * executed as f64 / ROM_SIZE as f64,
+            self.ranges().len()
+        );
+
+        println!("Functions reached with no static reference:");
+        for (target, from) in self.unreferenced(rom) {
+            println!(
+                "  0x{:06x} ({}) from {}",
+                target,
+                symbols.describe(target),
+                from.iter()
+                    .map(|pc| format!("0x{:06x}", pc))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+    }
+
+    pub fn write_script(&self, path: &Path, rom: &[u8]) -> anyhow::Result<()> {
+        let mut script = String::from(SCRIPT_PRELUDE);
+        for (start, end) in self.ranges() {
+            writeln!(
+                script,
+                "cover(0x{:06x}, 0x{:06x})",
+                ROM_BASE as usize + start,
+                ROM_BASE as usize + end - 1
+            )?;
+        }
+        script.push('\n');
+        for (target, from) in self.unreferenced(rom) {
+            writeln!(
+                script,
+                "unreferenced(0x{:06x}, \"Reached with no static reference, from {}\")",
+                target,
+                from.iter()
+                    .map(|pc| format!("0x{:06x}", pc))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )?;
+        }
+        fs::write(path, script).with_context(|| format!("Couldn't write {}", path.display()))?;
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// Static references.
+//
+// A deliberately generous scan: every long in the ROM that looks like
+// a ROM address counts (as do pre-relocation ones), as does the target
+// of anything at an even offset that decodes as a PC-relative branch,
+// call, jump, LEA or PEA, whether or not it's really code. Add the
+// trap table, and anything left over is reached in some way we don't
+// understand yet.
+//
+
+fn static_references(rom: &[u8]) -> HashSet<u32> {
+    let mut refs = HashSet::new();
+
+    for addr in extract_traps::Decoder::new(rom) {
+        refs.insert(addr - extract_traps::ROM_BASE + ROM_BASE);
+    }
+
+    for offset in (0..rom.len() - 3).step_by(2) {
+        let long = read_long(rom, offset) & 0xffffff;
+        if in_rom(long) {
+            refs.insert(long);
+        }
+        let orig_base = extract_traps::ROM_BASE;
+        if (orig_base..orig_base + ROM_SIZE).contains(&long) {
+            refs.insert(long - orig_base + ROM_BASE);
+        }
+
+        let opcode = read_word(rom, offset);
+        let disp16 = read_word(rom, offset + 2) as i16 as i32;
+        let disp = match opcode {
+            // Bcc, BRA and BSR.
+            _ if opcode & 0xf000 == 0x6000 => match opcode & 0xff {
+                0 => Some(disp16),
+                // 32-bit displacement, 68020 and later only.
+                0xff => None,
+                d => Some(d as i8 as i32),
+            },
+            // JSR, JMP and PEA d16(PC).
+            0x4eba | 0x4efa | 0x487a => Some(disp16),
+            // LEA d16(PC),An.
+            _ if opcode & 0xf1ff == 0x41fa => Some(disp16),
+            // DBcc.
+            _ if opcode & 0xf0f8 == 0x50c8 => Some(disp16),
+            _ => None,
+        };
+        if let Some(disp) = disp {
+            let target = (ROM_BASE + offset as u32 + 2).wrapping_add(disp as u32);
+            refs.insert(target);
+        }
+    }
+
+    refs
+}
+
+////////////////////////////////////////////////////////////////////////
+// Ghidra script.
+//
+// Colours executed code, and bookmarks the functions reached with no
+// static reference. To be run against the patched ROM.
+//
+
+const SCRIPT_PRELUDE: &str = r#"# Colours code executed while running the ROM.
+# Generated by the "emu" tool.
+#@category Mac ROM
+
+from java.awt import Color
+from ghidra.program.model.address import AddressSet
+
+EXECUTED = Color(0xc0, 0xf0, 0xc0)
+
+def cover(start, end):
+    setBackgroundColor(AddressSet(toAddr(start), toAddr(end)), EXECUTED)
+
+def unreferenced(addr, text):
+    createBookmark(toAddr(addr), "Unreferenced function", text)
+
+"#;
@@ -0,0 +1,1820 @@
+//
+// 68000 CPU core
+//
+// A straightforward interpreter for the plain 68000 instruction set,
+// which is all the Mac SE ROM needs. There's no attempt at cycle
+// accuracy, and the trace bit is ignored.
+//
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Size {
+    Byte,
+    Word,
+    Long,
+}
+
+impl Size {
+    pub fn bytes(self) -> u32 {
+        match self {
+            Size::Byte => 1,
+            Size::Word => 2,
+            Size::Long => 4,
+        }
+    }
+
+    fn mask(self) -> u32 {
+        match self {
+            Size::Byte => 0xff,
+            Size::Word => 0xffff,
+            Size::Long => 0xffffffff,
+        }
+    }
+
+    fn msb(self) -> u32 {
+        match self {
+            Size::Byte => 0x80,
+            Size::Word => 0x8000,
+            Size::Long => 0x80000000,
+        }
+    }
+
+    fn bits(self) -> u32 {
+        self.bytes() * 8
+    }
+
+    // The usual 2-bit size field.
+    fn decode(bits: u16) -> Option<Size> {
+        match bits & 3 {
+            0 => Some(Size::Byte),
+            1 => Some(Size::Word),
+            2 => Some(Size::Long),
+            _ => None,
+        }
+    }
+}
+
+fn sign_extend(value: u32, size: Size) -> u32 {
+    match size {
+        Size::Byte => value as u8 as i8 as i32 as u32,
+        Size::Word => value as u16 as i16 as i32 as u32,
+        Size::Long => value,
+    }
+}
+
+// The CPU's view of the world. Word and long accesses are always
+// aligned; the CPU raises address errors itself.
+pub trait Bus {
+    fn read_byte(&mut self, addr: u32) -> u8;
+    fn write_byte(&mut self, addr: u32, value: u8);
+
+    fn read_word(&mut self, addr: u32) -> u16 {
+        ((self.read_byte(addr) as u16) << 8) | self.read_byte(addr.wrapping_add(1)) as u16
+    }
+
+    fn write_word(&mut self, addr: u32, value: u16) {
+        self.write_byte(addr, (value >> 8) as u8);
+        self.write_byte(addr.wrapping_add(1), value as u8);
+    }
+
+    // Highest pending interrupt level, 0 if none.
+    fn irq_level(&mut self) -> u8;
+
+    // The RESET instruction was executed.
+    fn reset_devices(&mut self) {}
+}
+
+// Exception vectors we raise.
+pub const VEC_BUS_ERROR: u8 = 2;
+pub const VEC_ADDRESS_ERROR: u8 = 3;
+pub const VEC_ILLEGAL: u8 = 4;
+pub const VEC_ZERO_DIVIDE: u8 = 5;
+pub const VEC_CHK: u8 = 6;
+pub const VEC_TRAPV: u8 = 7;
+pub const VEC_PRIVILEGE: u8 = 8;
+pub const VEC_LINE_A: u8 = 10;
+pub const VEC_LINE_F: u8 = 11;
+pub const VEC_AUTOVECTOR_BASE: u8 = 24;
+pub const VEC_TRAP_BASE: u8 = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Exception {
+    AddressError { addr: u32, write: bool },
+    Vector(u8),
+}
+
+type Result<T> = std::result::Result<T, Exception>;
+
+// A resolved effective address.
+#[derive(Clone, Copy, Debug)]
+enum Ea {
+    D(usize),
+    A(usize),
+    Mem(u32),
+    Imm(u32),
+}
+
+// Condition code bits.
+const C: u16 = 0x01;
+const V: u16 = 0x02;
+const Z: u16 = 0x04;
+const N: u16 = 0x08;
+const X: u16 = 0x10;
+const S: u16 = 0x2000;
+
+#[derive(Clone, Debug, Default)]
+pub struct Cpu {
+    pub d: [u32; 8],
+    // a[7] is the active stack pointer.
+    pub a: [u32; 8],
+    pub pc: u32,
+    pub sr: u16,
+    // The inactive stack pointer: USP in supervisor mode, SSP in user
+    // mode.
+    other_sp: u32,
+    // Start of the instruction being executed.
+    insn_pc: u32,
+    opcode: u16,
+    pub stopped: bool,
+    pub halted: bool,
+}
+
+impl Cpu {
+    pub fn new() -> Cpu {
+        Cpu::default()
+    }
+
+    // Reset the CPU, loading the initial SSP and PC from the vectors.
+    pub fn reset(&mut self, bus: &mut impl Bus) {
+        *self = Cpu::default();
+        self.sr = 0x2700;
+        self.a[7] = read_long_raw(bus, 0);
+        self.pc = read_long_raw(bus, 4);
+    }
+
+    pub fn usp(&self) -> u32 {
+        if self.supervisor() {
+            self.other_sp
+        } else {
+            self.a[7]
+        }
+    }
+
+    pub fn ssp(&self) -> u32 {
+        if self.supervisor() {
+            self.a[7]
+        } else {
+            self.other_sp
+        }
+    }
+
+    fn supervisor(&self) -> bool {
+        self.sr & S != 0
+    }
+
+    pub fn set_sr(&mut self, sr: u16) {
+        let sr = sr & 0xa71f;
+        if (sr ^ self.sr) & S != 0 {
+            std::mem::swap(&mut self.a[7], &mut self.other_sp);
+        }
+        self.sr = sr;
+    }
+
+    fn set_ccr(&mut self, ccr: u16) {
+        self.sr = (self.sr & 0xff00) | (ccr & 0x1f);
+    }
+
+    fn flag(&self, f: u16) -> bool {
+        self.sr & f != 0
+    }
+
+    fn set_flag(&mut self, f: u16, value: bool) {
+        if value {
+            self.sr |= f;
+        } else {
+            self.sr &= !f;
+        }
+    }
+
+    // Execute one instruction, or take a pending interrupt. Returns
+    // the vector number of any exception taken.
+    pub fn step(&mut self, bus: &mut impl Bus) -> Option<u8> {
+        if self.halted {
+            return None;
+        }
+
+        let level = bus.irq_level();
+        let mask = ((self.sr >> 8) & 7) as u8;
+        if level > mask || level == 7 {
+            self.stopped = false;
+            let vector = VEC_AUTOVECTOR_BASE + level;
+            self.insn_pc = self.pc;
+            self.take_exception(bus, Exception::Vector(vector));
+            self.sr = (self.sr & !0x0700) | ((level as u16) << 8);
+            return Some(vector);
+        }
+
+        if self.stopped {
+            return None;
+        }
+
+        self.insn_pc = self.pc;
+        match self.execute(bus) {
+            Ok(()) => None,
+            Err(e) => {
+                self.take_exception(bus, e);
+                Some(match e {
+                    Exception::AddressError { .. } => VEC_ADDRESS_ERROR,
+                    Exception::Vector(v) => v,
+                })
+            }
+        }
+    }
+
+    ////////////////////////////////////////////////////////////////////
+    // Exceptions.
+    //
+
+    fn take_exception(&mut self, bus: &mut impl Bus, e: Exception) {
+        let old_sr = self.sr;
+        self.set_sr((self.sr | S) & !0x8000);
+
+        match self.push_frame(bus, e, old_sr) {
+            Ok(vector) => {
+                let target = read_long_raw(bus, vector as u32 * 4);
+                if target & 1 != 0 {
+                    self.halted = true;
+                }
+                self.pc = target;
+            }
+            // Fault while processing a fault.
+            Err(_) => self.halted = true,
+        }
+    }
+
+    fn push_frame(&mut self, bus: &mut impl Bus, e: Exception, old_sr: u16) -> Result<u8> {
+        match e {
+            Exception::AddressError { addr, write } => {
+                // Group 0 frame: PC, SR, instruction register, access
+                // address, and access type.
+                self.push(bus, self.pc, Size::Long)?;
+                self.push(bus, old_sr as u32, Size::Word)?;
+                self.push(bus, self.opcode as u32, Size::Word)?;
+                self.push(bus, addr, Size::Long)?;
+                let access = if write { 0x05 } else { 0x15 };
+                self.push(bus, access, Size::Word)?;
+                Ok(VEC_ADDRESS_ERROR)
+            }
+            Exception::Vector(vector) => {
+                // Faults restart the instruction, traps continue after
+                // it.
+                let pc = match vector {
+                    VEC_ILLEGAL | VEC_PRIVILEGE | VEC_LINE_A | VEC_LINE_F => self.insn_pc,
+                    _ => self.pc,
+                };
+                self.push(bus, pc, Size::Long)?;
+                self.push(bus, old_sr as u32, Size::Word)?;
+                Ok(vector)
+            }
+        }
+    }
+
+    ////////////////////////////////////////////////////////////////////
+    // Memory access.
+    //
+
+    fn read(&mut self, bus: &mut impl Bus, addr: u32, size: Size) -> Result<u32> {
+        let addr = addr & 0xffffff;
+        if size != Size::Byte && addr & 1 != 0 {
+            return Err(Exception::AddressError { addr, write: false });
+        }
+        Ok(match size {
+            Size::Byte => bus.read_byte(addr) as u32,
+            Size::Word => bus.read_word(addr) as u32,
+            Size::Long => {
+                ((bus.read_word(addr) as u32) << 16) | bus.read_word((addr + 2) & 0xffffff) as u32
+            }
+        })
+    }
+
+    fn write(&mut self, bus: &mut impl Bus, addr: u32, size: Size, value: u32) -> Result<()> {
+        let addr = addr & 0xffffff;
+        if size != Size::Byte && addr & 1 != 0 {
+            return Err(Exception::AddressError { addr, write: true });
+        }
+        match size {
+            Size::Byte => bus.write_byte(addr, value as u8),
+            Size::Word => bus.write_word(addr, value as u16),
+            Size::Long => {
+                bus.write_word(addr, (value >> 16) as u16);
+                bus.write_word((addr + 2) & 0xffffff, value as u16);
+            }
+        }
+        Ok(())
+    }
+
+    fn fetch_word(&mut self, bus: &mut impl Bus) -> Result<u16> {
+        let value = self.read(bus, self.pc, Size::Word)? as u16;
+        self.pc = self.pc.wrapping_add(2);
+        Ok(value)
+    }
+
+    fn fetch_long(&mut self, bus: &mut impl Bus) -> Result<u32> {
+        let value = self.read(bus, self.pc, Size::Long)?;
+        self.pc = self.pc.wrapping_add(4);
+        Ok(value)
+    }
+
+    fn push(&mut self, bus: &mut impl Bus, value: u32, size: Size) -> Result<()> {
+        self.a[7] = self.a[7].wrapping_sub(size.bytes());
+        self.write(bus, self.a[7], size, value)
+    }
+
+    fn pop(&mut self, bus: &mut impl Bus, size: Size) -> Result<u32> {
+        let value = self.read(bus, self.a[7], size)?;
+        self.a[7] = self.a[7].wrapping_add(size.bytes());
+        Ok(value)
+    }
+
+    ////////////////////////////////////////////////////////////////////
+    // Effective addresses.
+    //
+
+    // Brief extension word indexed addressing.
+    fn index(&mut self, bus: &mut impl Bus, base: u32) -> Result<u32> {
+        let ext = self.fetch_word(bus)?;
+        let reg = ((ext >> 12) & 7) as usize;
+        let mut index = if ext & 0x8000 != 0 {
+            self.a[reg]
+        } else {
+            self.d[reg]
+        };
+        if ext & 0x0800 == 0 {
+            index = sign_extend(index, Size::Word);
+        }
+        let disp = sign_extend(ext as u32, Size::Byte);
+        Ok(base.wrapping_add(index).wrapping_add(disp))
+    }
+
+    fn ea(&mut self, bus: &mut impl Bus, mode: u16, reg: u16, size: Size) -> Result<Ea> {
+        let reg = reg as usize;
+        Ok(match mode {
+            0 => Ea::D(reg),
+            1 => Ea::A(reg),
+            2 => Ea::Mem(self.a[reg]),
+            3 => {
+                let addr = self.a[reg];
+                self.a[reg] = addr.wrapping_add(self.inc(reg, size));
+                Ea::Mem(addr)
+            }
+            4 => {
+                self.a[reg] = self.a[reg].wrapping_sub(self.inc(reg, size));
+                Ea::Mem(self.a[reg])
+            }
+            5 => {
+                let disp = sign_extend(self.fetch_word(bus)? as u32, Size::Word);
+                Ea::Mem(self.a[reg].wrapping_add(disp))
+            }
+            6 => Ea::Mem(self.index(bus, self.a[reg])?),
+            7 => match reg {
+                0 => Ea::Mem(sign_extend(self.fetch_word(bus)? as u32, Size::Word)),
+                1 => Ea::Mem(self.fetch_long(bus)?),
+                2 => {
+                    let base = self.pc;
+                    let disp = sign_extend(self.fetch_word(bus)? as u32, Size::Word);
+                    Ea::Mem(base.wrapping_add(disp))
+                }
+                3 => {
+                    let base = self.pc;
+                    Ea::Mem(self.index(bus, base)?)
+                }
+                4 => Ea::Imm(match size {
+                    Size::Byte => self.fetch_word(bus)? as u32 & 0xff,
+                    Size::Word => self.fetch_word(bus)? as u32,
+                    Size::Long => self.fetch_long(bus)?,
+                }),
+                _ => return Err(Exception::Vector(VEC_ILLEGAL)),
+            },
+            _ => unreachable!(),
+        })
+    }
+
+    // Post-increment/pre-decrement step. The stack pointer is kept
+    // word-aligned.
+    fn inc(&self, reg: usize, size: Size) -> u32 {
+        if reg == 7 && size == Size::Byte {
+            2
+        } else {
+            size.bytes()
+        }
+    }
+
+    fn read_ea(&mut self, bus: &mut impl Bus, ea: Ea, size: Size) -> Result<u32> {
+        Ok(match ea {
+            Ea::D(r) => self.d[r] & size.mask(),
+            Ea::A(r) => self.a[r] & size.mask(),
+            Ea::Mem(addr) => self.read(bus, addr, size)?,
+            Ea::Imm(value) => value,
+        })
+    }
+
+    fn write_ea(&mut self, bus: &mut impl Bus, ea: Ea, size: Size, value: u32) -> Result<()> {
+        match ea {
+            Ea::D(r) => self.d[r] = (self.d[r] & !size.mask()) | (value & size.mask()),
+            Ea::A(r) => self.a[r] = sign_extend(value, size),
+            Ea::Mem(addr) => self.write(bus, addr, size, value)?,
+            Ea::Imm(_) => return Err(Exception::Vector(VEC_ILLEGAL)),
+        }
+        Ok(())
+    }
+
+    // Decode the effective address in the bottom 6 bits of the opcode.
+    fn ea_low(&mut self, bus: &mut impl Bus, size: Size) -> Result<Ea> {
+        let op = self.opcode;
+        self.ea(bus, (op >> 3) & 7, op & 7, size)
+    }
+
+    // Address-only effective address, for LEA, PEA, JMP etc.
+    fn control_addr(&mut self, bus: &mut impl Bus) -> Result<u32> {
+        match self.ea_low(bus, Size::Long)? {
+            Ea::Mem(addr) if !matches!((self.opcode >> 3) & 7, 3 | 4) => Ok(addr),
+            _ => Err(Exception::Vector(VEC_ILLEGAL)),
+        }
+    }
+
+    ////////////////////////////////////////////////////////////////////
+    // Flag calculations.
+    //
+
+    fn set_nz(&mut self, value: u32, size: Size) {
+        let value = value & size.mask();
+        self.set_flag(N, value & size.msb() != 0);
+        self.set_flag(Z, value == 0);
+    }
+
+    // Flags for logical operations.
+    fn set_logic(&mut self, value: u32, size: Size) {
+        self.set_nz(value, size);
+        self.set_flag(V, false);
+        self.set_flag(C, false);
+    }
+
+    // Addition, with optional extend. With extend, Z is only ever
+    // cleared.
+    fn add(&mut self, src: u32, dst: u32, size: Size, extend: bool) -> u32 {
+        let x = (extend && self.flag(X)) as u64;
+        let mask = size.mask() as u64;
+        let wide = (src as u64 & mask) + (dst as u64 & mask) + x;
+        let result = (wide & mask) as u32;
+        let carry = wide > mask;
+        let overflow = (src ^ result) & (dst ^ result) & size.msb() != 0;
+        self.set_flag(N, result & size.msb() != 0);
+        if extend {
+            if result != 0 {
+                self.set_flag(Z, false);
+            }
+        } else {
+            self.set_flag(Z, result == 0);
+        }
+        self.set_flag(V, overflow);
+        self.set_flag(C, carry);
+        self.set_flag(X, carry);
+        result
+    }
+
+    // Calculates dst - src, setting flags. CMP leaves X alone.
+    fn sub(&mut self, src: u32, dst: u32, size: Size, extend: bool, set_x: bool) -> u32 {
+        let x = (extend && self.flag(X)) as u64;
+        let mask = size.mask() as u64;
+        let (s, d) = (src as u64 & mask, dst as u64 & mask);
+        let result = (d.wrapping_sub(s).wrapping_sub(x) & mask) as u32;
+        let borrow = s + x > d;
+        let overflow = (src ^ dst) & (result ^ dst) & size.msb() != 0;
+        self.set_flag(N, result & size.msb() != 0);
+        if extend {
+            if result != 0 {
+                self.set_flag(Z, false);
+            }
+        } else {
+            self.set_flag(Z, result == 0);
+        }
+        self.set_flag(V, overflow);
+        self.set_flag(C, borrow);
+        if set_x {
+            self.set_flag(X, borrow);
+        }
+        result
+    }
+
+    fn condition(&self, cond: u16) -> bool {
+        let (c, v, z, n) = (self.flag(C), self.flag(V), self.flag(Z), self.flag(N));
+        match cond & 0xf {
+            0x0 => true,
+            0x1 => false,
+            0x2 => !c && !z,
+            0x3 => c || z,
+            0x4 => !c,
+            0x5 => c,
+            0x6 => !z,
+            0x7 => z,
+            0x8 => !v,
+            0x9 => v,
+            0xa => !n,
+            0xb => n,
+            0xc => n == v,
+            0xd => n != v,
+            0xe => !z && n == v,
+            0xf => z || n != v,
+            _ => unreachable!(),
+        }
+    }
+
+    fn require_supervisor(&self) -> Result<()> {
+        if self.supervisor() {
+            Ok(())
+        } else {
+            Err(Exception::Vector(VEC_PRIVILEGE))
+        }
+    }
+
+    ////////////////////////////////////////////////////////////////////
+    // Instruction decode.
+    //
+
+    fn execute(&mut self, bus: &mut impl Bus) -> Result<()> {
+        self.opcode = self.fetch_word(bus)?;
+        match self.opcode >> 12 {
+            0x0 => self.group_0(bus),
+            0x1 => self.op_move(bus, Size::Byte),
+            0x2 => self.op_move(bus, Size::Long),
+            0x3 => self.op_move(bus, Size::Word),
+            0x4 => self.group_4(bus),
+            0x5 => self.group_5(bus),
+            0x6 => self.op_branch(bus),
+            0x7 => self.op_moveq(),
+            0x8 => self.group_8(bus),
+            0x9 => self.group_add_sub(bus, false),
+            0xa => Err(Exception::Vector(VEC_LINE_A)),
+            0xb => self.group_b(bus),
+            0xc => self.group_c(bus),
+            0xd => self.group_add_sub(bus, true),
+            0xe => self.group_e(bus),
+            0xf => Err(Exception::Vector(VEC_LINE_F)),
+            _ => unreachable!(),
+        }
+    }
+
+    // Immediate operations, bit operations and MOVEP.
+    fn group_0(&mut self, bus: &mut impl Bus) -> Result<()> {
+        let op = self.opcode;
+        let mode = (op >> 3) & 7;
+
+        if op & 0x0100 != 0 {
+            if mode == 1 {
+                return self.op_movep(bus);
+            }
+            let bit = self.d[((op >> 9) & 7) as usize];
+            return self.op_bit(bus, bit);
+        }
+
+        if op & 0x0f00 == 0x0800 {
+            let bit = self.fetch_word(bus)? as u32 & 0xff;
+            return self.op_bit(bus, bit);
+        }
+
+        let kind = (op >> 9) & 7;
+
+        // Operations on CCR and SR.
+        if op & 0x3f == 0x3c && matches!(kind, 0 | 1 | 5) {
+            let value = self.fetch_word(bus)?;
+            let to_sr = match (op >> 6) & 3 {
+                0 => false,
+                1 => true,
+                _ => return Err(Exception::Vector(VEC_ILLEGAL)),
+            };
+            if to_sr {
+                self.require_supervisor()?;
+            }
+            let mask = if to_sr { 0xffff } else { 0x00ff };
+            let old = self.sr & mask;
+            let new = match kind {
+                0 => old | value,
+                1 => old & value,
+                _ => old ^ value,
+            } & mask;
+            if to_sr {
+                self.set_sr(new);
+            } else {
+                self.set_ccr(new);
+            }
+            return Ok(());
+        }
+
+        let size = Size::decode(op >> 6).ok_or(Exception::Vector(VEC_ILLEGAL))?;
+        let imm = match size {
+            Size::Long => self.fetch_long(bus)?,
+            _ => self.fetch_word(bus)? as u32 & size.mask(),
+        };
+        let ea = self.ea_low(bus, size)?;
+        let dst = self.read_ea(bus, ea, size)?;
+        let result = match kind {
+            0 => imm | dst,
+            1 => imm & dst,
+            2 => self.sub(imm, dst, size, false, true),
+            3 => self.add(imm, dst, size, false),
+            5 => imm ^ dst,
+            6 => {
+                self.sub(imm, dst, size, false, false);
+                return Ok(());
+            }
+            _ => return Err(Exception::Vector(VEC_ILLEGAL)),
+        };
+        if matches!(kind, 0 | 1 | 5) {
+            self.set_logic(result, size);
+        }
+        self.write_ea(bus, ea, size, result)
+    }
+
+    fn op_bit(&mut self, bus: &mut impl Bus, bit: u32) -> Result<()> {
+        let op = self.opcode;
+        let kind = (op >> 6) & 3;
+        let size = if (op >> 3) & 7 == 0 {
+            Size::Long
+        } else {
+            Size::Byte
+        };
+        let mask = 1 << (bit % size.bits());
+        let ea = self.ea_low(bus, size)?;
+        let value = self.read_ea(bus, ea, size)?;
+        self.set_flag(Z, value & mask == 0);
+        let result = match kind {
+            0 => return Ok(()),
+            1 => value ^ mask,
+            2 => value & !mask,
+            _ => value | mask,
+        };
+        self.write_ea(bus, ea, size, result)
+    }
+
+    fn op_movep(&mut self, bus: &mut impl Bus) -> Result<()> {
+        let op = self.opcode;
+        let dreg = ((op >> 9) & 7) as usize;
+        let areg = (op & 7) as usize;
+        let disp = sign_extend(self.fetch_word(bus)? as u32, Size::Word);
+        let addr = self.a[areg].wrapping_add(disp);
+        let (size, to_mem) = match (op >> 6) & 3 {
+            0 => (Size::Word, false),
+            1 => (Size::Long, false),
+            2 => (Size::Word, true),
+            _ => (Size::Long, true),
+        };
+        let count = size.bytes();
+        if to_mem {
+            for i in 0..count {
+                let byte = self.d[dreg] >> (8 * (count - 1 - i));
+                self.write(bus, addr.wrapping_add(2 * i), Size::Byte, byte)?;
+            }
+        } else {
+            let mut value = 0;
+            for i in 0..count {
+                value = (value << 8) | self.read(bus, addr.wrapping_add(2 * i), Size::Byte)?;
+            }
+            self.d[dreg] = (self.d[dreg] & !size.mask()) | value;
+        }
+        Ok(())
+    }
+
+    fn op_move(&mut self, bus: &mut impl Bus, size: Size) -> Result<()> {
+        let op = self.opcode;
+        let src = self.ea_low(bus, size)?;
+        let value = self.read_ea(bus, src, size)?;
+        let dst_mode = (op >> 6) & 7;
+        let dst_reg = (op >> 9) & 7;
+        if dst_mode == 1 {
+            // MOVEA doesn't touch the flags.
+            if size == Size::Byte {
+                return Err(Exception::Vector(VEC_ILLEGAL));
+            }
+            self.a[dst_reg as usize] = sign_extend(value, size);
+            return Ok(());
+        }
+        let dst = self.ea(bus, dst_mode, dst_reg, size)?;
+        self.set_logic(value, size);
+        self.write_ea(bus, dst, size, value)
+    }
+
+    fn op_moveq(&mut self) -> Result<()> {
+        let op = self.opcode;
+        if op & 0x0100 != 0 {
+            return Err(Exception::Vector(VEC_ILLEGAL));
+        }
+        let value = sign_extend(op as u32, Size::Byte);
+        self.d[((op >> 9) & 7) as usize] = value;
+        self.set_logic(value, Size::Long);
+        Ok(())
+    }
+
+    // Miscellaneous instructions.
+    fn group_4(&mut self, bus: &mut impl Bus) -> Result<()> {
+        let op = self.opcode;
+        let mode = (op >> 3) & 7;
+        let reg = (op & 7) as usize;
+
+        if op & 0x01c0 == 0x01c0 {
+            // LEA
+            let addr = self.control_addr(bus)?;
+            self.a[((op >> 9) & 7) as usize] = addr;
+            return Ok(());
+        }
+        if op & 0x01c0 == 0x0180 {
+            return self.op_chk(bus);
+        }
+
+        match op & 0x0fc0 {
+            0x00c0 => {
+                // MOVE from SR (not privileged on the 68000).
+                let ea = self.ea_low(bus, Size::Word)?;
+                self.read_ea(bus, ea, Size::Word)?;
+                return self.write_ea(bus, ea, Size::Word, self.sr as u32);
+            }
+            0x04c0 => {
+                // MOVE to CCR
+                let ea = self.ea_low(bus, Size::Word)?;
+                let value = self.read_ea(bus, ea, Size::Word)?;
+                self.set_ccr(value as u16);
+                return Ok(());
+            }
+            0x06c0 => {
+                // MOVE to SR
+                self.require_supervisor()?;
+                let ea = self.ea_low(bus, Size::Word)?;
+                let value = self.read_ea(bus, ea, Size::Word)?;
+                self.set_sr(value as u16);
+                return Ok(());
+            }
+            0x0800 => return self.op_nbcd(bus),
+            0x0840 => {
+                if mode == 0 {
+                    // SWAP
+                    let value = self.d[reg].rotate_left(16);
+                    self.d[reg] = value;
+                    self.set_logic(value, Size::Long);
+                } else {
+                    // PEA
+                    let addr = self.control_addr(bus)?;
+                    self.push(bus, addr, Size::Long)?;
+                }
+                return Ok(());
+            }
+            0x0880 | 0x08c0 if mode == 0 => {
+                // EXT
+                if op & 0x0040 == 0 {
+                    let value = sign_extend(self.d[reg], Size::Byte) & 0xffff;
+                    self.d[reg] = (self.d[reg] & 0xffff0000) | value;
+                    self.set_logic(value, Size::Word);
+                } else {
+                    let value = sign_extend(self.d[reg], Size::Word);
+                    self.d[reg] = value;
+                    self.set_logic(value, Size::Long);
+                }
+                return Ok(());
+            }
+            0x0880 | 0x08c0 | 0x0c80 | 0x0cc0 => return self.op_movem(bus),
+            0x0ac0 => {
+                if op == 0x4afc {
+                    return Err(Exception::Vector(VEC_ILLEGAL));
+                }
+                // TAS
+                let ea = self.ea_low(bus, Size::Byte)?;
+                let value = self.read_ea(bus, ea, Size::Byte)?;
+                self.set_logic(value, Size::Byte);
+                return self.write_ea(bus, ea, Size::Byte, value | 0x80);
+            }
+            0x0e80 => {
+                // JSR
+                let addr = self.control_addr(bus)?;
+                self.push(bus, self.pc, Size::Long)?;
+                self.pc = addr;
+                return Ok(());
+            }
+            0x0ec0 => {
+                // JMP
+                self.pc = self.control_addr(bus)?;
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        if op & 0xfff0 == 0x4e40 {
+            return Err(Exception::Vector(VEC_TRAP_BASE + (op & 0xf) as u8));
+        }
+
+        match op {
+            0x4e50..=0x4e57 => {
+                // LINK
+                let disp = sign_extend(self.fetch_word(bus)? as u32, Size::Word);
+                self.push(bus, self.a[reg], Size::Long)?;
+                self.a[reg] = self.a[7];
+                self.a[7] = self.a[7].wrapping_add(disp);
+                Ok(())
+            }
+            0x4e58..=0x4e5f => {
+                // UNLK
+                self.a[7] = self.a[reg];
+                self.a[reg] = self.pop(bus, Size::Long)?;
+                Ok(())
+            }
+            0x4e60..=0x4e67 => {
+                self.require_supervisor()?;
+                self.other_sp = self.a[reg];
+                Ok(())
+            }
+            0x4e68..=0x4e6f => {
+                self.require_supervisor()?;
+                self.a[reg] = self.other_sp;
+                Ok(())
+            }
+            0x4e70 => {
+                self.require_supervisor()?;
+                bus.reset_devices();
+                Ok(())
+            }
+            0x4e71 => Ok(()),
+            0x4e72 => {
+                self.require_supervisor()?;
+                let sr = self.fetch_word(bus)?;
+                self.set_sr(sr);
+                self.stopped = true;
+                Ok(())
+            }
+            0x4e73 => {
+                // RTE
+                self.require_supervisor()?;
+                let sr = self.pop(bus, Size::Word)?;
+                let pc = self.pop(bus, Size::Long)?;
+                self.set_sr(sr as u16);
+                self.pc = pc;
+                Ok(())
+            }
+            0x4e75 => {
+                self.pc = self.pop(bus, Size::Long)?;
+                Ok(())
+            }
+            0x4e76 => {
+                if self.flag(V) {
+                    Err(Exception::Vector(VEC_TRAPV))
+                } else {
+                    Ok(())
+                }
+            }
+            0x4e77 => {
+                // RTR
+                let ccr = self.pop(bus, Size::Word)?;
+                self.set_ccr(ccr as u16);
+                self.pc = self.pop(bus, Size::Long)?;
+                Ok(())
+            }
+            _ => self.op_unary(bus),
+        }
+    }
+
+    // NEGX, CLR, NEG, NOT, TST.
+    fn op_unary(&mut self, bus: &mut impl Bus) -> Result<()> {
+        let op = self.opcode;
+        let size = Size::decode(op >> 6).ok_or(Exception::Vector(VEC_ILLEGAL))?;
+        let ea = self.ea_low(bus, size)?;
+        match op & 0x0f00 {
+            0x0000 => {
+                let value = self.read_ea(bus, ea, size)?;
+                let result = self.sub(value, 0, size, true, true);
+                self.write_ea(bus, ea, size, result)
+            }
+            0x0200 => {
+                // The 68000 reads before clearing.
+                self.read_ea(bus, ea, size)?;
+                self.set_logic(0, size);
+                self.write_ea(bus, ea, size, 0)
+            }
+            0x0400 => {
+                let value = self.read_ea(bus, ea, size)?;
+                let result = self.sub(value, 0, size, false, true);
+                self.write_ea(bus, ea, size, result)
+            }
+            0x0600 => {
+                let value = !self.read_ea(bus, ea, size)?;
+                self.set_logic(value, size);
+                self.write_ea(bus, ea, size, value)
+            }
+            0x0a00 => {
+                let value = self.read_ea(bus, ea, size)?;
+                self.set_logic(value, size);
+                Ok(())
+            }
+            _ => Err(Exception::Vector(VEC_ILLEGAL)),
+        }
+    }
+
+    fn op_chk(&mut self, bus: &mut impl Bus) -> Result<()> {
+        let op = self.opcode;
+        let ea = self.ea_low(bus, Size::Word)?;
+        let bound = self.read_ea(bus, ea, Size::Word)? as i16;
+        let value = self.d[((op >> 9) & 7) as usize] as i16;
+        if value < 0 {
+            self.set_flag(N, true);
+            return Err(Exception::Vector(VEC_CHK));
+        }
+        if value > bound {
+            self.set_flag(N, false);
+            return Err(Exception::Vector(VEC_CHK));
+        }
+        Ok(())
+    }
+
+    fn op_movem(&mut self, bus: &mut impl Bus) -> Result<()> {
+        let op = self.opcode;
+        let size = if op & 0x0040 != 0 {
+            Size::Long
+        } else {
+            Size::Word
+        };
+        let to_regs = op & 0x0400 != 0;
+        let mask = self.fetch_word(bus)?;
+        let mode = (op >> 3) & 7;
+        let reg = (op & 7) as usize;
+
+        if mode == 4 {
+            // Pre-decrement: the mask is reversed, and registers are
+            // stored from A7 down to D0.
+            if to_regs {
+                return Err(Exception::Vector(VEC_ILLEGAL));
+            }
+            let mut addr = self.a[reg];
+            for i in (0..16).rev() {
+                if mask & (1 << (15 - i)) != 0 {
+                    addr = addr.wrapping_sub(size.bytes());
+                    let value = if i < 8 { self.d[i] } else { self.a[i - 8] };
+                    self.write(bus, addr, size, value)?;
+                }
+            }
+            self.a[reg] = addr;
+            return Ok(());
+        }
+
+        let mut addr = if mode == 3 {
+            self.a[reg]
+        } else {
+            self.control_addr(bus)?
+        };
+        for i in 0..16 {
+            if mask & (1 << i) == 0 {
+                continue;
+            }
+            if to_regs {
+                let value = sign_extend(self.read(bus, addr, size)?, size);
+                if i < 8 {
+                    self.d[i] = value;
+                } else {
+                    self.a[i - 8] = value;
+                }
+            } else {
+                let value = if i < 8 { self.d[i] } else { self.a[i - 8] };
+                self.write(bus, addr, size, value)?;
+            }
+            addr = addr.wrapping_add(size.bytes());
+        }
+        if mode == 3 {
+            self.a[reg] = addr;
+        }
+        Ok(())
+    }
+
+    // ADDQ, SUBQ, Scc, DBcc.
+    fn group_5(&mut self, bus: &mut impl Bus) -> Result<()> {
+        let op = self.opcode;
+        let mode = (op >> 3) & 7;
+        let reg = (op & 7) as usize;
+
+        let Some(size) = Size::decode(op >> 6) else {
+            let cond = (op >> 8) & 0xf;
+            if mode == 1 {
+                // DBcc
+                let base = self.pc;
+                let disp = sign_extend(self.fetch_word(bus)? as u32, Size::Word);
+                if !self.condition(cond) {
+                    let count = (self.d[reg] as u16).wrapping_sub(1);
+                    self.d[reg] = (self.d[reg] & 0xffff0000) | count as u32;
+                    if count != 0xffff {
+                        self.pc = base.wrapping_add(disp);
+                    }
+                }
+                return Ok(());
+            }
+            // Scc
+            let ea = self.ea_low(bus, Size::Byte)?;
+            self.read_ea(bus, ea, Size::Byte)?;
+            let value = if self.condition(cond) { 0xff } else { 0x00 };
+            return self.write_ea(bus, ea, Size::Byte, value);
+        };
+
+        let mut data = ((op >> 9) & 7) as u32;
+        if data == 0 {
+            data = 8;
+        }
+        let subtract = op & 0x0100 != 0;
+
+        if mode == 1 {
+            // Address register destinations work on the whole register,
+            // and leave the flags alone.
+            if size == Size::Byte {
+                return Err(Exception::Vector(VEC_ILLEGAL));
+            }
+            self.a[reg] = if subtract {
+                self.a[reg].wrapping_sub(data)
+            } else {
+                self.a[reg].wrapping_add(data)
+            };
+            return Ok(());
+        }
+
+        let ea = self.ea_low(bus, size)?;
+        let value = self.read_ea(bus, ea, size)?;
+        let result = if subtract {
+            self.sub(data, value, size, false, true)
+        } else {
+            self.add(data, value, size, false)
+        };
+        self.write_ea(bus, ea, size, result)
+    }
+
+    fn op_branch(&mut self, bus: &mut impl Bus) -> Result<()> {
+        let op = self.opcode;
+        let cond = (op >> 8) & 0xf;
+        let base = self.pc;
+        let mut disp = sign_extend(op as u32, Size::Byte);
+        if disp == 0 {
+            disp = sign_extend(self.fetch_word(bus)? as u32, Size::Word);
+        }
+        let target = base.wrapping_add(disp);
+        match cond {
+            0 => self.pc = target,
+            1 => {
+                self.push(bus, self.pc, Size::Long)?;
+                self.pc = target;
+            }
+            _ => {
+                if self.condition(cond) {
+                    self.pc = target;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // OR, DIVU, DIVS, SBCD.
+    fn group_8(&mut self, bus: &mut impl Bus) -> Result<()> {
+        let op = self.opcode;
+        let opmode = (op >> 6) & 7;
+        let mode = (op >> 3) & 7;
+        match opmode {
+            3 => self.op_div(bus, false),
+            7 => self.op_div(bus, true),
+            4 if mode < 2 => self.op_bcd(bus, false),
+            _ => self.op_logic(bus, |a, b| a | b),
+        }
+    }
+
+    // CMP, CMPA, CMPM, EOR.
+    fn group_b(&mut self, bus: &mut impl Bus) -> Result<()> {
+        let op = self.opcode;
+        let opmode = (op >> 6) & 7;
+        let mode = (op >> 3) & 7;
+        let reg = ((op >> 9) & 7) as usize;
+        match opmode {
+            0..=2 => {
+                let size = Size::decode(opmode).unwrap();
+                let ea = self.ea_low(bus, size)?;
+                let src = self.read_ea(bus, ea, size)?;
+                self.sub(src, self.d[reg], size, false, false);
+                Ok(())
+            }
+            3 | 7 => {
+                let size = if opmode == 3 { Size::Word } else { Size::Long };
+                let ea = self.ea_low(bus, size)?;
+                let src = sign_extend(self.read_ea(bus, ea, size)?, size);
+                self.sub(src, self.a[reg], Size::Long, false, false);
+                Ok(())
+            }
+            _ if mode == 1 => {
+                // CMPM
+                let size = Size::decode(opmode).unwrap();
+                let src = self.ea(bus, 3, op & 7, size)?;
+                let src = self.read_ea(bus, src, size)?;
+                let dst = self.ea(bus, 3, reg as u16, size)?;
+                let dst = self.read_ea(bus, dst, size)?;
+                self.sub(src, dst, size, false, false);
+                Ok(())
+            }
+            _ => self.op_logic(bus, |a, b| a ^ b),
+        }
+    }
+
+    // AND, MULU, MULS, ABCD, EXG.
+    fn group_c(&mut self, bus: &mut impl Bus) -> Result<()> {
+        let op = self.opcode;
+        let opmode = (op >> 6) & 7;
+        let mode = (op >> 3) & 7;
+        let rx = ((op >> 9) & 7) as usize;
+        let ry = (op & 7) as usize;
+        match (opmode, mode) {
+            (3, _) => self.op_mul(bus, false),
+            (7, _) => self.op_mul(bus, true),
+            (4, 0) | (4, 1) => self.op_bcd(bus, true),
+            (5, 0) => {
+                self.d.swap(rx, ry);
+                Ok(())
+            }
+            (5, 1) => {
+                self.a.swap(rx, ry);
+                Ok(())
+            }
+            (6, 1) => {
+                std::mem::swap(&mut self.d[rx], &mut self.a[ry]);
+                Ok(())
+            }
+            _ => self.op_logic(bus, |a, b| a & b),
+        }
+    }
+
+    // Shared code for AND, OR and EOR.
+    fn op_logic(&mut self, bus: &mut impl Bus, f: fn(u32, u32) -> u32) -> Result<()> {
+        let op = self.opcode;
+        let reg = ((op >> 9) & 7) as usize;
+        let size = Size::decode(op >> 6).ok_or(Exception::Vector(VEC_ILLEGAL))?;
+        let ea = self.ea_low(bus, size)?;
+        let value = self.read_ea(bus, ea, size)?;
+        let result = f(value, self.d[reg]) & size.mask();
+        self.set_logic(result, size);
+        if op & 0x0100 != 0 {
+            self.write_ea(bus, ea, size, result)
+        } else {
+            self.write_ea(bus, Ea::D(reg), size, result)
+        }
+    }
+
+    fn op_mul(&mut self, bus: &mut impl Bus, signed: bool) -> Result<()> {
+        let reg = ((self.opcode >> 9) & 7) as usize;
+        let ea = self.ea_low(bus, Size::Word)?;
+        let src = self.read_ea(bus, ea, Size::Word)?;
+        let result = if signed {
+            (src as u16 as i16 as i32).wrapping_mul(self.d[reg] as u16 as i16 as i32) as u32
+        } else {
+            src * (self.d[reg] & 0xffff)
+        };
+        self.d[reg] = result;
+        self.set_logic(result, Size::Long);
+        Ok(())
+    }
+
+    fn op_div(&mut self, bus: &mut impl Bus, signed: bool) -> Result<()> {
+        let reg = ((self.opcode >> 9) & 7) as usize;
+        let ea = self.ea_low(bus, Size::Word)?;
+        let src = self.read_ea(bus, ea, Size::Word)?;
+        if src == 0 {
+            return Err(Exception::Vector(VEC_ZERO_DIVIDE));
+        }
+        self.set_flag(C, false);
+        let dividend = self.d[reg];
+        let (quot, rem, overflow) = if signed {
+            let (n, d) = (dividend as i32 as i64, src as u16 as i16 as i64);
+            let (q, r) = (n / d, n % d);
+            (
+                q as u32,
+                r as u32,
+                q < i16::MIN as i64 || q > i16::MAX as i64,
+            )
+        } else {
+            let (q, r) = (dividend / src, dividend % src);
+            (q, r, q > 0xffff)
+        };
+        if overflow {
+            self.set_flag(V, true);
+            return Ok(());
+        }
+        self.d[reg] = ((rem & 0xffff) << 16) | (quot & 0xffff);
+        self.set_logic(quot, Size::Word);
+        Ok(())
+    }
+
+    // ABCD and SBCD, register or pre-decrement memory forms.
+    fn op_bcd(&mut self, bus: &mut impl Bus, add: bool) -> Result<()> {
+        let op = self.opcode;
+        let rx = (op >> 9) & 7;
+        let ry = op & 7;
+        let (src_ea, dst_ea) = if op & 0x0008 != 0 {
+            let src = self.ea(bus, 4, ry, Size::Byte)?;
+            let dst = self.ea(bus, 4, rx, Size::Byte)?;
+            (src, dst)
+        } else {
+            (Ea::D(ry as usize), Ea::D(rx as usize))
+        };
+        let src = self.read_ea(bus, src_ea, Size::Byte)?;
+        let dst = self.read_ea(bus, dst_ea, Size::Byte)?;
+        let result = if add {
+            self.bcd_add(src, dst)
+        } else {
+            self.bcd_sub(src, dst)
+        };
+        self.write_ea(bus, dst_ea, Size::Byte, result)
+    }
+
+    fn op_nbcd(&mut self, bus: &mut impl Bus) -> Result<()> {
+        let ea = self.ea_low(bus, Size::Byte)?;
+        let value = self.read_ea(bus, ea, Size::Byte)?;
+        let result = self.bcd_sub(value, 0);
+        self.write_ea(bus, ea, Size::Byte, result)
+    }
+
+    fn bcd_add(&mut self, src: u32, dst: u32) -> u32 {
+        let x = self.flag(X) as u32;
+        let mut result = (src & 0x0f) + (dst & 0x0f) + x;
+        if result > 9 {
+            result += 6;
+        }
+        result += (src & 0xf0) + (dst & 0xf0);
+        let carry = result > 0x99;
+        if carry {
+            result = result.wrapping_sub(0xa0);
+        }
+        self.bcd_flags(result & 0xff, carry)
+    }
+
+    fn bcd_sub(&mut self, src: u32, dst: u32) -> u32 {
+        let x = self.flag(X) as u32;
+        let mut result = (dst & 0x0f).wrapping_sub(src & 0x0f).wrapping_sub(x);
+        if result > 9 {
+            result = result.wrapping_sub(6);
+        }
+        result = result.wrapping_add(dst & 0xf0).wrapping_sub(src & 0xf0);
+        let borrow = result > 0x99;
+        if borrow {
+            result = result.wrapping_add(0xa0);
+        }
+        self.bcd_flags(result & 0xff, borrow)
+    }
+
+    fn bcd_flags(&mut self, result: u32, carry: bool) -> u32 {
+        self.set_flag(C, carry);
+        self.set_flag(X, carry);
+        self.set_flag(N, result & 0x80 != 0);
+        if result != 0 {
+            self.set_flag(Z, false);
+        }
+        result
+    }
+
+    // ADD/SUB, ADDA/SUBA, ADDX/SUBX.
+    fn group_add_sub(&mut self, bus: &mut impl Bus, add: bool) -> Result<()> {
+        let op = self.opcode;
+        let reg = ((op >> 9) & 7) as usize;
+        let opmode = (op >> 6) & 7;
+        let mode = (op >> 3) & 7;
+
+        if opmode == 3 || opmode == 7 {
+            let size = if opmode == 3 { Size::Word } else { Size::Long };
+            let ea = self.ea_low(bus, size)?;
+            let src = sign_extend(self.read_ea(bus, ea, size)?, size);
+            self.a[reg] = if add {
+                self.a[reg].wrapping_add(src)
+            } else {
+                self.a[reg].wrapping_sub(src)
+            };
+            return Ok(());
+        }
+
+        let size = Size::decode(opmode).unwrap();
+        let to_ea = opmode & 4 != 0;
+
+        if to_ea && mode < 2 {
+            // ADDX/SUBX
+            let ry = op & 7;
+            let (src_ea, dst_ea) = if mode == 1 {
+                let src = self.ea(bus, 4, ry, size)?;
+                let dst = self.ea(bus, 4, reg as u16, size)?;
+                (src, dst)
+            } else {
+                (Ea::D(ry as usize), Ea::D(reg))
+            };
+            let src = self.read_ea(bus, src_ea, size)?;
+            let dst = self.read_ea(bus, dst_ea, size)?;
+            let result = if add {
+                self.add(src, dst, size, true)
+            } else {
+                self.sub(src, dst, size, true, true)
+            };
+            return self.write_ea(bus, dst_ea, size, result);
+        }
+
+        let ea = self.ea_low(bus, size)?;
+        let value = self.read_ea(bus, ea, size)?;
+        let (src, dst, dst_ea) = if to_ea {
+            (self.d[reg], value, ea)
+        } else {
+            (value, self.d[reg], Ea::D(reg))
+        };
+        let result = if add {
+            self.add(src, dst, size, false)
+        } else {
+            self.sub(src, dst, size, false, true)
+        };
+        self.write_ea(bus, dst_ea, size, result)
+    }
+
+    // Shifts and rotates.
+    fn group_e(&mut self, bus: &mut impl Bus) -> Result<()> {
+        let op = self.opcode;
+        let left = op & 0x0100 != 0;
+
+        let Some(size) = Size::decode(op >> 6) else {
+            // Memory form: shift a word by one.
+            let kind = (op >> 9) & 3;
+            let ea = self.ea_low(bus, Size::Word)?;
+            let value = self.read_ea(bus, ea, Size::Word)?;
+            let result = self.shift(kind, left, value, 1, Size::Word);
+            return self.write_ea(bus, ea, Size::Word, result);
+        };
+
+        let kind = (op >> 3) & 3;
+        let reg = (op & 7) as usize;
+        let count_field = (op >> 9) & 7;
+        let count = if op & 0x0020 != 0 {
+            self.d[count_field as usize] % 64
+        } else if count_field == 0 {
+            8
+        } else {
+            count_field as u32
+        };
+        let result = self.shift(kind, left, self.d[reg], count, size);
+        self.write_ea(bus, Ea::D(reg), size, result)
+    }
+
+    fn shift(&mut self, kind: u16, left: bool, value: u32, count: u32, size: Size) -> u32 {
+        let msb = size.msb();
+        let mut value = value & size.mask();
+        let mut carry = false;
+        let mut overflow = false;
+        let mut x = self.flag(X);
+
+        for _ in 0..count {
+            let out = if left {
+                value & msb != 0
+            } else {
+                value & 1 != 0
+            };
+            value = match (kind, left) {
+                // ASL/LSL
+                (0, true) | (1, true) => value << 1,
+                (0, false) => (value >> 1) | (value & msb),
+                (1, false) => value >> 1,
+                // ROXL/ROXR
+                (2, true) => (value << 1) | x as u32,
+                (2, false) => (value >> 1) | if x { msb } else { 0 },
+                // ROL/ROR
+                (_, true) => (value << 1) | out as u32,
+                (_, false) => (value >> 1) | if out { msb } else { 0 },
+            } & size.mask();
+            if kind == 0 && left && (value & msb != 0) != out {
+                overflow = true;
+            }
+            carry = out;
+            if kind != 3 {
+                x = out;
+            }
+        }
+
+        if count == 0 {
+            // ROXL/ROXR copy X to C; everything else clears C.
+            carry = kind == 2 && self.flag(X);
+        } else if kind != 3 {
+            self.set_flag(X, x);
+        }
+        self.set_nz(value, size);
+        self.set_flag(V, overflow);
+        self.set_flag(C, carry);
+        value
+    }
+}
+
+// Read a long without any checks, for vectors.
+fn read_long_raw(bus: &mut impl Bus, addr: u32) -> u32 {
+    ((bus.read_word(addr) as u32) << 16) | bus.read_word(addr + 2) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CODE: u32 = 0x1000;
+    const STACK: u32 = 0x8000;
+    // Every vector points here.
+    const HANDLER: u32 = 0x4000;
+
+    // 64kB of RAM, mirrored through the address space.
+    struct TestBus {
+        mem: Vec<u8>,
+    }
+
+    impl Bus for TestBus {
+        fn read_byte(&mut self, addr: u32) -> u8 {
+            self.mem[addr as usize & 0xffff]
+        }
+
+        fn write_byte(&mut self, addr: u32, value: u8) {
+            self.mem[addr as usize & 0xffff] = value;
+        }
+
+        fn irq_level(&mut self) -> u8 {
+            0
+        }
+    }
+
+    // Execute one instruction in supervisor mode, with the flags and
+    // registers set up by 'setup', returning the vector of any
+    // exception taken.
+    fn exec(code: &[u16], setup: impl FnOnce(&mut Cpu)) -> (Cpu, TestBus, Option<u8>) {
+        let mut bus = TestBus {
+            mem: vec![0; 0x10000],
+        };
+        for vector in 0..256 {
+            bus.write_word(vector * 4, (HANDLER >> 16) as u16);
+            bus.write_word(vector * 4 + 2, HANDLER as u16);
+        }
+        for (i, word) in code.iter().enumerate() {
+            bus.write_word(CODE + 2 * i as u32, *word);
+        }
+        let mut cpu = Cpu::new();
+        cpu.set_sr(0x2700);
+        cpu.a[7] = STACK;
+        cpu.pc = CODE;
+        setup(&mut cpu);
+        let vector = cpu.step(&mut bus);
+        (cpu, bus, vector)
+    }
+
+    fn ccr(cpu: &Cpu) -> u16 {
+        cpu.sr & 0x1f
+    }
+
+    fn read_long(bus: &mut TestBus, addr: u32) -> u32 {
+        ((bus.read_word(addr) as u32) << 16) | bus.read_word(addr + 2) as u32
+    }
+
+    ////////////////////////////////////////////////////////////////////
+    // Extended arithmetic. X goes into the result, and Z is only ever
+    // cleared, so that multi-precision results test as a whole.
+    //
+
+    const ADDX_B: u16 = 0xd101; // ADDX.B D1,D0
+    const ADDX_L: u16 = 0xd181; // ADDX.L D1,D0
+    const SUBX_B: u16 = 0x9101; // SUBX.B D1,D0
+    const NEGX_B: u16 = 0x4000; // NEGX.B D0
+
+    #[test]
+    fn addx_carries_and_keeps_z() {
+        let (cpu, _, _) = exec(&[ADDX_B], |cpu| {
+            cpu.d[0] = 0x123456ff;
+            cpu.d[1] = 0;
+            cpu.set_ccr(X | Z);
+        });
+        assert_eq!(cpu.d[0], 0x12345600);
+        assert_eq!(ccr(&cpu), X | Z | C);
+    }
+
+    #[test]
+    fn addx_clears_z_on_nonzero() {
+        let (cpu, _, _) = exec(&[ADDX_B], |cpu| {
+            cpu.d[0] = 1;
+            cpu.d[1] = 0;
+            cpu.set_ccr(Z);
+        });
+        assert_eq!(cpu.d[0], 1);
+        assert_eq!(ccr(&cpu), 0);
+    }
+
+    #[test]
+    fn addx_overflows() {
+        let (cpu, _, _) = exec(&[ADDX_B], |cpu| {
+            cpu.d[0] = 0x7f;
+            cpu.d[1] = 0;
+            cpu.set_ccr(X);
+        });
+        assert_eq!(cpu.d[0], 0x80);
+        assert_eq!(ccr(&cpu), N | V);
+    }
+
+    #[test]
+    fn addx_long() {
+        let (cpu, _, _) = exec(&[ADDX_L], |cpu| {
+            cpu.d[0] = 0xffffffff;
+            cpu.d[1] = 0;
+            cpu.set_ccr(X | Z);
+        });
+        assert_eq!(cpu.d[0], 0);
+        assert_eq!(ccr(&cpu), X | Z | C);
+    }
+
+    #[test]
+    fn subx_borrows() {
+        let (cpu, _, _) = exec(&[SUBX_B], |cpu| {
+            cpu.d[0] = 0;
+            cpu.d[1] = 0;
+            cpu.set_ccr(X | Z);
+        });
+        assert_eq!(cpu.d[0], 0xff);
+        assert_eq!(ccr(&cpu), X | N | C);
+    }
+
+    #[test]
+    fn subx_overflows() {
+        let (cpu, _, _) = exec(&[SUBX_B], |cpu| {
+            cpu.d[0] = 0x80;
+            cpu.d[1] = 0;
+            cpu.set_ccr(X);
+        });
+        assert_eq!(cpu.d[0], 0x7f);
+        assert_eq!(ccr(&cpu), V);
+    }
+
+    #[test]
+    fn negx_of_zero() {
+        let (cpu, _, _) = exec(&[NEGX_B], |cpu| {
+            cpu.d[0] = 0;
+            cpu.set_ccr(Z);
+        });
+        assert_eq!(cpu.d[0], 0);
+        assert_eq!(ccr(&cpu), Z);
+
+        let (cpu, _, _) = exec(&[NEGX_B], |cpu| {
+            cpu.d[0] = 0;
+            cpu.set_ccr(X | Z);
+        });
+        assert_eq!(cpu.d[0], 0xff);
+        assert_eq!(ccr(&cpu), X | N | C);
+    }
+
+    #[test]
+    fn negx_overflows() {
+        let (cpu, _, _) = exec(&[NEGX_B], |cpu| {
+            cpu.d[0] = 0x80;
+        });
+        assert_eq!(cpu.d[0], 0x80);
+        assert_eq!(ccr(&cpu), X | N | V | C);
+    }
+
+    ////////////////////////////////////////////////////////////////////
+    // BCD.
+    //
+
+    const ABCD: u16 = 0xc101; // ABCD D1,D0
+    const SBCD: u16 = 0x8101; // SBCD D1,D0
+    const NBCD: u16 = 0x4800; // NBCD D0
+
+    #[test]
+    fn abcd() {
+        let (cpu, _, _) = exec(&[ABCD], |cpu| {
+            cpu.d[0] = 0x19;
+            cpu.d[1] = 0x28;
+            cpu.set_ccr(Z);
+        });
+        assert_eq!(cpu.d[0], 0x47);
+        assert_eq!(ccr(&cpu), 0);
+    }
+
+    #[test]
+    fn abcd_carries() {
+        let (cpu, _, _) = exec(&[ABCD], |cpu| {
+            cpu.d[0] = 0x99;
+            cpu.d[1] = 0x00;
+            cpu.set_ccr(X | Z);
+        });
+        assert_eq!(cpu.d[0], 0x00);
+        assert_eq!(ccr(&cpu), X | Z | C);
+    }
+
+    #[test]
+    fn sbcd() {
+        let (cpu, _, _) = exec(&[SBCD], |cpu| {
+            cpu.d[0] = 0x47;
+            cpu.d[1] = 0x28;
+        });
+        assert_eq!(cpu.d[0], 0x19);
+        assert_eq!(ccr(&cpu), 0);
+    }
+
+    #[test]
+    fn sbcd_borrows() {
+        let (cpu, _, _) = exec(&[SBCD], |cpu| {
+            cpu.d[0] = 0x00;
+            cpu.d[1] = 0x01;
+            cpu.set_ccr(Z);
+        });
+        assert_eq!(cpu.d[0], 0x99);
+        assert_eq!(ccr(&cpu), X | N | C);
+    }
+
+    #[test]
+    fn nbcd() {
+        let (cpu, _, _) = exec(&[NBCD], |cpu| {
+            cpu.d[0] = 0x25;
+            cpu.set_ccr(Z);
+        });
+        assert_eq!(cpu.d[0], 0x75);
+        assert_eq!(ccr(&cpu), X | C);
+
+        let (cpu, _, _) = exec(&[NBCD], |cpu| {
+            cpu.d[0] = 0x00;
+            cpu.set_ccr(Z);
+        });
+        assert_eq!(cpu.d[0], 0x00);
+        assert_eq!(ccr(&cpu), Z);
+    }
+
+    ////////////////////////////////////////////////////////////////////
+    // Division. On overflow the destination is left alone.
+    //
+
+    const DIVU: u16 = 0x80c1; // DIVU.W D1,D0
+    const DIVS: u16 = 0x81c1; // DIVS.W D1,D0
+
+    #[test]
+    fn divu() {
+        let (cpu, _, _) = exec(&[DIVU], |cpu| {
+            cpu.d[0] = 100;
+            cpu.d[1] = 7;
+            cpu.set_ccr(C | V);
+        });
+        assert_eq!(cpu.d[0], (2 << 16) | 14);
+        assert_eq!(ccr(&cpu), 0);
+    }
+
+    #[test]
+    fn divu_overflows() {
+        let (cpu, _, vector) = exec(&[DIVU], |cpu| {
+            cpu.d[0] = 0x10000;
+            cpu.d[1] = 1;
+            cpu.set_ccr(C);
+        });
+        assert_eq!(vector, None);
+        assert_eq!(cpu.d[0], 0x10000);
+        assert_eq!(ccr(&cpu) & (V | C), V);
+    }
+
+    #[test]
+    fn divs() {
+        let (cpu, _, _) = exec(&[DIVS], |cpu| {
+            cpu.d[0] = -100i32 as u32;
+            cpu.d[1] = 7;
+        });
+        // The remainder has the sign of the dividend.
+        assert_eq!(cpu.d[0], 0xfffefff2);
+        assert_eq!(ccr(&cpu), N);
+    }
+
+    #[test]
+    fn divs_overflows() {
+        for (dividend, divisor) in [(0x8000, 1), (0x80000000, 0xffff)] {
+            let (cpu, _, vector) = exec(&[DIVS], |cpu| {
+                cpu.d[0] = dividend;
+                cpu.d[1] = divisor;
+            });
+            assert_eq!(vector, None);
+            assert_eq!(cpu.d[0], dividend);
+            assert_eq!(ccr(&cpu) & (V | C), V);
+        }
+    }
+
+    #[test]
+    fn divide_by_zero() {
+        let (cpu, mut bus, vector) = exec(&[DIVU], |cpu| {
+            cpu.d[0] = 100;
+            cpu.d[1] = 0;
+        });
+        assert_eq!(vector, Some(VEC_ZERO_DIVIDE));
+        assert_eq!(cpu.d[0], 100);
+        // A trap, so it returns after the instruction.
+        assert_eq!(read_long(&mut bus, cpu.a[7] + 2), CODE + 2);
+    }
+
+    ////////////////////////////////////////////////////////////////////
+    // Rotates through X.
+    //
+
+    const ROXL_B: u16 = 0xe330; // ROXL.B D1,D0
+    const ROXR_B: u16 = 0xe230; // ROXR.B D1,D0
+
+    #[test]
+    fn roxl_roxr_by_zero_copy_x_to_c() {
+        for op in [ROXL_B, ROXR_B] {
+            let (cpu, _, _) = exec(&[op], |cpu| {
+                cpu.d[0] = 0x81;
+                cpu.d[1] = 0;
+                cpu.set_ccr(X | V);
+            });
+            assert_eq!(cpu.d[0], 0x81);
+            assert_eq!(ccr(&cpu), X | N | C);
+
+            let (cpu, _, _) = exec(&[op], |cpu| {
+                cpu.d[0] = 0x81;
+                cpu.d[1] = 0;
+                cpu.set_ccr(C);
+            });
+            assert_eq!(cpu.d[0], 0x81);
+            assert_eq!(ccr(&cpu), N);
+        }
+    }
+
+    #[test]
+    fn roxl_rotates_through_x() {
+        let (cpu, _, _) = exec(&[ROXL_B], |cpu| {
+            cpu.d[0] = 0x80;
+            cpu.d[1] = 1;
+        });
+        assert_eq!(cpu.d[0], 0x00);
+        assert_eq!(ccr(&cpu), X | Z | C);
+
+        // Nine places round a byte and X gets back to the start.
+        let (cpu, _, _) = exec(&[ROXL_B], |cpu| {
+            cpu.d[0] = 0x5a;
+            cpu.d[1] = 9;
+            cpu.set_ccr(X);
+        });
+        assert_eq!(cpu.d[0], 0x5a);
+        assert_eq!(ccr(&cpu), X | C);
+    }
+
+    #[test]
+    fn roxr_rotates_through_x() {
+        let (cpu, _, _) = exec(&[ROXR_B], |cpu| {
+            cpu.d[0] = 0x01;
+            cpu.d[1] = 1;
+            cpu.set_ccr(X);
+        });
+        assert_eq!(cpu.d[0], 0x80);
+        assert_eq!(ccr(&cpu), X | N | C);
+    }
+
+    ////////////////////////////////////////////////////////////////////
+    // Exception stack frames.
+    //
+
+    #[test]
+    fn trap_frame() {
+        // TRAP #3, from user mode.
+        let (cpu, mut bus, vector) = exec(&[0x4e43], |cpu| {
+            cpu.set_sr(X);
+            cpu.a[7] = 0x6000;
+        });
+        assert_eq!(vector, Some(VEC_TRAP_BASE + 3));
+        assert_eq!(cpu.pc, HANDLER);
+        assert_eq!(cpu.sr, 0x2000 | X);
+        assert_eq!(cpu.usp(), 0x6000);
+        assert_eq!(cpu.a[7], STACK - 6);
+        assert_eq!(bus.read_word(cpu.a[7]), X);
+        // Traps return after the instruction.
+        assert_eq!(read_long(&mut bus, cpu.a[7] + 2), CODE + 2);
+    }
+
+    #[test]
+    fn fault_frames() {
+        // Illegal instructions and line A and F return to the
+        // instruction, so it can be emulated.
+        for (op, expected) in [
+            (0x4afc, VEC_ILLEGAL),
+            (0xa9f0, VEC_LINE_A),
+            (0xf200, VEC_LINE_F),
+        ] {
+            let (cpu, mut bus, vector) = exec(&[op], |_| {});
+            assert_eq!(vector, Some(expected));
+            assert_eq!(cpu.pc, HANDLER);
+            assert_eq!(cpu.a[7], STACK - 6);
+            assert_eq!(bus.read_word(cpu.a[7]), 0x2700);
+            assert_eq!(read_long(&mut bus, cpu.a[7] + 2), CODE);
+        }
+    }
+
+    #[test]
+    fn address_error_frame() {
+        // MOVE.W (A0),D0 from an odd address.
+        let (cpu, mut bus, vector) = exec(&[0x3010], |cpu| {
+            cpu.a[0] = 0x2001;
+        });
+        assert_eq!(vector, Some(VEC_ADDRESS_ERROR));
+        assert_eq!(cpu.pc, HANDLER);
+        // Access type, address, instruction register, SR and PC.
+        assert_eq!(cpu.a[7], STACK - 14);
+        let frame = cpu.a[7];
+        assert_eq!(bus.read_word(frame), 0x15);
+        assert_eq!(read_long(&mut bus, frame + 2), 0x2001);
+        assert_eq!(bus.read_word(frame + 6), 0x3010);
+        assert_eq!(bus.read_word(frame + 8), 0x2700);
+        assert_eq!(read_long(&mut bus, frame + 10), CODE + 2);
+    }
+}
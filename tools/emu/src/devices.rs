@@ -0,0 +1,275 @@
+//
+// Devices
+//
+// Just enough of the SE hardware for the ROM to make progress. The
+// VIA is modelled properly enough for its timers and the VBL and
+// one-second interrupts to work, the SCC is modelled in scc.rs, and the
+// IWM has no drives attached; everything else is a stub.
+//
+
+use crate::machine::{Device, Region, Stub};
+use crate::scc::Scc;
+
+// CPU clock / E clock / VBL rate: 7.8336MHz / 10 / 60.15Hz.
+pub const TICKS_PER_VBL: u32 = 13023;
+pub const VBLS_PER_SECOND: u32 = 60;
+
+// VIA interrupt flag bits.
+const IFR_CA2: u8 = 0x01;
+const IFR_CA1: u8 = 0x02;
+const IFR_SR: u8 = 0x04;
+const IFR_T2: u8 = 0x20;
+const IFR_T1: u8 = 0x40;
+
+// Rockwell 6522 VIA. Registers are spaced every 0x200 bytes.
+#[derive(Default)]
+pub struct Via {
+    orb: u8,
+    ora: u8,
+    ddrb: u8,
+    ddra: u8,
+    // Values driven onto the port pins by the outside world.
+    pub inputs_a: u8,
+    pub inputs_b: u8,
+    t1_counter: u16,
+    t1_latch: u16,
+    t1_armed: bool,
+    t2_counter: u16,
+    t2_latch_low: u8,
+    t2_armed: bool,
+    sr: u8,
+    acr: u8,
+    pcr: u8,
+    ifr: u8,
+    ier: u8,
+    vbl_ticks: u32,
+    vbl_count: u32,
+}
+
+impl Via {
+    pub fn new() -> Via {
+        Via {
+            // Nothing pulling any inputs low, so no ADB interrupt, and
+            // RTC data reads as ones.
+            inputs_a: 0xff,
+            inputs_b: 0xff,
+            ..Default::default()
+        }
+    }
+
+    // Current state of the port pins, as the CPU sees them.
+    pub fn port_a(&self) -> u8 {
+        (self.ora & self.ddra) | (self.inputs_a & !self.ddra)
+    }
+
+    pub fn port_b(&self) -> u8 {
+        (self.orb & self.ddrb) | (self.inputs_b & !self.ddrb)
+    }
+}
+
+impl Device for Via {
+    fn read(&mut self, offset: u32) -> u8 {
+        match (offset >> 9) & 0xf {
+            0 => self.port_b(),
+            1 | 15 => self.port_a(),
+            2 => self.ddrb,
+            3 => self.ddra,
+            4 => {
+                self.ifr &= !IFR_T1;
+                self.t1_counter as u8
+            }
+            5 => (self.t1_counter >> 8) as u8,
+            6 => self.t1_latch as u8,
+            7 => (self.t1_latch >> 8) as u8,
+            8 => {
+                self.ifr &= !IFR_T2;
+                self.t2_counter as u8
+            }
+            9 => (self.t2_counter >> 8) as u8,
+            10 => {
+                // Shifts complete instantly.
+                self.ifr |= IFR_SR;
+                self.sr
+            }
+            11 => self.acr,
+            12 => self.pcr,
+            13 => {
+                let active = self.ifr & self.ier & 0x7f != 0;
+                (self.ifr & 0x7f) | if active { 0x80 } else { 0x00 }
+            }
+            14 => self.ier | 0x80,
+            _ => unreachable!(),
+        }
+    }
+
+    fn write(&mut self, offset: u32, value: u8) {
+        match (offset >> 9) & 0xf {
+            0 => self.orb = value,
+            1 | 15 => self.ora = value,
+            2 => self.ddrb = value,
+            3 => self.ddra = value,
+            4 | 6 => self.t1_latch = (self.t1_latch & 0xff00) | value as u16,
+            5 => {
+                self.t1_latch = (self.t1_latch & 0x00ff) | ((value as u16) << 8);
+                self.t1_counter = self.t1_latch;
+                self.t1_armed = true;
+                self.ifr &= !IFR_T1;
+            }
+            7 => {
+                self.t1_latch = (self.t1_latch & 0x00ff) | ((value as u16) << 8);
+                self.ifr &= !IFR_T1;
+            }
+            8 => self.t2_latch_low = value,
+            9 => {
+                self.t2_counter = ((value as u16) << 8) | self.t2_latch_low as u16;
+                self.t2_armed = true;
+                self.ifr &= !IFR_T2;
+            }
+            10 => {
+                self.sr = value;
+                self.ifr |= IFR_SR;
+            }
+            11 => self.acr = value,
+            12 => self.pcr = value,
+            13 => self.ifr &= !value,
+            14 => {
+                if value & 0x80 != 0 {
+                    self.ier |= value & 0x7f;
+                } else {
+                    self.ier &= !value;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn tick(&mut self) {
+        let (t1, underflow) = self.t1_counter.overflowing_sub(1);
+        self.t1_counter = t1;
+        if underflow {
+            if self.t1_armed {
+                self.ifr |= IFR_T1;
+            }
+            if self.acr & 0x40 != 0 {
+                // Free-running.
+                self.t1_counter = self.t1_latch;
+            } else {
+                self.t1_armed = false;
+            }
+        }
+
+        let (t2, underflow) = self.t2_counter.overflowing_sub(1);
+        self.t2_counter = t2;
+        if underflow && self.t2_armed {
+            self.ifr |= IFR_T2;
+            self.t2_armed = false;
+        }
+
+        self.vbl_ticks += 1;
+        if self.vbl_ticks == TICKS_PER_VBL {
+            self.vbl_ticks = 0;
+            self.ifr |= IFR_CA1;
+            self.vbl_count += 1;
+            if self.vbl_count == VBLS_PER_SECOND {
+                self.vbl_count = 0;
+                self.ifr |= IFR_CA2;
+            }
+        }
+    }
+
+    fn irq_level(&self) -> u8 {
+        if self.ifr & self.ier & 0x7f != 0 {
+            1
+        } else {
+            0
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Via::new();
+    }
+}
+
+// Apple IWM floppy controller, with no drives attached. Registers
+// are spaced every 0x200 bytes, and any access flips one of its eight
+// state lines, so there's just enough here for the ROM to set the mode
+// register and find no disks.
+#[derive(Default)]
+pub struct Iwm {
+    // Indexed by line: CA0-2, LSTRB, ENABLE, SELECT, Q6, Q7.
+    lines: [bool; 8],
+    mode: u8,
+}
+
+const IWM_ENABLE: usize = 4;
+const IWM_Q6: usize = 6;
+const IWM_Q7: usize = 7;
+
+impl Iwm {
+    fn access(&mut self, offset: u32) {
+        self.lines[((offset >> 10) & 7) as usize] = (offset >> 9) & 1 != 0;
+    }
+}
+
+impl Device for Iwm {
+    fn read(&mut self, offset: u32) -> u8 {
+        self.access(offset);
+        match (self.lines[IWM_Q6], self.lines[IWM_Q7]) {
+            // Data register: nothing read from the (absent) disk.
+            (false, false) => 0xff,
+            // Status register: sense line high, mode bits.
+            (true, false) => 0x80 | if self.lines[IWM_ENABLE] { 0x20 } else { 0x00 } | self.mode,
+            // Write handshake register: always ready.
+            (false, true) => 0xc0,
+            (true, true) => 0xff,
+        }
+    }
+
+    fn write(&mut self, offset: u32, value: u8) {
+        self.access(offset);
+        if self.lines[IWM_Q6] && self.lines[IWM_Q7] && !self.lines[IWM_ENABLE] {
+            self.mode = value & 0x1f;
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Iwm::default();
+    }
+}
+
+// The remapped I/O regions, with the VIA modelled and stubs for
+// everything else.
+pub fn standard_regions() -> Vec<Region> {
+    vec![
+        Region {
+            name: "DEBUG",
+            start: memory_map::DEBUG_START,
+            end: memory_map::DEBUG_END,
+            device: Box::new(Stub(0x00)),
+        },
+        Region {
+            name: "SCSI",
+            start: memory_map::SCSI_START,
+            end: memory_map::SCSI_END,
+            device: Box::new(Stub(0x00)),
+        },
+        Region {
+            name: "SCC",
+            start: memory_map::SCC_READ_START,
+            end: memory_map::SCC_WRITE_END,
+            device: Box::new(Scc::new()),
+        },
+        Region {
+            name: "IWM",
+            start: memory_map::IWM_START,
+            end: memory_map::IWM_END,
+            device: Box::new(Iwm::default()),
+        },
+        Region {
+            name: "VIA",
+            start: memory_map::VIA_START,
+            end: memory_map::VIA_END,
+            device: Box::new(Via::new()),
+        },
+    ]
+}
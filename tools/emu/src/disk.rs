@@ -0,0 +1,77 @@
+//
+// Disk images
+//
+// Raw or DiskCopy 4.2 images of block devices, for the paravirtual
+// floppy and SCSI models.
+//
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{ensure, Context};
+
+pub const BLOCK_SIZE: usize = 512;
+
+// A raw or DiskCopy 4.2 disk image. Writes go straight through to the
+// file, unless it's a scratch copy. If the file can't be written, the
+// disk is locked.
+pub struct Disk {
+    pub data: Vec<u8>,
+    // Where the data starts in the file.
+    offset: usize,
+    diskcopy: bool,
+    file: Option<File>,
+    locked: bool,
+}
+
+impl Disk {
+    pub fn open(path: &Path, scratch: bool) -> anyhow::Result<Disk> {
+        let image = fs::read(path).with_context(|| format!("Couldn't read {}", path.display()))?;
+        let (offset, size, diskcopy) = match diskcopy::data(&image) {
+            Some((offset, size)) => (offset, size, true),
+            None => (0, image.len(), false),
+        };
+        ensure!(
+            size > 0 && size % BLOCK_SIZE == 0,
+            "{} isn't a whole number of 512-byte blocks",
+            path.display()
+        );
+        let file = if scratch {
+            None
+        } else {
+            OpenOptions::new().write(true).open(path).ok()
+        };
+        Ok(Disk {
+            data: image[offset..offset + size].to_vec(),
+            offset,
+            diskcopy,
+            locked: !scratch && file.is_none(),
+            file,
+        })
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn blocks(&self) -> u32 {
+        (self.data.len() / BLOCK_SIZE) as u32
+    }
+
+    // Write a block, returning any error writing the file.
+    pub fn write_block(&mut self, block: u32, data: &[u8]) -> std::io::Result<()> {
+        let start = block as usize * BLOCK_SIZE;
+        self.data[start..start + BLOCK_SIZE].copy_from_slice(data);
+        let Some(file) = self.file.as_mut() else {
+            return Ok(());
+        };
+        file.seek(SeekFrom::Start((self.offset + start) as u64))?;
+        file.write_all(data)?;
+        if self.diskcopy {
+            file.seek(SeekFrom::Start(diskcopy::DATA_CHECKSUM as u64))?;
+            file.write_all(&diskcopy::checksum(&self.data).to_be_bytes())?;
+        }
+        Ok(())
+    }
+}
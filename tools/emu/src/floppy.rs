@@ -0,0 +1,381 @@
+//
+// Paravirtual floppy
+//
+// The host side of the floppy mailbox that `patch rom --floppy-mailbox`
+// replaces the IWM driver with (see patch/src/floppy.s for the
+// protocol), backed by a disk image, and a check that drives the
+// patched driver through the Device Manager against it.
+//
+
+use anyhow::{bail, ensure};
+
+use crate::disk::{Disk, BLOCK_SIZE};
+use crate::harness::{self, History, Outcome};
+use crate::machine::{Device, Machine, ROM_BASE};
+use crate::session;
+
+pub const MAILBOX_SIZE: u32 = memory_map::FLOPPY_MAILBOX_SIZE;
+
+// Commands.
+const CMD_READ: u8 = 1;
+const CMD_WRITE: u8 = 2;
+const CMD_SIZE: u8 = 3;
+const CMD_EJECT: u8 = 4;
+
+// Statuses.
+const STS_DONE: u8 = 0x00;
+const STS_BUSY: u8 = 0x01;
+const STS_NO_DISK: u8 = 0x80;
+const STS_BAD_BLOCK: u8 = 0x81;
+const STS_LOCKED: u8 = 0x82;
+const STS_BAD_COMMAND: u8 = 0x83;
+const STS_IO_ERROR: u8 = 0x84;
+
+// Drive register bits.
+const DRV_PRESENT: u8 = 0x01;
+const DRV_LOCKED: u8 = 0x02;
+
+////////////////////////////////////////////////////////////////////////
+// The mailbox.
+//
+
+// Commands complete on the tick after they're written, so the driver
+// sees them busy at most once.
+pub struct FloppyMailbox {
+    disk: Option<Disk>,
+    block: u32,
+    buffer: u32,
+    command: u8,
+    status: u8,
+    pending: bool,
+}
+
+impl FloppyMailbox {
+    pub fn new(disk: Option<Disk>) -> FloppyMailbox {
+        FloppyMailbox {
+            disk,
+            block: 0,
+            buffer: 0,
+            command: 0,
+            status: STS_DONE,
+            pending: false,
+        }
+    }
+
+    fn drive(&self) -> u8 {
+        match self.disk.as_ref() {
+            Some(disk) if disk.locked() => DRV_PRESENT | DRV_LOCKED,
+            Some(_) => DRV_PRESENT,
+            None => 0,
+        }
+    }
+
+    // Carry out the command, returning its status.
+    fn execute(&mut self, ram: &mut [u8]) -> u8 {
+        if self.command == CMD_EJECT {
+            self.disk = None;
+            return STS_DONE;
+        }
+        let Some(disk) = self.disk.as_mut() else {
+            return STS_NO_DISK;
+        };
+        if self.command == CMD_SIZE {
+            self.block = disk.blocks();
+            return STS_DONE;
+        }
+        if self.command != CMD_READ && self.command != CMD_WRITE {
+            return STS_BAD_COMMAND;
+        }
+        if self.block >= disk.blocks() {
+            return STS_BAD_BLOCK;
+        }
+        let buffer = (self.buffer & 0xffffff) as usize;
+        if buffer + BLOCK_SIZE > ram.len() {
+            return STS_BAD_COMMAND;
+        }
+        let buffer = &mut ram[buffer..buffer + BLOCK_SIZE];
+        let start = self.block as usize * BLOCK_SIZE;
+        if self.command == CMD_READ {
+            buffer.copy_from_slice(&disk.data[start..start + BLOCK_SIZE]);
+            return STS_DONE;
+        }
+        if disk.locked() {
+            return STS_LOCKED;
+        }
+        match disk.write_block(self.block, buffer) {
+            Ok(()) => STS_DONE,
+            Err(_) => STS_IO_ERROR,
+        }
+    }
+}
+
+impl Device for FloppyMailbox {
+    fn read(&mut self, offset: u32) -> u8 {
+        match offset {
+            0..=3 => self.block.to_be_bytes()[offset as usize],
+            4..=7 => self.buffer.to_be_bytes()[offset as usize - 4],
+            8 => self.command,
+            9 => self.status,
+            10 => self.drive(),
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u32, value: u8) {
+        match offset {
+            0..=3 => {
+                let shift = 8 * (3 - offset);
+                self.block = (self.block & !(0xff << shift)) | ((value as u32) << shift);
+            }
+            4..=7 => {
+                let shift = 8 * (7 - offset);
+                self.buffer = (self.buffer & !(0xff << shift)) | ((value as u32) << shift);
+            }
+            8 => {
+                self.command = value;
+                self.status = STS_BUSY;
+                self.pending = true;
+            }
+            _ => {}
+        }
+    }
+
+    fn dma(&mut self, ram: &mut [u8]) {
+        if self.pending {
+            self.pending = false;
+            self.status = self.execute(ram);
+        }
+    }
+
+    // The disk stays in the drive.
+    fn reset(&mut self) {
+        *self = FloppyMailbox::new(self.disk.take());
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// Checking the driver.
+//
+
+// The boot code's read of the boot blocks returns here, with the
+// result in D0 and the blocks at A6.
+const BOOT_READ_DONE: u32 = ROM_BASE + 0xe0c;
+const BOOT_BLOCKS_SIZE: usize = 0x400;
+
+// Traps.
+const READ: u16 = 0xa002;
+const WRITE: u16 = 0xa003;
+const CONTROL: u16 = 0xa004;
+const STATUS: u16 = 0xa005;
+
+// Parameter block fields.
+const IO_RESULT: u32 = 0x10;
+const IO_VREFNUM: u32 = 0x16;
+const IO_REFNUM: u32 = 0x18;
+const CS_CODE: u32 = 0x1a;
+const CS_PARAM: u32 = 0x1c;
+const IO_BUFFER: u32 = 0x20;
+const IO_REQCOUNT: u32 = 0x24;
+const IO_ACTCOUNT: u32 = 0x28;
+const IO_POSMODE: u32 = 0x2c;
+const IO_POSOFFSET: u32 = 0x2e;
+const PB_SIZE: u32 = 0x50;
+
+const SONY_REFNUM: u16 = -5i16 as u16;
+const DRIVE: u16 = 1;
+const FS_FROM_START: u16 = 1;
+
+// Result codes.
+const NO_ERR: i16 = 0;
+const PARAM_ERR: i16 = -50;
+const OFF_LIN_ERR: i16 = -65;
+
+// csCodes.
+const EJECT: u16 = 7;
+const DRIVE_STATUS: u16 = 8;
+// Offset of diskInPlace in the drive status record.
+const DISK_IN_PLACE: u32 = 3;
+
+// Instructions allowed per call.
+const CALL_LIMIT: u64 = 1_000_000;
+
+// Room taken off the stack for the parameter block and buffers.
+const SCRATCH_SIZE: u32 = 0x3000;
+const MAX_BLOCKS: usize = 16;
+
+// Drives the driver through the Device Manager, with a parameter block
+// and buffer carved off the stack.
+struct Checker<'a> {
+    m: &'a mut Machine,
+    history: &'a mut History,
+    pb: u32,
+    buffer: u32,
+}
+
+impl Checker<'_> {
+    fn clear_pb(&mut self) {
+        for i in 0..PB_SIZE {
+            self.m.mem.poke(self.pb + i, 0);
+        }
+        self.m.mem.poke_word(self.pb + IO_VREFNUM, DRIVE);
+        self.m.mem.poke_word(self.pb + IO_REFNUM, SONY_REFNUM);
+    }
+
+    // Make the call, returning the result code.
+    fn call(&mut self, word: u16) -> anyhow::Result<i16> {
+        self.m.cpu.a[0] = self.pb;
+        match session::trap(self.m, word, CALL_LIMIT, self.history, &mut |_, _, _| {}) {
+            Outcome::Failed(why) => bail!("{}", why),
+            _ => Ok(self.m.mem.peek_word(self.pb + IO_RESULT) as i16),
+        }
+    }
+
+    // Read or write blocks through the buffer, returning the result and
+    // the bytes transferred.
+    fn transfer(&mut self, word: u16, offset: u32, count: u32) -> anyhow::Result<(i16, u32)> {
+        self.clear_pb();
+        self.m.mem.poke_long(self.pb + IO_BUFFER, self.buffer);
+        self.m.mem.poke_long(self.pb + IO_REQCOUNT, count);
+        self.m.mem.poke_word(self.pb + IO_POSMODE, FS_FROM_START);
+        self.m.mem.poke_long(self.pb + IO_POSOFFSET, offset);
+        let result = self.call(word)?;
+        Ok((result, self.m.mem.peek_long(self.pb + IO_ACTCOUNT)))
+    }
+
+    // Read blocks, checking they match the image.
+    fn read(&mut self, expected: &[u8], block: usize, count: usize) -> anyhow::Result<()> {
+        let len = count * BLOCK_SIZE;
+        let (result, actual) = self.transfer(READ, (block * BLOCK_SIZE) as u32, len as u32)?;
+        ensure!(
+            result == NO_ERR,
+            "Reading {} blocks from {} gave {}",
+            count,
+            block,
+            result
+        );
+        ensure!(
+            actual as usize == len,
+            "Reading {} blocks from {} read 0x{:x} bytes",
+            count,
+            block,
+            actual
+        );
+        let start = block * BLOCK_SIZE;
+        ensure!(
+            self.m.mem.peek_bytes(self.buffer, len) == expected[start..start + len],
+            "Blocks {} to {} don't match the image",
+            block,
+            block + count - 1
+        );
+        println!("Floppy: read blocks {} to {}", block, block + count - 1);
+        Ok(())
+    }
+
+    // A call that should fail with the given result.
+    fn expect(&mut self, what: &str, result: i16, expected: i16) -> anyhow::Result<()> {
+        ensure!(
+            result == expected,
+            "{} gave {}, not {}",
+            what,
+            result,
+            expected
+        );
+        println!("Floppy: {} gave {}", what, result);
+        Ok(())
+    }
+
+    fn disk_in_place(&mut self) -> anyhow::Result<bool> {
+        self.clear_pb();
+        self.m.mem.poke_word(self.pb + CS_CODE, DRIVE_STATUS);
+        let result = self.call(STATUS)?;
+        ensure!(result == NO_ERR, "Drive status gave {}", result);
+        Ok(self.m.mem.peek(self.pb + CS_PARAM + DISK_IN_PLACE) != 0)
+    }
+
+    fn eject(&mut self) -> anyhow::Result<()> {
+        self.clear_pb();
+        self.m.mem.poke_word(self.pb + CS_CODE, EJECT);
+        let result = self.call(CONTROL)?;
+        self.expect("Eject", result, NO_ERR)
+    }
+}
+
+fn check_driver(m: &mut Machine, history: &mut History, image: &[u8]) -> anyhow::Result<()> {
+    let sp = m.cpu.a[7];
+    m.cpu.a[7] = sp - SCRATCH_SIZE;
+    let mut checker = Checker {
+        pb: sp - SCRATCH_SIZE + 0x100,
+        buffer: sp - SCRATCH_SIZE + 0x200,
+        m,
+        history,
+    };
+    let blocks = image.len() / BLOCK_SIZE;
+    let result = (|| {
+        ensure!(checker.disk_in_place()?, "Drive status shows no disk");
+        println!("Floppy: drive status shows a disk");
+
+        checker.read(image, 0, 4)?;
+        checker.read(image, blocks / 2, MAX_BLOCKS)?;
+        checker.read(image, blocks - 2, 2)?;
+
+        let (result, _) = checker.transfer(READ, (blocks * BLOCK_SIZE) as u32, 512)?;
+        checker.expect("Reading past the end", result, PARAM_ERR)?;
+        let (result, _) = checker.transfer(READ, 0x100, 512)?;
+        checker.expect("Reading from mid-block", result, PARAM_ERR)?;
+
+        let pattern = (0..BLOCK_SIZE).map(|i| i as u8 ^ 0x5a).collect::<Vec<_>>();
+        checker.m.mem.poke_bytes(checker.buffer, &pattern);
+        let last = ((blocks - 1) * BLOCK_SIZE) as u32;
+        let (result, _) = checker.transfer(WRITE, last, BLOCK_SIZE as u32)?;
+        ensure!(result == NO_ERR, "Writing the last block gave {}", result);
+        let mut written = image.to_vec();
+        written[last as usize..].copy_from_slice(&pattern);
+        checker.read(&written, blocks - 1, 1)?;
+
+        checker.eject()?;
+        ensure!(
+            !checker.disk_in_place()?,
+            "Drive status shows a disk after ejecting"
+        );
+        println!("Floppy: drive status shows no disk");
+        let (result, _) = checker.transfer(READ, 0, 512)?;
+        checker.expect("Reading with no disk", result, OFF_LIN_ERR)
+    })();
+    checker.m.cpu.a[7] = sp;
+    result
+}
+
+// Boot until the ROM has read the boot blocks through the driver, then
+// make calls to it directly.
+pub fn check(m: &mut Machine, disk: &Disk, limit: u64, history: &mut History) -> Outcome {
+    if let Outcome::Failed(why) = harness::skip_adb(m, limit, history) {
+        return Outcome::Failed(why);
+    }
+
+    // The ROM keeps trying until the VBL task has found the disk.
+    loop {
+        match harness::run(m, Some(BOOT_READ_DONE), limit, history, &mut |_, _, _| {}) {
+            Outcome::Stopped if m.cpu.d[0] == 0 => break,
+            Outcome::Stopped => {
+                m.step();
+            }
+            Outcome::Completed => {
+                return Outcome::Failed("boot blocks not read".to_string());
+            }
+            failed => return failed,
+        }
+    }
+    let boot_blocks = m.mem.peek_bytes(m.cpu.a[6], BOOT_BLOCKS_SIZE);
+    if boot_blocks != disk.data[..BOOT_BLOCKS_SIZE] {
+        return Outcome::Failed("boot blocks don't match the image".to_string());
+    }
+    println!(
+        "Floppy: boot blocks read after {} instructions",
+        m.instructions
+    );
+
+    match check_driver(m, history, &disk.data) {
+        Ok(()) => Outcome::Completed,
+        Err(e) => Outcome::Failed(e.to_string()),
+    }
+}
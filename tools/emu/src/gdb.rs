@@ -0,0 +1,564 @@
+//
+// GDB remote stub
+//
+// Serves the GDB remote serial protocol on localhost, so that m68k
+// GDB (or anything else that speaks the protocol, such as Ghidra's
+// debugger) can debug the patched ROM running on the emulator.
+//
+// Supports software and hardware breakpoints (they're the same thing
+// here), write, read and access watchpoints on memory ranges,
+// single-stepping, and reading and writing registers and memory.
+// Memory writes only go to RAM. The target stops by itself, with a
+// signal, on the same things the test harness fails on.
+//
+
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+
+use crate::cpu::*;
+use crate::harness;
+use crate::machine::{Machine, WatchKind, Watchpoint};
+use crate::symbols::Symbols;
+
+// GDB's signal numbers.
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+const SIGFPE: u8 = 8;
+const SIGBUS: u8 = 10;
+const SIGSEGV: u8 = 11;
+
+// How many instructions to run between checks for an interrupt from
+// GDB.
+const POLL_INTERVAL: u64 = 4096;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>m68k</architecture>
+  <feature name="org.gnu.gdb.m68k.core">
+    <reg name="d0" bitsize="32"/>
+    <reg name="d1" bitsize="32"/>
+    <reg name="d2" bitsize="32"/>
+    <reg name="d3" bitsize="32"/>
+    <reg name="d4" bitsize="32"/>
+    <reg name="d5" bitsize="32"/>
+    <reg name="d6" bitsize="32"/>
+    <reg name="d7" bitsize="32"/>
+    <reg name="a0" bitsize="32" type="data_ptr"/>
+    <reg name="a1" bitsize="32" type="data_ptr"/>
+    <reg name="a2" bitsize="32" type="data_ptr"/>
+    <reg name="a3" bitsize="32" type="data_ptr"/>
+    <reg name="a4" bitsize="32" type="data_ptr"/>
+    <reg name="a5" bitsize="32" type="data_ptr"/>
+    <reg name="fp" bitsize="32" type="data_ptr"/>
+    <reg name="sp" bitsize="32" type="data_ptr"/>
+    <reg name="ps" bitsize="32"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+// d0-d7, a0-a7, ps and pc.
+const NUM_REGS: usize = 18;
+
+////////////////////////////////////////////////////////////////////////
+// Packet layer.
+//
+
+enum Packet {
+    Data(String),
+    // Ctrl-C from GDB.
+    Interrupt,
+}
+
+struct Connection {
+    stream: TcpStream,
+    // Bytes read while polling for interrupts, not yet consumed.
+    pending: Vec<u8>,
+    interrupted: bool,
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0u8, |acc, b| acc.wrapping_add(b))
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Connection {
+        Connection {
+            stream,
+            pending: Vec::new(),
+            interrupted: false,
+        }
+    }
+
+    // Next byte from GDB, or None if it's gone.
+    fn byte(&mut self) -> io::Result<Option<u8>> {
+        if !self.pending.is_empty() {
+            return Ok(Some(self.pending.remove(0)));
+        }
+        let mut buf = [0u8; 1];
+        match self.stream.read(&mut buf)? {
+            0 => Ok(None),
+            _ => Ok(Some(buf[0])),
+        }
+    }
+
+    fn read_packet(&mut self) -> io::Result<Option<Packet>> {
+        if self.interrupted {
+            self.interrupted = false;
+            return Ok(Some(Packet::Interrupt));
+        }
+        loop {
+            // Skip acks and anything else outside a packet.
+            match self.byte()? {
+                None => return Ok(None),
+                Some(0x03) => return Ok(Some(Packet::Interrupt)),
+                Some(b'$') => {}
+                Some(_) => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    // Escaped byte, as found in binary data.
+                    Some(b'}') => match self.byte()? {
+                        None => return Ok(None),
+                        Some(b) => data.push(b ^ 0x20),
+                    },
+                    Some(b) => data.push(b),
+                }
+            }
+            let mut sum = [0u8; 2];
+            for b in sum.iter_mut() {
+                match self.byte()? {
+                    None => return Ok(None),
+                    Some(x) => *b = x,
+                }
+            }
+            let data = String::from_utf8_lossy(&data).into_owned();
+            let expected = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok());
+            if expected == Some(checksum(&data)) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(Packet::Data(data)));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data));
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            // Wait for the ack, resending if GDB asks.
+            loop {
+                match self.byte()? {
+                    None | Some(b'+') => return Ok(()),
+                    Some(b'-') => break,
+                    Some(0x03) => self.interrupted = true,
+                    Some(_) => {}
+                }
+            }
+        }
+    }
+
+    // Check, without blocking, whether GDB has sent a Ctrl-C.
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut buf = [0u8; 64];
+        let result = self.stream.read(&mut buf);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(n) => {
+                for b in buf[..n].iter() {
+                    if *b == 0x03 {
+                        self.interrupted = true;
+                    } else {
+                        self.pending.push(*b);
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+        Ok(std::mem::take(&mut self.interrupted))
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    u32::from_str_radix(s, 16).ok()
+}
+
+// "addr,len", as used by the memory and breakpoint packets.
+fn parse_range(s: &str) -> Option<(u32, u32)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((parse_hex(addr)?, parse_hex(len)?))
+}
+
+////////////////////////////////////////////////////////////////////////
+// Debugging the machine.
+//
+
+struct Stub<'a> {
+    m: Machine,
+    symbols: &'a Symbols,
+    breakpoints: BTreeSet<u32>,
+    // Why the target last stopped, for "?".
+    last_stop: String,
+}
+
+impl<'a> Stub<'a> {
+    fn reg(&self, n: usize) -> u32 {
+        let cpu = &self.m.cpu;
+        match n {
+            0..=7 => cpu.d[n],
+            8..=15 => cpu.a[n - 8],
+            16 => cpu.sr as u32,
+            _ => cpu.pc,
+        }
+    }
+
+    fn set_reg(&mut self, n: usize, value: u32) {
+        let cpu = &mut self.m.cpu;
+        match n {
+            0..=7 => cpu.d[n] = value,
+            8..=15 => cpu.a[n - 8] = value,
+            16 => cpu.set_sr(value as u16),
+            _ => cpu.pc = value,
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        (0..NUM_REGS)
+            .map(|n| format!("{:08x}", self.reg(n)))
+            .collect()
+    }
+
+    fn write_registers(&mut self, hex: &str) -> String {
+        match from_hex(hex) {
+            Some(bytes) if bytes.len() == 4 * NUM_REGS => {
+                for (n, chunk) in bytes.chunks(4).enumerate() {
+                    self.set_reg(n, u32::from_be_bytes(chunk.try_into().unwrap()));
+                }
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        match parse_range(args) {
+            Some((addr, len)) => {
+                let bytes = (0..len)
+                    .map(|i| self.m.mem.peek(addr.wrapping_add(i)))
+                    .collect::<Vec<_>>();
+                to_hex(&bytes)
+            }
+            None => "E01".to_string(),
+        }
+    }
+
+    // Writes to anything but RAM fail, rather than being silently
+    // dropped.
+    fn write_memory(&mut self, args: &str) -> String {
+        let Some((range, data)) = args.split_once(':') else {
+            return "E01".to_string();
+        };
+        let (Some((addr, len)), Some(bytes)) = (parse_range(range), from_hex(data)) else {
+            return "E01".to_string();
+        };
+        if bytes.len() != len as usize || (0..len).any(|i| !self.m.mem.is_ram(addr.wrapping_add(i)))
+        {
+            return "E01".to_string();
+        }
+        self.m.mem.poke_bytes(addr, &bytes);
+        "OK".to_string()
+    }
+
+    // "Z" and "z" packets: "type,addr,kind", where kind is the length
+    // for watchpoints.
+    fn breakpoint(&mut self, insert: bool, args: &str) -> String {
+        let mut fields = args.split(',');
+        let (Some(kind), Some(addr), Some(len)) = (
+            fields.next(),
+            fields.next().and_then(parse_hex),
+            fields
+                .next()
+                .and_then(|s| parse_hex(s.split(';').next().unwrap())),
+        ) else {
+            return "E01".to_string();
+        };
+        let watch_kind = match kind {
+            "0" | "1" => {
+                if insert {
+                    self.breakpoints.insert(addr);
+                } else {
+                    self.breakpoints.remove(&addr);
+                }
+                return "OK".to_string();
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return String::new(),
+        };
+        let watchpoint = Watchpoint {
+            start: addr & 0xffffff,
+            end: (addr & 0xffffff) + len.max(1),
+            kind: watch_kind,
+        };
+        let watchpoints = &mut self.m.mem.watchpoints;
+        if insert {
+            watchpoints.push(watchpoint);
+        } else if let Some(idx) = watchpoints.iter().position(|w| *w == watchpoint) {
+            watchpoints.remove(idx);
+        }
+        "OK".to_string()
+    }
+
+    // Run until something stops us, returning the stop reply.
+    fn resume(&mut self, conn: &mut Connection, single_step: bool) -> io::Result<String> {
+        self.m.mem.watch_hit = None;
+        let mut count = 0u64;
+        loop {
+            let pc = self.m.cpu.pc;
+            // Don't stop on a breakpoint we're continuing from.
+            if count > 0 && self.breakpoints.contains(&pc) {
+                return Ok(format!("S{:02x}", SIGTRAP));
+            }
+            if pc == harness::SAD_MAC {
+                println!(
+                    "Sad Mac, error code {:08x} {:08x}",
+                    self.m.cpu.d[7], self.m.cpu.d[6]
+                );
+                return Ok(format!("S{:02x}", SIGABRT));
+            }
+            if !self.m.mem.is_rom(pc) && !self.m.mem.is_ram(pc) {
+                println!("Executing outside RAM and ROM at 0x{:06x}", pc);
+                return Ok(format!("S{:02x}", SIGSEGV));
+            }
+
+            let vector = self.m.step();
+            count += 1;
+
+            if let Some((w, addr)) = self.m.mem.watch_hit.take() {
+                let kind = match w.kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                return Ok(format!("T{:02x}{}:{:08x};", SIGTRAP, kind, addr));
+            }
+            if let Some(vector) = vector {
+                if let Some(what) = harness::fatal_exception(vector) {
+                    println!("{} at 0x{:06x}", what, pc);
+                    let signal = match vector {
+                        VEC_ZERO_DIVIDE => SIGFPE,
+                        VEC_PRIVILEGE => SIGILL,
+                        _ => SIGBUS,
+                    };
+                    return Ok(format!("S{:02x}", signal));
+                }
+            }
+            if self.m.cpu.halted {
+                println!("CPU halted by double fault at 0x{:06x}", pc);
+                return Ok(format!("S{:02x}", SIGBUS));
+            }
+            if single_step {
+                return Ok(format!("S{:02x}", SIGTRAP));
+            }
+            if count.is_multiple_of(POLL_INTERVAL) && conn.poll_interrupt()? {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+        }
+    }
+
+    // "monitor" commands, returning the text to show.
+    fn monitor(&mut self, command: &str) -> String {
+        let words = command.split_whitespace().collect::<Vec<_>>();
+        match words.as_slice() {
+            ["sym", name] => match self.symbols.lookup(name) {
+                Some(addr) => format!("{} = 0x{:06x}\n", name, addr),
+                None => format!("No symbol '{}'\n", name),
+            },
+            ["where"] => format!("{}\n", self.symbols.describe(self.m.cpu.pc)),
+            ["where", addr] => match parse_hex(addr.trim_start_matches("0x")) {
+                Some(addr) => format!("{}\n", self.symbols.describe(addr)),
+                None => format!("Bad address '{}'\n", addr),
+            },
+            ["reset"] => {
+                self.m.reset();
+                format!("Reset, PC = 0x{:06x}\n", self.m.cpu.pc)
+            }
+            _ => "Commands: sym <name>, where [<addr>], reset\n".to_string(),
+        }
+    }
+
+    // Handle a packet, returning the reply, or None to drop the
+    // connection.
+    fn handle(&mut self, conn: &mut Connection, packet: &str) -> io::Result<Option<String>> {
+        let (command, args) = packet.split_at(packet.len().min(1));
+        let reply = match command {
+            "?" => self.last_stop.clone(),
+            "g" => self.read_registers(),
+            "G" => self.write_registers(args),
+            "p" => match parse_hex(args) {
+                Some(n) if (n as usize) < NUM_REGS => format!("{:08x}", self.reg(n as usize)),
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let parsed = args
+                    .split_once('=')
+                    .and_then(|(n, value)| Some((parse_hex(n)? as usize, from_hex(value)?)));
+                match parsed {
+                    Some((n, value)) if n < NUM_REGS && value.len() == 4 => {
+                        self.set_reg(n, u32::from_be_bytes(value.try_into().unwrap()));
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "c" | "s" => {
+                // We don't support resuming at another address.
+                self.last_stop = self.resume(conn, command == "s")?;
+                self.last_stop.clone()
+            }
+            "Z" => self.breakpoint(true, args),
+            "z" => self.breakpoint(false, args),
+            "H" => "OK".to_string(),
+            "T" => "OK".to_string(),
+            "k" | "D" => {
+                if command == "D" {
+                    conn.send("OK")?;
+                }
+                return Ok(None);
+            }
+            "q" => self.query(args),
+            _ => String::new(),
+        };
+        Ok(Some(reply))
+    }
+
+    fn query(&mut self, query: &str) -> String {
+        if query.starts_with("Supported") {
+            return "PacketSize=4000;qXfer:features:read+".to_string();
+        }
+        if let Some(args) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            return match parse_range(args) {
+                Some((offset, len)) => {
+                    let xml = TARGET_XML.as_bytes();
+                    let start = (offset as usize).min(xml.len());
+                    let end = (start + len as usize).min(xml.len());
+                    let more = if end < xml.len() { "m" } else { "l" };
+                    format!("{}{}", more, String::from_utf8_lossy(&xml[start..end]))
+                }
+                None => "E01".to_string(),
+            };
+        }
+        if let Some(hex) = query.strip_prefix("Rcmd,") {
+            let command = from_hex(hex).unwrap_or_default();
+            let output = self.monitor(&String::from_utf8_lossy(&command));
+            return to_hex(output.as_bytes());
+        }
+        match query {
+            "Attached" => "1".to_string(),
+            "C" => "QC1".to_string(),
+            "fThreadInfo" => "m1".to_string(),
+            "sThreadInfo" => "l".to_string(),
+            "Symbol::" => "OK".to_string(),
+            _ => String::new(),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// GDB command file.
+//
+// Connects to the stub and defines a convenience variable for each
+// trap entry point, so that e.g. "break *$BlockMove" works.
+//
+
+pub fn write_script(path: &Path, symbols: &Symbols, port: u16) -> anyhow::Result<()> {
+    let mut script = String::from("# Generated by the \"emu\" tool.\nset architecture m68k\n");
+    for (addr, name) in symbols.iter() {
+        writeln!(
+            script,
+            "set ${} = 0x{:06x}",
+            name.trim_start_matches('_'),
+            addr
+        )?;
+    }
+    writeln!(script, "target remote localhost:{}", port)?;
+    fs::write(path, script).with_context(|| format!("Couldn't write {}", path.display()))?;
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////
+// Server.
+//
+
+// Serve one GDB connection at a time, keeping the machine's state
+// between connections.
+pub fn serve(m: Machine, symbols: &Symbols, port: u16) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .with_context(|| format!("Couldn't listen on port {}", port))?;
+    let mut stub = Stub {
+        m,
+        symbols,
+        breakpoints: BTreeSet::new(),
+        last_stop: format!("S{:02x}", SIGTRAP),
+    };
+    println!("Waiting for GDB on localhost:{}", port);
+    loop {
+        let (stream, addr) = listener.accept()?;
+        println!("GDB connected from {}", addr);
+        stream.set_nodelay(true)?;
+        let mut conn = Connection::new(stream);
+        loop {
+            let packet = match conn.read_packet() {
+                Ok(Some(packet)) => packet,
+                Ok(None) => break,
+                Err(e) => return Err(anyhow!(e).context("Lost connection to GDB")),
+            };
+            let reply = match packet {
+                // Only meaningful while running, where resume picks it up.
+                Packet::Interrupt => continue,
+                Packet::Data(data) => stub.handle(&mut conn, &data)?,
+            };
+            match reply {
+                Some(reply) => conn.send(&reply)?,
+                None => break,
+            }
+        }
+        println!(
+            "GDB disconnected, at 0x{:06x} after {} instructions",
+            stub.m.cpu.pc, stub.m.instructions
+        );
+    }
+}
@@ -0,0 +1,121 @@
+//
+// Test harness
+//
+// Runs the machine until it reaches a given PC, runs out of
+// instructions or goes wrong in a way a correctly-relocated ROM
+// shouldn't.
+//
+
+use std::collections::VecDeque;
+
+use crate::cpu::*;
+use crate::machine::{Machine, ROM_BASE};
+
+// Exceptions that a correctly-relocated ROM should never take while
+// booting. Illegal and line F instructions aren't on the list, as the
+// ROM deliberately executes them to find out which CPU and FPU it has.
+pub fn fatal_exception(vector: u8) -> Option<&'static str> {
+    match vector {
+        VEC_BUS_ERROR => Some("bus error"),
+        VEC_ADDRESS_ERROR => Some("address error"),
+        VEC_ZERO_DIVIDE => Some("divide by zero"),
+        VEC_PRIVILEGE => Some("privilege violation"),
+        _ => None,
+    }
+}
+
+// The startup tests jump here to draw the Sad Mac, with the error
+// codes in D6 and D7.
+pub const SAD_MAC: u32 = ROM_BASE + 0x1092;
+
+// The emulator has no ADB transceiver, which the ROM waits for
+// forever, so checks that boot the ROM step over the call that
+// initialises it. A ROM patched for the paravirtual VIA functions gets
+// through it, and returns here.
+const INIT_ADB_CALL: u32 = ROM_BASE + 0x102;
+pub const INIT_ADB_RETURN: u32 = ROM_BASE + 0x106;
+
+// Why a run stopped.
+pub enum Outcome {
+    Stopped,
+    Completed,
+    Failed(String),
+}
+
+// The most recently executed PCs, for post-mortems.
+pub struct History {
+    pub pcs: VecDeque<u32>,
+    len: usize,
+}
+
+impl History {
+    pub fn new(len: usize) -> History {
+        History {
+            pcs: VecDeque::with_capacity(len),
+            len,
+        }
+    }
+
+    fn push(&mut self, pc: u32) {
+        if self.len == 0 {
+            return;
+        }
+        if self.pcs.len() == self.len {
+            self.pcs.pop_front();
+        }
+        self.pcs.push_back(pc);
+    }
+}
+
+// Called after each instruction, with the PC it was executed from and
+// the exception it raised, if any.
+pub type Observer<'a> = &'a mut dyn FnMut(&Machine, u32, Option<u8>);
+
+// Run until the PC reaches "stop", the machine has executed "limit"
+// instructions in total, or something goes wrong.
+pub fn run(
+    m: &mut Machine,
+    stop: Option<u32>,
+    limit: u64,
+    history: &mut History,
+    observe: Observer,
+) -> Outcome {
+    while m.instructions < limit {
+        let pc = m.cpu.pc;
+        if Some(pc) == stop {
+            return Outcome::Stopped;
+        }
+        if pc == SAD_MAC {
+            return Outcome::Failed(format!(
+                "Sad Mac, error code {:08x} {:08x}",
+                m.cpu.d[7], m.cpu.d[6]
+            ));
+        }
+        if !m.mem.is_rom(pc) && !m.mem.is_ram(pc) {
+            return Outcome::Failed(format!("executing outside RAM and ROM at 0x{:06x}", pc));
+        }
+        history.push(pc);
+
+        let vector = m.step();
+        observe(m, pc, vector);
+        if let Some(what) = vector.and_then(fatal_exception) {
+            return Outcome::Failed(format!("{} at 0x{:06x}", what, pc));
+        }
+        if m.cpu.halted {
+            return Outcome::Failed(format!("CPU halted by double fault at 0x{:06x}", pc));
+        }
+    }
+    Outcome::Completed
+}
+
+// Run from reset to the ADB initialisation, and step over it.
+pub fn skip_adb(m: &mut Machine, limit: u64, history: &mut History) -> Outcome {
+    match run(m, Some(INIT_ADB_CALL), limit, history, &mut |_, _, _| {}) {
+        Outcome::Stopped => {
+            m.cpu.pc = INIT_ADB_RETURN;
+            Outcome::Completed
+        }
+        Outcome::Completed => Outcome::Failed("ADB initialisation not reached".to_string()),
+        failed => failed,
+    }
+}
@@ -0,0 +1,442 @@
+//
+// Memory map and machine
+//
+// Ties the CPU to RAM, the patched ROM and the devices, following the
+// remapped memory map.
+//
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::cpu::{Bus, Cpu};
+
+pub const ROM_BASE: u32 = memory_map::ROM_START;
+pub const ROM_SIZE: u32 = memory_map::ROM_SIZE;
+pub const IO_BASE: u32 = memory_map::IO_REGIONS[0].1;
+
+// RAM can fill everything up to the ROM. The patched ROM's memory
+// sizing probe gives up after 2.5MB and assumes it does.
+pub const MAX_RAM: u32 = memory_map::RAM_END;
+
+// A memory-mapped device. Offsets are relative to the start of the
+// device's region. Devices are clocked once per instruction, which is
+// roughly one tick of the 783kHz E clock the VIA runs from.
+pub trait Device {
+    fn read(&mut self, offset: u32) -> u8;
+    fn write(&mut self, offset: u32, value: u8);
+
+    fn tick(&mut self) {}
+
+    // Devices that move data to or from RAM themselves get at it here,
+    // after each tick.
+    fn dma(&mut self, _ram: &mut [u8]) {}
+
+    // Interrupt level requested, 0 if none.
+    fn irq_level(&self) -> u8 {
+        0
+    }
+
+    fn reset(&mut self) {}
+}
+
+// A device that does nothing, reading back a fixed value.
+pub struct Stub(pub u8);
+
+impl Device for Stub {
+    fn read(&mut self, _offset: u32) -> u8 {
+        self.0
+    }
+
+    fn write(&mut self, _offset: u32, _value: u8) {}
+}
+
+// A device that's shared with the code running the machine, so that a
+// check can feed it input and look at its state.
+impl<T: Device> Device for Rc<RefCell<T>> {
+    fn read(&mut self, offset: u32) -> u8 {
+        self.borrow_mut().read(offset)
+    }
+
+    fn write(&mut self, offset: u32, value: u8) {
+        self.borrow_mut().write(offset, value)
+    }
+
+    fn tick(&mut self) {
+        self.borrow_mut().tick()
+    }
+
+    fn dma(&mut self, ram: &mut [u8]) {
+        self.borrow_mut().dma(ram)
+    }
+
+    fn irq_level(&self) -> u8 {
+        self.borrow().irq_level()
+    }
+
+    fn reset(&mut self) {
+        self.borrow_mut().reset()
+    }
+}
+
+pub struct Region {
+    pub name: &'static str,
+    pub start: u32,
+    // Exclusive.
+    pub end: u32,
+    pub device: Box<dyn Device>,
+}
+
+// An access to memory that isn't there.
+#[derive(Clone, Copy, Debug)]
+pub struct Unmapped {
+    pub pc: u32,
+    pub addr: u32,
+    pub write: bool,
+}
+
+// A device access, as seen on the bus. Long accesses are made as two
+// word accesses, as on the real 68000.
+#[derive(Clone, Copy, Debug)]
+pub struct IoAccess {
+    pub pc: u32,
+    pub addr: u32,
+    // In bytes.
+    pub width: u8,
+    pub write: bool,
+    pub value: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+// A range of memory to stop on accesses to, for debuggers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: u32,
+    // Exclusive.
+    pub end: u32,
+    pub kind: WatchKind,
+}
+
+pub struct Memory {
+    ram: Vec<u8>,
+    rom: Vec<u8>,
+    // At reset the ROM also appears at address 0, until it's accessed
+    // at its real address.
+    overlay: bool,
+    pub regions: Vec<Region>,
+    // Start of the instruction being executed.
+    pub pc: u32,
+    pub unmapped: Vec<Unmapped>,
+    // Device accesses, if tracing.
+    pub io_log: Option<Vec<IoAccess>>,
+    pub watchpoints: Vec<Watchpoint>,
+    // The first watchpoint hit since this was cleared, and the address
+    // accessed.
+    pub watch_hit: Option<(Watchpoint, u32)>,
+}
+
+// Keep memory use bounded if something goes wild.
+const MAX_UNMAPPED_LOG: usize = 1000;
+
+impl Memory {
+    pub fn new(rom: Vec<u8>, ram_size: u32, regions: Vec<Region>) -> Memory {
+        assert_eq!(rom.len(), ROM_SIZE as usize, "ROM must be 256kB");
+        assert!(ram_size <= MAX_RAM);
+        Memory {
+            ram: vec![0; ram_size as usize],
+            rom,
+            overlay: true,
+            regions,
+            pc: 0,
+            unmapped: Vec::new(),
+            io_log: None,
+            watchpoints: Vec::new(),
+            watch_hit: None,
+        }
+    }
+
+    pub fn ram_size(&self) -> u32 {
+        self.ram.len() as u32
+    }
+
+    // The 68000 only has 24 address lines, so the top byte is ignored,
+    // as it is when the ROM jumps through a handle's master pointer.
+    pub fn is_ram(&self, addr: u32) -> bool {
+        let addr = addr & 0xffffff;
+        addr < self.ram_size() && !self.overlay
+    }
+
+    pub fn is_rom(&self, addr: u32) -> bool {
+        let addr = addr & 0xffffff;
+        (ROM_BASE..ROM_BASE + ROM_SIZE).contains(&addr) || (self.overlay && addr < ROM_SIZE)
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    pub fn region_at(&self, addr: u32) -> Option<&Region> {
+        let addr = addr & 0xffffff;
+        self.regions
+            .iter()
+            .find(|r| (r.start..r.end).contains(&addr))
+    }
+
+    // Read memory without side effects, for debuggers and tracing.
+    // Devices read as zero.
+    pub fn peek(&self, addr: u32) -> u8 {
+        let addr = addr & 0xffffff;
+        if self.overlay && addr < ROM_SIZE {
+            self.rom[addr as usize]
+        } else if addr < self.ram_size() {
+            self.ram[addr as usize]
+        } else if (ROM_BASE..ROM_BASE + ROM_SIZE).contains(&addr) {
+            self.rom[(addr - ROM_BASE) as usize]
+        } else {
+            0
+        }
+    }
+
+    // Write memory without side effects, for loaders and scripts.
+    // Only RAM can be poked.
+    pub fn poke(&mut self, addr: u32, value: u8) {
+        let addr = addr & 0xffffff;
+        if addr < self.ram_size() {
+            self.ram[addr as usize] = value;
+        }
+    }
+
+    // Big-endian versions of the above.
+    pub fn peek_word(&self, addr: u32) -> u16 {
+        ((self.peek(addr) as u16) << 8) | self.peek(addr + 1) as u16
+    }
+
+    pub fn peek_long(&self, addr: u32) -> u32 {
+        ((self.peek_word(addr) as u32) << 16) | self.peek_word(addr + 2) as u32
+    }
+
+    pub fn peek_bytes(&self, addr: u32, len: usize) -> Vec<u8> {
+        (0..len as u32).map(|i| self.peek(addr + i)).collect()
+    }
+
+    pub fn poke_word(&mut self, addr: u32, value: u16) {
+        self.poke_bytes(addr, &value.to_be_bytes());
+    }
+
+    pub fn poke_long(&mut self, addr: u32, value: u32) {
+        self.poke_bytes(addr, &value.to_be_bytes());
+    }
+
+    pub fn poke_bytes(&mut self, addr: u32, bytes: &[u8]) {
+        for (i, byte) in bytes.iter().enumerate() {
+            self.poke(addr + i as u32, *byte);
+        }
+    }
+
+    fn check_watchpoints(&mut self, addr: u32, width: u32, write: bool) {
+        if self.watch_hit.is_some() {
+            return;
+        }
+        let hit = self.watchpoints.iter().find(|w| {
+            let kind_matches = match w.kind {
+                WatchKind::Write => write,
+                WatchKind::Read => !write,
+                WatchKind::Access => true,
+            };
+            kind_matches && addr < w.end && addr + width > w.start
+        });
+        if let Some(w) = hit {
+            self.watch_hit = Some((*w, addr));
+        }
+    }
+
+    fn log_unmapped(&mut self, addr: u32, write: bool) {
+        if self.unmapped.len() < MAX_UNMAPPED_LOG {
+            self.unmapped.push(Unmapped {
+                pc: self.pc,
+                addr,
+                write,
+            });
+        }
+    }
+
+    fn device(&mut self, addr: u32) -> Option<(&mut Box<dyn Device>, u32)> {
+        self.regions
+            .iter_mut()
+            .find(|r| (r.start..r.end).contains(&addr))
+            .map(|r| (&mut r.device, addr - r.start))
+    }
+
+    fn log_io(&mut self, addr: u32, width: u8, write: bool, value: u16) {
+        let pc = self.pc;
+        if let Some(log) = self.io_log.as_mut() {
+            log.push(IoAccess {
+                pc,
+                addr,
+                width,
+                write,
+                value,
+            });
+        }
+    }
+
+    // Device accesses are made a byte at a time, but logged at the
+    // width the CPU asked for.
+    fn device_read(&mut self, addr: u32, width: u8) -> Option<u16> {
+        let (device, offset) = self.device(addr)?;
+        let mut value = 0;
+        for i in 0..width as u32 {
+            value = (value << 8) | device.read(offset + i) as u16;
+        }
+        self.log_io(addr, width, false, value);
+        Some(value)
+    }
+
+    fn device_write(&mut self, addr: u32, width: u8, value: u16) -> bool {
+        let Some((device, offset)) = self.device(addr) else {
+            return false;
+        };
+        for i in 0..width as u32 {
+            device.write(offset + i, (value >> (8 * (width as u32 - 1 - i))) as u8);
+        }
+        self.log_io(addr, width, true, value);
+        true
+    }
+
+    pub fn tick(&mut self) {
+        for region in self.regions.iter_mut() {
+            region.device.tick();
+            region.device.dma(&mut self.ram);
+        }
+    }
+}
+
+impl Bus for Memory {
+    fn read_byte(&mut self, addr: u32) -> u8 {
+        let addr = addr & 0xffffff;
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(addr, 1, false);
+        }
+        if (ROM_BASE..ROM_BASE + ROM_SIZE).contains(&addr) {
+            self.overlay = false;
+            return self.rom[(addr - ROM_BASE) as usize];
+        }
+        if self.overlay && addr < ROM_SIZE {
+            return self.rom[addr as usize];
+        }
+        if addr < self.ram_size() {
+            return self.ram[addr as usize];
+        }
+        if let Some(value) = self.device_read(addr, 1) {
+            return value as u8;
+        }
+        self.log_unmapped(addr, false);
+        0xff
+    }
+
+    fn write_byte(&mut self, addr: u32, value: u8) {
+        let addr = addr & 0xffffff;
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(addr, 1, true);
+        }
+        if (ROM_BASE..ROM_BASE + ROM_SIZE).contains(&addr) {
+            self.overlay = false;
+            return;
+        }
+        if addr < self.ram_size() {
+            self.ram[addr as usize] = value;
+            return;
+        }
+        if self.device_write(addr, 1, value as u16) {
+            return;
+        }
+        self.log_unmapped(addr, true);
+    }
+
+    fn read_word(&mut self, addr: u32) -> u16 {
+        let addr = addr & 0xffffff;
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(addr, 2, false);
+        }
+        // Fast path for plain RAM.
+        if addr + 1 < self.ram_size() && !self.overlay {
+            let idx = addr as usize;
+            return ((self.ram[idx] as u16) << 8) | self.ram[idx + 1] as u16;
+        }
+        if let Some(value) = self.device_read(addr, 2) {
+            return value;
+        }
+        ((self.read_byte(addr) as u16) << 8) | self.read_byte(addr + 1) as u16
+    }
+
+    fn write_word(&mut self, addr: u32, value: u16) {
+        let addr = addr & 0xffffff;
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(addr, 2, true);
+        }
+        if addr + 1 < self.ram_size() {
+            let idx = addr as usize;
+            self.ram[idx] = (value >> 8) as u8;
+            self.ram[idx + 1] = value as u8;
+            return;
+        }
+        if self.device_write(addr, 2, value) {
+            return;
+        }
+        self.write_byte(addr, (value >> 8) as u8);
+        self.write_byte(addr + 1, value as u8);
+    }
+
+    fn irq_level(&mut self) -> u8 {
+        self.regions
+            .iter()
+            .map(|r| r.device.irq_level())
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn reset_devices(&mut self) {
+        for region in self.regions.iter_mut() {
+            region.device.reset();
+        }
+    }
+}
+
+pub struct Machine {
+    pub cpu: Cpu,
+    pub mem: Memory,
+    pub instructions: u64,
+}
+
+impl Machine {
+    pub fn new(mem: Memory) -> Machine {
+        let mut machine = Machine {
+            cpu: Cpu::new(),
+            mem,
+            instructions: 0,
+        };
+        machine.reset();
+        machine
+    }
+
+    pub fn reset(&mut self) {
+        self.mem.overlay = true;
+        self.mem.reset_devices();
+        self.cpu.reset(&mut self.mem);
+        self.instructions = 0;
+    }
+
+    // Run one instruction, returning any exception vector taken.
+    pub fn step(&mut self) -> Option<u8> {
+        self.mem.pc = self.cpu.pc;
+        let vector = self.cpu.step(&mut self.mem);
+        self.mem.tick();
+        self.instructions += 1;
+        vector
+    }
+}
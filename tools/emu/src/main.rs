@@ -0,0 +1,572 @@
+//
+// Headless 68000 emulator
+//
+// Runs a patched ROM on the remapped memory map, so that a broken
+// relocation can be caught without firing up a full Mac emulator.
+//
+
+mod coverage;
+mod cpu;
+mod devices;
+mod disk;
+mod floppy;
+mod gdb;
+mod harness;
+mod machine;
+mod scc;
+mod scsi;
+mod session;
+mod symbols;
+mod trace;
+mod via;
+
+use std::cell::RefCell;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use anyhow::{bail, Context};
+use clap::{Args, Parser, Subcommand};
+
+use crate::coverage::Coverage;
+use crate::disk::Disk;
+use crate::floppy::FloppyMailbox;
+use crate::harness::{History, Outcome};
+use crate::machine::{Device, Machine, Memory, Region, IO_BASE, MAX_RAM};
+use crate::scc::Scc;
+use crate::scsi::ScsiMailbox;
+use crate::symbols::Symbols;
+use crate::trace::Calls;
+use crate::via::ViaMailbox;
+
+////////////////////////////////////////////////////////////////////////
+// Command line processing.
+//
+
+#[derive(Parser)]
+#[command(name = "Emu")]
+#[command(author = "Simon Frankau <sgf@arbitrary.name")]
+#[command(version = "0.1")]
+#[command(about = "Runs patched Apple 68k ROMs headless.", long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Run the ROM from reset, and report whether it got to the
+    /// checkpoint (or ran the requested number of instructions)
+    /// without going wrong.
+    Run {
+        #[command(flatten)]
+        run: RunArgs,
+    },
+    /// Run the ROM as for "run", logging every access to the I/O
+    /// regions, and summarising them by device and register.
+    Trace {
+        #[command(flatten)]
+        run: RunArgs,
+        /// Where to write the full log of accesses.
+        #[arg(long, default_value = "../../ROM.trace.txt")]
+        log: PathBuf,
+        /// Where to write a Ghidra script annotating the accesses.
+        #[arg(long, default_value = "../../ROM.trace.py")]
+        script: PathBuf,
+    },
+    /// Run the ROM as for "run", then an optional scripted session,
+    /// recording which parts of the ROM are executed.
+    Coverage {
+        #[command(flatten)]
+        run: RunArgs,
+        /// Commands to run after booting (see the README for the
+        /// format).
+        #[arg(long)]
+        session: Option<PathBuf>,
+        /// Where to write the coverage bitmap, one bit per ROM byte.
+        #[arg(long, default_value = "../../ROM.coverage.bin")]
+        bitmap: PathBuf,
+        /// Where to write a Ghidra script colouring executed code.
+        #[arg(long, default_value = "../../ROM.coverage.py")]
+        script: PathBuf,
+    },
+    /// Serve the GDB remote protocol on localhost, to debug the ROM
+    /// from reset.
+    Gdb {
+        #[command(flatten)]
+        machine: MachineArgs,
+        #[arg(long, default_value_t = 1234)]
+        port: u16,
+        /// Where to write a GDB command file that connects and defines
+        /// the trap names.
+        #[arg(long, default_value = "../../ROM.gdb")]
+        script: PathBuf,
+    },
+    /// Boot a ROM patched with the paravirtual floppy driver from a
+    /// disk image, then check reads, writes, errors and ejecting
+    /// through the driver. Writes are kept in memory.
+    Floppy {
+        #[command(flatten)]
+        check: CheckArgs,
+    },
+    /// Boot a ROM patched with the paravirtual SCSI Manager with the
+    /// first "--scsi" target attached, then check commands, transfers
+    /// and errors through the SCSI Manager. Writes are kept in memory.
+    Scsi {
+        #[command(flatten)]
+        check: CheckArgs,
+    },
+    /// Boot a ROM patched with the paravirtual VIA functions through
+    /// the ADB initialisation, then check the clock, parameter RAM,
+    /// interrupts, keyboard, mouse and _ADBOp against it.
+    Via {
+        #[command(flatten)]
+        check: CheckArgs,
+    },
+    /// Boot the ROM past its I/O initialisation with a modelled SCC,
+    /// then send and receive through the RAM serial drivers on both
+    /// ports and open AppleTalk, checking every SCC access goes to the
+    /// remapped registers.
+    Scc {
+        #[command(flatten)]
+        check: CheckArgs,
+        /// Where to write the log of SCC register accesses.
+        #[arg(long, default_value = "../../ROM.scc.txt")]
+        log: PathBuf,
+    },
+}
+
+#[derive(Args)]
+struct MachineArgs {
+    #[arg(long, default_value = "../../ROM.patched")]
+    rom: PathBuf,
+    /// Amount of RAM. The patched ROM assumes RAM runs all the way up
+    /// to the ROM, and fails its RAM test with less.
+    #[arg(long, default_value = memory_map::RAM_END_HEX, value_parser = parse_ram)]
+    ram: u32,
+    /// Disk image for the paravirtual floppy drive, raw or DiskCopy
+    /// 4.2. Writes go back to the file, unless it's read-only, in
+    /// which case the disk is locked.
+    #[arg(long)]
+    disk: Option<PathBuf>,
+    /// Disk image for a paravirtual SCSI target, as <ID>=<IMAGE>, for
+    /// IDs 0 to 6. May be given more than once.
+    #[arg(long, value_parser = parse_scsi)]
+    scsi: Vec<(u8, PathBuf)>,
+    /// Keep writes to the disk images in memory.
+    #[arg(long)]
+    scratch: bool,
+    /// I/O address of the paravirtual floppy mailbox, in hex, as
+    /// given to "patch rom --floppy-mailbox".
+    #[arg(long, default_value = memory_map::FLOPPY_MAILBOX_START_HEX,
+          value_parser = parse_mailbox)]
+    floppy_mailbox: u32,
+    /// I/O address of the paravirtual SCSI mailbox, in hex, as given
+    /// to "patch rom --scsi-mailbox".
+    #[arg(long, default_value = memory_map::SCSI_MAILBOX_START_HEX,
+          value_parser = parse_mailbox)]
+    scsi_mailbox: u32,
+    /// I/O address of the paravirtual VIA functions, in hex, as given
+    /// to "patch rom --via-mailbox".
+    #[arg(long, default_value = memory_map::VIA_MAILBOX_START_HEX,
+          value_parser = parse_via_mailbox)]
+    via_mailbox: u32,
+}
+
+#[derive(Args)]
+struct RunArgs {
+    #[command(flatten)]
+    machine: MachineArgs,
+    /// Succeed when the PC reaches this address.
+    #[arg(long, value_parser = parse_hex)]
+    checkpoint: Option<u32>,
+    /// Maximum number of instructions to run. There's no ADB
+    /// transceiver, so unless the ROM is patched with "--via-mailbox",
+    /// its ADB code goes wrong after about 80 million, and longer runs
+    /// end in a Sad Mac.
+    #[arg(long, default_value_t = 5_000_000)]
+    instructions: u64,
+    /// Number of recently executed instructions to show on failure.
+    #[arg(long, default_value_t = 16)]
+    history: usize,
+}
+
+// Arguments for the checks of each device, which boot the ROM up to
+// where it first uses the device, then exercise it.
+#[derive(Args)]
+struct CheckArgs {
+    #[command(flatten)]
+    machine: MachineArgs,
+    /// Maximum number of instructions to run before the ROM gets to
+    /// the device being checked.
+    #[arg(long, default_value_t = 100_000_000)]
+    instructions: u64,
+    /// Number of recently executed instructions to show on failure.
+    #[arg(long, default_value_t = 16)]
+    history: usize,
+}
+
+fn parse_hex(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s.trim_start_matches("0x"), 16)
+        .map_err(|e| format!("Bad address '{}': {}", s, e))
+}
+
+fn parse_ram(s: &str) -> Result<u32, String> {
+    let size = parse_hex(s)?;
+    if size > MAX_RAM {
+        return Err(format!(
+            "At most 0x{:x} bytes of RAM are supported",
+            MAX_RAM
+        ));
+    }
+    Ok(size)
+}
+
+fn parse_io(s: &str, size: u32) -> Result<u32, String> {
+    let addr = parse_hex(s)?;
+    let io = IO_BASE..=memory_map::ADDRESS_SPACE_END - size;
+    if addr % size != 0 || !io.contains(&addr) {
+        return Err(format!(
+            "Mailbox 0x{:06x} must be {}-byte aligned, in the I/O space",
+            addr, size
+        ));
+    }
+    Ok(addr)
+}
+
+// The floppy and SCSI mailboxes are the same size.
+fn parse_mailbox(s: &str) -> Result<u32, String> {
+    parse_io(s, floppy::MAILBOX_SIZE)
+}
+
+fn parse_via_mailbox(s: &str) -> Result<u32, String> {
+    parse_io(s, via::MAILBOX_SIZE)
+}
+
+fn parse_scsi(s: &str) -> Result<(u8, PathBuf), String> {
+    let Some((id, path)) = s.split_once('=') else {
+        return Err(format!("'{}' isn't of the form <ID>=<IMAGE>", s));
+    };
+    match id.parse::<u8>() {
+        Ok(id) if (id as usize) < scsi::TARGETS => Ok((id, PathBuf::from(path))),
+        _ => Err(format!(
+            "SCSI ID '{}' must be from 0 to {}",
+            id,
+            scsi::TARGETS - 1
+        )),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// Running the ROM.
+//
+
+fn boot(args: &MachineArgs) -> anyhow::Result<Machine> {
+    boot_with_via(args, Rc::new(RefCell::new(ViaMailbox::new())))
+}
+
+// Boot with the given paravirtual VIA, which the caller can keep a
+// handle on.
+fn boot_with_via(args: &MachineArgs, via: Rc<RefCell<ViaMailbox>>) -> anyhow::Result<Machine> {
+    let rom = &args.rom;
+    let data = fs::read(rom).with_context(|| format!("Couldn't read {}", rom.display()))?;
+    if data.len() != machine::ROM_SIZE as usize {
+        bail!("{} is not a 256kB ROM image", rom.display());
+    }
+    let mut regions = devices::standard_regions();
+    let disk = match &args.disk {
+        Some(path) => Some(Disk::open(path, args.scratch)?),
+        None => None,
+    };
+    add_mailbox(
+        &mut regions,
+        "FLOPPY",
+        args.floppy_mailbox,
+        floppy::MAILBOX_SIZE,
+        Box::new(FloppyMailbox::new(disk)),
+    )?;
+    let mut targets = (0..scsi::TARGETS).map(|_| None).collect::<Vec<_>>();
+    for (id, path) in args.scsi.iter() {
+        if targets[*id as usize].is_some() {
+            bail!("More than one image for SCSI ID {}", id);
+        }
+        targets[*id as usize] = Some(Disk::open(path, args.scratch)?);
+    }
+    add_mailbox(
+        &mut regions,
+        "SCSI_MAILBOX",
+        args.scsi_mailbox,
+        scsi::MAILBOX_SIZE,
+        Box::new(ScsiMailbox::new(targets)),
+    )?;
+    add_mailbox(
+        &mut regions,
+        "VIA_MAILBOX",
+        args.via_mailbox,
+        via::MAILBOX_SIZE,
+        Box::new(via),
+    )?;
+    let mem = Memory::new(data, args.ram, regions);
+    let m = Machine::new(mem);
+    println!("Reset: SSP = 0x{:08x}, PC = 0x{:08x}", m.cpu.a[7], m.cpu.pc);
+    Ok(m)
+}
+
+// Add a paravirtual device's mailbox, which mustn't overlap anything
+// else.
+fn add_mailbox(
+    regions: &mut Vec<Region>,
+    name: &'static str,
+    start: u32,
+    size: u32,
+    device: Box<dyn Device>,
+) -> anyhow::Result<()> {
+    let end = start + size;
+    if let Some(region) = regions.iter().find(|r| start < r.end && end > r.start) {
+        bail!("{} at 0x{:06x} overlaps {}", name, start, region.name);
+    }
+    regions.push(Region {
+        name,
+        start,
+        end,
+        device,
+    });
+    Ok(())
+}
+
+// Swap in a device the caller keeps a handle on, before the ROM runs.
+fn attach(m: &mut Machine, name: &str, device: Box<dyn Device>) {
+    let region = m.mem.regions.iter_mut().find(|r| r.name == name);
+    region.expect("No such region").device = device;
+}
+
+fn disassemble_at(m: &Machine, pc: u32) -> String {
+    let (text, _) = disasm::disassemble(pc, |addr| m.mem.peek_word(addr));
+    text
+}
+
+fn print_history(m: &Machine, history: &History) {
+    println!("Recent instructions:");
+    for pc in history.pcs.iter() {
+        println!("  0x{:06x}: {}", pc, disassemble_at(m, *pc));
+    }
+}
+
+fn print_state(m: &Machine) {
+    let cpu = &m.cpu;
+    for i in 0..8 {
+        println!("  D{} = {:08x}  A{} = {:08x}", i, cpu.d[i], i, cpu.a[i]);
+    }
+    println!("  PC = {:08x}  SR = {:04x}", cpu.pc, cpu.sr);
+    println!("  USP = {:08x}  SSP = {:08x}", cpu.usp(), cpu.ssp());
+}
+
+// Run from reset to the checkpoint, or for the given number of
+// instructions if there's no checkpoint.
+fn run(
+    m: &mut Machine,
+    args: &RunArgs,
+    history: &mut History,
+    observe: harness::Observer,
+) -> Outcome {
+    match harness::run(m, args.checkpoint, args.instructions, history, observe) {
+        Outcome::Completed if args.checkpoint.is_some() => Outcome::Failed(format!(
+            "checkpoint 0x{:06x} not reached",
+            args.checkpoint.unwrap()
+        )),
+        outcome => outcome,
+    }
+}
+
+// Report how the run went, failing if it went wrong.
+fn report(m: &Machine, outcome: Outcome, history: &History) -> anyhow::Result<()> {
+    if !m.mem.unmapped.is_empty() {
+        println!("Accesses to unmapped memory (first few):");
+        for access in m.mem.unmapped.iter().take(10) {
+            println!(
+                "  0x{:06x}: {} 0x{:06x}",
+                access.pc,
+                if access.write { "write" } else { "read" },
+                access.addr
+            );
+        }
+    }
+
+    match outcome {
+        Outcome::Stopped => {
+            println!(
+                "PASS: reached 0x{:06x} after {} instructions",
+                m.cpu.pc, m.instructions
+            );
+            Ok(())
+        }
+        Outcome::Completed => {
+            println!(
+                "PASS: ran {} instructions, now at 0x{:06x}",
+                m.instructions, m.cpu.pc
+            );
+            Ok(())
+        }
+        Outcome::Failed(why) => {
+            print_history(m, history);
+            print_state(m);
+            bail!("FAIL: {} after {} instructions", why, m.instructions);
+        }
+    }
+}
+
+fn run_rom(args: &RunArgs) -> anyhow::Result<()> {
+    let mut m = boot(&args.machine)?;
+    let mut history = History::new(args.history);
+    let outcome = run(&mut m, args, &mut history, &mut |_, _, _| {});
+    report(&m, outcome, &history)
+}
+
+fn trace_rom(args: &RunArgs, log: &Path, script: &Path) -> anyhow::Result<()> {
+    let mut m = boot(&args.machine)?;
+    let symbols = Symbols::load(m.mem.rom())?;
+    m.mem.io_log = Some(Vec::new());
+    let mut calls = Calls::new();
+    let mut history = History::new(args.history);
+    let outcome = run(&mut m, args, &mut history, &mut |m, pc, vector| {
+        calls.observe(m, pc, vector)
+    });
+
+    let accesses = m.mem.io_log.take().unwrap();
+    println!("{} hardware accesses", accesses.len());
+    trace::print_summary(&m.mem, &accesses, &calls.functions, &symbols);
+    trace::write_log(log, &m.mem, &accesses, &calls.functions, &symbols)?;
+    trace::write_script(script, &m.mem, &accesses)?;
+
+    report(&m, outcome, &history)
+}
+
+fn coverage_rom(
+    args: &RunArgs,
+    session: Option<&Path>,
+    bitmap: &Path,
+    script: &Path,
+) -> anyhow::Result<()> {
+    let mut m = boot(&args.machine)?;
+    let symbols = Symbols::load(m.mem.rom())?;
+    let commands = match session {
+        Some(path) => {
+            let text = fs::read_to_string(path)
+                .with_context(|| format!("Couldn't read {}", path.display()))?;
+            session::parse(&text, &symbols)
+                .with_context(|| format!("Couldn't parse {}", path.display()))?
+        }
+        None => Vec::new(),
+    };
+
+    let mut coverage = Coverage::new();
+    let mut history = History::new(args.history);
+    let outcome = {
+        let mut observe =
+            |m: &Machine, pc: u32, vector: Option<u8>| coverage.observe(m, pc, vector);
+        match run(&mut m, args, &mut history, &mut observe) {
+            Outcome::Failed(why) => Outcome::Failed(why),
+            _ => session::execute(&mut m, &commands, &mut history, &mut observe),
+        }
+    };
+
+    coverage.print_report(m.mem.rom(), &symbols);
+    coverage.write_bitmap(bitmap)?;
+    coverage.write_script(script, m.mem.rom())?;
+
+    report(&m, outcome, &history)
+}
+
+fn debug_rom(args: &MachineArgs, port: u16, script: &Path) -> anyhow::Result<()> {
+    let m = boot(args)?;
+    let symbols = Symbols::load(m.mem.rom())?;
+    gdb::write_script(script, &symbols, port)?;
+    println!("Wrote {}", script.display());
+    gdb::serve(m, &symbols, port)
+}
+
+fn floppy_rom(check: CheckArgs) -> anyhow::Result<()> {
+    let CheckArgs {
+        machine: mut args,
+        instructions: limit,
+        history,
+    } = check;
+    let Some(path) = &args.disk else {
+        bail!("Need a --disk image to check the floppy driver against");
+    };
+    let disk = Disk::open(path, true)?;
+    args.scratch = true;
+    let mut m = boot(&args)?;
+    let mut history = History::new(history);
+    let outcome = floppy::check(&mut m, &disk, limit, &mut history);
+    report(&m, outcome, &history)
+}
+
+fn scsi_rom(check: CheckArgs) -> anyhow::Result<()> {
+    let CheckArgs {
+        machine: mut args,
+        instructions: limit,
+        history,
+    } = check;
+    let Some((target, path)) = args.scsi.first() else {
+        bail!("Need a --scsi target to check the SCSI Manager against");
+    };
+    let target = *target;
+    let disk = Disk::open(path, true)?;
+    // An ID with nothing attached, for the errors.
+    let absent = (0..scsi::TARGETS as u8)
+        .find(|id| args.scsi.iter().all(|(t, _)| t != id))
+        .context("Need a SCSI ID with no target attached")?;
+    args.scratch = true;
+    let mut m = boot(&args)?;
+    let mut history = History::new(history);
+    let outcome = scsi::check(&mut m, target, absent, &disk, limit, &mut history);
+    report(&m, outcome, &history)
+}
+
+fn via_rom(check: &CheckArgs) -> anyhow::Result<()> {
+    let via = Rc::new(RefCell::new(ViaMailbox::new()));
+    let mut m = boot_with_via(&check.machine, via.clone())?;
+    let mut history = History::new(check.history);
+    let outcome = via::check(&mut m, &via, check.instructions, &mut history);
+    report(&m, outcome, &history)
+}
+
+fn scc_rom(check: &CheckArgs, log: &Path) -> anyhow::Result<()> {
+    let scc = Rc::new(RefCell::new(Scc::new()));
+    let mut m = boot(&check.machine)?;
+    attach(&mut m, "SCC", Box::new(scc.clone()));
+    let mut history = History::new(check.history);
+    let outcome = scc::check(&mut m, &scc, check.instructions, &mut history, log);
+    report(&m, outcome, &history)
+}
+
+////////////////////////////////////////////////////////////////////////
+// Main entry point.
+//
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    match cli.command {
+        Commands::Run { run } => run_rom(&run)?,
+        Commands::Trace { run, log, script } => trace_rom(&run, &log, &script)?,
+        Commands::Coverage {
+            run,
+            session,
+            bitmap,
+            script,
+        } => coverage_rom(&run, session.as_deref(), &bitmap, &script)?,
+        Commands::Gdb {
+            machine,
+            port,
+            script,
+        } => debug_rom(&machine, port, &script)?,
+        Commands::Floppy { check } => floppy_rom(check)?,
+        Commands::Scsi { check } => scsi_rom(check)?,
+        Commands::Via { check } => via_rom(&check)?,
+        Commands::Scc { check, log } => scc_rom(&check, &log)?,
+    }
+
+    Ok(())
+}
@@ -0,0 +1,1154 @@
+//
+// Z8530 SCC
+//
+// A register-level model of the serial controller, enough for the
+// serial drivers and AppleTalk: the register pointer, asynchronous and
+// SDLC transmission at the programmed rate, a receive FIFO fed by the
+// host, and the interrupt logic the level 2 handler relies on. Every
+// register access is logged by channel, so the protocol the drivers
+// use can be checked against the remapped addresses. Also a check that
+// drives the serial drivers and AppleTalk through it.
+//
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+
+use anyhow::{bail, ensure, Context};
+
+use crate::harness::{self, History, Outcome};
+use crate::machine::{Device, Machine, ROM_BASE};
+use crate::session;
+
+////////////////////////////////////////////////////////////////////////
+// Addressing.
+//
+// The region has separate read and write halves. Within each, A1
+// selects channel A and A2 the data register. The SCC sits on the upper
+// byte lane for reads and the lower one for writes, so reads are at
+// even addresses and writes at odd ones.
+//
+
+const WRITE_HALF: u32 = 0x1000;
+const CHANNEL_A: u32 = 2;
+const DATA: u32 = 4;
+
+// Where the patched ROM puts its base addresses: the registers are the
+// top 8 bytes of each half, with writes on the odd byte lane.
+pub const SCC_READ: u32 = memory_map::SCC_READ_END - 8;
+pub const SCC_WRITE: u32 = memory_map::SCC_WRITE_END - 7;
+
+pub const PORT_A: usize = 0;
+pub const PORT_B: usize = 1;
+pub const PORT_NAMES: [&str; 2] = ["A", "B"];
+
+// The SCC's clock, and the E clock that the devices tick at.
+const PCLK: u64 = 3_672_000;
+const E_CLOCK: u64 = 783_360;
+
+// RR0 bits.
+const RX_AVAILABLE: u8 = 0x01;
+const TX_EMPTY: u8 = 0x04;
+const DCD: u8 = 0x08;
+const SYNC_HUNT: u8 = 0x10;
+const CTS: u8 = 0x20;
+const TX_UNDERRUN: u8 = 0x40;
+// The bits that can cause external/status interrupts.
+const EXT_STATUS_BITS: u8 = 0xfa;
+
+// RR1 bits.
+const ALL_SENT: u8 = 0x01;
+const RESIDUE_8_BITS: u8 = 0x06;
+const RX_OVERRUN: u8 = 0x20;
+
+// WR0 commands.
+const CMD_POINT_HIGH: u8 = 1;
+const CMD_RESET_EXT: u8 = 2;
+const CMD_SEND_ABORT: u8 = 3;
+const CMD_INT_NEXT_RX: u8 = 4;
+const CMD_RESET_TX_IP: u8 = 5;
+const CMD_ERROR_RESET: u8 = 6;
+// WR0 CRC reset codes.
+const RESET_TX_UNDERRUN: u8 = 3;
+
+// WR1 bits.
+const EXT_IE: u8 = 0x01;
+const TX_IE: u8 = 0x02;
+const RX_IE_MASK: u8 = 0x18;
+const RX_IE_FIRST: u8 = 0x08;
+const RX_IE_ALL: u8 = 0x10;
+const RX_IE_SPECIAL: u8 = 0x18;
+
+// WR3, WR4 and WR5 bits.
+const RX_ENABLE: u8 = 0x01;
+const PARITY_ENABLE: u8 = 0x01;
+const STOP_BITS_MASK: u8 = 0x0c;
+const SYNC_MODE_MASK: u8 = 0x30;
+const SDLC_MODE: u8 = 0x20;
+const TX_ENABLE: u8 = 0x08;
+
+// WR9 bits.
+const STATUS_HIGH: u8 = 0x10;
+const MIE: u8 = 0x08;
+const RESET_MASK: u8 = 0xc0;
+const RESET_B: u8 = 0x40;
+const RESET_A: u8 = 0x80;
+const RESET_HARDWARE: u8 = 0xc0;
+
+// WR14 bits.
+const BRG_ENABLE: u8 = 0x01;
+
+// Receive FIFO depth.
+const RX_FIFO: usize = 3;
+
+// The SCC's interrupts come in at level 2.
+const IRQ_LEVEL: u8 = 2;
+
+////////////////////////////////////////////////////////////////////////
+// The protocol log.
+//
+
+// An access that reached a register. Data registers are logged as
+// register 8, which is what they are.
+#[derive(Clone, Copy, Debug)]
+pub struct SccAccess {
+    pub port: usize,
+    pub write: bool,
+    pub register: u8,
+    pub value: u8,
+}
+
+////////////////////////////////////////////////////////////////////////
+// A channel.
+//
+
+struct Channel {
+    wr: [u8; 16],
+    pointer: usize,
+    // Transmitter: the buffer, and the character being shifted out with
+    // the ticks it has left.
+    tx_buffer: Option<u8>,
+    tx_shift: Option<(u8, u64)>,
+    tx_ip: bool,
+    // Set when the transmitter has run dry, which in SDLC mode ends the
+    // frame.
+    tx_underrun: bool,
+    frame: Vec<u8>,
+    // Receiver: characters on the line, waiting to be clocked in, and
+    // the FIFO.
+    incoming: VecDeque<u8>,
+    rx_ticks: u64,
+    rx_fifo: VecDeque<u8>,
+    rx_errors: u8,
+    // "Enable interrupt on next receive character" is armed, or has
+    // fired.
+    rx_first_armed: bool,
+    rx_first_ip: bool,
+    // External/status interrupt, and the RR0 bits it latched.
+    ext_ip: bool,
+    ext_latched: Option<u8>,
+    ext_last: u8,
+    // What's been sent: characters in asynchronous mode, and complete
+    // frames in SDLC mode.
+    sent: Vec<u8>,
+    frames: Vec<Vec<u8>>,
+}
+
+impl Channel {
+    fn new() -> Channel {
+        let mut channel = Channel {
+            wr: [0; 16],
+            pointer: 0,
+            tx_buffer: None,
+            tx_shift: None,
+            tx_ip: false,
+            tx_underrun: true,
+            frame: Vec::new(),
+            incoming: VecDeque::new(),
+            rx_ticks: 0,
+            rx_fifo: VecDeque::new(),
+            rx_errors: 0,
+            rx_first_armed: false,
+            rx_first_ip: false,
+            ext_ip: false,
+            ext_latched: None,
+            ext_last: 0,
+            sent: Vec::new(),
+            frames: Vec::new(),
+        };
+        channel.reset();
+        channel
+    }
+
+    // A channel reset, which leaves the clocking and what's been sent
+    // alone.
+    fn reset(&mut self) {
+        self.wr[0] = 0;
+        self.wr[1] &= 0x24;
+        self.wr[3] &= !RX_ENABLE;
+        self.wr[4] |= 0x04;
+        self.wr[5] &= 0x61;
+        self.wr[10] &= 0x60;
+        self.wr[14] = (self.wr[14] & 0xc3) | 0x20;
+        self.wr[15] = 0xf8;
+        self.pointer = 0;
+        self.tx_buffer = None;
+        self.tx_shift = None;
+        self.tx_ip = false;
+        self.tx_underrun = true;
+        self.frame.clear();
+        self.rx_fifo.clear();
+        self.rx_errors = 0;
+        self.rx_first_armed = false;
+        self.rx_first_ip = false;
+        self.ext_ip = false;
+        self.ext_latched = None;
+        self.ext_last = self.ext_status();
+    }
+
+    fn sdlc(&self) -> bool {
+        self.wr[4] & 0x0c == 0 && self.wr[4] & SYNC_MODE_MASK == SDLC_MODE
+    }
+
+    fn synchronous(&self) -> bool {
+        self.wr[4] & STOP_BITS_MASK == 0
+    }
+
+    // The live external/status bits. Nothing's plugged in, so DCD and
+    // CTS are never asserted. In synchronous modes the receiver is
+    // always hunting, as nothing else is talking on the line.
+    fn ext_status(&self) -> u8 {
+        let mut bits = 0;
+        if self.synchronous() {
+            bits |= SYNC_HUNT;
+        }
+        if self.tx_underrun {
+            bits |= TX_UNDERRUN;
+        }
+        bits & !(DCD | CTS)
+    }
+
+    // Latch the external/status bits and interrupt if one that's
+    // enabled has changed.
+    fn update_ext(&mut self) {
+        let bits = self.ext_status();
+        let changed = (bits ^ self.ext_last) & self.wr[15] & EXT_STATUS_BITS;
+        self.ext_last = bits;
+        if changed != 0 && !self.ext_ip && self.wr[1] & EXT_IE != 0 {
+            self.ext_ip = true;
+            self.ext_latched = Some(bits);
+        }
+    }
+
+    fn rr0(&self) -> u8 {
+        let ext = self.ext_latched.unwrap_or_else(|| self.ext_status());
+        let mut rr0 = ext & EXT_STATUS_BITS;
+        if !self.rx_fifo.is_empty() {
+            rr0 |= RX_AVAILABLE;
+        }
+        if self.tx_buffer.is_none() {
+            rr0 |= TX_EMPTY;
+        }
+        rr0
+    }
+
+    fn rr1(&self) -> u8 {
+        let all_sent = self.tx_buffer.is_none() && self.tx_shift.is_none();
+        self.rx_errors | RESIDUE_8_BITS | if all_sent { ALL_SENT } else { 0 }
+    }
+
+    fn rx_special(&self) -> bool {
+        self.rx_errors & RX_OVERRUN != 0
+    }
+
+    fn rx_ip(&self) -> bool {
+        match self.wr[1] & RX_IE_MASK {
+            RX_IE_FIRST => self.rx_first_ip || self.rx_special(),
+            RX_IE_ALL => !self.rx_fifo.is_empty() || self.rx_special(),
+            RX_IE_SPECIAL => self.rx_special(),
+            _ => false,
+        }
+    }
+
+    // Bits per character, including start, parity and stop bits.
+    fn bits_per_char(&self) -> u64 {
+        let data = match self.wr[5] & 0x60 {
+            0x00 => 5,
+            0x20 => 7,
+            0x40 => 6,
+            _ => 8,
+        };
+        if self.synchronous() {
+            return 8;
+        }
+        let parity = (self.wr[4] & PARITY_ENABLE) as u64;
+        let stop = if self.wr[4] & STOP_BITS_MASK == 0x04 {
+            1
+        } else {
+            2
+        };
+        1 + data + parity + stop
+    }
+
+    // Time for a character in E clock ticks, from the clock mode and
+    // the baud rate generator's time constant. Without the generator,
+    // the clock is taken to be the SCC's own.
+    fn char_ticks(&self) -> u64 {
+        let mode = match self.wr[4] >> 6 {
+            0 => 1,
+            1 => 16,
+            2 => 32,
+            _ => 64,
+        };
+        let divisor = if self.wr[14] & BRG_ENABLE != 0 {
+            let constant = ((self.wr[13] as u64) << 8) | self.wr[12] as u64;
+            2 * (constant + 2) * mode
+        } else {
+            mode
+        };
+        (self.bits_per_char() * divisor * E_CLOCK / PCLK).max(1)
+    }
+
+    fn write_data(&mut self, value: u8) {
+        self.tx_buffer = Some(value);
+        self.tx_ip = false;
+    }
+
+    fn read_data(&mut self) -> u8 {
+        let value = self.rx_fifo.pop_front().unwrap_or(0);
+        self.rx_first_ip = false;
+        value
+    }
+
+    fn command(&mut self, value: u8) {
+        match (value >> 3) & 7 {
+            CMD_RESET_EXT => {
+                self.ext_ip = false;
+                self.ext_latched = None;
+                self.ext_last = self.ext_status();
+            }
+            CMD_SEND_ABORT => {
+                self.tx_buffer = None;
+                self.tx_shift = None;
+                self.frame.clear();
+                self.tx_underrun = true;
+                self.update_ext();
+            }
+            CMD_INT_NEXT_RX => self.rx_first_armed = true,
+            CMD_RESET_TX_IP => self.tx_ip = false,
+            CMD_ERROR_RESET => self.rx_errors = 0,
+            _ => {}
+        }
+        if value >> 6 == RESET_TX_UNDERRUN {
+            self.tx_underrun = false;
+            self.update_ext();
+        }
+    }
+
+    fn tick(&mut self) {
+        // Transmitter.
+        if let Some((value, ticks)) = self.tx_shift {
+            if ticks > 1 {
+                self.tx_shift = Some((value, ticks - 1));
+            } else {
+                self.tx_shift = None;
+                if self.sdlc() {
+                    self.frame.push(value);
+                } else {
+                    self.sent.push(value);
+                }
+            }
+        }
+        if self.tx_shift.is_none() && self.wr[5] & TX_ENABLE != 0 {
+            if let Some(value) = self.tx_buffer.take() {
+                self.tx_shift = Some((value, self.char_ticks()));
+                if self.wr[1] & TX_IE != 0 {
+                    self.tx_ip = true;
+                }
+            } else if self.sdlc() && !self.tx_underrun {
+                // The frame ends when the transmitter runs dry, with
+                // the CRC and a closing flag.
+                self.tx_underrun = true;
+                if !self.frame.is_empty() {
+                    self.frames.push(std::mem::take(&mut self.frame));
+                }
+                self.update_ext();
+            }
+        }
+
+        // Receiver.
+        if self.wr[3] & RX_ENABLE != 0 && !self.incoming.is_empty() {
+            self.rx_ticks += 1;
+            if self.rx_ticks >= self.char_ticks() {
+                self.rx_ticks = 0;
+                let value = self.incoming.pop_front().unwrap();
+                if self.rx_fifo.len() == RX_FIFO {
+                    self.rx_errors |= RX_OVERRUN;
+                    self.rx_fifo.pop_back();
+                }
+                self.rx_fifo.push_back(value);
+                if self.rx_first_armed {
+                    self.rx_first_armed = false;
+                    self.rx_first_ip = true;
+                }
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// The chip.
+//
+
+pub struct Scc {
+    channels: [Channel; 2],
+    // Shared between the channels.
+    wr2: u8,
+    wr9: u8,
+    pub log: Vec<SccAccess>,
+    // Offsets of accesses on the wrong half or byte lane, which the
+    // SCC never sees.
+    pub misrouted: Vec<(u32, bool)>,
+}
+
+impl Scc {
+    pub fn new() -> Scc {
+        Scc {
+            channels: [Channel::new(), Channel::new()],
+            wr2: 0,
+            wr9: 0,
+            log: Vec::new(),
+            misrouted: Vec::new(),
+        }
+    }
+
+    // Characters arriving on a port.
+    pub fn receive(&mut self, port: usize, data: &[u8]) {
+        self.channels[port].incoming.extend(data);
+    }
+
+    // Characters sent from a port in asynchronous mode.
+    pub fn sent(&self, port: usize) -> &[u8] {
+        &self.channels[port].sent
+    }
+
+    // Frames sent from a port in SDLC mode, without their CRCs.
+    pub fn frames(&self, port: usize) -> &[Vec<u8>] {
+        &self.channels[port].frames
+    }
+
+    // What was last written to a write register.
+    pub fn wr(&self, port: usize, register: usize) -> u8 {
+        match register {
+            2 => self.wr2,
+            9 => self.wr9,
+            _ => self.channels[port].wr[register],
+        }
+    }
+
+    fn ip_bits(&self) -> u8 {
+        let mut bits = 0;
+        for (i, shift) in [(PORT_B, 0), (PORT_A, 3)] {
+            let channel = &self.channels[i];
+            let pending = [channel.ext_ip, channel.tx_ip, channel.rx_ip()];
+            for (bit, pending) in pending.iter().enumerate() {
+                if *pending {
+                    bits |= 1 << (shift + bit);
+                }
+            }
+        }
+        bits
+    }
+
+    // The vector as modified by the highest priority interrupt
+    // pending, which is what channel B's RR2 gives.
+    fn modified_vector(&self) -> u8 {
+        let status = |port: usize| {
+            let channel = &self.channels[port];
+            let base = if port == PORT_A { 4 } else { 0 };
+            if channel.rx_special() {
+                Some(base + 3)
+            } else if channel.rx_ip() {
+                Some(base + 2)
+            } else if channel.tx_ip {
+                Some(base)
+            } else if channel.ext_ip {
+                Some(base + 1)
+            } else {
+                None
+            }
+        };
+        // No interrupt pending reads as channel B special receive.
+        let code = status(PORT_A).or_else(|| status(PORT_B)).unwrap_or(3);
+        if self.wr9 & STATUS_HIGH != 0 {
+            let reversed = ((code & 1) << 2) | (code & 2) | ((code >> 2) & 1);
+            (self.wr2 & 0x8f) | (reversed << 4)
+        } else {
+            (self.wr2 & 0xf1) | (code << 1)
+        }
+    }
+
+    fn read_register(&mut self, port: usize, register: usize) -> u8 {
+        let channel = &self.channels[port];
+        match register & 0xf {
+            0 | 4 => channel.rr0(),
+            1 | 5 => channel.rr1(),
+            2 | 6 if port == PORT_B => self.modified_vector(),
+            2 | 6 => self.wr2,
+            3 | 7 if port == PORT_A => self.ip_bits(),
+            3 | 7 => 0,
+            8 => self.channels[port].read_data(),
+            12 => channel.wr[12],
+            9 | 13 => channel.wr[13],
+            11 | 15 => channel.wr[15] & EXT_STATUS_BITS,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, port: usize, register: usize, value: u8) {
+        match register {
+            0 => {
+                let channel = &mut self.channels[port];
+                channel.pointer = (value & 7) as usize;
+                if (value >> 3) & 7 == CMD_POINT_HIGH {
+                    channel.pointer += 8;
+                }
+                channel.wr[0] = value;
+                channel.command(value);
+            }
+            2 => self.wr2 = value,
+            8 => self.channels[port].write_data(value),
+            9 => {
+                match value & RESET_MASK {
+                    RESET_B => self.channels[PORT_B].reset(),
+                    RESET_A => self.channels[PORT_A].reset(),
+                    RESET_HARDWARE => {
+                        for channel in self.channels.iter_mut() {
+                            channel.reset();
+                        }
+                    }
+                    _ => {}
+                }
+                self.wr9 = value & !RESET_MASK;
+            }
+            _ => {
+                let channel = &mut self.channels[port];
+                channel.wr[register] = value;
+                channel.update_ext();
+            }
+        }
+    }
+
+    fn log(&mut self, port: usize, write: bool, register: usize, value: u8) {
+        self.log.push(SccAccess {
+            port,
+            write,
+            register: register as u8,
+            value,
+        });
+    }
+}
+
+impl Default for Scc {
+    fn default() -> Scc {
+        Scc::new()
+    }
+}
+
+impl Device for Scc {
+    fn read(&mut self, offset: u32) -> u8 {
+        if offset & WRITE_HALF != 0 || offset & 1 != 0 {
+            self.misrouted.push((offset, false));
+            return 0xff;
+        }
+        let port = if offset & CHANNEL_A != 0 {
+            PORT_A
+        } else {
+            PORT_B
+        };
+        let register = if offset & DATA != 0 {
+            8
+        } else {
+            std::mem::take(&mut self.channels[port].pointer)
+        };
+        let value = self.read_register(port, register);
+        self.log(port, false, register, value);
+        value
+    }
+
+    fn write(&mut self, offset: u32, value: u8) {
+        if offset & WRITE_HALF == 0 || offset & 1 == 0 {
+            self.misrouted.push((offset, true));
+            return;
+        }
+        let port = if offset & CHANNEL_A != 0 {
+            PORT_A
+        } else {
+            PORT_B
+        };
+        let register = if offset & DATA != 0 {
+            8
+        } else {
+            std::mem::take(&mut self.channels[port].pointer)
+        };
+        self.write_register(port, register, value);
+        self.log(port, true, register, value);
+    }
+
+    fn tick(&mut self) {
+        for channel in self.channels.iter_mut() {
+            channel.tick();
+        }
+    }
+
+    fn irq_level(&self) -> u8 {
+        if self.wr9 & MIE != 0 && self.ip_bits() != 0 {
+            IRQ_LEVEL
+        } else {
+            0
+        }
+    }
+
+    // What's been sent and logged is kept, for checks that reset.
+    fn reset(&mut self) {
+        for channel in self.channels.iter_mut() {
+            channel.reset();
+        }
+        self.wr9 = 0;
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// Checking the remapping.
+//
+
+// Past the ROM's I/O initialisation, with the unit table set up.
+const IO_INITIALISED: u32 = ROM_BASE + 0x140;
+
+// The RAM serial driver's installer, which is the start of 'SERD' 0.
+const SERD: u32 = ROM_BASE + 0x31bae;
+
+// Low memory.
+const SCC_RD: u32 = 0x1d8;
+const SCC_WR: u32 = 0x1dc;
+
+// What InitSCC leaves in each channel.
+const INIT_WR4: u8 = 0x4c;
+const INIT_WR9: u8 = 0x0a;
+
+// Traps.
+const OPEN: u16 = 0xa000;
+const CLOSE: u16 = 0xa001;
+const READ: u16 = 0xa002;
+const WRITE: u16 = 0xa003;
+const CONTROL: u16 = 0xa004;
+const STATUS: u16 = 0xa005;
+
+// Parameter block fields.
+const IO_RESULT: u32 = 0x10;
+const IO_NAME_PTR: u32 = 0x12;
+const IO_REFNUM: u32 = 0x18;
+const CS_CODE: u32 = 0x1a;
+const CS_PARAM: u32 = 0x1c;
+const IO_BUFFER: u32 = 0x20;
+const IO_REQCOUNT: u32 = 0x24;
+const IO_ACTCOUNT: u32 = 0x28;
+const PB_SIZE: u32 = 0x50;
+
+const NO_ERR: i16 = 0;
+
+// csCodes.
+const SER_RESET: u16 = 8;
+const SER_GET_BUF: u16 = 2;
+// 9600 baud, 8 data bits, no parity, 1 stop bit.
+const SER_CONFIG: u16 = 0x4c0a;
+// What that puts in WR4, and the baud rate generator's time constant.
+const SER_WR4: u8 = 0x44;
+const SER_TIME_CONSTANT: u16 = 10;
+
+// The serial drivers for each port, input then output.
+const SERIAL_DRIVERS: [(&str, i16, &str, i16); 2] =
+    [(".AIn", -6, ".AOut", -7), (".BIn", -8, ".BOut", -9)];
+
+// AppleTalk takes over port B, and sends lapENQs for the node address
+// it wants until it's sure nobody has it. After that everything it
+// sends, such as the broadcast RTMP request looking for a bridge, should
+// come from that node.
+const MPP: &str = ".MPP";
+const MPP_PORT: usize = PORT_B;
+const LAP_ENQ: u8 = 0x81;
+// Destination, source, type.
+const LAP_HEADER: usize = 3;
+
+// Instructions allowed per call, and for a character to get through.
+const CALL_LIMIT: u64 = 2_000_000;
+const CHAR_LIMIT: u64 = 2_000;
+
+// BRA.S to itself, and supervisor mode with all interrupts enabled,
+// for idling.
+const BRA_SELF: u16 = 0x60fe;
+const IDLE_SR: u16 = 0x2000;
+
+// Room taken off the stack for the parameter block, name and buffer,
+// and for idling.
+const SCRATCH_SIZE: u32 = 0x400;
+const PB_OFFSET: u32 = 0x100;
+const NAME_OFFSET: u32 = 0x180;
+const BUFFER_OFFSET: u32 = 0x200;
+const IDLE_STACK: u32 = 0x100;
+
+// The addresses the SCC's registers should be reached at: the control
+// and data registers of channels B and A, in that order.
+fn register_addresses(base: u32) -> [u32; 4] {
+    [base, base + CHANNEL_A, base + DATA, base + DATA + CHANNEL_A]
+}
+
+struct Checker<'a> {
+    m: &'a mut Machine,
+    scc: &'a Rc<RefCell<Scc>>,
+    history: &'a mut History,
+    pb: u32,
+    name: u32,
+    buffer: u32,
+    // Where each step starts in the protocol log, for the log file.
+    sections: Vec<(usize, String)>,
+}
+
+impl Checker<'_> {
+    fn section(&mut self, title: String) {
+        self.sections.push((self.scc.borrow().log.len(), title));
+    }
+
+    fn clear_pb(&mut self, refnum: i16) {
+        for i in 0..PB_SIZE {
+            self.m.mem.poke(self.pb + i, 0);
+        }
+        self.m.mem.poke_word(self.pb + IO_REFNUM, refnum as u16);
+    }
+
+    // Make the call, returning the result code.
+    fn call(&mut self, word: u16) -> anyhow::Result<i16> {
+        self.m.cpu.a[0] = self.pb;
+        match session::trap(self.m, word, CALL_LIMIT, self.history, &mut |_, _, _| {}) {
+            Outcome::Failed(why) => bail!("{}", why),
+            _ => Ok(self.m.mem.peek_word(self.pb + IO_RESULT) as i16),
+        }
+    }
+
+    fn expect(&mut self, what: &str, result: i16) -> anyhow::Result<()> {
+        ensure!(result == NO_ERR, "{} gave {}", what, result);
+        Ok(())
+    }
+
+    fn open(&mut self, name: &str, refnum: i16) -> anyhow::Result<()> {
+        self.clear_pb(0);
+        let mut pstring = vec![name.len() as u8];
+        pstring.extend_from_slice(name.as_bytes());
+        self.m.mem.poke_bytes(self.name, &pstring);
+        self.m.mem.poke_long(self.pb + IO_NAME_PTR, self.name);
+        let result = self.call(OPEN)?;
+        self.expect(&format!("Opening {}", name), result)?;
+        let opened = self.m.mem.peek_word(self.pb + IO_REFNUM) as i16;
+        ensure!(
+            opened == refnum,
+            "{} opened as {}, not {}",
+            name,
+            opened,
+            refnum
+        );
+        Ok(())
+    }
+
+    fn close(&mut self, name: &str, refnum: i16) -> anyhow::Result<()> {
+        self.clear_pb(refnum);
+        let result = self.call(CLOSE)?;
+        self.expect(&format!("Closing {}", name), result)
+    }
+
+    // Idle with interrupts enabled for the given number of
+    // instructions, in a loop just below the stack, with the stack
+    // moved down out of its way.
+    fn run(&mut self, count: u64) -> anyhow::Result<()> {
+        let (pc, sr, sp) = (self.m.cpu.pc, self.m.cpu.sr, self.m.cpu.a[7]);
+        self.m.mem.poke_word(sp - 2, BRA_SELF);
+        self.m.cpu.pc = sp - 2;
+        self.m.cpu.set_sr(IDLE_SR);
+        self.m.cpu.a[7] = sp - IDLE_STACK;
+        let limit = self.m.instructions + count;
+        let outcome = harness::run(self.m, None, limit, self.history, &mut |_, _, _| {});
+        self.m.cpu.pc = pc;
+        self.m.cpu.set_sr(sr);
+        self.m.cpu.a[7] = sp;
+        match outcome {
+            Outcome::Failed(why) => bail!("{}", why),
+            _ => Ok(()),
+        }
+    }
+
+    fn init(&mut self) -> anyhow::Result<()> {
+        let (rd, wr) = (self.m.mem.peek_long(SCC_RD), self.m.mem.peek_long(SCC_WR));
+        ensure!(
+            (rd, wr) == (SCC_READ, SCC_WRITE),
+            "SCCRd and SCCWr are 0x{:06x} and 0x{:06x}",
+            rd,
+            wr
+        );
+        println!(
+            "SCC: SCCRd is 0x{:06x} and SCCWr is 0x{:06x}",
+            SCC_READ, SCC_WRITE
+        );
+
+        let scc = self.scc.borrow();
+        for port in [PORT_A, PORT_B] {
+            ensure!(
+                scc.wr(port, 4) == INIT_WR4,
+                "InitSCC left WR4 of port {} as 0x{:02x}",
+                PORT_NAMES[port],
+                scc.wr(port, 4)
+            );
+        }
+        ensure!(
+            scc.wr(PORT_A, 9) == INIT_WR9,
+            "InitSCC left WR9 as 0x{:02x}",
+            scc.wr(PORT_A, 9)
+        );
+        println!("SCC: InitSCC set up both channels and enabled interrupts");
+        Ok(())
+    }
+
+    fn install(&mut self) -> anyhow::Result<()> {
+        self.section("Installing 'SERD'".to_string());
+        let outcome = session::call(self.m, SERD, CALL_LIMIT, self.history, &mut |_, _, _| {});
+        if let Outcome::Failed(why) = outcome {
+            bail!("{}", why);
+        }
+        println!("SCC: 'SERD' installed the RAM serial drivers");
+        Ok(())
+    }
+
+    // Open a port's drivers, send a message and read a reply.
+    fn serial(&mut self, port: usize) -> anyhow::Result<()> {
+        let name = PORT_NAMES[port];
+        let (input, input_refnum, output, output_refnum) = SERIAL_DRIVERS[port];
+        self.section(format!("Opening {} and {}", output, input));
+        self.open(output, output_refnum)?;
+        self.open(input, input_refnum)?;
+
+        self.section(format!("Resetting port {}", name));
+        self.clear_pb(output_refnum);
+        self.m.mem.poke_word(self.pb + CS_CODE, SER_RESET);
+        self.m.mem.poke_word(self.pb + CS_PARAM, SER_CONFIG);
+        let result = self.call(CONTROL)?;
+        self.expect("SerReset", result)?;
+        let (wr4, constant) = {
+            let scc = self.scc.borrow();
+            let constant = ((scc.wr(port, 13) as u16) << 8) | scc.wr(port, 12) as u16;
+            (scc.wr(port, 4), constant)
+        };
+        ensure!(
+            (wr4, constant) == (SER_WR4, SER_TIME_CONSTANT),
+            "SerReset left port {} with WR4 0x{:02x}, time constant {}",
+            name,
+            wr4,
+            constant
+        );
+        println!(
+            "SCC: port {} reset to 9600 baud, time constant {}",
+            name, constant
+        );
+
+        self.section(format!("Writing to {}", output));
+        let message = format!("Hello from port {}", name).into_bytes();
+        let before = self.scc.borrow().sent(port).len();
+        self.clear_pb(output_refnum);
+        self.m.mem.poke_bytes(self.buffer, &message);
+        self.m.mem.poke_long(self.pb + IO_BUFFER, self.buffer);
+        self.m
+            .mem
+            .poke_long(self.pb + IO_REQCOUNT, message.len() as u32);
+        let result = self.call(WRITE)?;
+        self.expect(&format!("Writing to {}", output), result)?;
+        // The last characters may still be on their way out.
+        self.run(2 * CHAR_LIMIT)?;
+        let sent = self.scc.borrow().sent(port)[before..].to_vec();
+        ensure!(
+            sent == message,
+            "Writing '{}' to {} sent '{}'",
+            String::from_utf8_lossy(&message),
+            output,
+            String::from_utf8_lossy(&sent)
+        );
+        println!(
+            "SCC: port {} sent '{}'",
+            name,
+            String::from_utf8_lossy(&sent)
+        );
+
+        self.section(format!("Reading from {}", input));
+        let reply = format!("Reply to port {}", name).into_bytes();
+        self.scc.borrow_mut().receive(port, &reply);
+        self.run(reply.len() as u64 * CHAR_LIMIT)?;
+        self.clear_pb(input_refnum);
+        self.m.mem.poke_word(self.pb + CS_CODE, SER_GET_BUF);
+        let result = self.call(STATUS)?;
+        self.expect("SerGetBuf", result)?;
+        let count = self.m.mem.peek_long(self.pb + CS_PARAM);
+        ensure!(
+            count as usize == reply.len(),
+            "SerGetBuf on {} found {} characters, not {}",
+            input,
+            count,
+            reply.len()
+        );
+        self.clear_pb(input_refnum);
+        self.m.mem.poke_long(self.pb + IO_BUFFER, self.buffer);
+        self.m.mem.poke_long(self.pb + IO_REQCOUNT, count);
+        let result = self.call(READ)?;
+        self.expect(&format!("Reading from {}", input), result)?;
+        let actual = self.m.mem.peek_long(self.pb + IO_ACTCOUNT);
+        let received = self.m.mem.peek_bytes(self.buffer, actual as usize);
+        ensure!(
+            received == reply,
+            "Receiving '{}' on port {} read '{}'",
+            String::from_utf8_lossy(&reply),
+            name,
+            String::from_utf8_lossy(&received)
+        );
+        println!(
+            "SCC: port {} received '{}'",
+            name,
+            String::from_utf8_lossy(&received)
+        );
+
+        self.section(format!("Closing {} and {}", input, output));
+        self.close(input, input_refnum)?;
+        self.close(output, output_refnum)
+    }
+
+    // Open AppleTalk, which should put its port into SDLC mode and
+    // claim a node address with lapENQs.
+    fn appletalk(&mut self) -> anyhow::Result<()> {
+        self.section(format!("Opening {}", MPP));
+        let before = [PORT_A, PORT_B].map(|port| self.scc.borrow().frames(port).len());
+        self.clear_pb(0);
+        let mut pstring = vec![MPP.len() as u8];
+        pstring.extend_from_slice(MPP.as_bytes());
+        self.m.mem.poke_bytes(self.name, &pstring);
+        self.m.mem.poke_long(self.pb + IO_NAME_PTR, self.name);
+        let result = self.call(OPEN)?;
+        self.expect(&format!("Opening {}", MPP), result)?;
+
+        let scc = self.scc.borrow();
+        let wr4 = scc.wr(MPP_PORT, 4);
+        ensure!(
+            wr4 & (STOP_BITS_MASK | SYNC_MODE_MASK) == SDLC_MODE,
+            "{} left port {} with WR4 0x{:02x}, not in SDLC mode",
+            MPP,
+            PORT_NAMES[MPP_PORT],
+            wr4
+        );
+        for port in [PORT_A, PORT_B] {
+            if port != MPP_PORT {
+                ensure!(
+                    scc.frames(port).len() == before[port],
+                    "{} sent frames on port {}",
+                    MPP,
+                    PORT_NAMES[port]
+                );
+            }
+        }
+        let frames = &scc.frames(MPP_PORT)[before[MPP_PORT]..];
+        ensure!(
+            !frames.is_empty(),
+            "{} sent nothing on port {}",
+            MPP,
+            PORT_NAMES[MPP_PORT]
+        );
+        let node = frames[0][0];
+        let enqs = frames
+            .iter()
+            .take_while(|frame| **frame == [node, node, LAP_ENQ])
+            .count();
+        for frame in frames[enqs..].iter() {
+            ensure!(
+                frame.len() >= LAP_HEADER && frame[1] == node && frame[2] != LAP_ENQ,
+                "{} sent {:02x?} after taking node 0x{:02x}",
+                MPP,
+                frame,
+                node
+            );
+        }
+        println!(
+            "SCC: {} sent {} lapENQs on port {} and took node 0x{:02x}, then {} more frames",
+            MPP,
+            enqs,
+            PORT_NAMES[MPP_PORT],
+            node,
+            frames.len() - enqs
+        );
+        Ok(())
+    }
+}
+
+// Every access the drivers made should have gone to one of the
+// registers at the remapped addresses, and nothing should have gone
+// to the SCC's old addresses.
+fn check_accesses(m: &Machine, scc: &Scc, misrouted: usize) -> anyhow::Result<()> {
+    let reads = register_addresses(SCC_READ);
+    let writes = register_addresses(SCC_WRITE);
+    let accesses = m.mem.io_log.as_deref().unwrap_or_default();
+    let mut count = 0;
+    for access in accesses.iter() {
+        if m.mem.region_at(access.addr).map(|r| r.name) != Some("SCC") {
+            continue;
+        }
+        let expected = if access.write { &writes } else { &reads };
+        ensure!(
+            access.width == 1 && expected.contains(&access.addr),
+            "0x{:06x}: {} {} of 0x{:06x} isn't to an SCC register",
+            access.pc,
+            if access.write { "write" } else { "read" },
+            if access.width == 1 { "byte" } else { "word" },
+            access.addr
+        );
+        count += 1;
+    }
+    ensure!(
+        scc.misrouted.len() == misrouted,
+        "The SCC was accessed on the wrong half or byte lane at +0x{:x}",
+        scc.misrouted[misrouted].0
+    );
+    if let Some(access) = m
+        .mem
+        .unmapped
+        .iter()
+        .find(|a| (0x900000..0xc00000).contains(&a.addr))
+    {
+        bail!(
+            "0x{:06x}: access to the old SCC address 0x{:06x}",
+            access.pc,
+            access.addr
+        );
+    }
+    println!("SCC: all {} accesses went to the remapped registers", count);
+    Ok(())
+}
+
+fn register_name(access: &SccAccess) -> String {
+    format!(
+        "{}{}",
+        if access.write { "WR" } else { "RR" },
+        access.register
+    )
+}
+
+// Print how often each register was used on each port.
+fn print_summary(log: &[SccAccess]) {
+    let mut counts = BTreeMap::<(usize, bool, u8), usize>::new();
+    for access in log.iter() {
+        *counts
+            .entry((access.port, !access.write, access.register))
+            .or_default() += 1;
+    }
+    for port in [PORT_A, PORT_B] {
+        let used = counts
+            .iter()
+            .filter(|((p, _, _), _)| *p == port)
+            .map(|((_, read, register), count)| {
+                let access = SccAccess {
+                    port,
+                    write: !read,
+                    register: *register,
+                    value: 0,
+                };
+                format!("{} x{}", register_name(&access), count)
+            })
+            .collect::<Vec<_>>();
+        println!("SCC: port {} used {}", PORT_NAMES[port], used.join(", "));
+    }
+}
+
+// The register protocol, one access per line, under a heading for each
+// step of the check.
+fn write_log(path: &Path, log: &[SccAccess], sections: &[(usize, String)]) -> anyhow::Result<()> {
+    let mut text = String::new();
+    let mut sections = sections.iter().peekable();
+    writeln!(text, "# Booting")?;
+    for (i, access) in log.iter().enumerate() {
+        while let Some((_, title)) = sections.next_if(|(start, _)| *start == i) {
+            writeln!(text, "# {}", title)?;
+        }
+        writeln!(
+            text,
+            "{} {:<4} {} {:02x}",
+            PORT_NAMES[access.port],
+            register_name(access),
+            if access.write { "<-" } else { "->" },
+            access.value
+        )?;
+    }
+    fs::write(path, text).with_context(|| format!("Couldn't write {}", path.display()))?;
+    println!("Wrote {}", path.display());
+    Ok(())
+}
+
+// Boot past the ROM's I/O initialisation, then drive the RAM serial
+// drivers on both ports and open AppleTalk, writing the register
+// protocol they used to the log.
+pub fn check(
+    m: &mut Machine,
+    scc: &Rc<RefCell<Scc>>,
+    limit: u64,
+    history: &mut History,
+    log: &Path,
+) -> Outcome {
+    if let Outcome::Failed(why) = harness::skip_adb(m, limit, history) {
+        return Outcome::Failed(why);
+    }
+    match harness::run(m, Some(IO_INITIALISED), limit, history, &mut |_, _, _| {}) {
+        Outcome::Stopped => {}
+        Outcome::Completed => {
+            return Outcome::Failed("I/O initialisation not reached".to_string());
+        }
+        failed => return failed,
+    }
+    println!("SCC: I/O initialised after {} instructions", m.instructions);
+
+    let sp = m.cpu.a[7];
+    m.cpu.a[7] = sp - SCRATCH_SIZE;
+    let scratch = sp - SCRATCH_SIZE;
+    // Only the drivers' accesses are checked, as InitSCC makes a dummy
+    // read below the registers.
+    let misrouted = scc.borrow().misrouted.len();
+    m.mem.io_log = Some(Vec::new());
+    let mut checker = Checker {
+        m,
+        scc,
+        history,
+        pb: scratch + PB_OFFSET,
+        name: scratch + NAME_OFFSET,
+        buffer: scratch + BUFFER_OFFSET,
+        sections: Vec::new(),
+    };
+    let result = (|| {
+        checker.init()?;
+        checker.install()?;
+        checker.serial(PORT_A)?;
+        checker.serial(PORT_B)?;
+        checker.appletalk()?;
+        check_accesses(checker.m, &checker.scc.borrow(), misrouted)
+    })();
+    checker.m.cpu.a[7] = sp;
+    checker.m.mem.io_log = None;
+
+    let scc = scc.borrow();
+    print_summary(&scc.log);
+    let written = write_log(log, &scc.log, &checker.sections);
+    match result.and(written) {
+        Ok(()) => Outcome::Completed,
+        Err(e) => Outcome::Failed(e.to_string()),
+    }
+}
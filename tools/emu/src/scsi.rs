@@ -0,0 +1,821 @@
+//
+// Paravirtual SCSI
+//
+// The host side of the block mailbox that `patch rom --scsi-mailbox`
+// replaces the SCSI Manager with (see patch/src/scsi.s for the
+// protocol). Targets are disk images, and the commands the Mac sends
+// them are decoded here, as an SD card adapter would. Also a check
+// that drives the patched SCSI Manager against them.
+//
+
+use std::ops::Range;
+
+use anyhow::{bail, ensure};
+
+use crate::disk::{Disk, BLOCK_SIZE};
+use crate::harness::{self, History, Outcome};
+use crate::machine::{Device, Machine, ROM_BASE};
+use crate::session;
+
+pub const MAILBOX_SIZE: u32 = memory_map::SCSI_MAILBOX_SIZE;
+
+// The Mac itself is ID 7.
+pub const TARGETS: usize = 7;
+
+// Commands.
+const CMD_SELECT: u8 = 1;
+const CMD_COMMAND: u8 = 2;
+const CMD_READ: u8 = 3;
+const CMD_WRITE: u8 = 4;
+const CMD_COMPLETE: u8 = 5;
+const CMD_RESET: u8 = 6;
+
+// Statuses.
+const STS_DONE: u8 = 0x00;
+const STS_BUSY: u8 = 0x01;
+const STS_NO_TARGET: u8 = 0x80;
+const STS_PHASE: u8 = 0x81;
+const STS_BAD_PARMS: u8 = 0x82;
+const STS_SEQUENCE: u8 = 0x83;
+const STS_IO_ERROR: u8 = 0x84;
+
+// SCSI status bytes.
+const GOOD: u8 = 0x00;
+const CHECK_CONDITION: u8 = 0x02;
+
+// SCSI commands.
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const FORMAT_UNIT: u8 = 0x04;
+const READ_6: u8 = 0x08;
+const WRITE_6: u8 = 0x0a;
+const INQUIRY: u8 = 0x12;
+const MODE_SENSE_6: u8 = 0x1a;
+const START_STOP_UNIT: u8 = 0x1b;
+const PREVENT_ALLOW_REMOVAL: u8 = 0x1e;
+const READ_CAPACITY: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2a;
+const VERIFY_10: u8 = 0x2f;
+
+// Sense keys, and their additional sense codes.
+const NO_SENSE: (u8, u8) = (0x0, 0x00);
+const WRITE_ERROR: (u8, u8) = (0x3, 0x0c);
+const INVALID_OPCODE: (u8, u8) = (0x5, 0x20);
+const BAD_BLOCK_ADDRESS: (u8, u8) = (0x5, 0x21);
+const WRITE_PROTECTED: (u8, u8) = (0x7, 0x27);
+
+const INQUIRY_VENDOR: &[u8; 8] = b"BIGMAC  ";
+const INQUIRY_PRODUCT: &[u8; 16] = b"PARAVIRTUAL DISK";
+const INQUIRY_REVISION: &[u8; 4] = b"0001";
+
+// Length of a command descriptor block, from its group.
+fn cdb_length(opcode: u8) -> usize {
+    match opcode >> 5 {
+        0 => 6,
+        1 | 2 => 10,
+        _ => 12,
+    }
+}
+
+fn read_long(data: &[u8]) -> u32 {
+    u32::from_be_bytes(data[..4].try_into().unwrap())
+}
+
+////////////////////////////////////////////////////////////////////////
+// The mailbox.
+//
+
+// A write command waiting for its data.
+struct WriteData {
+    block: u32,
+    len: usize,
+    data: Vec<u8>,
+}
+
+// Commands complete on the tick after they're written, like the
+// floppy's.
+pub struct ScsiMailbox {
+    targets: Vec<Option<Disk>>,
+    buffer: u32,
+    length: u32,
+    command: u8,
+    status: u8,
+    target: u8,
+    scsi_status: u8,
+    pending: bool,
+    // The selected target, and whether it's been sent a command.
+    selected: Option<usize>,
+    commanded: bool,
+    // Data for the data phase of the current command.
+    data_in: Vec<u8>,
+    data_in_pos: usize,
+    data_out: Option<WriteData>,
+    // Sense key and additional sense code from the last command.
+    sense: (u8, u8),
+}
+
+impl ScsiMailbox {
+    pub fn new(targets: Vec<Option<Disk>>) -> ScsiMailbox {
+        ScsiMailbox {
+            targets,
+            buffer: 0,
+            length: 0,
+            command: 0,
+            status: STS_DONE,
+            target: 0,
+            scsi_status: GOOD,
+            pending: false,
+            selected: None,
+            commanded: false,
+            data_in: Vec::new(),
+            data_in_pos: 0,
+            data_out: None,
+            sense: NO_SENSE,
+        }
+    }
+
+    // Drop the command in progress, and free the bus.
+    fn release(&mut self) {
+        self.selected = None;
+        self.commanded = false;
+        self.data_in.clear();
+        self.data_in_pos = 0;
+        self.data_out = None;
+    }
+
+    // Where in RAM the buffer is, if it's all there.
+    fn buffer(&self, ram: &[u8]) -> Option<Range<usize>> {
+        let start = (self.buffer & 0xffffff) as usize;
+        let end = start + self.length as usize;
+        (end <= ram.len()).then_some(start..end)
+    }
+
+    // Finish the command with a check condition.
+    fn check_condition(&mut self, sense: (u8, u8)) -> u8 {
+        self.sense = sense;
+        CHECK_CONDITION
+    }
+
+    // Carry out the command, returning its status.
+    fn execute(&mut self, ram: &mut [u8]) -> u8 {
+        match self.command {
+            CMD_SELECT => {
+                self.release();
+                let target = self.target as usize;
+                if target >= self.targets.len() || self.targets[target].is_none() {
+                    return STS_NO_TARGET;
+                }
+                self.selected = Some(target);
+                STS_DONE
+            }
+            CMD_COMMAND => {
+                let Some(target) = self.selected else {
+                    return STS_SEQUENCE;
+                };
+                if self.commanded {
+                    return STS_SEQUENCE;
+                }
+                let Some(range) = self.buffer(ram) else {
+                    return STS_BAD_PARMS;
+                };
+                let cdb = ram[range].to_vec();
+                if cdb.is_empty() || cdb.len() < cdb_length(cdb[0]) {
+                    return STS_BAD_PARMS;
+                }
+                self.commanded = true;
+                self.scsi_status = self.decode(target, &cdb);
+                STS_DONE
+            }
+            CMD_READ => {
+                if !self.commanded {
+                    return STS_SEQUENCE;
+                }
+                let Some(range) = self.buffer(ram) else {
+                    return STS_BAD_PARMS;
+                };
+                let start = self.data_in_pos;
+                let end = start + range.len();
+                if end > self.data_in.len() {
+                    return STS_PHASE;
+                }
+                ram[range].copy_from_slice(&self.data_in[start..end]);
+                self.data_in_pos = end;
+                STS_DONE
+            }
+            CMD_WRITE => {
+                if !self.commanded {
+                    return STS_SEQUENCE;
+                }
+                let Some(range) = self.buffer(ram) else {
+                    return STS_BAD_PARMS;
+                };
+                let Some(write) = self.data_out.as_mut() else {
+                    return STS_PHASE;
+                };
+                if write.data.len() + range.len() > write.len {
+                    return STS_PHASE;
+                }
+                write.data.extend_from_slice(&ram[range]);
+                if write.data.len() == write.len {
+                    return self.commit(self.selected.unwrap());
+                }
+                STS_DONE
+            }
+            CMD_COMPLETE => {
+                if !self.commanded {
+                    return STS_SEQUENCE;
+                }
+                self.release();
+                STS_DONE
+            }
+            CMD_RESET => {
+                self.release();
+                self.sense = NO_SENSE;
+                STS_DONE
+            }
+            _ => STS_BAD_PARMS,
+        }
+    }
+
+    // Write out the data of a completed data phase.
+    fn commit(&mut self, target: usize) -> u8 {
+        let write = self.data_out.take().unwrap();
+        let disk = self.targets[target].as_mut().unwrap();
+        for (i, block) in write.data.chunks(BLOCK_SIZE).enumerate() {
+            if disk.write_block(write.block + i as u32, block).is_err() {
+                self.scsi_status = self.check_condition(WRITE_ERROR);
+                return STS_IO_ERROR;
+            }
+        }
+        STS_DONE
+    }
+
+    // Act on a command descriptor block, setting up any data phase, and
+    // returning the SCSI status.
+    fn decode(&mut self, target: usize, cdb: &[u8]) -> u8 {
+        let disk = self.targets[target].as_ref().unwrap();
+        let blocks = disk.blocks();
+        let locked = disk.locked();
+        // Allocation length, for the commands that return information.
+        let allocation = cdb[4] as usize;
+
+        let (block, count, write) = match cdb[0] {
+            TEST_UNIT_READY | FORMAT_UNIT | START_STOP_UNIT | PREVENT_ALLOW_REMOVAL | VERIFY_10 => {
+                self.sense = NO_SENSE;
+                return GOOD;
+            }
+            REQUEST_SENSE => {
+                let (key, code) = self.sense;
+                let mut data = vec![0; 18];
+                data[0] = 0x70;
+                data[2] = key;
+                data[7] = 10;
+                data[12] = code;
+                // Zero means four bytes to SCSI-1 targets.
+                data.truncate(if allocation == 0 { 4 } else { allocation });
+                self.data_in = data;
+                self.sense = NO_SENSE;
+                return GOOD;
+            }
+            INQUIRY => {
+                // A SCSI-1 direct-access device.
+                let mut data = vec![0x00, 0x00, 0x01, 0x01, 31, 0, 0, 0];
+                data.extend_from_slice(INQUIRY_VENDOR);
+                data.extend_from_slice(INQUIRY_PRODUCT);
+                data.extend_from_slice(INQUIRY_REVISION);
+                data.truncate(allocation);
+                self.data_in = data;
+                self.sense = NO_SENSE;
+                return GOOD;
+            }
+            MODE_SENSE_6 => {
+                // Just the header and a block descriptor.
+                let mut data = vec![11, 0, if locked { 0x80 } else { 0 }, 8];
+                data.extend_from_slice(&blocks.to_be_bytes());
+                data.extend_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+                data[4] = 0;
+                data.truncate(allocation);
+                self.data_in = data;
+                self.sense = NO_SENSE;
+                return GOOD;
+            }
+            READ_CAPACITY => {
+                let mut data = (blocks - 1).to_be_bytes().to_vec();
+                data.extend_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+                self.data_in = data;
+                self.sense = NO_SENSE;
+                return GOOD;
+            }
+            READ_6 | WRITE_6 => {
+                let block = read_long(cdb) & 0x1fffff;
+                let count = if cdb[4] == 0 { 256 } else { cdb[4] as u32 };
+                (block, count, cdb[0] == WRITE_6)
+            }
+            READ_10 | WRITE_10 => {
+                let block = read_long(&cdb[2..]);
+                let count = ((cdb[7] as u32) << 8) | cdb[8] as u32;
+                (block, count, cdb[0] == WRITE_10)
+            }
+            _ => return self.check_condition(INVALID_OPCODE),
+        };
+
+        if block.checked_add(count).is_none_or(|end| end > blocks) {
+            return self.check_condition(BAD_BLOCK_ADDRESS);
+        }
+        let len = count as usize * BLOCK_SIZE;
+        if write {
+            if locked {
+                return self.check_condition(WRITE_PROTECTED);
+            }
+            self.data_out = Some(WriteData {
+                block,
+                len,
+                data: Vec::new(),
+            });
+        } else {
+            let start = block as usize * BLOCK_SIZE;
+            self.data_in = disk.data[start..start + len].to_vec();
+        }
+        self.sense = NO_SENSE;
+        GOOD
+    }
+}
+
+impl Device for ScsiMailbox {
+    fn read(&mut self, offset: u32) -> u8 {
+        match offset {
+            0..=3 => self.buffer.to_be_bytes()[offset as usize],
+            4..=7 => self.length.to_be_bytes()[offset as usize - 4],
+            8 => self.command,
+            9 => self.status,
+            10 => self.target,
+            11 => self.scsi_status,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u32, value: u8) {
+        match offset {
+            0..=3 => {
+                let shift = 8 * (3 - offset);
+                self.buffer = (self.buffer & !(0xff << shift)) | ((value as u32) << shift);
+            }
+            4..=7 => {
+                let shift = 8 * (7 - offset);
+                self.length = (self.length & !(0xff << shift)) | ((value as u32) << shift);
+            }
+            8 => {
+                self.command = value;
+                self.status = STS_BUSY;
+                self.pending = true;
+            }
+            10 => self.target = value,
+            _ => {}
+        }
+    }
+
+    fn dma(&mut self, ram: &mut [u8]) {
+        if self.pending {
+            self.pending = false;
+            self.status = self.execute(ram);
+        }
+    }
+
+    // The disks stay attached.
+    fn reset(&mut self) {
+        *self = ScsiMailbox::new(std::mem::take(&mut self.targets));
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// Checking the SCSI Manager.
+//
+
+// The boot code's routine that reads blocks from a SCSI disk returns
+// here, with the result in D0, the target in D5 and the blocks at A2.
+const SCSI_READ_DONE: u32 = ROM_BASE + 0x427a;
+
+// _SCSIDispatch selectors.
+const SCSI_RESET: u16 = 0;
+const SCSI_GET: u16 = 1;
+const SCSI_SELECT: u16 = 2;
+const SCSI_CMD: u16 = 3;
+const SCSI_COMPLETE: u16 = 4;
+const SCSI_READ: u16 = 5;
+const SCSI_WRITE: u16 = 6;
+const SCSI_RBLIND: u16 = 8;
+const SCSI_WBLIND: u16 = 9;
+
+// Transfer instruction opcodes.
+const SC_INC: u16 = 1;
+const SC_LOOP: u16 = 5;
+const SC_STOP: u16 = 7;
+const SC_COMP: u16 = 8;
+const TIB_SIZE: u32 = 10;
+
+// A transfer instruction: the opcode and its two operands.
+type Instruction = (u16, u32, u32);
+
+// Result codes.
+const NO_ERR: i16 = 0;
+const SC_COMM_ERR: i16 = 2;
+const SC_PHASE_ERR: i16 = 5;
+const SC_COMPARE_ERR: i16 = 6;
+const SC_SEQUENCE_ERR: i16 = 8;
+
+// Ticks for SCSIComplete to wait.
+const COMPLETE_WAIT: u32 = 60;
+
+// Makes a call with the arguments already on the stack, and records
+// where it left the stack:
+//
+//   movea.l (sp)+,a2
+//   _SCSIDispatch
+//   move.l  sp,d7
+//   jmp     (a2)
+const GLUE: [u8; 8] = [0x24, 0x5f, 0xa8, 0x15, 0x2e, 0x0f, 0x4e, 0xd2];
+
+// Instructions allowed per call.
+const CALL_LIMIT: u64 = 1_000_000;
+
+// Room taken off the stack for the glue, command, transfer
+// instructions and buffers.
+const SCRATCH_SIZE: u32 = 0x3000;
+const GLUE_OFFSET: u32 = 0x00;
+const CDB_OFFSET: u32 = 0x10;
+const STAT_OFFSET: u32 = 0x20;
+const MSG_OFFSET: u32 = 0x22;
+const TIB_OFFSET: u32 = 0x40;
+const COMPARE_OFFSET: u32 = 0x200;
+const BUFFER_OFFSET: u32 = 0x400;
+const MAX_BLOCKS: usize = 16;
+
+enum Arg {
+    Word(u16),
+    Long(u32),
+}
+
+// Drives the SCSI Manager through its trap, with its buffers carved off
+// the stack.
+struct Checker<'a> {
+    m: &'a mut Machine,
+    history: &'a mut History,
+    scratch: u32,
+    target: u16,
+}
+
+impl Checker<'_> {
+    fn buffer(&self) -> u32 {
+        self.scratch + BUFFER_OFFSET
+    }
+
+    // Make a call, Pascal style: space for the result, then the
+    // arguments in order, then the selector, go on the stack.
+    fn dispatch(&mut self, selector: u16, args: &[Arg]) -> anyhow::Result<i16> {
+        let sp = self.m.cpu.a[7];
+        let mut addr = sp - 2;
+        self.m.mem.poke_word(addr, 0xffff);
+        for arg in args.iter() {
+            match *arg {
+                Arg::Word(value) => {
+                    addr -= 2;
+                    self.m.mem.poke_word(addr, value);
+                }
+                Arg::Long(value) => {
+                    addr -= 4;
+                    self.m.mem.poke_long(addr, value);
+                }
+            }
+        }
+        addr -= 2;
+        self.m.mem.poke_word(addr, selector);
+        self.m.cpu.a[7] = addr;
+        let glue = self.scratch + GLUE_OFFSET;
+        let outcome = session::call(self.m, glue, CALL_LIMIT, self.history, &mut |_, _, _| {});
+        self.m.cpu.a[7] = sp;
+        if let Outcome::Failed(why) = outcome {
+            bail!("{}", why);
+        }
+        ensure!(
+            self.m.cpu.d[7] == sp - 2,
+            "Selector {} left the stack at 0x{:06x}, not 0x{:06x}",
+            selector,
+            self.m.cpu.d[7],
+            sp - 2
+        );
+        Ok(self.m.mem.peek_word(sp - 2) as i16)
+    }
+
+    fn select(&mut self, target: u16) -> anyhow::Result<i16> {
+        self.dispatch(SCSI_SELECT, &[Arg::Word(target)])
+    }
+
+    fn cmd(&mut self, cdb: &[u8]) -> anyhow::Result<i16> {
+        let addr = self.scratch + CDB_OFFSET;
+        self.m.mem.poke_bytes(addr, cdb);
+        self.dispatch(SCSI_CMD, &[Arg::Long(addr), Arg::Word(cdb.len() as u16)])
+    }
+
+    // Run a transfer instruction block of (opcode, operands).
+    fn transfer(&mut self, selector: u16, tib: &[Instruction]) -> anyhow::Result<i16> {
+        let addr = self.scratch + TIB_OFFSET;
+        for (i, (opcode, p1, p2)) in tib.iter().enumerate() {
+            let op = addr + i as u32 * TIB_SIZE;
+            self.m.mem.poke_word(op, *opcode);
+            self.m.mem.poke_long(op + 2, *p1);
+            self.m.mem.poke_long(op + 6, *p2);
+        }
+        self.dispatch(selector, &[Arg::Long(addr)])
+    }
+
+    // Complete the command, returning the result and SCSI status.
+    fn complete(&mut self) -> anyhow::Result<(i16, u16)> {
+        let stat = self.scratch + STAT_OFFSET;
+        let msg = self.scratch + MSG_OFFSET;
+        let result = self.dispatch(
+            SCSI_COMPLETE,
+            &[Arg::Long(stat), Arg::Long(msg), Arg::Long(COMPLETE_WAIT)],
+        )?;
+        Ok((result, self.m.mem.peek_word(stat)))
+    }
+
+    fn expect(&mut self, what: &str, result: i16, expected: i16) -> anyhow::Result<()> {
+        ensure!(
+            result == expected,
+            "{} gave {}, not {}",
+            what,
+            result,
+            expected
+        );
+        println!("SCSI: {} gave {}", what, result);
+        Ok(())
+    }
+
+    // Send a command to the target, with an optional data phase,
+    // checking each step works, and returning the SCSI status.
+    fn command(&mut self, cdb: &[u8], data: Option<(u16, &[Instruction])>) -> anyhow::Result<u16> {
+        let result = self.dispatch(SCSI_GET, &[])?;
+        ensure!(result == NO_ERR, "Arbitrating gave {}", result);
+        let result = self.select(self.target)?;
+        ensure!(
+            result == NO_ERR,
+            "Selecting target {} gave {}",
+            self.target,
+            result
+        );
+        let result = self.cmd(cdb)?;
+        ensure!(result == NO_ERR, "Command 0x{:02x} gave {}", cdb[0], result);
+        if let Some((selector, tib)) = data {
+            let result = self.transfer(selector, tib)?;
+            ensure!(
+                result == NO_ERR,
+                "Data phase of command 0x{:02x} gave {}",
+                cdb[0],
+                result
+            );
+        }
+        let (result, stat) = self.complete()?;
+        ensure!(
+            result == NO_ERR,
+            "Completing command 0x{:02x} gave {}",
+            cdb[0],
+            result
+        );
+        Ok(stat)
+    }
+
+    // Read into the buffer with a command that should succeed.
+    fn read_data(&mut self, cdb: &[u8], len: usize) -> anyhow::Result<Vec<u8>> {
+        let tib = [(SC_INC, self.buffer(), len as u32), (SC_STOP, 0, 0)];
+        let stat = self.command(cdb, Some((SCSI_READ, &tib)))?;
+        ensure!(
+            stat == GOOD as u16,
+            "Command 0x{:02x} gave status {}",
+            cdb[0],
+            stat
+        );
+        Ok(self.m.mem.peek_bytes(self.buffer(), len))
+    }
+
+    // Read blocks, a block at a time with a blind transfer loop, and
+    // check they match the image.
+    fn read(&mut self, expected: &[u8], block: usize, count: usize) -> anyhow::Result<()> {
+        let mut cdb = vec![READ_10, 0];
+        cdb.extend_from_slice(&(block as u32).to_be_bytes());
+        cdb.extend_from_slice(&[0, 0, count as u8, 0]);
+        let tib = [
+            (SC_INC, self.buffer(), BLOCK_SIZE as u32),
+            (SC_LOOP, -(TIB_SIZE as i32) as u32, count as u32),
+            (SC_STOP, 0, 0),
+        ];
+        let stat = self.command(&cdb, Some((SCSI_RBLIND, &tib)))?;
+        ensure!(
+            stat == GOOD as u16,
+            "Reading block {} gave status {}",
+            block,
+            stat
+        );
+        let len = count * BLOCK_SIZE;
+        let start = block * BLOCK_SIZE;
+        ensure!(
+            self.m.mem.peek_bytes(self.buffer(), len) == expected[start..start + len],
+            "Blocks {} to {} don't match the image",
+            block,
+            block + count - 1
+        );
+        println!("SCSI: read blocks {} to {}", block, block + count - 1);
+        Ok(())
+    }
+
+    // Read block 0 comparing it with the compare buffer, returning the
+    // result of the data phase.
+    fn compare(&mut self) -> anyhow::Result<i16> {
+        let compare = self.scratch + COMPARE_OFFSET;
+        let tib = [(SC_COMP, compare, BLOCK_SIZE as u32), (SC_STOP, 0, 0)];
+        let result = self.select(self.target)?;
+        ensure!(
+            result == NO_ERR,
+            "Selecting target {} gave {}",
+            self.target,
+            result
+        );
+        let result = self.cmd(&[READ_6, 0, 0, 0, 1, 0])?;
+        ensure!(result == NO_ERR, "Reading block 0 gave {}", result);
+        let result = self.transfer(SCSI_READ, &tib)?;
+        let (complete, _) = self.complete()?;
+        ensure!(complete == NO_ERR, "Completing a compare gave {}", complete);
+        Ok(result)
+    }
+}
+
+fn check_manager(
+    m: &mut Machine,
+    history: &mut History,
+    target: u8,
+    absent: u8,
+    image: &[u8],
+) -> anyhow::Result<()> {
+    let sp = m.cpu.a[7];
+    m.cpu.a[7] = sp - SCRATCH_SIZE;
+    let scratch = sp - SCRATCH_SIZE;
+    m.mem.poke_bytes(scratch + GLUE_OFFSET, &GLUE);
+    let mut checker = Checker {
+        m,
+        history,
+        scratch,
+        target: target as u16,
+    };
+    let blocks = image.len() / BLOCK_SIZE;
+    let result = (|| {
+        let result = checker.dispatch(SCSI_RESET, &[])?;
+        checker.expect("Resetting the bus", result, NO_ERR)?;
+        let result = checker.select(absent as u16)?;
+        checker.expect("Selecting an absent target", result, SC_COMM_ERR)?;
+        let result = checker.cmd(&[TEST_UNIT_READY, 0, 0, 0, 0, 0])?;
+        checker.expect("A command without selecting", result, SC_SEQUENCE_ERR)?;
+
+        let inquiry = checker.read_data(&[INQUIRY, 0, 0, 0, 36, 0], 36)?;
+        ensure!(inquiry[0] == 0, "Inquiry shows device type {}", inquiry[0]);
+        println!(
+            "SCSI: target {} is '{}'",
+            target,
+            String::from_utf8_lossy(&inquiry[8..32])
+        );
+        let capacity = checker.read_data(&[READ_CAPACITY, 0, 0, 0, 0, 0, 0, 0, 0, 0], 8)?;
+        ensure!(
+            read_long(&capacity) as usize == blocks - 1
+                && read_long(&capacity[4..]) as usize == BLOCK_SIZE,
+            "Capacity of {} blocks of {} bytes, not {} of {}",
+            read_long(&capacity) + 1,
+            read_long(&capacity[4..]),
+            blocks,
+            BLOCK_SIZE
+        );
+        println!("SCSI: capacity is {} blocks", blocks);
+
+        checker.read(image, 0, 4)?;
+        checker.read(image, blocks / 2, MAX_BLOCKS)?;
+        checker.read(image, blocks - 2, 2)?;
+        let data = checker.read_data(&[READ_6, 0, 0, 2, 2, 0], 2 * BLOCK_SIZE)?;
+        ensure!(
+            data == image[2 * BLOCK_SIZE..4 * BLOCK_SIZE],
+            "Polled read of blocks 2 and 3 doesn't match the image"
+        );
+        println!("SCSI: polled read of blocks 2 to 3");
+
+        let compare = checker.scratch + COMPARE_OFFSET;
+        checker.m.mem.poke_bytes(compare, &image[..BLOCK_SIZE]);
+        let result = checker.compare()?;
+        checker.expect("Comparing block 0", result, NO_ERR)?;
+        checker.m.mem.poke(compare + 0x100, image[0x100] ^ 0xff);
+        let result = checker.compare()?;
+        checker.expect("Comparing a changed block 0", result, SC_COMPARE_ERR)?;
+
+        let pattern = (0..BLOCK_SIZE).map(|i| i as u8 ^ 0xa5).collect::<Vec<_>>();
+        let buffer = checker.buffer();
+        checker.m.mem.poke_bytes(buffer, &pattern);
+        let last = blocks - 1;
+        let mut cdb = vec![WRITE_10, 0];
+        cdb.extend_from_slice(&(last as u32).to_be_bytes());
+        cdb.extend_from_slice(&[0, 0, 1, 0]);
+        let tib = [(SC_INC, buffer, BLOCK_SIZE as u32), (SC_STOP, 0, 0)];
+        let stat = checker.command(&cdb, Some((SCSI_WBLIND, &tib)))?;
+        ensure!(
+            stat == GOOD as u16,
+            "Writing the last block gave status {}",
+            stat
+        );
+        let mut written = image.to_vec();
+        written[last * BLOCK_SIZE..].copy_from_slice(&pattern);
+        checker.read(&written, last, 1)?;
+
+        let mut cdb = vec![READ_10, 0];
+        cdb.extend_from_slice(&(blocks as u32).to_be_bytes());
+        cdb.extend_from_slice(&[0, 0, 1, 0]);
+        let stat = checker.command(&cdb, None)?;
+        ensure!(
+            stat == CHECK_CONDITION as u16,
+            "Reading past the end gave status {}",
+            stat
+        );
+        let sense = checker.read_data(&[REQUEST_SENSE, 0, 0, 0, 18, 0], 18)?;
+        ensure!(
+            (sense[2] & 0xf, sense[12]) == BAD_BLOCK_ADDRESS,
+            "Reading past the end gave sense key {} code 0x{:02x}",
+            sense[2] & 0xf,
+            sense[12]
+        );
+        println!("SCSI: reading past the end gave a check condition");
+
+        let result = checker.select(checker.target)?;
+        ensure!(
+            result == NO_ERR,
+            "Selecting target {} gave {}",
+            target,
+            result
+        );
+        let result = checker.cmd(&[TEST_UNIT_READY, 0, 0, 0, 0, 0])?;
+        ensure!(
+            result == NO_ERR,
+            "Testing the unit is ready gave {}",
+            result
+        );
+        let tib = [(SC_INC, buffer, BLOCK_SIZE as u32), (SC_STOP, 0, 0)];
+        let result = checker.transfer(SCSI_WRITE, &tib)?;
+        checker.expect("Writing with no data phase", result, SC_PHASE_ERR)?;
+        let (result, stat) = checker.complete()?;
+        ensure!(
+            result == NO_ERR && stat == GOOD as u16,
+            "Completing after a phase error gave {}, status {}",
+            result,
+            stat
+        );
+        Ok(())
+    })();
+    checker.m.cpu.a[7] = sp;
+    result
+}
+
+// Boot until the ROM has looked for a driver on the target through the
+// SCSI Manager, then make calls to it directly.
+pub fn check(
+    m: &mut Machine,
+    target: u8,
+    absent: u8,
+    disk: &Disk,
+    limit: u64,
+    history: &mut History,
+) -> Outcome {
+    if let Outcome::Failed(why) = harness::skip_adb(m, limit, history) {
+        return Outcome::Failed(why);
+    }
+
+    // The ROM scans the other IDs first.
+    loop {
+        match harness::run(m, Some(SCSI_READ_DONE), limit, history, &mut |_, _, _| {}) {
+            Outcome::Stopped if m.cpu.d[0] & 0xffff == 0 && m.cpu.d[5] & 7 == target as u32 => {
+                break
+            }
+            Outcome::Stopped => {
+                m.step();
+            }
+            Outcome::Completed => {
+                return Outcome::Failed(format!("target {} not read by the ROM", target));
+            }
+            failed => return failed,
+        }
+    }
+    if m.mem.peek_bytes(m.cpu.a[2], BLOCK_SIZE) != disk.data[..BLOCK_SIZE] {
+        return Outcome::Failed("block 0 doesn't match the image".to_string());
+    }
+    println!(
+        "SCSI: ROM read block 0 of target {} after {} instructions",
+        target, m.instructions
+    );
+
+    match check_manager(m, history, target, absent, &disk.data) {
+        Ok(()) => Outcome::Completed,
+        Err(e) => Outcome::Failed(e.to_string()),
+    }
+}
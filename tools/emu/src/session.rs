@@ -0,0 +1,276 @@
+//
+// Scripted sessions
+//
+// Drives the machine through a script after booting, to reach code
+// that a plain boot doesn't. One command per line, with "#" starting
+// a comment:
+//
+//   run <count>                    Run for more instructions.
+//   until <addr> [<limit>]         Run until the PC reaches the address.
+//   set <reg> <value>              Set d0-d7, a0-a7, pc or sr.
+//   poke <addr> <byte>...          Write bytes to RAM.
+//   call <addr|name> [<limit>]     Call a subroutine until it returns.
+//   trap <word> [<limit>]          Run an A-line trap until it returns.
+//
+// Addresses and values are hex, with an optional "0x". Counts and
+// limits are decimal numbers of instructions, by default 1,000,000.
+// Names are trap names, as labelled by extract_traps.
+//
+
+use std::fmt;
+
+use anyhow::{anyhow, bail, Context};
+
+use crate::harness::{self, History, Observer, Outcome};
+use crate::machine::{Machine, ROM_BASE, ROM_SIZE};
+use crate::symbols::Symbols;
+
+const DEFAULT_LIMIT: u64 = 1_000_000;
+
+// Calls and traps return here. It's never executed, as the harness
+// stops when the PC reaches it.
+const RETURN_ADDR: u32 = ROM_BASE + ROM_SIZE - 2;
+
+// RTS, to return from code we place on the stack.
+const RTS: u16 = 0x4e75;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Reg {
+    D(usize),
+    A(usize),
+    Pc,
+    Sr,
+}
+
+#[derive(Clone, Debug)]
+pub enum Command {
+    Run(u64),
+    Until(u32, u64),
+    Set(Reg, u32),
+    Poke(u32, Vec<u8>),
+    Call(u32, u64),
+    Trap(u16, u64),
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reg::D(n) => write!(f, "d{}", n),
+            Reg::A(n) => write!(f, "a{}", n),
+            Reg::Pc => write!(f, "pc"),
+            Reg::Sr => write!(f, "sr"),
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Command::Run(count) => write!(f, "run {}", count),
+            Command::Until(addr, limit) => write!(f, "until 0x{:06x} {}", addr, limit),
+            Command::Set(reg, value) => write!(f, "set {} 0x{:x}", reg, value),
+            Command::Poke(addr, bytes) => {
+                write!(f, "poke 0x{:06x}", addr)?;
+                for b in bytes.iter() {
+                    write!(f, " {:02x}", b)?;
+                }
+                Ok(())
+            }
+            Command::Call(addr, limit) => write!(f, "call 0x{:06x} {}", addr, limit),
+            Command::Trap(word, limit) => write!(f, "trap 0x{:04x} {}", word, limit),
+        }
+    }
+}
+
+fn parse_hex(s: &str) -> anyhow::Result<u32> {
+    u32::from_str_radix(s.trim_start_matches("0x"), 16)
+        .with_context(|| format!("Bad hex number '{}'", s))
+}
+
+fn parse_reg(s: &str) -> anyhow::Result<Reg> {
+    let s = s.to_ascii_lowercase();
+    match s.as_str() {
+        "pc" => return Ok(Reg::Pc),
+        "sr" => return Ok(Reg::Sr),
+        "sp" => return Ok(Reg::A(7)),
+        _ => {}
+    }
+    let (kind, num) = s.split_at(1);
+    let num = num
+        .parse::<usize>()
+        .ok()
+        .filter(|n| *n < 8)
+        .ok_or_else(|| anyhow!("Bad register '{}'", s))?;
+    match kind {
+        "d" => Ok(Reg::D(num)),
+        "a" => Ok(Reg::A(num)),
+        _ => bail!("Bad register '{}'", s),
+    }
+}
+
+fn parse_limit(arg: Option<&str>) -> anyhow::Result<u64> {
+    match arg {
+        Some(s) => s
+            .parse()
+            .with_context(|| format!("Bad instruction count '{}'", s)),
+        None => Ok(DEFAULT_LIMIT),
+    }
+}
+
+fn parse_line(line: &str, symbols: &Symbols) -> anyhow::Result<Option<Command>> {
+    let line = line.split('#').next().unwrap();
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    let Some((command, args)) = words.split_first() else {
+        return Ok(None);
+    };
+
+    let (min_args, max_args) = match *command {
+        "run" => (1, 1),
+        "until" | "call" | "trap" => (1, 2),
+        "set" => (2, 2),
+        "poke" => (2, usize::MAX),
+        _ => bail!("Unknown command '{}'", command),
+    };
+    if args.len() < min_args || args.len() > max_args {
+        bail!("Wrong number of arguments to '{}'", command);
+    }
+
+    Ok(Some(match *command {
+        "run" => Command::Run(parse_limit(Some(args[0]))?),
+        "until" => Command::Until(parse_hex(args[0])?, parse_limit(args.get(1).copied())?),
+        "set" => Command::Set(parse_reg(args[0])?, parse_hex(args[1])?),
+        "poke" => {
+            let bytes = args[1..]
+                .iter()
+                .map(|b| u8::from_str_radix(b, 16).with_context(|| format!("Bad byte '{}'", b)))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            Command::Poke(parse_hex(args[0])?, bytes)
+        }
+        "call" => {
+            let addr = match symbols.lookup(args[0]) {
+                Some(addr) => addr,
+                None => parse_hex(args[0])
+                    .with_context(|| format!("'{}' is neither a name nor an address", args[0]))?,
+            };
+            Command::Call(addr, parse_limit(args.get(1).copied())?)
+        }
+        "trap" => {
+            let word = parse_hex(args[0])?;
+            if word & 0xf000 != 0xa000 || word > 0xffff {
+                bail!("'{}' isn't an A-line trap", args[0]);
+            }
+            Command::Trap(word as u16, parse_limit(args.get(1).copied())?)
+        }
+        _ => unreachable!(),
+    }))
+}
+
+pub fn parse(text: &str, symbols: &Symbols) -> anyhow::Result<Vec<Command>> {
+    let mut commands = Vec::new();
+    for (idx, line) in text.lines().enumerate() {
+        if let Some(command) =
+            parse_line(line, symbols).with_context(|| format!("Line {}", idx + 1))?
+        {
+            commands.push(command);
+        }
+    }
+    Ok(commands)
+}
+
+fn push_long(m: &mut Machine, value: u32) {
+    m.cpu.a[7] -= 4;
+    m.mem.poke_long(m.cpu.a[7], value);
+}
+
+// Run a subroutine at the given address until it returns to
+// RETURN_ADDR, putting the PC and stack pointer back afterwards.
+pub fn call(
+    m: &mut Machine,
+    addr: u32,
+    limit: u64,
+    history: &mut History,
+    observe: Observer,
+) -> Outcome {
+    let (pc, sp) = (m.cpu.pc, m.cpu.a[7]);
+    push_long(m, RETURN_ADDR);
+    m.cpu.pc = addr;
+    let outcome = harness::run(
+        m,
+        Some(RETURN_ADDR),
+        m.instructions + limit,
+        history,
+        observe,
+    );
+    m.cpu.pc = pc;
+    m.cpu.a[7] = sp;
+    match outcome {
+        Outcome::Stopped => Outcome::Completed,
+        Outcome::Completed => Outcome::Failed(format!(
+            "0x{:06x} didn't return in {} instructions",
+            addr, limit
+        )),
+        failed => failed,
+    }
+}
+
+// Run an A-line trap until it returns, by putting it and an RTS on the
+// stack and calling them.
+pub fn trap(
+    m: &mut Machine,
+    word: u16,
+    limit: u64,
+    history: &mut History,
+    observe: Observer,
+) -> Outcome {
+    let sp = m.cpu.a[7];
+    m.cpu.a[7] -= 4;
+    let code = m.cpu.a[7];
+    m.mem.poke_long(code, ((word as u32) << 16) | RTS as u32);
+    let outcome = call(m, code, limit, history, observe);
+    m.cpu.a[7] = sp;
+    outcome
+}
+
+// Run the commands, stopping at the first that fails.
+pub fn execute(
+    m: &mut Machine,
+    commands: &[Command],
+    history: &mut History,
+    observe: Observer,
+) -> Outcome {
+    for command in commands.iter() {
+        println!("Session: {}", command);
+        let outcome = match *command {
+            Command::Run(count) => harness::run(m, None, m.instructions + count, history, observe),
+            Command::Until(addr, limit) => {
+                match harness::run(m, Some(addr), m.instructions + limit, history, observe) {
+                    Outcome::Stopped => Outcome::Completed,
+                    Outcome::Completed => Outcome::Failed(format!(
+                        "0x{:06x} not reached in {} instructions",
+                        addr, limit
+                    )),
+                    failed => failed,
+                }
+            }
+            Command::Set(reg, value) => {
+                match reg {
+                    Reg::D(n) => m.cpu.d[n] = value,
+                    Reg::A(n) => m.cpu.a[n] = value,
+                    Reg::Pc => m.cpu.pc = value,
+                    Reg::Sr => m.cpu.set_sr(value as u16),
+                }
+                Outcome::Completed
+            }
+            Command::Poke(addr, ref bytes) => {
+                m.mem.poke_bytes(addr, bytes);
+                Outcome::Completed
+            }
+            Command::Call(addr, limit) => call(m, addr, limit, history, observe),
+            Command::Trap(word, limit) => trap(m, word, limit, history, observe),
+        };
+        if let Outcome::Failed(_) = outcome {
+            return outcome;
+        }
+    }
+    Outcome::Completed
+}
@@ -0,0 +1,67 @@
+//
+// Symbols
+//
+// Names for ROM addresses, taken from the trap table labels that
+// extract_traps generates for Ghidra.
+//
+
+use crate::machine::{ROM_BASE, ROM_SIZE};
+
+const TRAP_NAMES: &str = "../extract_traps/trap_names.txt";
+
+pub struct Symbols {
+    // Sorted by address, one name per address.
+    labels: Vec<(u32, String)>,
+}
+
+impl Symbols {
+    pub fn load(rom: &[u8]) -> anyhow::Result<Symbols> {
+        let traps = extract_traps::read_traps(TRAP_NAMES)?;
+        let mut labels = extract_traps::labels(rom, &traps)
+            .into_iter()
+            .map(|l| (l.addr - extract_traps::ROM_BASE + ROM_BASE, l.name))
+            .collect::<Vec<_>>();
+        labels.sort();
+        labels.dedup_by_key(|(addr, _)| *addr);
+        Ok(Symbols { labels })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &str)> {
+        self.labels
+            .iter()
+            .map(|(addr, name)| (*addr, name.as_str()))
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<u32> {
+        self.labels
+            .iter()
+            .find(|(_, n)| n == name)
+            .map(|(addr, _)| *addr)
+    }
+
+    // The label at exactly the address.
+    pub fn name(&self, addr: u32) -> Option<&str> {
+        let idx = self.labels.binary_search_by_key(&addr, |(a, _)| *a).ok()?;
+        Some(&self.labels[idx].1)
+    }
+
+    // The nearest label at or before the address, and the offset from
+    // it. Only trap entry points are named, so for code that isn't
+    // itself a trap this is just a landmark.
+    pub fn containing(&self, addr: u32) -> Option<(&str, u32)> {
+        if !(ROM_BASE..ROM_BASE + ROM_SIZE).contains(&addr) {
+            return None;
+        }
+        let idx = self.labels.partition_point(|(a, _)| *a <= addr);
+        let (start, name) = self.labels.get(idx.checked_sub(1)?)?;
+        Some((name, addr - start))
+    }
+
+    pub fn describe(&self, addr: u32) -> String {
+        match self.containing(addr) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, offset)) => format!("{}+0x{:x}", name, offset),
+            None => format!("0x{:06x}", addr),
+        }
+    }
+}
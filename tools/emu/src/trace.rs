@@ -0,0 +1,413 @@
+//
+// Hardware access tracing
+//
+// Everything the ROM does to the remapped I/O regions, as seen on the
+// bus. Unlike a static scan, this catches accesses made through base
+// registers, such as the SCSI Manager's use of A4-A6. Each access is
+// put down to the function it was made from, by following the calls
+// as the ROM runs.
+//
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::machine::{IoAccess, Machine, Memory, ROM_BASE, ROM_SIZE};
+use crate::symbols::Symbols;
+
+////////////////////////////////////////////////////////////////////////
+// Calls.
+//
+
+// The functions being executed, as the entry points that were called
+// (or the handlers of the exceptions that were taken), along with the
+// stack pointer just after the return address was pushed. Once the
+// stack pointer goes above that, the function has returned.
+pub struct Calls {
+    frames: Vec<(u32, u32)>,
+    // The function each access in the I/O log was made from, if it
+    // was reached by a call.
+    pub functions: Vec<Option<u32>>,
+}
+
+impl Calls {
+    pub fn new() -> Calls {
+        Calls {
+            frames: Vec::new(),
+            functions: Vec::new(),
+        }
+    }
+
+    // Harness observer.
+    pub fn observe(&mut self, m: &Machine, pc: u32, vector: Option<u8>) {
+        // Any accesses were made by the instruction just executed,
+        // inside the function that was running before it.
+        let logged = m.mem.io_log.as_ref().map_or(0, |log| log.len());
+        let current = self.frames.last().map(|(entry, _)| *entry);
+        self.functions.resize(logged, current);
+
+        let sp = m.cpu.a[7];
+        while self.frames.last().is_some_and(|(_, frame)| *frame < sp) {
+            self.frames.pop();
+        }
+        let opcode = m.mem.peek_word(pc);
+        let call = opcode & 0xff00 == 0x6100 || opcode & 0xffc0 == 0x4e80;
+        if vector.is_some() || call {
+            self.frames.push((m.cpu.pc, sp));
+        }
+    }
+}
+
+// The name of the function an access was made from: a trap's name, or
+// Ghidra's default name for a function at the entry point. Code that
+// wasn't reached by a call, like the startup code, which returns
+// through A6 rather than the stack, is put down to the nearest trap
+// before it.
+fn function_name(symbols: &Symbols, function: Option<u32>, pc: u32) -> String {
+    match function {
+        Some(entry) => match symbols.name(entry) {
+            Some(name) => name.to_string(),
+            None => format!("FUN_{:08x}", entry),
+        },
+        None => match symbols.containing(pc) {
+            Some((name, _)) => name.to_string(),
+            None if (ROM_BASE..ROM_BASE + ROM_SIZE).contains(&pc) => "(ROM)".to_string(),
+            None => "(RAM)".to_string(),
+        },
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// Register names.
+//
+// The names from Apple's equate files, where there are any.
+//
+
+// Registers every 0x200 bytes.
+const VIA_REGISTERS: [&str; 16] = [
+    "vBufB", "vBufAH", "vDirB", "vDirA", "vT1C", "vT1CH", "vT1L", "vT1LH", "vT2C", "vT2CH", "vSR",
+    "vACR", "vPCR", "vIFR", "vIER", "vBufA",
+];
+
+// Registers every 0x200 bytes.
+const IWM_REGISTERS: [&str; 16] = [
+    "ph0L", "ph0H", "ph1L", "ph1H", "ph2L", "ph2H", "ph3L", "ph3H", "mtrOff", "mtrOn", "intDrive",
+    "extDrive", "q6L", "q6H", "q7L", "q7H",
+];
+
+// NCR 5380 registers every 0x10 bytes, which are different for reads
+// and writes.
+const SCSI_READ_REGISTERS: [&str; 8] = [
+    "sCDR", "sICR", "sMR", "sTCR", "sCSR", "sBSR", "sIDR", "sRESET",
+];
+const SCSI_WRITE_REGISTERS: [&str; 8] = [
+    "sODR", "sICR", "sMR", "sTCR", "sSER", "sDMAtx", "sTDMArx", "sIDMArx",
+];
+// Accesses with this bit set in the offset also assert DACK, for
+// pseudo-DMA.
+const SCSI_DACK: u32 = 0x200;
+
+// Separate read and write halves, with registers every two bytes.
+const SCC_REGISTERS: [&str; 4] = ["bCtl", "aCtl", "bData", "aData"];
+const SCC_WRITE_HALF: u32 = 0x1000;
+
+// The paravirtual floppy's mailbox.
+fn floppy_register(offset: u32) -> &'static str {
+    match offset {
+        0..=3 => "Block",
+        4..=7 => "Buffer",
+        8 => "Command",
+        9 => "Status",
+        10 => "Drive",
+        _ => "Unused",
+    }
+}
+
+// The paravirtual SCSI mailbox.
+fn scsi_mailbox_register(offset: u32) -> &'static str {
+    match offset {
+        0..=3 => "Buffer",
+        4..=7 => "Length",
+        8 => "Command",
+        9 => "Status",
+        10 => "Target",
+        11 => "ScsiStatus",
+        _ => "Unused",
+    }
+}
+
+// The paravirtual VIA functions.
+fn via_mailbox_register(offset: u32) -> &'static str {
+    match offset {
+        0 => "Flags",
+        1 => "Enable",
+        2 => "PramAddr",
+        3 => "PramData",
+        4..=7 => "Seconds",
+        8 => "AdbCommand",
+        9 => "AdbStatus",
+        10 => "AdbCount",
+        16..=23 => "AdbData",
+        _ => "Unused",
+    }
+}
+
+fn register_name(device: &str, offset: u32, write: bool) -> String {
+    match device {
+        "VIA" => VIA_REGISTERS[((offset >> 9) & 0xf) as usize].to_string(),
+        "IWM" => IWM_REGISTERS[((offset >> 9) & 0xf) as usize].to_string(),
+        "SCSI" => {
+            let names = if write {
+                &SCSI_WRITE_REGISTERS
+            } else {
+                &SCSI_READ_REGISTERS
+            };
+            let name = names[((offset >> 4) & 7) as usize];
+            if offset & SCSI_DACK != 0 {
+                format!("{} (DACK)", name)
+            } else {
+                name.to_string()
+            }
+        }
+        "SCC" => format!(
+            "{}{}",
+            SCC_REGISTERS[((offset >> 1) & 3) as usize],
+            if offset & SCC_WRITE_HALF != 0 {
+                "Wr"
+            } else {
+                "Rd"
+            }
+        ),
+        "FLOPPY" => floppy_register(offset).to_string(),
+        "SCSI_MAILBOX" => scsi_mailbox_register(offset).to_string(),
+        "VIA_MAILBOX" => via_mailbox_register(offset).to_string(),
+        _ => format!("+0x{:x}", offset),
+    }
+}
+
+fn width_name(width: u8) -> &'static str {
+    match width {
+        1 => "byte",
+        2 => "word",
+        _ => "long",
+    }
+}
+
+fn direction(write: bool) -> &'static str {
+    if write {
+        "write"
+    } else {
+        "read"
+    }
+}
+
+// An access, decoded into device and register.
+struct Decoded {
+    device: &'static str,
+    region_start: u32,
+    register: String,
+}
+
+fn decode(mem: &Memory, access: &IoAccess) -> Decoded {
+    let region = mem
+        .region_at(access.addr)
+        .expect("Logged access outside any device");
+    let offset = access.addr - region.start;
+    Decoded {
+        device: region.name,
+        region_start: region.start,
+        register: register_name(region.name, offset, access.write),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// Full log.
+//
+
+pub fn write_log(
+    path: &Path,
+    mem: &Memory,
+    accesses: &[IoAccess],
+    functions: &[Option<u32>],
+    symbols: &Symbols,
+) -> anyhow::Result<()> {
+    let mut log = String::new();
+    for (access, function) in accesses.iter().zip(functions) {
+        let d = decode(mem, access);
+        writeln!(
+            log,
+            "{:06x} {:<24} {:<5} {} {:06x} {:<5} {:<16} {:0width$x}",
+            access.pc,
+            function_name(symbols, *function, access.pc),
+            direction(access.write),
+            width_name(access.width),
+            access.addr,
+            d.device,
+            d.register,
+            access.value,
+            width = 2 * access.width as usize
+        )?;
+    }
+    fs::write(path, log).with_context(|| format!("Couldn't write {}", path.display()))?;
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////
+// Summary by device and register.
+//
+
+#[derive(Default)]
+struct Stats {
+    device: &'static str,
+    reads: usize,
+    writes: usize,
+    widths: BTreeSet<u8>,
+    addrs: BTreeSet<u32>,
+    // With the function each was in.
+    pcs: BTreeSet<(u32, Option<u32>)>,
+}
+
+pub fn print_summary(
+    mem: &Memory,
+    accesses: &[IoAccess],
+    functions: &[Option<u32>],
+    symbols: &Symbols,
+) {
+    let mut stats: BTreeMap<(u32, String), Stats> = BTreeMap::new();
+    for (access, function) in accesses.iter().zip(functions) {
+        let d = decode(mem, access);
+        let s = stats.entry((d.region_start, d.register)).or_default();
+        s.device = d.device;
+        if access.write {
+            s.writes += 1;
+        } else {
+            s.reads += 1;
+        }
+        s.widths.insert(access.width);
+        s.addrs.insert(access.addr);
+        s.pcs.insert((access.pc, *function));
+    }
+
+    // Registers in address order within each device.
+    let mut registers = stats.into_iter().collect::<Vec<_>>();
+    registers.sort_by_key(|((start, _), s)| (*start, *s.addrs.iter().next().unwrap()));
+
+    println!("Hardware accesses by register:");
+    for ((_, register), s) in registers.iter() {
+        println!(
+            "  {} {}: {} reads, {} writes ({}) at {}",
+            s.device,
+            register,
+            s.reads,
+            s.writes,
+            s.widths
+                .iter()
+                .map(|w| width_name(*w))
+                .collect::<Vec<_>>()
+                .join("/"),
+            s.addrs
+                .iter()
+                .map(|a| format!("0x{:06x}", a))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        // Group the PCs by function.
+        let mut functions: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+        for (pc, function) in s.pcs.iter() {
+            let pcs = functions
+                .entry(function_name(symbols, *function, *pc))
+                .or_default();
+            if !pcs.contains(pc) {
+                pcs.push(*pc);
+            }
+        }
+        for (function, pcs) in functions.iter() {
+            println!(
+                "    {}: {}",
+                function,
+                pcs.iter()
+                    .map(|pc| format!("0x{:06x}", pc))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// Ghidra script.
+//
+// Comments and bookmarks every instruction in the ROM seen accessing
+// hardware, and labels the registers. To be run against the patched
+// ROM, after the script "patch" generates has set up the I/O blocks.
+//
+
+const SCRIPT_PRELUDE: &str = r#"# Annotates hardware accesses seen while running the ROM.
+# Generated by the "emu" tool.
+#@category Mac ROM
+
+memory = currentProgram.getMemory()
+listing = currentProgram.getListing()
+
+def annotate(addr, text):
+    addr = toAddr(addr)
+    createBookmark(addr, "Hardware access", text)
+    unit = listing.getCodeUnitContaining(addr)
+    if unit is not None:
+        addr = unit.getMinAddress()
+    old = getEOLComment(addr)
+    if old is None:
+        setEOLComment(addr, text)
+    elif text not in old:
+        setEOLComment(addr, old + "\n" + text)
+
+def io_label(addr, name):
+    addr = toAddr(addr)
+    if memory.getBlock(addr) is None:
+        print("Skipping %s, no memory block" % name)
+        return
+    createLabel(addr, name, True)
+
+"#;
+
+pub fn write_script(path: &Path, mem: &Memory, accesses: &[IoAccess]) -> anyhow::Result<()> {
+    let mut by_pc: BTreeMap<u32, BTreeSet<String>> = BTreeMap::new();
+    let mut labels: BTreeMap<u32, String> = BTreeMap::new();
+    for access in accesses.iter() {
+        let d = decode(mem, access);
+        labels.insert(
+            access.addr,
+            format!("{}_{}", d.device, d.register.replace(' ', "_")),
+        );
+        if (ROM_BASE..ROM_BASE + ROM_SIZE).contains(&access.pc) {
+            by_pc.entry(access.pc).or_default().insert(format!(
+                "{} {} {} {}",
+                d.device,
+                d.register,
+                direction(access.write),
+                width_name(access.width)
+            ));
+        }
+    }
+
+    let mut script = String::from(SCRIPT_PRELUDE);
+    for (addr, name) in labels.iter() {
+        let name = name.replace(['(', ')'], "");
+        writeln!(script, "io_label(0x{:06x}, \"{}\")", addr, name)?;
+    }
+    script.push('\n');
+    for (pc, what) in by_pc.iter() {
+        writeln!(
+            script,
+            "annotate(0x{:06x}, \"Hardware access: {}\")",
+            pc,
+            what.iter().cloned().collect::<Vec<_>>().join(", ")
+        )?;
+    }
+
+    fs::write(path, script).with_context(|| format!("Couldn't write {}", path.display()))?;
+    Ok(())
+}
@@ -0,0 +1,665 @@
+//
+// Paravirtual VIA functions
+//
+// The host side of the peripheral that `patch rom --via-mailbox` moves
+// the VBL and one-second interrupts, the clock and parameter RAM, and
+// ADB to (see patch/src/via.s for the registers), with an ADB keyboard
+// and mouse attached. Also a check that boots the patched ROM against
+// it and drives the clock, the interrupts and ADB.
+//
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, ensure};
+
+use crate::devices::{TICKS_PER_VBL, VBLS_PER_SECOND};
+use crate::harness::{self, History, Outcome};
+use crate::machine::{Device, Machine};
+use crate::session;
+
+pub const MAILBOX_SIZE: u32 = memory_map::VIA_MAILBOX_SIZE;
+
+// Registers.
+const FLAGS: u32 = 0;
+const ENABLE: u32 = 1;
+const PRAM_ADDR: u32 = 2;
+const PRAM_DATA: u32 = 3;
+const SECONDS: u32 = 4;
+const ADB_COMMAND: u32 = 8;
+const ADB_STATUS: u32 = 9;
+const ADB_COUNT: u32 = 10;
+const ADB_DATA: u32 = 16;
+const ADB_DATA_SIZE: usize = 8;
+
+// Interrupt flags.
+const FLAG_SECOND: u8 = 0x01;
+const FLAG_VBL: u8 = 0x02;
+const FLAG_ADB: u8 = 0x04;
+
+// ADB status bits.
+const STS_BUSY: u8 = 0x01;
+const STS_NO_REPLY: u8 = 0x02;
+const STS_SERVICE: u8 = 0x04;
+
+// An ADB transaction at 10kbit/s, with a couple of bytes of data, takes
+// about 3ms, in E clock ticks.
+const ADB_TICKS: u32 = 2350;
+
+// Seconds from 1904, when the Mac's clock starts, to 1970.
+const MAC_EPOCH_OFFSET: u64 = 2082844800;
+
+// Default ADB addresses and handler IDs.
+const KEYBOARD_ADDR: u8 = 2;
+const MOUSE_ADDR: u8 = 3;
+const KEYBOARD_HANDLER: u8 = 1;
+const MOUSE_HANDLER: u8 = 1;
+// Listen R3 with this handler ID just moves the device.
+const CHANGE_ADDRESS: u8 = 0xfe;
+
+////////////////////////////////////////////////////////////////////////
+// ADB devices.
+//
+
+// A standard keyboard. Key transitions queue up to be sent two at a
+// time. Register 2 holds the LEDs, among other things.
+pub struct Keyboard {
+    addr: u8,
+    keys: VecDeque<u8>,
+    pub register2: [u8; 2],
+}
+
+impl Keyboard {
+    fn new() -> Keyboard {
+        Keyboard {
+            addr: KEYBOARD_ADDR,
+            keys: VecDeque::new(),
+            register2: [0xff, 0xff],
+        }
+    }
+
+    fn pending(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    fn talk(&mut self, reg: u8) -> Option<Vec<u8>> {
+        match reg {
+            0 => {
+                let first = self.keys.pop_front()?;
+                let second = self.keys.pop_front().unwrap_or(0xff);
+                Some(vec![first, second])
+            }
+            2 => Some(self.register2.to_vec()),
+            3 => Some(register3(self.addr, KEYBOARD_HANDLER)),
+            _ => None,
+        }
+    }
+
+    fn listen(&mut self, reg: u8, data: &[u8]) {
+        match (reg, data) {
+            (2, [high, low, ..]) => self.register2 = [*high, *low],
+            (3, [addr, CHANGE_ADDRESS, ..]) => self.addr = addr & 0xf,
+            _ => {}
+        }
+    }
+}
+
+// A standard mouse, which has something to say when it's moved or its
+// button changes.
+pub struct Mouse {
+    addr: u8,
+    dx: i32,
+    dy: i32,
+    button: bool,
+    sent_button: bool,
+}
+
+impl Mouse {
+    fn new() -> Mouse {
+        Mouse {
+            addr: MOUSE_ADDR,
+            dx: 0,
+            dy: 0,
+            button: false,
+            sent_button: false,
+        }
+    }
+
+    fn pending(&self) -> bool {
+        self.dx != 0 || self.dy != 0 || self.button != self.sent_button
+    }
+
+    // Each report carries up to 64 counts either way, with the rest
+    // left for the next one.
+    fn take(delta: &mut i32) -> u8 {
+        let sent = (*delta).clamp(-64, 63);
+        *delta -= sent;
+        sent as u8 & 0x7f
+    }
+
+    fn talk(&mut self, reg: u8) -> Option<Vec<u8>> {
+        match reg {
+            0 if self.pending() => {
+                self.sent_button = self.button;
+                let up = if self.button { 0x00 } else { 0x80 };
+                let dy = Mouse::take(&mut self.dy);
+                let dx = Mouse::take(&mut self.dx);
+                Some(vec![up | dy, 0x80 | dx])
+            }
+            3 => Some(register3(self.addr, MOUSE_HANDLER)),
+            _ => None,
+        }
+    }
+
+    fn listen(&mut self, reg: u8, data: &[u8]) {
+        if let (3, [addr, CHANGE_ADDRESS, ..]) = (reg, data) {
+            self.addr = addr & 0xf;
+        }
+    }
+}
+
+// Register 3: the address, with exceptional events and service requests
+// enabled, and the handler ID.
+fn register3(addr: u8, handler: u8) -> Vec<u8> {
+    vec![0x60 | addr, handler]
+}
+
+////////////////////////////////////////////////////////////////////////
+// The peripheral.
+//
+
+pub struct ViaMailbox {
+    flags: u8,
+    enable: u8,
+    vbl_ticks: u32,
+    vbl_count: u32,
+    // Battery-backed, so they survive a reset.
+    pram: [u8; 256],
+    pram_addr: u8,
+    seconds: u32,
+    adb_command: u8,
+    adb_status: u8,
+    adb_count: u8,
+    adb_data: [u8; ADB_DATA_SIZE],
+    adb_ticks: u32,
+    pub keyboard: Keyboard,
+    pub mouse: Mouse,
+}
+
+impl ViaMailbox {
+    pub fn new() -> ViaMailbox {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        ViaMailbox {
+            flags: 0,
+            enable: 0,
+            vbl_ticks: 0,
+            vbl_count: 0,
+            pram: [0; 256],
+            pram_addr: 0,
+            seconds: (now + MAC_EPOCH_OFFSET) as u32,
+            adb_command: 0,
+            adb_status: 0,
+            adb_count: 0,
+            adb_data: [0; ADB_DATA_SIZE],
+            adb_ticks: 0,
+            keyboard: Keyboard::new(),
+            mouse: Mouse::new(),
+        }
+    }
+
+    pub fn seconds(&self) -> u32 {
+        self.seconds
+    }
+
+    pub fn pram(&self) -> &[u8] {
+        &self.pram
+    }
+
+    // Key codes as the keyboard sends them, with bit 7 set for key up.
+    pub fn key(&mut self, code: u8, down: bool) {
+        let code = if down { code & 0x7f } else { code | 0x80 };
+        self.keyboard.keys.push_back(code);
+    }
+
+    pub fn move_mouse(&mut self, dx: i32, dy: i32) {
+        self.mouse.dx += dx;
+        self.mouse.dy += dy;
+    }
+
+    pub fn mouse_button(&mut self, down: bool) {
+        self.mouse.button = down;
+    }
+
+    // Run the command, leaving any reply in the data registers.
+    fn transact(&mut self) {
+        let addr = self.adb_command >> 4;
+        let reg = self.adb_command & 3;
+        let data = &self.adb_data[..(self.adb_count as usize).min(ADB_DATA_SIZE)];
+        let reply = match self.adb_command & 0xf {
+            // SendReset.
+            0 => {
+                self.keyboard = Keyboard::new();
+                self.mouse = Mouse::new();
+                None
+            }
+            // Flush.
+            1 => {
+                if addr == self.keyboard.addr {
+                    self.keyboard.keys.clear();
+                }
+                None
+            }
+            // Listen.
+            0x8..=0xb => {
+                let data = data.to_vec();
+                if addr == self.keyboard.addr {
+                    self.keyboard.listen(reg, &data);
+                } else if addr == self.mouse.addr {
+                    self.mouse.listen(reg, &data);
+                }
+                None
+            }
+            // Talk.
+            0xc..=0xf => {
+                if addr == self.keyboard.addr {
+                    self.keyboard.talk(reg)
+                } else if addr == self.mouse.addr {
+                    self.mouse.talk(reg)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+
+        self.adb_status = 0;
+        match reply {
+            Some(reply) => {
+                self.adb_count = reply.len() as u8;
+                self.adb_data[..reply.len()].copy_from_slice(&reply);
+            }
+            None => {
+                self.adb_count = 0;
+                if self.adb_command & 0xc == 0xc {
+                    self.adb_status |= STS_NO_REPLY;
+                }
+            }
+        }
+        let others_pending = (self.keyboard.pending() && addr != self.keyboard.addr)
+            || (self.mouse.pending() && addr != self.mouse.addr);
+        if others_pending {
+            self.adb_status |= STS_SERVICE;
+        }
+        self.flags |= FLAG_ADB;
+    }
+}
+
+impl Device for ViaMailbox {
+    fn read(&mut self, offset: u32) -> u8 {
+        match offset {
+            FLAGS => {
+                let active = self.flags & self.enable & 0x7f != 0;
+                self.flags | if active { 0x80 } else { 0x00 }
+            }
+            ENABLE => self.enable | 0x80,
+            PRAM_ADDR => self.pram_addr,
+            PRAM_DATA => self.pram[self.pram_addr as usize],
+            4..=7 => self.seconds.to_be_bytes()[(offset - SECONDS) as usize],
+            ADB_COMMAND => self.adb_command,
+            ADB_STATUS => self.adb_status,
+            ADB_COUNT => self.adb_count,
+            16..=23 => self.adb_data[(offset - ADB_DATA) as usize],
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u32, value: u8) {
+        match offset {
+            FLAGS => self.flags &= !value,
+            ENABLE => {
+                if value & 0x80 != 0 {
+                    self.enable |= value & 0x7f;
+                } else {
+                    self.enable &= !value;
+                }
+            }
+            PRAM_ADDR => self.pram_addr = value,
+            PRAM_DATA => self.pram[self.pram_addr as usize] = value,
+            4..=7 => {
+                let shift = 8 * (7 - offset);
+                self.seconds = (self.seconds & !(0xff << shift)) | ((value as u32) << shift);
+            }
+            ADB_COMMAND => {
+                self.adb_command = value;
+                self.adb_status = STS_BUSY;
+                self.adb_ticks = ADB_TICKS;
+            }
+            ADB_COUNT => self.adb_count = value,
+            16..=23 => self.adb_data[(offset - ADB_DATA) as usize] = value,
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self) {
+        self.vbl_ticks += 1;
+        if self.vbl_ticks == TICKS_PER_VBL {
+            self.vbl_ticks = 0;
+            self.flags |= FLAG_VBL;
+            self.vbl_count += 1;
+            if self.vbl_count == VBLS_PER_SECOND {
+                self.vbl_count = 0;
+                self.flags |= FLAG_SECOND;
+                self.seconds = self.seconds.wrapping_add(1);
+            }
+        }
+
+        if self.adb_ticks > 0 {
+            self.adb_ticks -= 1;
+            if self.adb_ticks == 0 {
+                self.transact();
+            }
+        }
+    }
+
+    fn irq_level(&self) -> u8 {
+        if self.flags & self.enable & 0x7f != 0 {
+            1
+        } else {
+            0
+        }
+    }
+
+    // The clock and parameter RAM keep going.
+    fn reset(&mut self) {
+        *self = ViaMailbox {
+            pram: self.pram,
+            seconds: self.seconds,
+            ..ViaMailbox::new()
+        };
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// Checking the patches.
+//
+
+// Low memory.
+const TICKS: u32 = 0x16a;
+const KEY_MAP: u32 = 0x174;
+const MB_STATE: u32 = 0x172;
+const SP_VALID: u32 = 0x1f8;
+const TIME: u32 = 0x20c;
+const MTEMP: u32 = 0x828;
+
+// Traps.
+const SET_DATE_TIME: u16 = 0xa03a;
+const COUNT_ADBS: u16 = 0xa077;
+const ADB_OP: u16 = 0xa07c;
+
+// What InitUtil leaves in parameter RAM when it finds it invalid.
+const PRAM_VALID: u8 = 0xa8;
+const PRAM_VALID_ADDR: usize = 0x10;
+const XPRAM_SIGNATURE: &[u8; 4] = b"Bugs";
+const XPRAM_SIGNATURE_ADDR: usize = 0x0c;
+
+// The space bar.
+const KEY_CODE: u8 = 0x31;
+
+// Instructions allowed per call, and for things to happen.
+const CALL_LIMIT: u64 = 1_000_000;
+const VBLS_TO_WAIT: u64 = 10;
+
+// BRA.S to itself, and supervisor mode with all interrupts enabled,
+// for idling.
+const BRA_SELF: u16 = 0x60fe;
+const IDLE_SR: u16 = 0x2000;
+
+// Room taken off the stack for the parameter block and buffer, and
+// for idling.
+const SCRATCH_SIZE: u32 = 0x100;
+const PB_OFFSET: u32 = 0x00;
+const BUFFER_OFFSET: u32 = 0x10;
+
+struct Checker<'a> {
+    m: &'a mut Machine,
+    via: &'a Rc<RefCell<ViaMailbox>>,
+    history: &'a mut History,
+}
+
+impl Checker<'_> {
+    // Idle with interrupts enabled for the given number of
+    // instructions, in a loop just below the stack, with the stack
+    // moved down out of its way.
+    fn run(&mut self, count: u64) -> anyhow::Result<()> {
+        let (pc, sr, sp) = (self.m.cpu.pc, self.m.cpu.sr, self.m.cpu.a[7]);
+        self.m.mem.poke_word(sp - 2, BRA_SELF);
+        self.m.cpu.pc = sp - 2;
+        self.m.cpu.set_sr(IDLE_SR);
+        self.m.cpu.a[7] = sp - SCRATCH_SIZE;
+        let limit = self.m.instructions + count;
+        let outcome = harness::run(self.m, None, limit, self.history, &mut |_, _, _| {});
+        self.m.cpu.pc = pc;
+        self.m.cpu.set_sr(sr);
+        self.m.cpu.a[7] = sp;
+        match outcome {
+            Outcome::Failed(why) => bail!("{}", why),
+            _ => Ok(()),
+        }
+    }
+
+    // Give the VBL task time to poll the devices.
+    fn wait_vbls(&mut self) -> anyhow::Result<()> {
+        self.run(VBLS_TO_WAIT * TICKS_PER_VBL as u64)
+    }
+
+    fn trap(&mut self, word: u16) -> anyhow::Result<()> {
+        match session::trap(self.m, word, CALL_LIMIT, self.history, &mut |_, _, _| {}) {
+            Outcome::Failed(why) => bail!("{}", why),
+            _ => Ok(()),
+        }
+    }
+
+    fn clock(&mut self) -> anyhow::Result<()> {
+        let time = self.m.mem.peek_long(TIME);
+        let seconds = self.via.borrow().seconds();
+        ensure!(
+            (seconds.wrapping_sub(time) as i32).abs() <= 1,
+            "Time is 0x{:08x}, but the clock is at 0x{:08x}",
+            time,
+            seconds
+        );
+        println!("VIA: Time 0x{:08x} read from the clock", time);
+
+        let pram = self.via.borrow().pram().to_vec();
+        ensure!(
+            pram[PRAM_VALID_ADDR] == PRAM_VALID && self.m.mem.peek(SP_VALID) == PRAM_VALID,
+            "Parameter RAM wasn't validated"
+        );
+        ensure!(
+            pram[XPRAM_SIGNATURE_ADDR..XPRAM_SIGNATURE_ADDR + 4] == *XPRAM_SIGNATURE,
+            "Extended parameter RAM wasn't initialised"
+        );
+        println!("VIA: parameter RAM initialised");
+
+        let new_time = time.wrapping_add(0x12345678);
+        self.m.cpu.d[0] = new_time;
+        self.trap(SET_DATE_TIME)?;
+        let seconds = self.via.borrow().seconds();
+        ensure!(
+            seconds == new_time,
+            "SetDateTime(0x{:08x}) left the clock at 0x{:08x}",
+            new_time,
+            seconds
+        );
+        println!("VIA: SetDateTime set the clock");
+        Ok(())
+    }
+
+    fn ticks(&mut self) -> anyhow::Result<()> {
+        let before = self.m.mem.peek_long(TICKS);
+        self.wait_vbls()?;
+        let ticks = self.m.mem.peek_long(TICKS).wrapping_sub(before);
+        ensure!(
+            ticks.abs_diff(VBLS_TO_WAIT as u32) <= 1,
+            "Ticks went up by {} in {} VBLs",
+            ticks,
+            VBLS_TO_WAIT
+        );
+        println!("VIA: Ticks went up by {} in {} VBLs", ticks, VBLS_TO_WAIT);
+        Ok(())
+    }
+
+    fn devices(&mut self) -> anyhow::Result<()> {
+        self.trap(COUNT_ADBS)?;
+        let count = self.m.cpu.d[0] as u16;
+        ensure!(count == 2, "CountADBs gave {}, not 2", count);
+        println!("VIA: CountADBs found the keyboard and mouse");
+        Ok(())
+    }
+
+    fn mouse(&mut self) -> anyhow::Result<()> {
+        let (dx, dy) = (5, -3);
+        let v = self.m.mem.peek_word(MTEMP) as i16;
+        let h = self.m.mem.peek_word(MTEMP + 2) as i16;
+        self.via.borrow_mut().move_mouse(dx, dy);
+        self.wait_vbls()?;
+        let moved_v = (self.m.mem.peek_word(MTEMP) as i16).wrapping_sub(v);
+        let moved_h = (self.m.mem.peek_word(MTEMP + 2) as i16).wrapping_sub(h);
+        ensure!(
+            (moved_h as i32, moved_v as i32) == (dx, dy),
+            "Moving the mouse by ({}, {}) moved MTemp by ({}, {})",
+            dx,
+            dy,
+            moved_h,
+            moved_v
+        );
+        println!("VIA: mouse moved by ({}, {})", dx, dy);
+
+        for down in [true, false] {
+            self.via.borrow_mut().mouse_button(down);
+            self.wait_vbls()?;
+            let state = self.m.mem.peek(MB_STATE);
+            ensure!(
+                (state & 0x80 == 0) == down,
+                "MBState is 0x{:02x} with the button {}",
+                state,
+                if down { "down" } else { "up" }
+            );
+        }
+        println!("VIA: mouse button went down and up in MBState");
+        Ok(())
+    }
+
+    fn key_down(&self) -> bool {
+        let byte = self.m.mem.peek(KEY_MAP + KEY_CODE as u32 / 8);
+        byte & (1 << (KEY_CODE % 8)) != 0
+    }
+
+    fn keyboard(&mut self) -> anyhow::Result<()> {
+        self.via.borrow_mut().key(KEY_CODE, true);
+        self.wait_vbls()?;
+        ensure!(
+            self.key_down(),
+            "Key 0x{:02x} isn't down in KeyMap",
+            KEY_CODE
+        );
+        self.via.borrow_mut().key(KEY_CODE, false);
+        self.wait_vbls()?;
+        ensure!(
+            !self.key_down(),
+            "Key 0x{:02x} is still down in KeyMap",
+            KEY_CODE
+        );
+        println!("VIA: key 0x{:02x} went down and up in KeyMap", KEY_CODE);
+        Ok(())
+    }
+
+    // Call _ADBOp with the buffer, and wait for it to complete.
+    fn adb_op(&mut self, command: u8, buffer: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let scratch = self.m.cpu.a[7] - SCRATCH_SIZE;
+        let (pb, buf) = (scratch + PB_OFFSET, scratch + BUFFER_OFFSET);
+        self.m.mem.poke_bytes(buf, buffer);
+        self.m.mem.poke_long(pb, buf);
+        self.m.mem.poke_long(pb + 4, 0);
+        self.m.mem.poke_long(pb + 8, 0);
+        self.m.cpu.a[7] = scratch;
+        self.m.cpu.a[0] = pb;
+        self.m.cpu.d[0] = command as u32;
+        let result = self.trap(ADB_OP).and_then(|_| {
+            ensure!(
+                self.m.cpu.d[0] == 0,
+                "_ADBOp gave {}",
+                self.m.cpu.d[0] as i32
+            );
+            self.wait_vbls()
+        });
+        self.m.cpu.a[7] = scratch + SCRATCH_SIZE;
+        result?;
+        Ok(self.m.mem.peek_bytes(buf, 1 + ADB_DATA_SIZE))
+    }
+
+    fn calls(&mut self) -> anyhow::Result<()> {
+        let talk = (MOUSE_ADDR << 4) | 0xf;
+        let reply = self.adb_op(talk, &[0; 1 + ADB_DATA_SIZE])?;
+        ensure!(
+            reply[..3] == [2, 0x60 | MOUSE_ADDR, MOUSE_HANDLER],
+            "Talk R3 to the mouse gave {:02x?}",
+            &reply[..3]
+        );
+        println!("VIA: Talk R3 to the mouse gave {:02x?}", &reply[..3]);
+
+        let leds = [0xff, 0xfa];
+        let listen = (KEYBOARD_ADDR << 4) | 0xa;
+        self.adb_op(listen, &[2, leds[0], leds[1]])?;
+        let register2 = self.via.borrow().keyboard.register2;
+        ensure!(
+            register2 == leds,
+            "Listen R2 to the keyboard left {:02x?}",
+            register2
+        );
+        println!("VIA: Listen R2 to the keyboard set {:02x?}", register2);
+        Ok(())
+    }
+}
+
+// Boot through the ADB initialisation, which the patched ROM can do,
+// then check everything the peripheral took over.
+pub fn check(
+    m: &mut Machine,
+    via: &Rc<RefCell<ViaMailbox>>,
+    limit: u64,
+    history: &mut History,
+) -> Outcome {
+    match harness::run(
+        m,
+        Some(harness::INIT_ADB_RETURN),
+        limit,
+        history,
+        &mut |_, _, _| {},
+    ) {
+        Outcome::Stopped => {}
+        Outcome::Completed => {
+            return Outcome::Failed("ADB initialisation didn't finish".to_string());
+        }
+        failed => return failed,
+    }
+    println!("VIA: ADB initialised after {} instructions", m.instructions);
+
+    let mut checker = Checker { m, via, history };
+    let result = (|| {
+        checker.clock()?;
+        checker.ticks()?;
+        checker.devices()?;
+        checker.mouse()?;
+        checker.keyboard()?;
+        checker.calls()
+    })();
+    match result {
+        Ok(()) => Outcome::Completed,
+        Err(e) => Outcome::Failed(e.to_string()),
+    }
+}
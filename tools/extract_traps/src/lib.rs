@@ -0,0 +1,288 @@
+//
+// Trap extractor
+//
+// Decodes the trap table in the ROM, and names the entries. Shared
+// with the other tools, which use the trap names as symbols.
+//
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::Path;
+
+// Offset from start of ROM where the offset for the table is.
+pub const TABLE_OFFSET: usize = 0x22;
+
+// Base address of the ROM.
+pub const ROM_BASE: u32 = memory_map::ROM_MOVED_FROM;
+
+// Address of "unimplemented" function.
+pub const UNIMPL: u32 = ROM_BASE + 0x768;
+
+fn read_long(mem: &[u8], addr: usize) -> u32 {
+    ((mem[addr] as u32) << 24)
+        | ((mem[addr + 1] as u32) << 16)
+        | ((mem[addr + 2] as u32) << 8)
+        | (mem[addr + 3] as u32)
+}
+
+pub fn get_table_start(mem: &[u8]) -> usize {
+    read_long(mem, TABLE_OFFSET) as usize
+}
+
+#[derive(Debug)]
+pub struct Decoder<'a> {
+    mem: &'a [u8],
+    table: usize,
+    pointer: u32,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(mem: &'a [u8]) -> Decoder<'a> {
+        Decoder {
+            mem,
+            table: get_table_start(mem),
+            pointer: ROM_BASE,
+        }
+    }
+}
+
+impl<'a> Iterator for Decoder<'a> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let b = self.mem[self.table];
+
+        if b == 0x80 {
+            self.table += 1;
+            return Some(UNIMPL);
+        }
+
+        if b == 0xff {
+            self.pointer = read_long(self.mem, self.table + 1) + ROM_BASE;
+            self.table += 5;
+            return Some(self.pointer);
+        }
+
+        let offset;
+        if b & 0x80 != 0x00 {
+            offset = (b & 0x7f) as u32;
+            self.table += 1;
+        } else {
+            offset = ((b as u32) << 8) | (self.mem[self.table + 1] as u32);
+            self.table += 2;
+
+            if offset == 0 {
+                return None;
+            }
+        }
+
+        self.pointer += offset * 2;
+        // DIY signed arithmetic as I'm lazy.
+        if offset & 0x4000 != 0x0000 {
+            self.pointer -= 0x10000;
+        }
+        Some(self.pointer)
+    }
+}
+
+// Offset of the end of the compressed trap table.
+pub fn table_end(mem: &[u8]) -> usize {
+    let mut d = Decoder::new(mem);
+    d.by_ref().for_each(drop);
+    d.table
+}
+
+// Compress a trap table, the inverse of Decoder. Each entry is the
+// shortest form that decodes to the address: UNIMPL, a single byte
+// for a short step forward, a word for a step either way, or else the
+// absolute address. The 0x80 and 0xff bytes and the zero word are
+// taken, so steps of 0 and 0x7f words need the longer forms.
+pub fn encode(addrs: &[u32]) -> Vec<u8> {
+    let mut table = Vec::new();
+    let mut pointer = ROM_BASE;
+    for addr in addrs.iter() {
+        if *addr == UNIMPL {
+            table.push(0x80);
+            continue;
+        }
+
+        let step = addr.wrapping_sub(pointer) as i32;
+        let words = step / 2;
+        if step % 2 == 0 && (1..0x7f).contains(&words) {
+            table.push(0x80 | words as u8);
+        } else if step % 2 == 0 && words != 0 && (-0x4000..0x4000).contains(&words) {
+            table.extend_from_slice(&((words as u16) & 0x7fff).to_be_bytes());
+        } else {
+            table.push(0xff);
+            table.extend_from_slice(&(addr - ROM_BASE).to_be_bytes());
+        }
+        pointer = *addr;
+    }
+    table.extend_from_slice(&[0x00, 0x00]);
+    table
+}
+
+fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<fs::File>>>
+where
+    P: AsRef<Path>,
+{
+    let file = fs::File::open(filename)?;
+    Ok(io::BufReader::new(file).lines())
+}
+
+pub fn trap_to_idx(trap_num: u32) -> usize {
+    (if trap_num & 0x0800 != 0 {
+        // Toolbox
+        trap_num & 0x1ff
+    } else {
+        // OS
+        (trap_num & 0xff) + 0x200
+    }) as usize
+}
+
+pub fn idx_to_trap(idx: usize) -> u32 {
+    (if idx >= 0x200 {
+        0xa000 + idx - 0x200
+    } else {
+        0xa800 + idx
+    }) as u32
+}
+
+pub fn read_traps<P>(filename: P) -> anyhow::Result<HashMap<usize, String>>
+where
+    P: AsRef<Path>,
+{
+    let lines = read_lines(filename)?;
+    let mut map = HashMap::new();
+    for line in lines {
+        let line_unwrapped = line?;
+        let mut bits = line_unwrapped.split(',');
+        let addr_str = bits.next().unwrap();
+        let fn_str = bits.next().unwrap();
+        assert_eq!(bits.next(), None);
+        if let Some(existing) = map.insert(
+            trap_to_idx(u32::from_str_radix(addr_str, 16)?),
+            fn_str.to_string(),
+        ) {
+            // "A12F,_PPostEvent" was taken out of "trap_names.txt" as
+            // it triggered this, and it's clearly the same trap under
+            // a different name (vs. "_PostEvent"), with different
+            // return conventions.
+            //
+            // Ditto "A87D,_CloseCPort" with ClosePort.
+            //
+            // "FrameRoundRect" should be A8B0, the Almanac is
+            // incorrect; this check found something real!
+            //
+            // Synonyms "A9EB,_FP68K" and "A9EC,_Elems68K" also
+            // removed.
+            panic!(
+                "Multiple entries for 0x{}: {} vs {}",
+                addr_str, fn_str, existing
+            );
+        }
+    }
+    Ok(map)
+}
+
+// A named trap implementation. The address is based at ROM_BASE.
+#[derive(Clone, Debug)]
+pub struct Label {
+    pub addr: u32,
+    pub name: String,
+}
+
+// Name every implemented trap in the ROM's trap table. Unnamed traps
+// get a name from their trap number.
+pub fn labels(rom: &[u8], traps: &HashMap<usize, String>) -> Vec<Label> {
+    let mut labels = Vec::new();
+
+    for (idx, addr) in Decoder::new(rom).enumerate() {
+        let opt_name = traps.get(&idx);
+
+        if addr == UNIMPL && opt_name.is_none() {
+            // No name found and unimplemented function?
+            // No label needed!
+            continue;
+        }
+
+        let name = if let Some(name) = opt_name {
+            name.clone()
+        } else {
+            format!("_Unk_{:04X}", idx_to_trap(idx))
+        };
+
+        labels.push(Label { addr, name });
+    }
+
+    labels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Decode a table on its own, with the pointer to it where the ROM
+    // keeps it.
+    fn decode(table: &[u8]) -> Vec<u32> {
+        let start = 0x100;
+        let mut mem = vec![0; start + table.len()];
+        mem[TABLE_OFFSET..TABLE_OFFSET + 4].copy_from_slice(&(start as u32).to_be_bytes());
+        mem[start..].copy_from_slice(table);
+        Decoder::new(&mem).collect()
+    }
+
+    #[test]
+    fn round_trips_rom_table() {
+        let rom = fs::read("../../ROM.sefdhd").unwrap();
+        let addrs = Decoder::new(&rom).collect::<Vec<_>>();
+        let table = encode(&addrs);
+        assert_eq!(decode(&table), addrs);
+        // Each entry is as short as it can be, so it's no longer than
+        // Apple's.
+        assert!(table.len() <= table_end(&rom) - get_table_start(&rom));
+    }
+
+    #[test]
+    fn encodes_each_form() {
+        let base = ROM_BASE;
+        // Address, and how it's encoded after the one before.
+        let entries: [(u32, &[u8]); 12] = [
+            (UNIMPL, &[0x80]),
+            // Short steps forward, of 1 to 0x7e words.
+            (base + 2, &[0x81]),
+            (base + 2 + 0xfc, &[0xfe]),
+            // 0x7f words would be 0xff, so it's a word step.
+            (base + 2 + 0xfc + 0xfe, &[0x00, 0x7f]),
+            // No step at all would be the end of the table.
+            (base + 2 + 0xfc + 0xfe, &[0xff, 0x00, 0x00, 0x01, 0xfc]),
+            // Word steps back, and as far as 15 bits go.
+            (base + 0x1fa, &[0x7f, 0xff]),
+            (base + 0x1fa + 0x7ffe, &[0x3f, 0xff]),
+            (base + 0x1fa, &[0x40, 0x01]),
+            // Too far for a word step.
+            (base + 0x1fa + 0x8000, &[0xff, 0x00, 0x00, 0x81, 0xfa]),
+            // UNIMPL doesn't move the pointer.
+            (UNIMPL, &[0x80]),
+            (base + 0x1fc + 0x8000, &[0x81]),
+            // Odd addresses can only be given absolutely.
+            (base + 0x3ffff, &[0xff, 0x00, 0x03, 0xff, 0xff]),
+        ];
+        let addrs = entries.iter().map(|(addr, _)| *addr).collect::<Vec<_>>();
+        let mut expected = entries
+            .iter()
+            .flat_map(|(_, bytes)| bytes.iter().copied())
+            .collect::<Vec<_>>();
+        expected.extend_from_slice(&[0x00, 0x00]);
+        let table = encode(&addrs);
+        assert_eq!(table, expected);
+        assert_eq!(decode(&table), addrs);
+    }
+
+    #[test]
+    fn encodes_empty_table() {
+        assert_eq!(encode(&[]), [0x00, 0x00]);
+        assert!(decode(&encode(&[])).is_empty());
+    }
+}
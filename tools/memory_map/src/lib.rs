@@ -0,0 +1,425 @@
+//
+// Memory map
+//
+// Reads the remapped memory map from memory_map/memory_map.txt, and
+// generates the address decoder, C header and Rust constants from it.
+// The generated Rust constants are included here, for the tools to
+// use where they need constants.
+//
+
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::sync::OnceLock;
+
+use anyhow::{bail, ensure, Context};
+
+#[path = "../../../memory_map/memory_map.rs"]
+mod generated;
+
+pub use generated::*;
+
+// The definition, as built in.
+pub const DEFINITION: &str = include_str!("../../../memory_map/memory_map.txt");
+
+// What the Rust constants built in were generated as, to check they're
+// up to date.
+const GENERATED_RUST: &str = include_str!("../../../memory_map/memory_map.rs");
+
+// Top of the 24-bit address space.
+pub const ADDRESS_SPACE_END: u32 = 0x1000000;
+
+const HEADER: &str = "Generated from memory_map.txt by `patch memory-map`. Don't edit.";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Ram,
+    Rom,
+    // The SE's hardware, moved.
+    Io,
+    // A paravirtual device, if patched in.
+    Mailbox,
+}
+
+impl Kind {
+    fn parse(s: &str) -> anyhow::Result<Kind> {
+        Ok(match s {
+            "ram" => Kind::Ram,
+            "rom" => Kind::Rom,
+            "io" => Kind::Io,
+            "mailbox" => Kind::Mailbox,
+            _ => bail!("Unknown kind '{}'", s),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Region {
+    pub name: String,
+    pub start: u32,
+    // Exclusive.
+    pub end: u32,
+    // Where the region was on an unmodified SE.
+    pub moved_from: Option<u32>,
+    pub kind: Kind,
+    pub description: String,
+}
+
+impl Region {
+    pub fn size(&self) -> u32 {
+        self.end - self.start
+    }
+
+    fn select(&self) -> String {
+        format!("{}_sel", self.name.to_lowercase())
+    }
+}
+
+pub struct MemoryMap {
+    pub regions: Vec<Region>,
+}
+
+fn parse_hex(s: &str) -> anyhow::Result<u32> {
+    let digits = s
+        .strip_prefix("0x")
+        .with_context(|| format!("'{}' isn't a hex address", s))?;
+    u32::from_str_radix(digits, 16).with_context(|| format!("Bad address '{}'", s))
+}
+
+fn parse_region(line: &str) -> anyhow::Result<Region> {
+    let mut fields = line.split_whitespace();
+    let mut field = |what| fields.next().with_context(|| format!("No {}", what));
+    let name = field("name")?.to_string();
+    let start = parse_hex(field("start")?)?;
+    let end = parse_hex(field("end")?)?;
+    let moved_from = match field("moved from")? {
+        "-" => None,
+        s => Some(parse_hex(s)?),
+    };
+    let kind = Kind::parse(field("kind")?)?;
+    let description = fields.collect::<Vec<_>>().join(" ");
+
+    ensure!(
+        name.chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_'),
+        "Name '{}' must be upper case, digits and underscores",
+        name
+    );
+    ensure!(
+        start < end && end <= ADDRESS_SPACE_END,
+        "{} must have its start below its end, within 24 bits",
+        name
+    );
+    ensure!(
+        start % 2 == 0 && end % 2 == 0,
+        "{} must start and end on a word boundary",
+        name
+    );
+    ensure!(
+        moved_from.is_some() == matches!(kind, Kind::Rom | Kind::Io),
+        "{} must say where it moved from if and only if it's ROM or I/O",
+        name
+    );
+    if let Some(from) = moved_from {
+        ensure!(
+            from + (end - start) <= ADDRESS_SPACE_END,
+            "{} was moved from beyond 24 bits",
+            name
+        );
+    }
+    ensure!(!description.is_empty(), "{} has no description", name);
+    Ok(Region {
+        name,
+        start,
+        end,
+        moved_from,
+        kind,
+        description,
+    })
+}
+
+impl MemoryMap {
+    pub fn parse(text: &str) -> anyhow::Result<MemoryMap> {
+        let mut regions: Vec<Region> = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let region = parse_region(line).with_context(|| format!("Line {}", i + 1))?;
+            if let Some(last) = regions.last() {
+                ensure!(
+                    last.end <= region.start,
+                    "Line {}: {} must come after {}, without overlapping",
+                    i + 1,
+                    region.name,
+                    last.name
+                );
+            }
+            regions.push(region);
+        }
+        let mut names = HashSet::new();
+        for region in regions.iter() {
+            ensure!(
+                names.insert(region.name.as_str()),
+                "{} is defined twice",
+                region.name
+            );
+        }
+        for name in ["RAM", "ROM"] {
+            ensure!(names.contains(name), "There's no {} region", name);
+        }
+        Ok(MemoryMap { regions })
+    }
+
+    // The built-in map, which must match the built-in Rust constants.
+    pub fn standard() -> &'static MemoryMap {
+        static MAP: OnceLock<MemoryMap> = OnceLock::new();
+        MAP.get_or_init(|| {
+            let map = MemoryMap::parse(DEFINITION).expect("Bad memory_map.txt");
+            assert!(
+                map.rust() == GENERATED_RUST,
+                "memory_map.rs is out of date: run 'patch memory-map'"
+            );
+            map
+        })
+    }
+
+    pub fn region(&self, name: &str) -> Option<&Region> {
+        self.regions.iter().find(|r| r.name == name)
+    }
+
+    pub fn of_kind(&self, kind: Kind) -> impl Iterator<Item = &Region> {
+        self.regions.iter().filter(move |r| r.kind == kind)
+    }
+
+    // Where an address on an unmodified SE has been moved to, if it's
+    // in a region that's been moved.
+    pub fn moved(&self, addr: u32) -> Option<u32> {
+        self.regions.iter().find_map(|r| {
+            let from = r.moved_from?;
+            (from..from + r.size())
+                .contains(&addr)
+                .then(|| r.start + (addr - from))
+        })
+    }
+
+    fn aligned(&self, lines: &[(String, String)]) -> String {
+        let width = lines.iter().map(|(l, _)| l.len()).max().unwrap_or(0);
+        lines
+            .iter()
+            .map(|(l, r)| format!("{:<width$}{}\n", l, r, width = width))
+            .collect()
+    }
+
+    // The comparisons for a region's select, on the 24-bit address 'a',
+    // leaving out those that are always true.
+    fn bounds(
+        &self,
+        region: &Region,
+        ge: impl Fn(u32) -> String,
+        lt: impl Fn(u32) -> String,
+    ) -> String {
+        let mut terms = Vec::new();
+        if region.start > 0 {
+            terms.push(ge(region.start));
+        }
+        if region.end < ADDRESS_SPACE_END {
+            terms.push(lt(region.end));
+        }
+        terms.join(" and ")
+    }
+
+    pub fn verilog(&self) -> String {
+        let mut s = String::new();
+        writeln!(s, "// {}", HEADER).unwrap();
+        writeln!(s, "//").unwrap();
+        writeln!(
+            s,
+            "// Address decoder for the remapped memory map. Each select is"
+        )
+        .unwrap();
+        writeln!(s, "// high while the 68000's address is in its region.").unwrap();
+        writeln!(s).unwrap();
+        writeln!(s, "module address_decoder (").unwrap();
+        writeln!(s, "    input  wire [23:1] addr,").unwrap();
+        let ports = self
+            .regions
+            .iter()
+            .map(|r| format!("    output wire        {}", r.select()))
+            .collect::<Vec<_>>();
+        writeln!(s, "{}", ports.join(",\n")).unwrap();
+        writeln!(s, ");").unwrap();
+        writeln!(s).unwrap();
+        writeln!(s, "    wire [23:0] a = {{addr, 1'b0}};").unwrap();
+        for region in self.regions.iter() {
+            let bounds = self.bounds(
+                region,
+                |x| format!("a >= 24'h{:06x}", x),
+                |x| format!("a < 24'h{:06x}", x),
+            );
+            writeln!(s).unwrap();
+            writeln!(s, "    // {}", region.description).unwrap();
+            writeln!(
+                s,
+                "    assign {} = {};",
+                region.select(),
+                bounds.replace(" and ", " && ")
+            )
+            .unwrap();
+        }
+        writeln!(s).unwrap();
+        writeln!(s, "endmodule").unwrap();
+        s
+    }
+
+    pub fn vhdl(&self) -> String {
+        let mut s = String::new();
+        writeln!(s, "-- {}", HEADER).unwrap();
+        writeln!(s, "--").unwrap();
+        writeln!(
+            s,
+            "-- Address decoder for the remapped memory map. Each select is"
+        )
+        .unwrap();
+        writeln!(s, "-- high while the 68000's address is in its region.").unwrap();
+        writeln!(s).unwrap();
+        writeln!(s, "library ieee;").unwrap();
+        writeln!(s, "use ieee.std_logic_1164.all;").unwrap();
+        writeln!(s, "use ieee.numeric_std.all;").unwrap();
+        writeln!(s).unwrap();
+        writeln!(s, "entity address_decoder is").unwrap();
+        writeln!(s, "    port (").unwrap();
+        let mut ports = vec![(
+            "addr".to_string(),
+            ": in  unsigned(23 downto 1)".to_string(),
+        )];
+        ports.extend(
+            self.regions
+                .iter()
+                .map(|r| (r.select(), ": out std_logic".to_string())),
+        );
+        let width = ports.iter().map(|(p, _)| p.len()).max().unwrap_or(0) + 1;
+        let ports = ports
+            .iter()
+            .map(|(p, t)| format!("        {:<width$}{}", p, t, width = width))
+            .collect::<Vec<_>>();
+        writeln!(s, "{}", ports.join(";\n")).unwrap();
+        writeln!(s, "    );").unwrap();
+        writeln!(s, "end entity address_decoder;").unwrap();
+        writeln!(s).unwrap();
+        writeln!(s, "architecture rtl of address_decoder is").unwrap();
+        writeln!(s, "    signal a : unsigned(23 downto 0);").unwrap();
+        writeln!(s, "begin").unwrap();
+        writeln!(s, "    a <= addr & '0';").unwrap();
+        for region in self.regions.iter() {
+            let bounds = self.bounds(
+                region,
+                |x| format!("a >= x\"{:06x}\"", x),
+                |x| format!("a < x\"{:06x}\"", x),
+            );
+            writeln!(s).unwrap();
+            writeln!(s, "    -- {}", region.description).unwrap();
+            writeln!(
+                s,
+                "    {} <= '1' when {} else '0';",
+                region.select(),
+                bounds
+            )
+            .unwrap();
+        }
+        writeln!(s, "end architecture rtl;").unwrap();
+        s
+    }
+
+    // Start, end, size and where it was moved from, for each region.
+    fn constants(&self, region: &Region) -> Vec<(String, u32)> {
+        let mut constants = vec![
+            (format!("{}_START", region.name), region.start),
+            (format!("{}_END", region.name), region.end),
+            (format!("{}_SIZE", region.name), region.size()),
+        ];
+        if let Some(from) = region.moved_from {
+            constants.push((format!("{}_MOVED_FROM", region.name), from));
+        }
+        constants
+    }
+
+    pub fn c_header(&self) -> String {
+        let mut s = String::new();
+        writeln!(s, "/* {} */", HEADER).unwrap();
+        writeln!(s).unwrap();
+        writeln!(s, "#ifndef MEMORY_MAP_H").unwrap();
+        writeln!(s, "#define MEMORY_MAP_H").unwrap();
+        for region in self.regions.iter() {
+            writeln!(s).unwrap();
+            writeln!(s, "/* {} */", region.description).unwrap();
+            let lines = self
+                .constants(region)
+                .into_iter()
+                .map(|(name, value)| (format!("#define {} ", name), format!("0x{:06x}UL", value)))
+                .collect::<Vec<_>>();
+            s += &self.aligned(&lines);
+        }
+        writeln!(s).unwrap();
+        writeln!(s, "#endif /* MEMORY_MAP_H */").unwrap();
+        s
+    }
+
+    fn rust_table(&self, s: &mut String, name: &str, comment: &str, kind: Kind) {
+        let regions = self.of_kind(kind).collect::<Vec<_>>();
+        writeln!(s).unwrap();
+        writeln!(s, "// {}: name, start and end.", comment).unwrap();
+        writeln!(
+            s,
+            "pub const {}: [(&str, u32, u32); {}] = [",
+            name,
+            regions.len()
+        )
+        .unwrap();
+        for region in regions {
+            writeln!(
+                s,
+                "    (\"{}\", {}_START, {}_END),",
+                region.name, region.name, region.name
+            )
+            .unwrap();
+        }
+        writeln!(s, "];").unwrap();
+    }
+
+    pub fn rust(&self) -> String {
+        let mut s = String::new();
+        writeln!(s, "// {}", HEADER).unwrap();
+        for region in self.regions.iter() {
+            writeln!(s).unwrap();
+            writeln!(s, "// {}", region.description).unwrap();
+            let constants = self.constants(region);
+            for (name, value) in constants.iter() {
+                writeln!(s, "pub const {}: u32 = 0x{:06x};", name, value).unwrap();
+            }
+            // The addresses again as strings, for command-line
+            // defaults.
+            for (name, value) in constants[..2].iter() {
+                writeln!(s, "pub const {}_HEX: &str = \"0x{:06x}\";", name, value).unwrap();
+            }
+        }
+        self.rust_table(&mut s, "IO_REGIONS", "The SE's hardware, moved", Kind::Io);
+        self.rust_table(
+            &mut s,
+            "MAILBOXES",
+            "The paravirtual devices' mailboxes, if patched in",
+            Kind::Mailbox,
+        );
+        s
+    }
+
+    // The generated files, by name.
+    pub fn generated(&self) -> [(&'static str, String); 4] {
+        [
+            ("address_decoder.v", self.verilog()),
+            ("address_decoder.vhd", self.vhdl()),
+            ("memory_map.h", self.c_header()),
+            ("memory_map.rs", self.rust()),
+        ]
+    }
+}
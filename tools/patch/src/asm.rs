@@ -0,0 +1,1453 @@
+//
+// 68000 assembler
+//
+// A Motorola-syntax subset, enough for the new code that goes into the
+// patched ROM. Each snippet is assembled in two passes, the first to
+// find the labels and the second to generate the code. Sizes come from
+// the source alone, with no branch or address optimisation, so code
+// is the same size wherever it goes: branches are word-sized unless
+// given ".s", and absolute addresses are long unless written as
+// "(addr).w".
+//
+// Labels end with a colon, comments start with ';' (or '*' at the
+// start of a line), and names that the snippet doesn't define are
+// looked up in the externals: the ROM symbols and the code cave. A
+// trap name as an instruction, like "_GetResource", assembles to the
+// trap word, as in the Mac's own assembler.
+//
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, ensure};
+
+// What the assembler knows about the world outside the snippet.
+#[derive(Default)]
+pub struct Externals {
+    // Addresses, by name.
+    pub symbols: HashMap<String, u32>,
+    // Trap words, by trap name.
+    pub traps: HashMap<String, u16>,
+}
+
+////////////////////////////////////////////////////////////////////////
+// Expressions.
+//
+// Sums and differences of numbers ($hex, 0xhex, %binary, decimal or
+// 'ABCD' character constants), names and '*' for the current address.
+//
+
+#[derive(Clone, Debug)]
+enum Term {
+    Number(i64),
+    Name(String),
+    Here,
+}
+
+#[derive(Clone, Debug)]
+struct Expr(Vec<(bool, Term)>);
+
+impl Expr {
+    fn zero() -> Expr {
+        Expr(vec![(false, Term::Number(0))])
+    }
+}
+
+fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '.'
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+fn parse_number(s: &str) -> anyhow::Result<i64> {
+    let (digits, radix) = if let Some(hex) = s.strip_prefix('$') {
+        (hex, 16)
+    } else if let Some(hex) = s.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(bin) = s.strip_prefix('%') {
+        (bin, 2)
+    } else {
+        (s, 10)
+    };
+    i64::from_str_radix(digits, radix).map_err(|_| anyhow!("Bad number '{}'", s))
+}
+
+fn parse_expr(s: &str) -> anyhow::Result<Expr> {
+    let chars = s.trim().chars().collect::<Vec<_>>();
+    let mut terms = Vec::new();
+    let mut i = 0;
+    let mut negate = false;
+    let mut want_term = true;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if !want_term && (c == '+' || c == '-') {
+            negate = c == '-';
+            want_term = true;
+            i += 1;
+        } else if want_term && (c == '+' || c == '-') && terms.is_empty() {
+            negate = c == '-';
+            i += 1;
+        } else if want_term && c == '*' {
+            terms.push((negate, Term::Here));
+            want_term = false;
+            i += 1;
+        } else if want_term && c == '\'' {
+            let end = chars[i + 1..]
+                .iter()
+                .position(|c| *c == '\'')
+                .ok_or_else(|| anyhow!("Unterminated character constant in '{}'", s))?;
+            let text = &chars[i + 1..i + 1 + end];
+            ensure!(
+                !text.is_empty() && text.len() <= 4,
+                "Character constants are 1 to 4 characters: '{}'",
+                s
+            );
+            let value = text
+                .iter()
+                .fold(0i64, |acc, c| (acc << 8) | (*c as u8) as i64);
+            terms.push((negate, Term::Number(value)));
+            want_term = false;
+            i += end + 2;
+        } else if want_term {
+            let start = i;
+            if c == '$' || c == '%' {
+                i += 1;
+            }
+            while i < chars.len() && is_name_char(chars[i]) {
+                i += 1;
+            }
+            let token = chars[start..i].iter().collect::<String>();
+            ensure!(!token.is_empty(), "Bad expression '{}'", s);
+            let term = if is_name_start(c) {
+                Term::Name(token)
+            } else {
+                Term::Number(parse_number(&token)?)
+            };
+            terms.push((negate, term));
+            want_term = false;
+        } else {
+            bail!("Bad expression '{}'", s);
+        }
+    }
+    ensure!(!want_term, "Bad expression '{}'", s);
+    Ok(Expr(terms))
+}
+
+////////////////////////////////////////////////////////////////////////
+// Operands.
+//
+
+// An index register: 0-7 for data registers, 8-15 for address
+// registers.
+#[derive(Clone, Copy, Debug)]
+struct IndexReg {
+    reg: u16,
+    long: bool,
+}
+
+#[derive(Clone, Debug)]
+enum Operand {
+    DataReg(u16),
+    AddrReg(u16),
+    Indirect(u16),
+    PostInc(u16),
+    PreDec(u16),
+    Disp(Expr, u16),
+    Index(Expr, u16, IndexReg),
+    AbsWord(Expr),
+    AbsLong(Expr),
+    // The PC-relative forms hold the target address, not the
+    // displacement.
+    PcDisp(Expr),
+    PcIndex(Expr, IndexReg),
+    Immediate(Expr),
+    RegList(u16),
+    Sr,
+    Ccr,
+    Usp,
+}
+
+// Effective address classes, as sets of addressing modes.
+const DN: u16 = 1 << 0;
+const AN: u16 = 1 << 1;
+const IND: u16 = 1 << 2;
+const POST: u16 = 1 << 3;
+const PRE: u16 = 1 << 4;
+const DISP: u16 = 1 << 5;
+const IDX: u16 = 1 << 6;
+const ABSW: u16 = 1 << 7;
+const ABSL: u16 = 1 << 8;
+const PCD: u16 = 1 << 9;
+const PCX: u16 = 1 << 10;
+const IMM: u16 = 1 << 11;
+
+const ALL: u16 = 0x0fff;
+const DATA: u16 = ALL & !AN;
+const MEMORY: u16 = DATA & !DN;
+const CONTROL: u16 = IND | DISP | IDX | ABSW | ABSL | PCD | PCX;
+const ALTERABLE: u16 = ALL & !(PCD | PCX | IMM);
+const DATA_ALT: u16 = DATA & ALTERABLE;
+const MEM_ALT: u16 = MEMORY & ALTERABLE;
+const CONTROL_ALT: u16 = CONTROL & ALTERABLE;
+
+impl Operand {
+    fn class(&self) -> u16 {
+        match self {
+            Operand::DataReg(_) => DN,
+            Operand::AddrReg(_) => AN,
+            Operand::Indirect(_) => IND,
+            Operand::PostInc(_) => POST,
+            Operand::PreDec(_) => PRE,
+            Operand::Disp(_, _) => DISP,
+            Operand::Index(_, _, _) => IDX,
+            Operand::AbsWord(_) => ABSW,
+            Operand::AbsLong(_) => ABSL,
+            Operand::PcDisp(_) => PCD,
+            Operand::PcIndex(_, _) => PCX,
+            Operand::Immediate(_) => IMM,
+            _ => 0,
+        }
+    }
+
+    // The 6-bit mode and register field.
+    fn fields(&self) -> u16 {
+        match self {
+            Operand::DataReg(r) => *r,
+            Operand::AddrReg(r) => 0o10 | r,
+            Operand::Indirect(r) => 0o20 | r,
+            Operand::PostInc(r) => 0o30 | r,
+            Operand::PreDec(r) => 0o40 | r,
+            Operand::Disp(_, r) => 0o50 | r,
+            Operand::Index(_, r, _) => 0o60 | r,
+            Operand::AbsWord(_) => 0o70,
+            Operand::AbsLong(_) => 0o71,
+            Operand::PcDisp(_) => 0o72,
+            Operand::PcIndex(_, _) => 0o73,
+            Operand::Immediate(_) => 0o74,
+            _ => 0,
+        }
+    }
+}
+
+fn parse_reg(s: &str) -> Option<u16> {
+    let s = s.trim().to_ascii_lowercase();
+    if s == "sp" {
+        return Some(15);
+    }
+    let (kind, num) = s.split_at_checked(1)?;
+    let num = num.parse::<u16>().ok().filter(|n| *n < 8)?;
+    match kind {
+        "d" => Some(num),
+        "a" => Some(num + 8),
+        _ => None,
+    }
+}
+
+fn parse_addr_reg(s: &str) -> Option<u16> {
+    parse_reg(s).filter(|r| *r >= 8).map(|r| r - 8)
+}
+
+fn parse_index(s: &str) -> anyhow::Result<IndexReg> {
+    let lower = s.trim().to_ascii_lowercase();
+    let (reg, long) = if let Some(reg) = lower.strip_suffix(".l") {
+        (reg, true)
+    } else {
+        (lower.strip_suffix(".w").unwrap_or(&lower), false)
+    };
+    let reg = parse_reg(reg).ok_or_else(|| anyhow!("Bad index register '{}'", s))?;
+    Ok(IndexReg { reg, long })
+}
+
+// Register lists for MOVEM, like "d0-d3/a0/a2", as a mask with d0 in
+// bit 0 and a7 in bit 15.
+fn parse_reg_list(s: &str) -> Option<u16> {
+    let mut mask = 0;
+    for part in s.split('/') {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (parse_reg(first)?, parse_reg(last)?),
+            None => (parse_reg(part)?, parse_reg(part)?),
+        };
+        if first > last {
+            return None;
+        }
+        for reg in first..=last {
+            mask |= 1 << reg;
+        }
+    }
+    Some(mask)
+}
+
+fn parse_operand(s: &str) -> anyhow::Result<Operand> {
+    let s = s.trim();
+    let lower = s.to_ascii_lowercase();
+
+    if let Some(imm) = s.strip_prefix('#') {
+        return Ok(Operand::Immediate(parse_expr(imm)?));
+    }
+    match lower.as_str() {
+        "sr" => return Ok(Operand::Sr),
+        "ccr" => return Ok(Operand::Ccr),
+        "usp" => return Ok(Operand::Usp),
+        _ => {}
+    }
+    if let Some(reg) = parse_reg(s) {
+        return Ok(if reg < 8 {
+            Operand::DataReg(reg)
+        } else {
+            Operand::AddrReg(reg - 8)
+        });
+    }
+    if let Some(mask) = parse_reg_list(s) {
+        return Ok(Operand::RegList(mask));
+    }
+    if let Some(inner) = lower.strip_prefix("-(").and_then(|s| s.strip_suffix(')')) {
+        if let Some(reg) = parse_addr_reg(inner) {
+            return Ok(Operand::PreDec(reg));
+        }
+    }
+    if let Some(inner) = lower.strip_prefix('(').and_then(|s| s.strip_suffix(")+")) {
+        if let Some(reg) = parse_addr_reg(inner) {
+            return Ok(Operand::PostInc(reg));
+        }
+    }
+    if lower.starts_with('(') && (lower.ends_with(").w") || lower.ends_with(").l")) {
+        let expr = parse_expr(&s[1..s.len() - 3])?;
+        return Ok(if lower.ends_with(".w") {
+            Operand::AbsWord(expr)
+        } else {
+            Operand::AbsLong(expr)
+        });
+    }
+
+    if s.ends_with(')') {
+        // Find the bracket matching the last one.
+        let mut depth = 0;
+        let mut open = None;
+        for (i, c) in s.char_indices().rev() {
+            match c {
+                ')' => depth += 1,
+                '(' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        open = Some(i);
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+        let open = open.ok_or_else(|| anyhow!("Unbalanced brackets in '{}'", s))?;
+        let disp = if s[..open].trim().is_empty() {
+            Expr::zero()
+        } else {
+            parse_expr(&s[..open])?
+        };
+        let parts = s[open + 1..s.len() - 1].split(',').collect::<Vec<_>>();
+        let base = parts[0].trim().to_ascii_lowercase();
+        let pc = base == "pc";
+        let reg = parse_addr_reg(&base);
+        return match (parts.len(), pc, reg) {
+            (1, true, _) => Ok(Operand::PcDisp(disp)),
+            (1, false, Some(reg)) if s[..open].trim().is_empty() => Ok(Operand::Indirect(reg)),
+            (1, false, Some(reg)) => Ok(Operand::Disp(disp, reg)),
+            (2, true, _) => Ok(Operand::PcIndex(disp, parse_index(parts[1])?)),
+            (2, false, Some(reg)) => Ok(Operand::Index(disp, reg, parse_index(parts[1])?)),
+            // A bracketed address, with the default size.
+            (1, false, None) if s[..open].trim().is_empty() => {
+                Ok(Operand::AbsLong(parse_expr(parts[0])?))
+            }
+            _ => bail!("Bad operand '{}'", s),
+        };
+    }
+
+    Ok(Operand::AbsLong(parse_expr(s)?))
+}
+
+// Split operands on commas that aren't in brackets or quotes.
+fn split_operands(s: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut quote = None;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                parts.push(s[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    let last = s[start..].trim();
+    if !last.is_empty() || !parts.is_empty() {
+        parts.push(last);
+    }
+    parts
+}
+
+////////////////////////////////////////////////////////////////////////
+// Source lines.
+//
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Size {
+    Byte,
+    Word,
+    Long,
+    Short,
+}
+
+impl Size {
+    fn bits(&self) -> u16 {
+        match self {
+            Size::Byte => 0,
+            Size::Long => 2,
+            _ => 1,
+        }
+    }
+
+    fn bytes(&self) -> usize {
+        match self {
+            Size::Byte => 1,
+            Size::Long => 4,
+            _ => 2,
+        }
+    }
+}
+
+struct Statement {
+    mnemonic: String,
+    size: Option<Size>,
+    operands: Vec<String>,
+}
+
+struct Line {
+    number: usize,
+    text: String,
+    label: Option<String>,
+    // For "name equ value".
+    equate: Option<(String, Expr)>,
+    statement: Option<Statement>,
+}
+
+// Strip a ';' comment, leaving quoted semicolons alone.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, ';') => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_line(number: usize, text: &str) -> anyhow::Result<Line> {
+    let mut line = Line {
+        number,
+        text: text.trim().to_string(),
+        label: None,
+        equate: None,
+        statement: None,
+    };
+    if line.text.starts_with('*') {
+        return Ok(line);
+    }
+
+    let mut rest = strip_comment(text).trim();
+    let first = rest.split_whitespace().next().unwrap_or("");
+    if let Some(label) = first.strip_suffix(':') {
+        ensure!(
+            label.starts_with(is_name_start) && label.chars().all(is_name_char),
+            "Bad label '{}'",
+            label
+        );
+        line.label = Some(label.to_string());
+        rest = rest[first.len()..].trim();
+    }
+    if rest.is_empty() {
+        return Ok(line);
+    }
+
+    let (mnemonic, operands) = match rest.split_once(char::is_whitespace) {
+        Some((mnemonic, operands)) => (mnemonic, operands.trim()),
+        None => (rest, ""),
+    };
+
+    // "name equ value", with or without the colon.
+    let mut words = operands.splitn(2, char::is_whitespace);
+    if words.next().is_some_and(|w| w.eq_ignore_ascii_case("equ")) && line.label.is_none() {
+        let value = parse_expr(words.next().unwrap_or(""))?;
+        line.equate = Some((mnemonic.to_string(), value));
+        return Ok(line);
+    }
+    if mnemonic.eq_ignore_ascii_case("equ") {
+        let label = line
+            .label
+            .take()
+            .ok_or_else(|| anyhow!("'equ' needs a name"))?;
+        line.equate = Some((label, parse_expr(operands)?));
+        return Ok(line);
+    }
+
+    // Trap names keep their case, everything else is case-insensitive.
+    let (name, size) = match mnemonic.rsplit_once('.') {
+        Some((name, suffix)) if !mnemonic.starts_with('_') => {
+            let size = match suffix.to_ascii_lowercase().as_str() {
+                "b" => Size::Byte,
+                "w" => Size::Word,
+                "l" => Size::Long,
+                "s" => Size::Short,
+                _ => bail!("Bad size '.{}'", suffix),
+            };
+            (name.to_ascii_lowercase(), Some(size))
+        }
+        _ if mnemonic.starts_with('_') => (mnemonic.to_string(), None),
+        _ => (mnemonic.to_ascii_lowercase(), None),
+    };
+    line.statement = Some(Statement {
+        mnemonic: name,
+        size,
+        operands: split_operands(operands)
+            .into_iter()
+            .map(String::from)
+            .collect(),
+    });
+    Ok(line)
+}
+
+////////////////////////////////////////////////////////////////////////
+// Code generation.
+//
+
+const CONDITIONS: [&str; 16] = [
+    "t", "f", "hi", "ls", "cc", "cs", "ne", "eq", "vc", "vs", "pl", "mi", "ge", "lt", "gt", "le",
+];
+
+fn condition(s: &str) -> Option<u16> {
+    match s {
+        "hs" => Some(4),
+        "lo" => Some(5),
+        _ => CONDITIONS.iter().position(|c| *c == s).map(|c| c as u16),
+    }
+}
+
+struct Assembler<'a> {
+    externals: &'a Externals,
+    // Labels and equates. Equates can be negative.
+    labels: HashMap<String, i64>,
+    // Address of the current statement.
+    pc: u32,
+    // On the first pass, unknown names are zero and values aren't
+    // range-checked.
+    last_pass: bool,
+}
+
+impl<'a> Assembler<'a> {
+    fn eval(&self, expr: &Expr) -> anyhow::Result<i64> {
+        let mut value = 0i64;
+        for (negate, term) in expr.0.iter() {
+            let v = match term {
+                Term::Number(n) => *n,
+                Term::Here => self.pc as i64,
+                Term::Name(name) => match self
+                    .labels
+                    .get(name)
+                    .copied()
+                    .or_else(|| self.externals.symbols.get(name).map(|addr| *addr as i64))
+                {
+                    Some(value) => value,
+                    None if self.last_pass => bail!("Unknown name '{}'", name),
+                    None => 0,
+                },
+            };
+            value = if *negate { value - v } else { value + v };
+        }
+        Ok(value)
+    }
+
+    fn check_range(&self, value: i64, min: i64, max: i64, what: &str) -> anyhow::Result<()> {
+        ensure!(
+            !self.last_pass || (min..=max).contains(&value),
+            "{} {} out of range",
+            what,
+            value
+        );
+        Ok(())
+    }
+
+    fn immediate(&self, out: &mut Vec<u8>, expr: &Expr, size: Size) -> anyhow::Result<()> {
+        let value = self.eval(expr)?;
+        match size {
+            Size::Byte => {
+                self.check_range(value, -0x80, 0xff, "Byte")?;
+                push_word(out, value as u8 as u16);
+            }
+            Size::Long => {
+                self.check_range(value, -0x8000_0000, 0xffff_ffff, "Long")?;
+                push_long(out, value as u32);
+            }
+            _ => {
+                self.check_range(value, -0x8000, 0xffff, "Word")?;
+                push_word(out, value as u16);
+            }
+        }
+        Ok(())
+    }
+
+    // Displacement from the extension word about to be written.
+    fn pc_disp(&self, out: &[u8], target: &Expr) -> anyhow::Result<i64> {
+        Ok(self.eval(target)? - (self.pc as i64 + out.len() as i64))
+    }
+
+    fn index_word(&self, disp: i64, index: IndexReg) -> anyhow::Result<u16> {
+        self.check_range(disp, -0x80, 0x7f, "Index displacement")?;
+        Ok((index.reg << 12) | if index.long { 0x0800 } else { 0 } | (disp as u8 as u16))
+    }
+
+    // Check an effective address is allowed, returning its mode and
+    // register fields.
+    fn ea(&self, op: &Operand, allowed: u16) -> anyhow::Result<u16> {
+        ensure!(
+            op.class() & allowed != 0,
+            "Addressing mode not allowed here"
+        );
+        Ok(op.fields())
+    }
+
+    // Write an effective address's extension words.
+    fn ea_ext(&self, out: &mut Vec<u8>, op: &Operand, size: Size) -> anyhow::Result<()> {
+        match op {
+            Operand::Disp(disp, _) => {
+                let disp = self.eval(disp)?;
+                self.check_range(disp, -0x8000, 0x7fff, "Displacement")?;
+                push_word(out, disp as u16);
+            }
+            Operand::Index(disp, _, index) => {
+                let word = self.index_word(self.eval(disp)?, *index)?;
+                push_word(out, word);
+            }
+            Operand::AbsWord(addr) => {
+                let addr = self.eval(addr)?;
+                // Sign-extended, so the top and bottom 32kB.
+                let addr = if addr >= 0xff8000 {
+                    addr - 0x1000000
+                } else {
+                    addr
+                };
+                self.check_range(addr, -0x8000, 0x7fff, "Short address")?;
+                push_word(out, addr as u16);
+            }
+            Operand::AbsLong(addr) => {
+                let addr = self.eval(addr)?;
+                self.check_range(addr, 0, 0xffff_ffff, "Address")?;
+                push_long(out, addr as u32);
+            }
+            Operand::PcDisp(target) => {
+                let disp = self.pc_disp(out, target)?;
+                self.check_range(disp, -0x8000, 0x7fff, "PC displacement")?;
+                push_word(out, disp as u16);
+            }
+            Operand::PcIndex(target, index) => {
+                let word = self.index_word(self.pc_disp(out, target)?, *index)?;
+                push_word(out, word);
+            }
+            Operand::Immediate(expr) => self.immediate(out, expr, size)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn statement(&self, s: &Statement) -> anyhow::Result<Vec<u8>> {
+        match s.mnemonic.as_str() {
+            "dc" => return self.data(s),
+            "ds" => {
+                ensure!(s.operands.len() == 1, "'ds' takes a count");
+                let count = self.eval(&parse_expr(&s.operands[0])?)?;
+                ensure!(count >= 0, "Negative 'ds' count");
+                return Ok(vec![
+                    0;
+                    count as usize * s.size.unwrap_or(Size::Word).bytes()
+                ]);
+            }
+            "even" => return Ok(vec![0; (self.pc & 1) as usize]),
+            _ => {}
+        }
+
+        ensure!(self.pc & 1 == 0, "Instruction at an odd address");
+        let ops = s
+            .operands
+            .iter()
+            .map(|o| parse_operand(o))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut out = Vec::new();
+        self.instruction(&mut out, &s.mnemonic, s.size, &ops)?;
+        Ok(out)
+    }
+
+    fn data(&self, s: &Statement) -> anyhow::Result<Vec<u8>> {
+        let size = s.size.unwrap_or(Size::Word);
+        let mut out = Vec::new();
+        for operand in s.operands.iter() {
+            // Bytes can be given as strings.
+            let quote = operand.chars().next().filter(|c| *c == '"' || *c == '\'');
+            if let (Size::Byte, Some(quote)) = (size, quote) {
+                ensure!(
+                    operand.len() >= 2 && operand.ends_with(quote),
+                    "Unterminated string {}",
+                    operand
+                );
+                out.extend(operand[1..operand.len() - 1].bytes());
+                continue;
+            }
+            let value = self.eval(&parse_expr(operand)?)?;
+            match size {
+                Size::Byte => {
+                    self.check_range(value, -0x80, 0xff, "Byte")?;
+                    out.push(value as u8);
+                }
+                Size::Long => {
+                    self.check_range(value, -0x8000_0000, 0xffff_ffff, "Long")?;
+                    out.extend((value as u32).to_be_bytes());
+                }
+                _ => {
+                    self.check_range(value, -0x8000, 0xffff, "Word")?;
+                    out.extend((value as u16).to_be_bytes());
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    fn instruction(
+        &self,
+        out: &mut Vec<u8>,
+        mnemonic: &str,
+        size: Option<Size>,
+        ops: &[Operand],
+    ) -> anyhow::Result<()> {
+        use Operand::*;
+
+        let sz = size.unwrap_or(Size::Word);
+        let arity = |n: usize| -> anyhow::Result<()> {
+            ensure!(ops.len() == n, "'{}' takes {} operand(s)", mnemonic, n);
+            Ok(())
+        };
+        let sized = |allowed: &[Size]| -> anyhow::Result<Size> {
+            ensure!(allowed.contains(&sz), "Bad size for '{}'", mnemonic);
+            Ok(sz)
+        };
+        // An instruction word followed by the extension words of up to
+        // two effective addresses.
+        let emit = |out: &mut Vec<u8>, op: u16, eas: &[(&Operand, Size)]| {
+            push_word(out, op);
+            for (ea, size) in eas.iter() {
+                self.ea_ext(out, ea, *size)?;
+            }
+            anyhow::Ok(())
+        };
+
+        // Instructions without operands.
+        let simple = match mnemonic {
+            "nop" => Some(0x4e71),
+            "rts" => Some(0x4e75),
+            "rte" => Some(0x4e73),
+            "rtr" => Some(0x4e77),
+            "reset" => Some(0x4e70),
+            "illegal" => Some(0x4afc),
+            "trapv" => Some(0x4e76),
+            _ => None,
+        };
+        if let Some(op) = simple {
+            arity(0)?;
+            push_word(out, op);
+            return Ok(());
+        }
+
+        if mnemonic.starts_with('_') {
+            arity(0)?;
+            let trap = self
+                .externals
+                .traps
+                .get(mnemonic)
+                .ok_or_else(|| anyhow!("Unknown trap '{}'", mnemonic))?;
+            push_word(out, *trap);
+            return Ok(());
+        }
+
+        match mnemonic {
+            "move" | "movea" => {
+                arity(2)?;
+                match (&ops[0], &ops[1]) {
+                    (Sr, dst) => emit(out, 0x40c0 | self.ea(dst, DATA_ALT)?, &[(dst, Size::Word)]),
+                    (src, Ccr) => emit(out, 0x44c0 | self.ea(src, DATA)?, &[(src, Size::Word)]),
+                    (src, Sr) => emit(out, 0x46c0 | self.ea(src, DATA)?, &[(src, Size::Word)]),
+                    (Usp, AddrReg(r)) => emit(out, 0x4e68 | r, &[]),
+                    (AddrReg(r), Usp) => emit(out, 0x4e60 | r, &[]),
+                    (src, AddrReg(r)) => {
+                        let sz = sized(&[Size::Word, Size::Long])?;
+                        let code = if sz == Size::Long { 0x2000 } else { 0x3000 };
+                        let op = code | (r << 9) | 0x0040 | self.ea(src, ALL)?;
+                        emit(out, op, &[(src, sz)])
+                    }
+                    (src, dst) => {
+                        ensure!(mnemonic == "move", "'movea' needs an address register");
+                        let sz = sized(&[Size::Byte, Size::Word, Size::Long])?;
+                        let code = match sz {
+                            Size::Byte => 0x1000,
+                            Size::Long => 0x2000,
+                            _ => 0x3000,
+                        };
+                        let allowed = if sz == Size::Byte { DATA } else { ALL };
+                        let dst_fields = self.ea(dst, DATA_ALT)?;
+                        let op = code
+                            | ((dst_fields & 7) << 9)
+                            | ((dst_fields >> 3) << 6)
+                            | self.ea(src, allowed)?;
+                        emit(out, op, &[(src, sz), (dst, sz)])
+                    }
+                }
+            }
+            "moveq" => {
+                arity(2)?;
+                match (&ops[0], &ops[1]) {
+                    (Immediate(value), DataReg(r)) => {
+                        let value = self.eval(value)?;
+                        self.check_range(value, -0x80, 0x7f, "Quick value")?;
+                        emit(out, 0x7000 | (r << 9) | (value as u8 as u16), &[])
+                    }
+                    _ => bail!("'moveq' takes #value,Dn"),
+                }
+            }
+            "movem" => {
+                arity(2)?;
+                let sz = sized(&[Size::Word, Size::Long])?;
+                let long = if sz == Size::Long { 0x0040 } else { 0 };
+                let mask = |op: &Operand| match op {
+                    RegList(mask) => Some(*mask),
+                    DataReg(r) => Some(1 << r),
+                    AddrReg(r) => Some(1 << (r + 8)),
+                    _ => None,
+                };
+                match (mask(&ops[0]), mask(&ops[1])) {
+                    (Some(m), None) => {
+                        let dst = &ops[1];
+                        let fields = self.ea(dst, CONTROL_ALT | PRE)?;
+                        let m = if let PreDec(_) = dst {
+                            m.reverse_bits()
+                        } else {
+                            m
+                        };
+                        push_word(out, 0x4880 | long | fields);
+                        push_word(out, m);
+                        self.ea_ext(out, dst, sz)
+                    }
+                    (None, Some(m)) => {
+                        let src = &ops[0];
+                        let fields = self.ea(src, CONTROL | POST)?;
+                        push_word(out, 0x4c80 | long | fields);
+                        push_word(out, m);
+                        self.ea_ext(out, src, sz)
+                    }
+                    _ => bail!("'movem' needs a register list and a memory operand"),
+                }
+            }
+            "lea" => {
+                arity(2)?;
+                match &ops[1] {
+                    AddrReg(r) => emit(
+                        out,
+                        0x41c0 | (r << 9) | self.ea(&ops[0], CONTROL)?,
+                        &[(&ops[0], Size::Long)],
+                    ),
+                    _ => bail!("'lea' needs an address register"),
+                }
+            }
+            "pea" | "jmp" | "jsr" => {
+                arity(1)?;
+                let base = match mnemonic {
+                    "pea" => 0x4840,
+                    "jmp" => 0x4ec0,
+                    _ => 0x4e80,
+                };
+                emit(
+                    out,
+                    base | self.ea(&ops[0], CONTROL)?,
+                    &[(&ops[0], Size::Long)],
+                )
+            }
+            "clr" | "neg" | "negx" | "not" | "tst" => {
+                arity(1)?;
+                let base = match mnemonic {
+                    "clr" => 0x4200,
+                    "neg" => 0x4400,
+                    "negx" => 0x4000,
+                    "not" => 0x4600,
+                    _ => 0x4a00,
+                };
+                let sz = sized(&[Size::Byte, Size::Word, Size::Long])?;
+                emit(
+                    out,
+                    base | (sz.bits() << 6) | self.ea(&ops[0], DATA_ALT)?,
+                    &[(&ops[0], sz)],
+                )
+            }
+            "tas" | "nbcd" => {
+                arity(1)?;
+                let base = if mnemonic == "tas" { 0x4ac0 } else { 0x4800 };
+                emit(
+                    out,
+                    base | self.ea(&ops[0], DATA_ALT)?,
+                    &[(&ops[0], Size::Byte)],
+                )
+            }
+            "ext" | "swap" | "unlk" => {
+                arity(1)?;
+                match (mnemonic, &ops[0]) {
+                    ("ext", DataReg(r)) => {
+                        let sz = sized(&[Size::Word, Size::Long])?;
+                        emit(out, if sz == Size::Long { 0x48c0 } else { 0x4880 } | r, &[])
+                    }
+                    ("swap", DataReg(r)) => emit(out, 0x4840 | r, &[]),
+                    ("unlk", AddrReg(r)) => emit(out, 0x4e58 | r, &[]),
+                    _ => bail!("Bad operand for '{}'", mnemonic),
+                }
+            }
+            "link" => {
+                arity(2)?;
+                match (&ops[0], &ops[1]) {
+                    (AddrReg(r), Immediate(_)) => emit(out, 0x4e50 | r, &[(&ops[1], Size::Word)]),
+                    _ => bail!("'link' takes An,#displacement"),
+                }
+            }
+            "trap" => {
+                arity(1)?;
+                match &ops[0] {
+                    Immediate(n) => {
+                        let n = self.eval(n)?;
+                        self.check_range(n, 0, 15, "Trap number")?;
+                        emit(out, 0x4e40 | n as u16, &[])
+                    }
+                    _ => bail!("'trap' takes #vector"),
+                }
+            }
+            "stop" | "aline" => {
+                arity(1)?;
+                match (mnemonic, &ops[0]) {
+                    ("stop", Immediate(_)) => emit(out, 0x4e72, &[(&ops[0], Size::Word)]),
+                    ("aline", Immediate(word)) => {
+                        let word = self.eval(word)?;
+                        self.check_range(word, 0xa000, 0xafff, "A-line word")?;
+                        emit(out, word as u16, &[])
+                    }
+                    _ => bail!("'{}' takes #value", mnemonic),
+                }
+            }
+            "chk" => {
+                arity(2)?;
+                match &ops[1] {
+                    DataReg(r) => emit(
+                        out,
+                        0x4180 | (r << 9) | self.ea(&ops[0], DATA)?,
+                        &[(&ops[0], Size::Word)],
+                    ),
+                    _ => bail!("'chk' needs a data register"),
+                }
+            }
+            "mulu" | "muls" | "divu" | "divs" => {
+                arity(2)?;
+                let base = match mnemonic {
+                    "mulu" => 0xc0c0,
+                    "muls" => 0xc1c0,
+                    "divu" => 0x80c0,
+                    _ => 0x81c0,
+                };
+                match &ops[1] {
+                    DataReg(r) => emit(
+                        out,
+                        base | (r << 9) | self.ea(&ops[0], DATA)?,
+                        &[(&ops[0], Size::Word)],
+                    ),
+                    _ => bail!("'{}' needs a data register", mnemonic),
+                }
+            }
+            "exg" => {
+                arity(2)?;
+                let op = match (&ops[0], &ops[1]) {
+                    (DataReg(x), DataReg(y)) => 0xc140 | (x << 9) | y,
+                    (AddrReg(x), AddrReg(y)) => 0xc148 | (x << 9) | y,
+                    (DataReg(x), AddrReg(y)) | (AddrReg(y), DataReg(x)) => 0xc188 | (x << 9) | y,
+                    _ => bail!("'exg' takes two registers"),
+                };
+                emit(out, op, &[])
+            }
+            "add" | "sub" | "adda" | "suba" | "addi" | "subi" | "addq" | "subq" => {
+                arity(2)?;
+                let sub = mnemonic.starts_with("sub");
+                let (src, dst) = (&ops[0], &ops[1]);
+                let sz = sized(&[Size::Byte, Size::Word, Size::Long])?;
+                match (src, dst) {
+                    (Immediate(n), _) if mnemonic.ends_with('q') => {
+                        let n = self.eval(n)?;
+                        self.check_range(n, 1, 8, "Quick value")?;
+                        let base = if sub { 0x5100 } else { 0x5000 };
+                        let allowed = if sz == Size::Byte {
+                            DATA_ALT
+                        } else {
+                            ALTERABLE
+                        };
+                        let op = base | ((n as u16 & 7) << 9) | (sz.bits() << 6);
+                        emit(out, op | self.ea(dst, allowed)?, &[(dst, sz)])
+                    }
+                    _ if mnemonic.ends_with('q') => bail!("'{}' takes #1 to #8", mnemonic),
+                    (_, AddrReg(r)) if !mnemonic.ends_with('i') => {
+                        let sz = sized(&[Size::Word, Size::Long])?;
+                        let base = if sub { 0x9000 } else { 0xd000 };
+                        let long = if sz == Size::Long { 0x01c0 } else { 0x00c0 };
+                        emit(
+                            out,
+                            base | (r << 9) | long | self.ea(src, ALL)?,
+                            &[(src, sz)],
+                        )
+                    }
+                    _ if mnemonic.ends_with('a') => {
+                        bail!("'{}' needs an address register", mnemonic)
+                    }
+                    _ => {
+                        let codes = if sub {
+                            (0x9000, 0x0400)
+                        } else {
+                            (0xd000, 0x0600)
+                        };
+                        let allowed = if sz == Size::Byte { DATA } else { ALL };
+                        self.arith(out, mnemonic, codes, allowed, sz, (src, dst))
+                    }
+                }
+            }
+            "and" | "or" | "andi" | "ori" | "eor" | "eori" => {
+                arity(2)?;
+                let codes = match mnemonic.trim_end_matches('i') {
+                    "and" => (0xc000, 0x0200),
+                    "or" => (0x8000, 0x0000),
+                    _ => (0xb000, 0x0a00),
+                };
+                match (&ops[0], &ops[1]) {
+                    (Immediate(_), Ccr) => emit(out, codes.1 | 0x003c, &[(&ops[0], Size::Byte)]),
+                    (Immediate(_), Sr) => emit(out, codes.1 | 0x007c, &[(&ops[0], Size::Word)]),
+                    (src, dst) => {
+                        let sz = sized(&[Size::Byte, Size::Word, Size::Long])?;
+                        self.arith(out, mnemonic, codes, DATA, sz, (src, dst))
+                    }
+                }
+            }
+            "cmp" | "cmpa" | "cmpi" | "cmpm" => {
+                arity(2)?;
+                let sz = sized(&[Size::Byte, Size::Word, Size::Long])?;
+                match (&ops[0], &ops[1]) {
+                    (PostInc(y), PostInc(x)) => {
+                        emit(out, 0xb108 | (x << 9) | (sz.bits() << 6) | y, &[])
+                    }
+                    (src, AddrReg(r)) if mnemonic != "cmpi" => {
+                        let sz = sized(&[Size::Word, Size::Long])?;
+                        let long = if sz == Size::Long { 0x01c0 } else { 0x00c0 };
+                        emit(
+                            out,
+                            0xb000 | (r << 9) | long | self.ea(src, ALL)?,
+                            &[(src, sz)],
+                        )
+                    }
+                    _ if mnemonic == "cmpa" || mnemonic == "cmpm" => {
+                        bail!("Bad operands for '{}'", mnemonic)
+                    }
+                    (src, dst) => {
+                        let allowed = if sz == Size::Byte { DATA } else { ALL };
+                        self.arith(out, mnemonic, (0xb000, 0x0c00), allowed, sz, (src, dst))
+                    }
+                }
+            }
+            "btst" | "bchg" | "bclr" | "bset" => {
+                arity(2)?;
+                let kind = ["btst", "bchg", "bclr", "bset"]
+                    .iter()
+                    .position(|m| *m == mnemonic)
+                    .unwrap() as u16;
+                let dst = &ops[1];
+                // BTST can test a bit of an immediate, but only with
+                // the bit number in a register.
+                let allowed = match (&ops[0], kind) {
+                    (DataReg(_), 0) => DATA,
+                    (_, 0) => DATA & !IMM,
+                    _ => DATA_ALT,
+                };
+                match &ops[0] {
+                    DataReg(r) => emit(
+                        out,
+                        0x0100 | (r << 9) | (kind << 6) | self.ea(dst, allowed)?,
+                        &[(dst, Size::Byte)],
+                    ),
+                    Immediate(_) => emit(
+                        out,
+                        0x0800 | (kind << 6) | self.ea(dst, allowed)?,
+                        &[(&ops[0], Size::Byte), (dst, Size::Byte)],
+                    ),
+                    _ => bail!("'{}' takes a bit number in Dn or #n", mnemonic),
+                }
+            }
+            "asl" | "asr" | "lsl" | "lsr" | "roxl" | "roxr" | "rol" | "ror" => {
+                let kind = ["as", "ls", "rox", "ro"]
+                    .iter()
+                    .position(|k| *k == &mnemonic[..mnemonic.len() - 1])
+                    .unwrap() as u16;
+                let left = if mnemonic.ends_with('l') { 0x0100 } else { 0 };
+                match ops {
+                    [mem] => {
+                        sized(&[Size::Word])?;
+                        emit(
+                            out,
+                            0xe0c0 | (kind << 9) | left | self.ea(mem, MEM_ALT)?,
+                            &[(mem, Size::Word)],
+                        )
+                    }
+                    [count, DataReg(r)] => {
+                        let sz = sized(&[Size::Byte, Size::Word, Size::Long])?;
+                        let count = match count {
+                            DataReg(c) => (c << 9) | 0x0020,
+                            Immediate(n) => {
+                                let n = self.eval(n)?;
+                                self.check_range(n, 1, 8, "Shift count")?;
+                                (n as u16 & 7) << 9
+                            }
+                            _ => bail!("Bad shift count"),
+                        };
+                        emit(
+                            out,
+                            0xe000 | count | left | (sz.bits() << 6) | (kind << 3) | r,
+                            &[],
+                        )
+                    }
+                    _ => bail!("Bad operands for '{}'", mnemonic),
+                }
+            }
+            "bra" | "bsr" => self.branch(out, if mnemonic == "bra" { 0 } else { 1 }, size, ops),
+            "dbra" => self.db(out, 1, ops),
+            _ => {
+                if let Some(cond) = mnemonic.strip_prefix("db").and_then(condition) {
+                    return self.db(out, cond, ops);
+                }
+                if let Some(cond) = mnemonic.strip_prefix('b').and_then(condition) {
+                    ensure!(cond > 1, "Unknown instruction '{}'", mnemonic);
+                    return self.branch(out, cond, size, ops);
+                }
+                if let Some(cond) = mnemonic.strip_prefix('s').and_then(condition) {
+                    arity(1)?;
+                    return emit(
+                        out,
+                        0x50c0 | (cond << 8) | self.ea(&ops[0], DATA_ALT)?,
+                        &[(&ops[0], Size::Byte)],
+                    );
+                }
+                bail!("Unknown instruction '{}'", mnemonic)
+            }
+        }
+    }
+
+    // The "<ea>,Dn", "Dn,<ea>" and immediate forms of ADD, SUB, AND, OR,
+    // EOR and CMP. 'codes' are the base opcodes of the register and
+    // immediate forms, and 'allowed' is what the source can be in the
+    // "<ea>,Dn" form.
+    fn arith(
+        &self,
+        out: &mut Vec<u8>,
+        mnemonic: &str,
+        (base, immediate): (u16, u16),
+        allowed: u16,
+        sz: Size,
+        (src, dst): (&Operand, &Operand),
+    ) -> anyhow::Result<()> {
+        let size = sz.bits() << 6;
+        let eor = base == 0xb000 && !mnemonic.starts_with("cmp");
+        let to_memory = match (src, dst) {
+            (Operand::Immediate(_), _) if mnemonic.ends_with('i') || eor => true,
+            (Operand::Immediate(_), Operand::DataReg(_)) => false,
+            (Operand::Immediate(_), _) => true,
+            _ => false,
+        };
+        if to_memory {
+            push_word(out, immediate | size | self.ea(dst, DATA_ALT)?);
+            self.ea_ext(out, src, sz)?;
+            return self.ea_ext(out, dst, sz);
+        }
+        ensure!(
+            !mnemonic.ends_with('i'),
+            "'{}' needs an immediate source",
+            mnemonic
+        );
+        match (src, dst) {
+            (src, Operand::DataReg(r)) if !eor => {
+                push_word(out, base | (r << 9) | size | self.ea(src, allowed)?);
+                self.ea_ext(out, src, sz)
+            }
+            (Operand::DataReg(r), dst) if !mnemonic.starts_with("cmp") => {
+                let allowed = if eor { DATA_ALT } else { MEM_ALT };
+                push_word(
+                    out,
+                    base | (r << 9) | 0x0100 | size | self.ea(dst, allowed)?,
+                );
+                self.ea_ext(out, dst, sz)
+            }
+            _ => bail!("Bad operands for '{}'", mnemonic),
+        }
+    }
+
+    fn branch(
+        &self,
+        out: &mut Vec<u8>,
+        cond: u16,
+        size: Option<Size>,
+        ops: &[Operand],
+    ) -> anyhow::Result<()> {
+        let target = match ops {
+            [Operand::AbsLong(target)] => target,
+            _ => bail!("Branches take a target address"),
+        };
+        let disp = self.eval(target)? - (self.pc as i64 + 2);
+        match size {
+            Some(Size::Short) | Some(Size::Byte) => {
+                self.check_range(disp, -0x80, 0x7f, "Short branch")?;
+                ensure!(
+                    !self.last_pass || disp != 0,
+                    "Short branch to the next instruction"
+                );
+                push_word(out, 0x6000 | (cond << 8) | (disp as u8 as u16));
+            }
+            Some(Size::Long) => bail!("No long branches on the 68000"),
+            _ => {
+                self.check_range(disp, -0x8000, 0x7fff, "Branch")?;
+                push_word(out, 0x6000 | (cond << 8));
+                push_word(out, disp as u16);
+            }
+        }
+        Ok(())
+    }
+
+    fn db(&self, out: &mut Vec<u8>, cond: u16, ops: &[Operand]) -> anyhow::Result<()> {
+        let (reg, target) = match ops {
+            [Operand::DataReg(reg), Operand::AbsLong(target)] => (reg, target),
+            _ => bail!("'db<cc>' takes Dn,target"),
+        };
+        let disp = self.eval(target)? - (self.pc as i64 + 2);
+        self.check_range(disp, -0x8000, 0x7fff, "Branch")?;
+        push_word(out, 0x50c8 | (cond << 8) | reg);
+        push_word(out, disp as u16);
+        Ok(())
+    }
+
+    // One pass over the source, returning the code.
+    fn pass(&mut self, lines: &[Line], origin: u32) -> anyhow::Result<Vec<u8>> {
+        let mut code = Vec::new();
+        for line in lines.iter() {
+            self.pc = origin + code.len() as u32;
+            let in_line = |e: anyhow::Error| anyhow!("Line {}: {}: {}", line.number, e, line.text);
+            if let Some(label) = &line.label {
+                if !self.last_pass && self.labels.contains_key(label) {
+                    return Err(in_line(anyhow!("'{}' defined twice", label)));
+                }
+                self.labels.insert(label.clone(), self.pc as i64);
+            }
+            if let Some((name, value)) = &line.equate {
+                let value = self.eval(value).map_err(in_line)?;
+                if !self.last_pass && self.labels.contains_key(name) {
+                    return Err(in_line(anyhow!("'{}' defined twice", name)));
+                }
+                self.labels.insert(name.clone(), value);
+            }
+            if let Some(statement) = &line.statement {
+                code.extend(self.statement(statement).map_err(in_line)?);
+            }
+        }
+        Ok(code)
+    }
+}
+
+fn push_word(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn push_long(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn parse_source(source: &str) -> anyhow::Result<Vec<Line>> {
+    source
+        .lines()
+        .enumerate()
+        .map(|(idx, text)| {
+            parse_line(idx + 1, text)
+                .map_err(|e| anyhow!("Line {}: {}: {}", idx + 1, e, text.trim()))
+        })
+        .collect()
+}
+
+// The size of the code, which doesn't depend on where it goes.
+pub fn size(source: &str, externals: &Externals) -> anyhow::Result<usize> {
+    let lines = parse_source(source)?;
+    let mut asm = Assembler {
+        externals,
+        labels: HashMap::new(),
+        pc: 0,
+        last_pass: false,
+    };
+    Ok(asm.pass(&lines, 0)?.len())
+}
+
+// Assemble the source to go at 'origin'.
+pub fn assemble(source: &str, origin: u32, externals: &Externals) -> anyhow::Result<Vec<u8>> {
+    let lines = parse_source(source)?;
+    let mut asm = Assembler {
+        externals,
+        labels: HashMap::new(),
+        pc: origin,
+        last_pass: false,
+    };
+    asm.pass(&lines, origin)?;
+    asm.last_pass = true;
+    asm.pass(&lines, origin)
+}
+
+// Evaluate an expression on its own, with '*' as 'here'.
+pub fn evaluate(expr: &str, here: u32, externals: &Externals) -> anyhow::Result<i64> {
+    let asm = Assembler {
+        externals,
+        labels: HashMap::new(),
+        pc: here,
+        last_pass: true,
+    };
+    asm.eval(&parse_expr(expr)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGIN: u32 = 0xf80000;
+
+    fn externals() -> Externals {
+        Externals {
+            symbols: HashMap::from([("target".to_string(), 0xf80100)]),
+            traps: HashMap::from([("_GetResource".to_string(), 0xa9a0)]),
+        }
+    }
+
+    fn assemble_hex(source: &str) -> String {
+        let code = assemble(source, ORIGIN, &externals())
+            .unwrap_or_else(|e| panic!("Couldn't assemble '{}': {}", source, e));
+        code.chunks(2)
+            .map(|w| format!("{:02x}{:02x}", w[0], w[1]))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    // Source, and its encoding.
+    const ENCODINGS: [(&str, &str); 36] = [
+        ("nop", "4e71"),
+        ("rts", "4e75"),
+        ("moveq #1,d0", "7001"),
+        ("moveq #-1,d7", "7eff"),
+        ("move.l d0,d1", "2200"),
+        ("move.w #$1234,d0", "303c 1234"),
+        ("move.l (a7)+,d0", "201f"),
+        ("move.w ($1234).w,d0", "3038 1234"),
+        ("move.b -(a0),$10(a1)", "1360 0010"),
+        ("movea.l a0,a1", "2248"),
+        ("movem.l d0-d1/a0,-(a7)", "48e7 c080"),
+        ("movem.l (a7)+,d0-d1/a0", "4cdf 0103"),
+        ("lea $10(a0),a1", "43e8 0010"),
+        ("lea 4(a0,d1.w),a1", "43f0 1004"),
+        ("pea (a0)", "4850"),
+        ("clr.l -(a7)", "42a7"),
+        ("tst.b (a0)", "4a10"),
+        ("swap d0", "4840"),
+        ("ext.l d0", "48c0"),
+        ("exg d0,d1", "c141"),
+        ("addq.w #1,d0", "5240"),
+        ("subq.l #8,a7", "518f"),
+        ("cmp.l d1,d0", "b081"),
+        ("divu.w d1,d0", "80c1"),
+        ("lsl.w #2,d0", "e548"),
+        ("roxr.b d1,d0", "e230"),
+        ("btst #3,d0", "0800 0003"),
+        ("btst d1,d0", "0300"),
+        ("btst d1,#$55", "033c 0055"),
+        ("bset #7,(a0)", "08d0 0007"),
+        ("link a6,#-4", "4e56 fffc"),
+        ("unlk a6", "4e5e"),
+        ("jsr (a0)", "4e90"),
+        ("jmp target", "4ef9 00f8 0100"),
+        ("_GetResource", "a9a0"),
+        ("loop: dbra d0,loop", "51c8 fffe"),
+    ];
+
+    #[test]
+    fn encodings() {
+        for (source, expected) in ENCODINGS.iter() {
+            assert_eq!(assemble_hex(source), *expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn branches() {
+        assert_eq!(assemble_hex("bra.s next\nnop\nnext:"), "6002 4e71");
+        assert_eq!(assemble_hex("bsr next\nnop\nnext:"), "6100 0004 4e71");
+        assert_eq!(assemble_hex("beq target"), "6700 00fe");
+    }
+
+    // The disassembler reads back what the assembler wrote.
+    #[test]
+    fn round_trips_through_disasm() {
+        for (source, _) in ENCODINGS.iter() {
+            // Labels and trap names don't come back.
+            if source.contains(':') || source.starts_with('_') {
+                continue;
+            }
+            let code = assemble(source, ORIGIN, &externals()).unwrap();
+            let word = |addr: u32| {
+                let i = (addr - ORIGIN) as usize;
+                u16::from_be_bytes([code[i], code[i + 1]])
+            };
+            let (text, len) = disasm::disassemble(ORIGIN, word);
+            assert_eq!(len as usize, code.len(), "{} -> {}", source, text);
+            let again = assemble(&text, ORIGIN, &externals())
+                .unwrap_or_else(|e| panic!("Couldn't reassemble '{}': {}", text, e));
+            assert_eq!(again, code, "{} -> {}", source, text);
+        }
+    }
+
+    #[test]
+    fn rejects() {
+        let externals = Externals::default();
+        for source in [
+            "btst #1,#$55",
+            "bset d1,#$55",
+            "moveq #256,d0",
+            "addq.w #9,d0",
+            "bra nowhere",
+        ] {
+            assert!(
+                assemble(source, 0, &externals).is_err(),
+                "'{}' assembled",
+                source
+            );
+        }
+    }
+}
@@ -0,0 +1,292 @@
+//
+// Code cave
+//
+// Manages the parts of the ROM that are proven unused as a pool of
+// free space for new code. That's the `bbmc` dev team pictures, once
+// they've been taken out of the resource map and `tcsl`, the code that
+// shows them, has been disabled, plus the filler after the resources
+// and at the end of the ROM.
+//
+// New code is written in assembly, and allocated space by name. It
+// can refer to other allocations, the trap implementations and the
+// ROM resources by name, so it doesn't matter where anything ends up.
+//
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, ensure, Context};
+
+use crate::asm::{self, Externals};
+use crate::{Applied, Category, Patch};
+
+pub const TRAP_NAMES: &str = "../extract_traps/trap_names.txt";
+
+// Where the dev team pictures are. 80kB of them!
+const BBMC_OFFSET: usize = 0x1d924;
+
+// Make `tcsl` return straight away rather than go looking for the
+// pictures. When they're missing it drops into the debugger instead.
+// The first long of the resource isn't code, so the patch is on the
+// first instruction, the "JSR" into the set-up code.
+const TCSL_PATCH: Patch = Patch {
+    category: Category::EasterEgg,
+    addr: 0x1d89a,
+    before: &[0x4e, 0xba, 0x00, 0x18],
+    after: &[0x4e, 0x75, 0x4e, 0x71],
+};
+
+// The ROM is padded out with this.
+const FILLER: &[u8] = b"SWC JSP GGD ";
+
+// Filler, as (start, end) offsets: after the resources, up to the disk
+// code, and after the disk code, up to the date string at the end of
+// the ROM.
+const FILLER_REGIONS: [(usize, usize); 2] = [(0x3cba8, 0x3d000), (0x3dd54, 0x3ffee)];
+
+// New code for the cave, as assembly source. The name is a symbol
+// for its start.
+#[derive(Debug)]
+pub struct CaveCode<'a> {
+    pub name: &'a str,
+    pub source: &'a str,
+}
+
+// Assembly that replaces code in the ROM, to hook the new code in. It
+// must assemble to the same size as 'before', padding with NOPs if
+// need be.
+#[derive(Debug)]
+pub struct CaveHook<'a> {
+    pub addr: usize,
+    pub before: &'a [u8],
+    pub source: &'a str,
+}
+
+pub struct Cave {
+    // Free space, as (start, end) offsets, in ROM order.
+    free: Vec<(usize, usize)>,
+    // Allocations, as name and offset, in the order they were made.
+    symbols: Vec<(String, usize)>,
+    size: usize,
+}
+
+// Check that a region is nothing but filler.
+fn is_filler(data: &[u8], start: usize, end: usize) -> bool {
+    let phase = FILLER.iter().position(|b| *b == data[start]);
+    phase.is_some_and(|phase| {
+        (start..end).all(|i| data[i] == FILLER[(phase + i - start) % FILLER.len()])
+    })
+}
+
+impl Cave {
+    // Disable the easter egg and take the pictures out of the resource
+    // map, then collect up the free space.
+    pub fn reclaim(
+        data: &mut [u8],
+        rom_base: usize,
+        log: &mut Vec<Applied>,
+    ) -> anyhow::Result<Cave> {
+        TCSL_PATCH.apply(data, rom_base, log);
+
+        let map = rom_resources::parse(data)?;
+        let idx = map
+            .resources
+            .iter()
+            .position(|r| r.res_type == "bbmc" && r.id == 0)
+            .ok_or_else(|| anyhow!("No 'bbmc' resource to reclaim"))?;
+        let bbmc = &map.resources[idx];
+        ensure!(
+            bbmc.offset == BBMC_OFFSET,
+            "'bbmc' found at 0x{:05x}, expected 0x{:05x}",
+            bbmc.offset,
+            BBMC_OFFSET
+        );
+
+        // Record the part of the prefix and map that changed.
+        let map_range = map.header..map.map + map.map_len;
+        let before = data[map_range.clone()].to_vec();
+        rom_resources::unlist(data, &map, idx)?;
+        let changed = |i: &usize| before[*i] != data[map.header + i];
+        let first = (0..before.len()).find(changed).unwrap_or(0);
+        let last = (0..before.len()).rfind(changed).unwrap_or(0);
+        log.push(Applied {
+            addr: map.header + first,
+            category: Category::EasterEgg,
+            before: before[first..=last].to_vec(),
+            after: data[map_range][first..=last].to_vec(),
+        });
+
+        // Overwrite the pictures with filler, so that the free space in
+        // the cave is all filler until it's allocated, and 'rom_resources
+        // rebuild' can tell whether it's safe to pack resources into
+        // it. That isn't logged, as 80kB of bytes would swamp the Ghidra
+        // script.
+        let bbmc_range = (bbmc.block_start(), bbmc.block_start() + bbmc.block_size);
+        for (i, b) in data[bbmc_range.0..bbmc_range.1].iter_mut().enumerate() {
+            *b = FILLER[i % FILLER.len()];
+        }
+
+        let mut free = vec![bbmc_range];
+        for (start, end) in FILLER_REGIONS.iter() {
+            ensure!(
+                is_filler(data, *start, *end),
+                "0x{:05x}-0x{:05x} isn't filler",
+                start,
+                end
+            );
+            free.push((*start, *end));
+        }
+        free.sort();
+        let size = free.iter().map(|(start, end)| end - start).sum();
+
+        Ok(Cave {
+            free,
+            symbols: Vec::new(),
+            size,
+        })
+    }
+
+    // Allocate word-aligned space for 'len' bytes, first fit, returning
+    // its offset.
+    pub fn alloc(&mut self, name: &str, len: usize) -> anyhow::Result<usize> {
+        if self.offset(name).is_some() {
+            bail!("'{}' is already allocated in the cave", name);
+        }
+        let Some(region) = self
+            .free
+            .iter_mut()
+            .find(|(start, end)| end.saturating_sub(*start) >= len)
+        else {
+            bail!(
+                "No room for 0x{:x} bytes of '{}' in the cave (0x{:x} free)",
+                len,
+                name,
+                self.free()
+            );
+        };
+        let offset = region.0;
+        region.0 = (offset + len + 1) & !1;
+        self.symbols.push((name.to_string(), offset));
+        Ok(offset)
+    }
+
+    // Offset of an allocation.
+    pub fn offset(&self, name: &str) -> Option<usize> {
+        self.symbols
+            .iter()
+            .find(|(sym, _)| sym == name)
+            .map(|(_, offset)| *offset)
+    }
+
+    pub fn symbols(&self) -> &[(String, usize)] {
+        &self.symbols
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn free(&self) -> usize {
+        self.free
+            .iter()
+            .map(|(start, end)| end.saturating_sub(*start))
+            .sum()
+    }
+
+    // The ROM's names, plus the allocations in the cave.
+    pub fn externals(&self, data: &[u8], rom_base: usize) -> anyhow::Result<Externals> {
+        let mut externals = rom_externals(data, rom_base)?;
+        for (name, offset) in self.symbols.iter() {
+            ensure!(
+                !externals.symbols.contains_key(name),
+                "'{}' is already a ROM symbol",
+                name
+            );
+            externals
+                .symbols
+                .insert(name.clone(), (rom_base + offset) as u32);
+        }
+        Ok(externals)
+    }
+
+    // Allocate space for and assemble the new code, then assemble the
+    // hooks into it.
+    pub fn place(
+        &mut self,
+        data: &mut [u8],
+        rom_base: usize,
+        code: &[CaveCode],
+        hooks: &[CaveHook],
+        log: &mut Vec<Applied>,
+    ) -> anyhow::Result<()> {
+        let externals = rom_externals(data, rom_base)?;
+
+        // Everything's allocated before anything's assembled, so that
+        // the snippets can refer to each other.
+        for c in code.iter() {
+            let len = asm::size(c.source, &externals)
+                .with_context(|| format!("Couldn't assemble '{}'", c.name))?;
+            self.alloc(c.name, len)?;
+        }
+        let externals = self.externals(data, rom_base)?;
+
+        for c in code.iter() {
+            let offset = self.offset(c.name).unwrap();
+            let after = asm::assemble(c.source, (rom_base + offset) as u32, &externals)
+                .with_context(|| format!("Couldn't assemble '{}'", c.name))?;
+            let target = &mut data[offset..offset + after.len()];
+            log.push(Applied {
+                addr: offset,
+                category: Category::NewCode,
+                before: target.to_vec(),
+                after: after.clone(),
+            });
+            target.copy_from_slice(&after);
+        }
+
+        for h in hooks.iter() {
+            let after = asm::assemble(h.source, (rom_base + h.addr) as u32, &externals)
+                .with_context(|| format!("Couldn't assemble the hook at 0x{:05x}", h.addr))?;
+            ensure!(
+                after.len() == h.before.len(),
+                "Hook at 0x{:05x} is 0x{:x} bytes, replacing 0x{:x}",
+                h.addr,
+                after.len(),
+                h.before.len()
+            );
+            let target = &mut data[h.addr..h.addr + after.len()];
+            ensure!(
+                h.before == target,
+                "Hook at 0x{:05x}: 'before' doesn't match ROM",
+                h.addr
+            );
+            target.copy_from_slice(&after);
+            log.push(Applied {
+                addr: h.addr,
+                category: Category::NewCodeHook,
+                before: h.before.to_vec(),
+                after,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+// The names new code can use from the ROM: the trap implementations
+// and resources as addresses, and the trap words.
+pub fn rom_externals(data: &[u8], rom_base: usize) -> anyhow::Result<Externals> {
+    let traps = extract_traps::read_traps(TRAP_NAMES)?;
+    let mut symbols = HashMap::new();
+    for label in extract_traps::labels(data, &traps) {
+        let offset = label.addr - extract_traps::ROM_BASE;
+        symbols.insert(label.name, rom_base as u32 + offset);
+    }
+    for r in rom_resources::parse(data)?.resources.iter() {
+        symbols.insert(r.label(), (rom_base + r.offset) as u32);
+    }
+    let traps = traps
+        .into_iter()
+        .map(|(idx, name)| (name, extract_traps::idx_to_trap(idx) as u16))
+        .collect();
+    Ok(Externals { symbols, traps })
+}
@@ -0,0 +1,199 @@
+//
+// ROM/System consistency
+//
+// The ROM and System patch sets are separate tables, and the System's
+// repeat the ROM's mappings by hand: where the ROM went, and where the
+// SCC went. This applies both sets, works out which memory map region
+// every ROM reference and hardware patch points into and where it's
+// been moved to, and checks that the System moves everything to the
+// same place as the ROM, and only uses regions the ROM still provides.
+//
+
+use std::collections::BTreeMap;
+use std::fs;
+
+use anyhow::{bail, ensure};
+use memory_map::{MemoryMap, Region};
+
+use crate::{apply_rom_patches, patched_address, Applied, Category, Machine, SystemPatchSet};
+
+// How one region is moved by a set of patches.
+struct Mapping {
+    // Where it's moved to.
+    start: u32,
+    // How many patches moved it.
+    count: usize,
+}
+
+// The memory map region a patched address was in before patching, and
+// where the patch moved that region's start to. Addresses just past
+// the end of a region, like the end of the ROM, count as being in it.
+// Values that aren't addresses (the offset between the SCC's read and
+// write registers) aren't in any region.
+fn mapping<'a>(map: &'a MemoryMap, applied: &Applied) -> Option<(&'a Region, u32)> {
+    if applied.category != Category::RomReference && !applied.category.moves_hardware() {
+        return None;
+    }
+    let (_, before) = patched_address(applied.category, &applied.before);
+    let (_, after) = patched_address(applied.category, &applied.after);
+    let region = map.regions.iter().find(|r| match r.moved_from {
+        Some(from) => (from..=from + r.size()).contains(&before),
+        None => false,
+    })?;
+    let from = region.moved_from?;
+    Some((region, after.wrapping_sub(before - from)))
+}
+
+// Collect the mappings from a patch log. Patches that disagree about
+// where a region goes are reported, and counted.
+fn mappings<'a>(
+    map: &'a MemoryMap,
+    name: &str,
+    log: &[Applied],
+    problems: &mut usize,
+) -> BTreeMap<&'a str, Mapping> {
+    let mut mappings = BTreeMap::<&str, Mapping>::new();
+    for applied in log.iter() {
+        let Some((region, start)) = mapping(map, applied) else {
+            continue;
+        };
+        match mappings.get_mut(region.name.as_str()) {
+            Some(mapping) if mapping.start != start => {
+                println!(
+                    "  {} +0x{:05x}: moves {} to 0x{:06x}, elsewhere to 0x{:06x}",
+                    name, applied.addr, region.name, start, mapping.start
+                );
+                *problems += 1;
+            }
+            Some(mapping) => mapping.count += 1,
+            None => {
+                mappings.insert(region.name.as_str(), Mapping { start, count: 1 });
+            }
+        }
+    }
+    mappings
+}
+
+fn print_mappings(mappings: &BTreeMap<&str, Mapping>) {
+    for (name, mapping) in mappings.iter() {
+        println!(
+            "  {:<10} -> 0x{:06x} ({} patches)",
+            name, mapping.start, mapping.count
+        );
+    }
+}
+
+// Regions the ROM has stopped using, as the paravirtual devices have
+// taken over from the hardware.
+fn replaced(machine: &Machine) -> Vec<(&'static str, &'static str)> {
+    let mut replaced = Vec::new();
+    if machine.floppy_mailbox.is_some() {
+        replaced.push(("IWM", "the paravirtual floppy driver"));
+    }
+    if machine.scsi_mailbox.is_some() {
+        replaced.push(("SCSI", "the paravirtual SCSI Manager"));
+    }
+    replaced
+}
+
+// Check one set of System patches' mappings against the ROM's.
+fn compare(
+    name: &str,
+    mappings: &BTreeMap<&str, Mapping>,
+    rom_mappings: &BTreeMap<&str, Mapping>,
+    replaced: &[(&str, &str)],
+    problems: &mut usize,
+) {
+    for (region, mapping) in mappings.iter() {
+        match rom_mappings.get(region) {
+            None => {
+                println!("  {} moves {}, which the ROM patches don't", name, region);
+                *problems += 1;
+            }
+            Some(rom_mapping) if rom_mapping.start != mapping.start => {
+                println!(
+                    "  {} moves {} to 0x{:06x}, but the ROM moves it to 0x{:06x}",
+                    name, region, mapping.start, rom_mapping.start
+                );
+                *problems += 1;
+            }
+            Some(_) => {}
+        }
+        if let Some((_, by)) = replaced.iter().find(|(r, _)| r == region) {
+            println!(
+                "  {} uses {}, which the ROM has replaced with {}",
+                name, region, by
+            );
+            *problems += 1;
+        }
+    }
+}
+
+pub fn check(rom_base: usize, system: &SystemPatchSet, machine: &Machine) -> anyhow::Result<()> {
+    let map = MemoryMap::standard();
+    let mut problems = 0;
+
+    let mut rom = fs::read("../../ROM.sefdhd")?;
+    let (log, _) = apply_rom_patches(&mut rom, rom_base, machine)?;
+    println!();
+    println!("ROM patches:");
+    let rom_mappings = mappings(map, "ROM", &log, &mut problems);
+    print_mappings(&rom_mappings);
+    ensure!(
+        rom_mappings.contains_key("ROM"),
+        "The ROM patches don't relocate the ROM"
+    );
+    let replaced = replaced(machine);
+
+    for res in system.resources.iter() {
+        let name = format!("{} {}", res.res_type, res.res_id);
+        let mut data = fs::read(format!("{}/{}_{}", system.dir, res.res_type, res.res_id))?;
+        let mut log = Vec::new();
+        res.patch_data(&mut data, rom_base, machine, &mut log)?;
+        println!();
+        println!("System {} {}:", system.version, name);
+        let res_mappings = mappings(map, &name, &log, &mut problems);
+        print_mappings(&res_mappings);
+        compare(
+            &name,
+            &res_mappings,
+            &rom_mappings,
+            &replaced,
+            &mut problems,
+        );
+    }
+
+    // The patches by type are generic, so they're checked on sample
+    // code rather than resources from a disk.
+    for patch in system.type_patches.iter() {
+        let name = format!("'{}' in '{}' files", patch.res_type, patch.file_type);
+        let mut data = patch.sample();
+        let mut log = Vec::new();
+        patch.patch_data(&mut data, rom_base, &mut log);
+        println!();
+        println!("System {} {}:", system.version, name);
+        let type_mappings = mappings(map, &name, &log, &mut problems);
+        print_mappings(&type_mappings);
+        compare(
+            &name,
+            &type_mappings,
+            &rom_mappings,
+            &replaced,
+            &mut problems,
+        );
+    }
+
+    println!();
+    if problems != 0 {
+        bail!(
+            "{} inconsistencies between the ROM and System {} patches",
+            problems,
+            system.version
+        );
+    }
+    println!(
+        "The ROM and System {} patches are consistent",
+        system.version
+    );
+    Ok(())
+}
@@ -0,0 +1,473 @@
+//
+// System disk scan
+//
+// The System patches only cover the resources we've looked at by
+// hand. This goes through every file on a System disk, and every
+// code-bearing resource in them (plus the boot blocks), looking for
+// longs that are absolute ROM addresses, or addresses in the hardware
+// regions the memory map moves. Each hit is disassembled in place, and
+// checked against the System's resource patches. Hits in instructions
+// that aren't patched yet are printed as Patch entries, ready to paste
+// into the resource patch tables.
+//
+// Longs that don't disassemble as part of an instruction are counted
+// as data. The instruction hits that turned out not to be references
+// (placeholders filled in at install time, and constants that happen
+// to look like ROM addresses) are listed as reviewed, so that the scan
+// only fails on new ones.
+//
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{ensure, Context};
+use memory_map::{MemoryMap, Region};
+
+use crate::hfs::{self, Resource};
+use crate::{
+    system_patch_set, Applied, Category, SystemPatchSet, DEFAULT_MACHINE, PATCHED_ROM_BASE,
+};
+
+// Resource types that hold 68000 code. Hits in the others are just
+// data.
+const CODE_TYPES: [&str; 23] = [
+    "ADBS", "boot", "CACH", "CDEF", "cdev", "CODE", "DRVR", "FKEY", "INIT", "LDEF", "lmgr", "lpch",
+    "MBDF", "MDEF", "PACK", "PDEF", "PTCH", "ptch", "RDEV", "SERD", "snth", "WDEF", "XPRT",
+];
+
+// How far back from a hit to look for the start of the instruction
+// holding it. Enough for an opcode and two extension words.
+const MAX_LEAD: usize = 6;
+
+// Instruction hits that have been looked at, and aren't references
+// to patch.
+pub struct Reviewed {
+    // The file's name, without its folder.
+    file: &'static str,
+    res_type: &'static str,
+    res_id: i16,
+    addrs: &'static [usize],
+    why: &'static str,
+}
+
+pub const SYSTEM_601_REVIEWED: [Reviewed; 7] = [
+    Reviewed {
+        file: "Installer",
+        res_type: "CODE",
+        res_id: 2,
+        addrs: &[0x01c2e],
+        why: "item number and type for _SetDItem",
+    },
+    Reviewed {
+        file: "Installer",
+        res_type: "CODE",
+        res_id: 12,
+        addrs: &[0x01198],
+        why: "result space and updateMask for _GetNextEvent",
+    },
+    Reviewed {
+        file: "System",
+        res_type: "PTCH",
+        res_id: 1660,
+        addrs: &[0x01640],
+        why: "offset from movea.l $42(a3),a0, and the next opcode",
+    },
+    Reviewed {
+        file: "System",
+        res_type: "ptch",
+        res_id: 0,
+        addrs: &[0x02b98],
+        why: "offset from movea.l $42(a3),a0, and the next opcode",
+    },
+    Reviewed {
+        file: "System",
+        res_type: "ptch",
+        res_id: 7,
+        addrs: &[0x00038, 0x00054, 0x0005c, 0x00066, 0x00070, 0x00078],
+        why: "placeholder, filled in with a trap address when installed",
+    },
+    Reviewed {
+        file: "System",
+        res_type: "INIT",
+        res_id: 3,
+        addrs: &[0x00054, 0x00062],
+        why: "placeholder, filled in by _GetTrapAddress when installed",
+    },
+    Reviewed {
+        file: "System",
+        res_type: "PACK",
+        res_id: 2,
+        addrs: &[0x0047a],
+        why: "mask of progress indicator segments",
+    },
+];
+
+fn read_long(data: &[u8], addr: usize) -> u32 {
+    u32::from_be_bytes(data[addr..addr + 4].try_into().unwrap())
+}
+
+fn read_word(data: &[u8], addr: usize) -> u16 {
+    if addr + 1 < data.len() {
+        u16::from_be_bytes([data[addr], data[addr + 1]])
+    } else {
+        0
+    }
+}
+
+// The patch category for references into a region.
+fn category(region: &Region) -> Option<Category> {
+    match region.name.as_str() {
+        "ROM" => Some(Category::RomReference),
+        "DEBUG" => Some(Category::DebugHook),
+        "SCSI" => Some(Category::Scsi),
+        "SCC_READ" => Some(Category::SccRead),
+        "SCC_WRITE" => Some(Category::SccWrite),
+        "IWM" => Some(Category::Iwm),
+        "VIA" => Some(Category::Via),
+        _ => None,
+    }
+}
+
+// The moved region a long points into on an unmodified SE. As with
+// the consistency check, the address just past the end of a region
+// (like the end of the ROM) counts as being in it.
+fn region_for(map: &MemoryMap, value: u32) -> Option<&Region> {
+    if value >> 24 != 0 {
+        return None;
+    }
+    map.regions.iter().find(|r| match r.moved_from {
+        Some(from) => (from..=from + r.size()).contains(&value),
+        None => false,
+    })
+}
+
+// The instruction a long is an operand of: the nearest start before it
+// that disassembles to an instruction covering the long and showing its
+// value. Returns the start and the text.
+fn instruction(data: &[u8], addr: usize, value: u32) -> Option<(usize, String)> {
+    let needle = format!("${:x}", value);
+    (2..=MAX_LEAD.min(addr)).step_by(2).find_map(|lead| {
+        let start = addr - lead;
+        let (text, len) = disasm::disassemble(start as u32, |a| read_word(data, a as usize));
+        (start + len as usize >= addr + 4 && text.contains(&needle)).then_some((start, text))
+    })
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("0x{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// What's known about a hit.
+enum Status {
+    // Covered by one of the System's patches.
+    Patched(Category),
+    Reviewed(&'static str),
+    // In an instruction, and not looked at yet.
+    New,
+    // Not in an instruction.
+    Data,
+}
+
+struct Hit {
+    addr: usize,
+    value: u32,
+    region: &'static str,
+    category: Category,
+    // Start and text of the instruction the long is an operand of.
+    insn: Option<(usize, String)>,
+    status: Status,
+}
+
+impl Hit {
+    // The table entry that would patch this hit, in the style of the
+    // existing resource patches: ROM references patch the top word of
+    // the long, hardware the two bytes after the top one, counted from
+    // the start of the instruction.
+    fn patch(&self, map: &MemoryMap) -> String {
+        let moved = match self.category {
+            Category::RomReference => {
+                self.value - memory_map::ROM_MOVED_FROM + memory_map::ROM_START
+            }
+            _ => map.moved(self.value).unwrap_or(self.value),
+        };
+        let (before, after) = (self.value.to_be_bytes(), moved.to_be_bytes());
+        let (addr, before, after) = match (self.category, &self.insn) {
+            (Category::RomReference, _) => {
+                (format!("0x{:04x}", self.addr), &before[..2], &after[..2])
+            }
+            (_, Some((start, _))) => (
+                format!("0x{:04x} + {}", start, self.addr + 1 - start),
+                &before[1..3],
+                &after[1..3],
+            ),
+            (_, None) => (
+                format!("0x{:04x}", self.addr + 1),
+                &before[1..3],
+                &after[1..3],
+            ),
+        };
+        let comment = match &self.insn {
+            Some((_, text)) => format!("    // {}\n", text),
+            None => String::new(),
+        };
+        format!(
+            "{}    Patch {{\n        category: Category::{:?},\n        addr: {},\n        \
+             before: &[{}],\n        after: &[{}],\n    }},\n",
+            comment,
+            self.category,
+            addr,
+            hex_bytes(before),
+            hex_bytes(after)
+        )
+    }
+}
+
+// Find the hits in a piece of code. Patched hits are those where a
+// patch from 'log' overlaps the long.
+fn scan_code(
+    map: &'static MemoryMap,
+    data: &[u8],
+    log: &[Applied],
+    reviewed: Option<&Reviewed>,
+) -> Vec<Hit> {
+    let mut hits = Vec::new();
+    for addr in (0..data.len().saturating_sub(3)).step_by(2) {
+        let value = read_long(data, addr);
+        let Some(region) = region_for(map, value) else {
+            continue;
+        };
+        let Some(category) = category(region) else {
+            continue;
+        };
+        let insn = instruction(data, addr, value);
+        let patched = log
+            .iter()
+            .find(|a| a.addr < addr + 4 && addr < a.addr + a.after.len());
+        let status = match (patched, reviewed) {
+            (Some(applied), _) => Status::Patched(applied.category),
+            (None, Some(r)) if r.addrs.contains(&addr) => Status::Reviewed(r.why),
+            _ if insn.is_some() => Status::New,
+            _ => Status::Data,
+        };
+        hits.push(Hit {
+            addr,
+            value,
+            region: &region.name,
+            category,
+            insn,
+            status,
+        });
+    }
+    hits
+}
+
+// Apply the System's patches for a resource to a copy of its data,
+// for the log of what they cover, and whether there are any.
+fn patch_log(
+    system: &SystemPatchSet,
+    file_type: &str,
+    res: &Resource,
+    data: &[u8],
+) -> anyhow::Result<(Vec<Applied>, bool)> {
+    let mut log = Vec::new();
+    let patched = system.patch_resource(
+        file_type,
+        res,
+        &mut data.to_vec(),
+        PATCHED_ROM_BASE,
+        &DEFAULT_MACHINE,
+        &mut log,
+    )?;
+    Ok((log, patched))
+}
+
+// What's been found in one resource, or the boot blocks.
+struct Scanned {
+    file: String,
+    what: String,
+    length: usize,
+    prefix: Vec<u8>,
+    res: Option<(String, i16)>,
+    // Whether the System already has patches for it.
+    has_patches: bool,
+    hits: Vec<Hit>,
+}
+
+fn print_hits(scanned: &[Scanned], show_data: bool) {
+    let mut last_file = "";
+    for s in scanned.iter() {
+        let hits = s
+            .hits
+            .iter()
+            .filter(|h| show_data || !matches!(h.status, Status::Data))
+            .collect::<Vec<_>>();
+        if hits.is_empty() {
+            continue;
+        }
+        if s.file != last_file {
+            println!();
+            println!("{}", s.file);
+            last_file = &s.file;
+        }
+        println!("  {}", s.what);
+        for hit in hits.iter() {
+            let status = match hit.status {
+                Status::Patched(category) => format!("patched ({:?})", category),
+                Status::Reviewed(why) => format!("reviewed: {}", why),
+                Status::New => "NEW".to_string(),
+                Status::Data => "data".to_string(),
+            };
+            let insn = match &hit.insn {
+                Some((start, text)) => format!("0x{:05x}: {}", start, text),
+                None => "-".to_string(),
+            };
+            println!(
+                "    0x{:05x} 0x{:06x} {:<9} {:<36} {}",
+                hit.addr, hit.value, hit.region, insn, status
+            );
+        }
+    }
+}
+
+// Print the new hits as tables to add, with the ResourcePatch entries
+// to go with them, or just the entries to add to the existing tables.
+fn print_suggestions(map: &MemoryMap, scanned: &[Scanned]) {
+    for s in scanned.iter() {
+        let new = s
+            .hits
+            .iter()
+            .filter(|h| matches!(h.status, Status::New))
+            .collect::<Vec<_>>();
+        if new.is_empty() {
+            continue;
+        }
+        println!();
+        println!("// {}, {}", s.file, s.what);
+        let Some((res_type, res_id)) = s.res.as_ref().filter(|_| !s.has_patches) else {
+            for hit in new.iter() {
+                print!("{}", hit.patch(map));
+            }
+            continue;
+        };
+        let name = format!("{}_{}", res_type.to_uppercase(), res_id).replace('-', "M");
+        println!("const {}_PATCHES: [Patch; {}] = [", name, new.len());
+        for hit in new.iter() {
+            print!("{}", hit.patch(map));
+        }
+        println!("];");
+        println!();
+        println!("    ResourcePatch {{");
+        println!("        res_type: \"{}\",", res_type);
+        println!("        res_id: {},", res_id);
+        println!("        patch_imm_ops: false,");
+        println!("        patches: &{}_PATCHES,", name);
+        println!("        high_ram: &highram::NO_SITES,");
+        println!("        length: 0x{:x},", s.length);
+        println!("        prefix: &[{}],", hex_bytes(&s.prefix));
+        println!("    }},");
+    }
+}
+
+// Scan a disk, checking against the patches for the given System
+// version, or the one on the disk.
+pub fn scan(
+    disk: &Path,
+    version: Option<&str>,
+    show_data: bool,
+    untested: bool,
+) -> anyhow::Result<()> {
+    let map = MemoryMap::standard();
+    let image = fs::read(disk).with_context(|| format!("Couldn't read {}", disk.display()))?;
+    let volume = hfs::read(&image)?;
+    let version = match version {
+        Some(version) => version.to_string(),
+        None => volume.system_version(&image)?,
+    };
+    let system = system_patch_set(&version, untested)?;
+
+    // The boot blocks hold the 'boot' 1 code, and get its patches.
+    let boot = &image[volume.offset..volume.offset + hfs::BOOT_BLOCKS_SIZE];
+    let mut log = Vec::new();
+    let boot_patch = system.resource_patch(hfs::SYSTEM_FILE_TYPE, "boot", 1);
+    if let Some(res) = boot_patch {
+        res.patch_data(
+            &mut boot.to_vec(),
+            PATCHED_ROM_BASE,
+            &DEFAULT_MACHINE,
+            &mut log,
+        )?;
+    }
+    let mut scanned = vec![Scanned {
+        file: volume.name.clone(),
+        what: "boot blocks".to_string(),
+        length: boot.len(),
+        prefix: boot[..4].to_vec(),
+        res: None,
+        has_patches: boot_patch.is_some(),
+        hits: scan_code(map, boot, &log, None),
+    }];
+
+    for file in volume.files.iter() {
+        let fork = file.rsrc.read(&image);
+        let resources = hfs::resources(&fork)
+            .with_context(|| format!("Couldn't read the resources in {}", file.path))?;
+        let name = file.path.rsplit(':').next().unwrap_or_default();
+        for res in resources.iter() {
+            if !CODE_TYPES.contains(&res.res_type.as_str()) {
+                continue;
+            }
+            let Resource { offset, size, .. } = *res;
+            let data = &fork[offset..offset + size];
+            let (log, has_patches) = patch_log(system, &file.file_type, res, data)?;
+            let reviewed = system
+                .reviewed
+                .iter()
+                .find(|r| r.file == name && r.res_type == res.res_type && r.res_id == res.id);
+            let hits = scan_code(map, data, &log, reviewed);
+            scanned.push(Scanned {
+                file: file.path.clone(),
+                what: res.label(),
+                length: size,
+                prefix: data[..size.min(4)].to_vec(),
+                res: Some((res.res_type.clone(), res.id)),
+                has_patches,
+                hits,
+            });
+        }
+    }
+
+    println!();
+    println!(
+        "Volume \"{}\": {} files, {} code resources, checked against the System {} patches",
+        volume.name,
+        volume.files.len(),
+        scanned.len() - 1,
+        system.version
+    );
+    print_hits(&scanned, show_data);
+
+    let hits = scanned.iter().flat_map(|s| s.hits.iter());
+    let count = |f: fn(&Status) -> bool| hits.clone().filter(|h| f(&h.status)).count();
+    let new = count(|s| matches!(s, Status::New));
+    println!();
+    println!(
+        "{} hits: {} patched, {} reviewed, {} new, {} in data",
+        hits.clone().count(),
+        count(|s| matches!(s, Status::Patched(_))),
+        count(|s| matches!(s, Status::Reviewed(_))),
+        new,
+        count(|s| matches!(s, Status::Data)),
+    );
+
+    print_suggestions(map, &scanned);
+    ensure!(
+        new == 0,
+        "{} hits in instructions haven't been patched or reviewed",
+        new
+    );
+    Ok(())
+}
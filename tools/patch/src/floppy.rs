@@ -0,0 +1,77 @@
+//
+// Paravirtual floppy driver
+//
+// Our clone has no IWM, so this replaces the `.Sony` driver with one
+// that reads and writes whole blocks through a mailbox in the I/O
+// space (see floppy.s for the protocol). The new driver goes in the
+// code cave, behind a block header of its own, and the resource map's
+// entry for DRVR 4 is pointed at it, so that the ROM's `_Open` of
+// ".Sony" finds it. The old driver is left where it is: the disk code
+// after the resources still refers to it, and System 6.0.1 checks its
+// version byte before patching it, which is harmless as nothing calls
+// it any more.
+//
+
+use anyhow::{anyhow, Context};
+
+use crate::asm;
+use crate::cave::Cave;
+use crate::{Applied, Category};
+
+const SOURCE: &str = include_str!("floppy.s");
+
+// Where the mailbox goes if no address is given, and its registers, as
+// far as the address decoding goes.
+pub const DEFAULT_MAILBOX: &str = memory_map::FLOPPY_MAILBOX_START_HEX;
+pub const MAILBOX_SIZE: usize = memory_map::FLOPPY_MAILBOX_SIZE as usize;
+
+pub fn parse_mailbox(s: &str) -> Result<usize, String> {
+    crate::parse_io_address(s, "Floppy mailbox", MAILBOX_SIZE)
+}
+
+// Put the driver in the cave and point `.Sony` at it.
+pub fn patch(
+    data: &mut [u8],
+    rom_base: usize,
+    mailbox: usize,
+    cave: &mut Cave,
+    log: &mut Vec<Applied>,
+) -> anyhow::Result<()> {
+    println!(
+        "Replacing .Sony with the paravirtual floppy driver, mailbox at 0x{:06x}",
+        mailbox
+    );
+    let source = format!("Mailbox equ ${:06x}\n{}", mailbox, SOURCE);
+    let externals = cave.externals(data, rom_base)?;
+    let len = asm::size(&source, &externals).context("Couldn't assemble the floppy driver")?;
+    let size = rom_resources::block_size(len);
+    let block = cave.alloc("FloppyDriver", size)?;
+    let start = block + rom_resources::BLOCK_HEADER_SIZE;
+    let code = asm::assemble(&source, (rom_base + start) as u32, &externals)
+        .context("Couldn't assemble the floppy driver")?;
+
+    let map = rom_resources::parse(data)?;
+    let idx = map
+        .resources
+        .iter()
+        .position(|r| r.res_type == "DRVR" && r.name.as_deref() == Some(".Sony"))
+        .ok_or_else(|| anyhow!("No .Sony driver to replace"))?;
+    let entry = map.resources[idx].ref_entry + 4;
+
+    let before = data[block..block + size].to_vec();
+    let entry_before = data[entry..entry + 4].to_vec();
+    rom_resources::repoint(data, &map, idx, block, &code)?;
+    log.push(Applied {
+        addr: block,
+        category: Category::NewCode,
+        before,
+        after: data[block..block + size].to_vec(),
+    });
+    log.push(Applied {
+        addr: entry,
+        category: Category::Floppy,
+        before: entry_before,
+        after: data[entry..entry + 4].to_vec(),
+    });
+    Ok(())
+}
@@ -0,0 +1,281 @@
+* Paravirtual floppy driver
+*
+* Replaces the ROM's .Sony driver, which drives the IWM, with one that
+* moves whole 512-byte blocks through a mailbox at 'Mailbox' (defined
+* by the patch tool):
+*
+*   +0   long  Block number. A Size command leaves the block count here.
+*   +4   long  Buffer address, for Read and Write.
+*   +8   byte  Command, written last to start it.
+*   +9   byte  Status: 0 done, 1 busy, $80 and up for errors: $80 no
+*              disk, $81 bad block, $82 locked, $83 bad command or
+*              buffer, $84 I/O error on the host.
+*   +10  byte  Drive: bit 0 set if there's a disk, bit 1 if it's locked.
+*
+* There's one drive, drive 1. A VBL task looks for a disk every half
+* second while there isn't one, and posts a disk-inserted event when it
+* finds one. The mailbox is only used by the VBL task while there's no
+* disk, and by everything else while there is, so they never clash.
+*
+
+CmdRead         equ     1
+CmdWrite        equ     2
+CmdSize         equ     3
+CmdEject        equ     4
+
+StsBusy         equ     1
+StsNoDisk       equ     $80
+StsBadBlock     equ     $81
+StsLocked       equ     $82
+
+DrvPresent      equ     0
+DrvLocked       equ     1
+
+* Parameter block and DCE fields.
+ioTrap          equ     6
+ioBuffer        equ     $20
+ioReqCount      equ     $24
+ioActCount      equ     $28
+csCode          equ     $1a
+csParam         equ     $1c
+dCtlPosition    equ     $10
+dCtlStorage     equ     $14
+dCtlRefNum      equ     $18
+
+* Error codes.
+controlErr      equ     -17
+statusErr       equ     -18
+closErr         equ     -24
+wPrErr          equ     -44
+paramErr        equ     -50
+offLinErr       equ     -65
+ioErr           equ     -36
+
+* Globals, pointed to by dCtlStorage. The drive queue element is
+* preceded by its flags: locked, disk in place, installed and sides.
+Flags           equ     0
+Drive           equ     4
+VBLTask         equ     20
+GlobalsSize     equ     34
+
+DriveNum        equ     1
+
+Header:
+        dc.w    $4f00                   ; dNeedLock, status, control, write, read
+        dc.w    0                       ; No delay
+        dc.w    0                       ; No events
+        dc.w    0                       ; No menu
+        dc.w    Open-Header
+        dc.w    Prime-Header
+        dc.w    Control-Header
+        dc.w    Status-Header
+        dc.w    Close-Header
+        dc.b    5,'.Sony'
+        even
+
+* Set up the globals, add the drive and start looking for a disk.
+Open:
+        tst.l   dCtlStorage(a1)         ; Already open?
+        bne.s   OpenDone
+        movem.l a0-a2,-(sp)
+        moveq   #GlobalsSize,d0
+        aline   #$a71e                  ; _NewPtr ,Sys,Clear
+        bne.s   OpenFailed
+        movea.l 4(sp),a1
+        move.l  a0,dCtlStorage(a1)
+        movea.l a0,a2
+
+        move.b  #1,Flags+2(a2)          ; Installed
+        move.b  #$80,Flags+3(a2)        ; Double-sided
+        move.w  #1,Drive+4(a2)          ; qType: size is in dQDrvSz and dQDrvSz2
+        lea     Drive(a2),a0
+        move.w  #DriveNum,d0
+        swap    d0
+        move.w  dCtlRefNum(a1),d0
+        _AddDrive
+
+        lea     VBLTask(a2),a0
+        move.w  #1,4(a0)                ; qType: vType
+        lea     CheckDisk(pc),a1
+        move.l  a1,6(a0)                ; vblAddr
+        move.w  #1,10(a0)               ; vblCount: first look on the next tick
+        _VInstall
+OpenFailed:
+        movem.l (sp)+,a0-a2
+        rts
+OpenDone:
+        moveq   #0,d0
+        rts
+
+* The drive can't be closed.
+Close:
+        moveq   #closErr,d0
+        rts
+
+* Read or write whole blocks from dCtlPosition.
+Prime:
+        movem.l d3-d5/a2-a4,-(sp)
+        movea.l dCtlStorage(a1),a2
+        clr.l   ioActCount(a0)
+        moveq   #offLinErr,d0
+        tst.b   Flags+1(a2)             ; Disk in place?
+        beq.s   PrimeDone
+        moveq   #paramErr,d0
+        move.l  dCtlPosition(a1),d3
+        move.l  ioReqCount(a0),d4
+        move.l  d3,d1
+        or.l    d4,d1
+        andi.w  #$1ff,d1                ; Whole blocks only
+        bne.s   PrimeDone
+        moveq   #9,d1
+        lsr.l   d1,d3                   ; First block
+        lsr.l   d1,d4                   ; Block count
+        moveq   #CmdRead,d5
+        cmpi.b  #3,ioTrap+1(a0)         ; _Write?
+        bne.s   PrimeStart
+        moveq   #CmdWrite,d5
+PrimeStart:
+        movea.l ioBuffer(a0),a3
+        lea     Mailbox,a4
+        moveq   #0,d0
+        bra.s   PrimeNext
+PrimeLoop:
+        move.l  d3,(a4)
+        move.l  a3,4(a4)
+        move.b  d5,8(a4)
+        bsr.s   Wait
+        bne.s   PrimeDone
+        addq.l  #1,d3
+        lea     512(a3),a3
+        move.l  #512,d1
+        add.l   d1,ioActCount(a0)
+        add.l   d1,dCtlPosition(a1)
+PrimeNext:
+        subq.l  #1,d4
+        bcc.s   PrimeLoop
+PrimeDone:
+        movem.l (sp)+,d3-d5/a2-a4
+        bra.s   Done
+
+* Wait for the command in the mailbox at A4 to finish, and turn its
+* status into a result code in D0, setting the flags from it.
+Wait:
+        move.b  9(a4),d0
+        cmpi.b  #StsBusy,d0
+        beq.s   Wait
+        tst.b   d0
+        beq.s   WaitDone
+        cmpi.b  #StsNoDisk,d0
+        beq.s   WaitNoDisk
+        cmpi.b  #StsBadBlock,d0
+        beq.s   WaitBadBlock
+        cmpi.b  #StsLocked,d0
+        beq.s   WaitLocked
+        moveq   #ioErr,d0
+        rts
+WaitNoDisk:
+        moveq   #offLinErr,d0
+        rts
+WaitBadBlock:
+        moveq   #paramErr,d0
+        rts
+WaitLocked:
+        moveq   #wPrErr,d0
+WaitDone:
+        rts
+
+* Finish a Prime, Control or Status call with the result in D0, going
+* through IODone unless it was an immediate call.
+Done:
+        move.w  ioTrap(a0),d1
+        btst    #9,d1                   ; noQueueBit
+        bne.s   DoneImmediate
+        move.l  ($8fc).w,-(sp)          ; JIODone
+DoneImmediate:
+        rts
+
+* Eject, plus the calls the File Manager and Disk Initialization make
+* that have nothing to do here: KillIO, verify, format, set tag buffer
+* and track cache control.
+Control:
+        move.l  a2,-(sp)
+        movea.l dCtlStorage(a1),a2
+        move.w  csCode(a0),d1
+        moveq   #0,d0
+        cmpi.w  #1,d1
+        beq.s   ControlDone
+        cmpi.w  #5,d1
+        blt.s   ControlBad
+        cmpi.w  #7,d1
+        bne.s   ControlOther
+        bsr.s   Eject
+        bra.s   ControlDone
+ControlOther:
+        cmpi.w  #9,d1
+        ble.s   ControlDone
+ControlBad:
+        moveq   #controlErr,d0
+ControlDone:
+        movea.l (sp)+,a2
+        bra.s   Done
+
+Eject:
+        tst.b   Flags+1(a2)
+        beq.s   EjectDone
+        move.l  a4,-(sp)
+        lea     Mailbox,a4
+        move.b  #CmdEject,8(a4)
+        bsr     Wait
+        movea.l (sp)+,a4
+        clr.b   Flags+1(a2)             ; No disk in place
+EjectDone:
+        rts
+
+* Drive status, from the flags and the drive queue element.
+Status:
+        moveq   #statusErr,d0
+        cmpi.w  #8,csCode(a0)
+        bne.s   Done
+        movem.l a1-a2,-(sp)
+        movea.l dCtlStorage(a1),a2
+        lea     csParam(a0),a1
+        clr.w   (a1)+                   ; Track
+        move.l  Flags(a2),(a1)+         ; Locked, in place, installed, sides
+        move.l  Drive(a2),(a1)+         ; qLink
+        move.l  Drive+4(a2),(a1)+       ; qType, dQDrive
+        move.l  Drive+8(a2),(a1)+       ; dQRefNum, dQFSID
+        move.b  Flags+3(a2),(a1)+       ; twoSideFmt
+        clr.b   (a1)+                   ; needsFlush
+        clr.w   (a1)+                   ; diskErrs
+        movem.l (sp)+,a1-a2
+        moveq   #0,d0
+        bra     Done
+
+* VBL task, with A0 pointing to it: look for a disk, and if there is
+* one, get its size and tell the world.
+CheckDisk:
+        move.w  #30,10(a0)              ; Look again in half a second
+        lea     -VBLTask(a0),a1
+        tst.b   Flags+1(a1)
+        bne.s   CheckDone
+        lea     Mailbox,a2
+        btst    #DrvPresent,10(a2)
+        beq.s   CheckDone
+        move.b  #CmdSize,8(a2)
+CheckWait:
+        cmpi.b  #StsBusy,9(a2)
+        beq.s   CheckWait
+        tst.b   9(a2)
+        bne.s   CheckDone
+        move.l  (a2),d0
+        move.w  d0,Drive+12(a1)         ; dQDrvSz
+        swap    d0
+        move.w  d0,Drive+14(a1)         ; dQDrvSz2
+        btst    #DrvLocked,10(a2)
+        sne     Flags(a1)
+        move.b  #1,Flags+1(a1)          ; Disk in place
+        movea.w #7,a0                   ; diskEvt
+        moveq   #DriveNum,d0
+        _PostEvent
+CheckDone:
+        rts
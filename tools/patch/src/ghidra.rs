@@ -0,0 +1,374 @@
+//
+// Ghidra interop
+//
+// Reads comment and bookmark exports from the Ghidra disassembly, so
+// that the markings there can be checked against the patches, and
+// writes scripts that annotate the patched ROM.
+//
+
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+
+use crate::Applied;
+
+// Base address the ROM is loaded at in the Ghidra disassembly.
+const GHIDRA_ROM_BASE: usize = memory_map::ROM_MOVED_FROM as usize;
+const ROM_SIZE: usize = memory_map::ROM_SIZE as usize;
+
+// A comment or bookmark in the disassembly, at a ROM offset.
+#[derive(Debug)]
+pub struct Marking {
+    pub addr: usize,
+    pub text: String,
+}
+
+// Read the comments and bookmarks from a Ghidra export. Both the XML
+// export and CSV tables (e.g. exported from the bookmarks window) are
+// understood.
+pub fn read_markings(path: &Path) -> anyhow::Result<Vec<Marking>> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Couldn't read {}", path.display()))?;
+    parse_markings(&text)
+}
+
+fn parse_markings(text: &str) -> anyhow::Result<Vec<Marking>> {
+    let raw = if text.trim_start().starts_with('<') {
+        read_xml(text)?
+    } else {
+        read_csv(text)?
+    };
+
+    // Only keep the markings that land in the ROM.
+    let mut markings = Vec::new();
+    for (addr, text) in raw {
+        if (GHIDRA_ROM_BASE..GHIDRA_ROM_BASE + ROM_SIZE).contains(&addr) {
+            markings.push(Marking {
+                addr: addr - GHIDRA_ROM_BASE,
+                text,
+            });
+        }
+    }
+    markings.sort_by_key(|m| m.addr);
+    Ok(markings)
+}
+
+// Parse an address as Ghidra writes it, e.g. "ram:00400136" or
+// "0x400136". Short strings are rejected so that words that happen to
+// be hex ("Face") aren't mistaken for addresses.
+fn parse_addr(s: &str) -> Option<usize> {
+    let s = s.trim();
+    let s = s.rsplit(':').next()?;
+    let s = s.trim_start_matches("0x");
+    if s.len() < 6 {
+        return None;
+    }
+    usize::from_str_radix(s, 16).ok()
+}
+
+////////////////////////////////////////////////////////////////////////
+// XML export.
+//
+// We only need the COMMENT and BOOKMARK elements, so rather than pull
+// in a full XML parser we just pick those out.
+//
+
+fn read_xml(text: &str) -> anyhow::Result<Vec<(usize, String)>> {
+    let mut result = Vec::new();
+
+    for tag in ["COMMENT", "BOOKMARK"] {
+        let open = format!("<{}", tag);
+        let close = format!("</{}>", tag);
+        let mut rest = text;
+        while let Some(start) = rest.find(&open) {
+            rest = &rest[start + open.len()..];
+            // Skip e.g. "<COMMENTS>".
+            if !rest.starts_with(|c: char| c.is_whitespace()) {
+                continue;
+            }
+            let Some(end) = rest.find('>') else {
+                bail!("Unterminated <{}> element", tag);
+            };
+            let attrs = &rest[..end];
+            rest = &rest[end + 1..];
+
+            let mut body = String::new();
+            if !attrs.ends_with('/') {
+                let Some(body_end) = rest.find(&close) else {
+                    bail!("Missing {}", close);
+                };
+                body = unescape(&rest[..body_end]);
+                rest = &rest[body_end + close.len()..];
+            }
+
+            let Some(addr) = get_attr(attrs, "ADDRESS").and_then(|a| parse_addr(&a)) else {
+                bail!("<{}> without a valid address: {}", tag, attrs);
+            };
+            // Bookmarks keep their text in attributes.
+            for attr in ["CATEGORY", "DESCRIPTION"] {
+                if let Some(value) = get_attr(attrs, attr) {
+                    body.push(' ');
+                    body.push_str(&value);
+                }
+            }
+            result.push((addr, body));
+        }
+    }
+
+    Ok(result)
+}
+
+fn get_attr(attrs: &str, name: &str) -> Option<String> {
+    let key = format!("{}=\"", name);
+    let start = attrs.find(&key)? + key.len();
+    let len = attrs[start..].find('"')?;
+    Some(unescape(&attrs[start..][..len]))
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+////////////////////////////////////////////////////////////////////////
+// CSV export.
+//
+// The address is taken from a "Location" or "Address" column if there's
+// a header (a first row with no addresses in it), otherwise the first
+// field that looks like an address. The
+// whole row is kept as the text, so it doesn't matter which column the
+// comment lives in.
+//
+
+fn read_csv(text: &str) -> anyhow::Result<Vec<(usize, String)>> {
+    let mut lines = text.lines().filter(|l| !l.trim().is_empty()).peekable();
+
+    let mut addr_col = None;
+    if let Some(header) = lines.peek() {
+        let fields = split_csv(header);
+        if !fields.iter().any(|f| parse_addr(f).is_some()) {
+            addr_col = fields.iter().position(|f| {
+                let f = f.to_lowercase();
+                f == "location" || f.contains("address")
+            });
+        }
+        if addr_col.is_some() {
+            lines.next();
+        }
+    }
+
+    let mut result = Vec::new();
+    for line in lines {
+        let fields = split_csv(line);
+        let addr = match addr_col {
+            Some(col) => fields.get(col).and_then(|f| parse_addr(f)),
+            None => fields.iter().find_map(|f| parse_addr(f)),
+        };
+        let Some(addr) = addr else {
+            bail!("No address found in line: {}", line);
+        };
+        result.push((addr, fields.join(" ")));
+    }
+
+    Ok(result)
+}
+
+fn split_csv(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+////////////////////////////////////////////////////////////////////////
+// Annotation script.
+//
+// Generates a Ghidra Python script to be run against the patched ROM,
+// that bookmarks and comments each patch, and adds memory blocks for
+// the remapped hardware.
+//
+
+const SCRIPT_PRELUDE: &str = r#"# Annotates a patched ROM. Generated by the "patch" tool.
+#@category Mac ROM
+
+memory = currentProgram.getMemory()
+listing = currentProgram.getListing()
+
+def annotate(addr, text):
+    addr = toAddr(addr)
+    createBookmark(addr, "Patch", text)
+    # Put the comment on the start of the instruction or data item.
+    unit = listing.getCodeUnitContaining(addr)
+    if unit is not None:
+        addr = unit.getMinAddress()
+    old = getEOLComment(addr)
+    if old is None:
+        setEOLComment(addr, text)
+    elif text not in old:
+        setEOLComment(addr, old + "\n" + text)
+
+def io_block(name, start, length):
+    start = toAddr(start)
+    if memory.getBlock(start) is not None:
+        print("Skipping %s, already mapped" % name)
+        return
+    block = memory.createUninitializedBlock(name, start, length, False)
+    block.setRead(True)
+    block.setWrite(True)
+    block.setVolatile(True)
+
+"#;
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn write_script(
+    path: &Path,
+    rom_base: usize,
+    applied: &[Applied],
+    labels: &[(String, usize)],
+    io_regions: &[(&str, usize, usize)],
+) -> anyhow::Result<()> {
+    let mut script = String::from(SCRIPT_PRELUDE);
+
+    for (name, start, end) in io_regions.iter() {
+        writeln!(
+            script,
+            "io_block(\"{}\", 0x{:06x}, 0x{:x})",
+            name,
+            start,
+            end - start
+        )?;
+    }
+    script.push('\n');
+
+    for a in applied.iter() {
+        writeln!(
+            script,
+            "annotate(0x{:06x}, \"{:?}: {} -> {}. {}\")",
+            rom_base + a.addr,
+            a.category,
+            hex_bytes(&a.before),
+            hex_bytes(&a.after),
+            a.category.reason()
+        )?;
+    }
+
+    for (name, offset) in labels.iter() {
+        writeln!(
+            script,
+            "createLabel(toAddr(0x{:06x}), \"{}\", True)",
+            rom_base + offset,
+            name
+        )?;
+    }
+
+    fs::write(path, script).with_context(|| format!("Couldn't write {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(text: &str) -> Vec<(usize, String)> {
+        parse_markings(text)
+            .unwrap()
+            .into_iter()
+            .map(|m| (m.addr, m.text))
+            .collect()
+    }
+
+    #[test]
+    fn parses_addresses() {
+        assert_eq!(parse_addr("ram:00400136"), Some(0x400136));
+        assert_eq!(parse_addr(" 0x400136 "), Some(0x400136));
+        assert_eq!(parse_addr("Face"), None);
+        assert_eq!(parse_addr("ram:zz400136"), None);
+    }
+
+    #[test]
+    fn reads_xml() {
+        let xml = r#"<?xml version="1.0" standalone="yes"?>
+<PROGRAM NAME="ROM.sefdhd">
+    <BOOKMARKS>
+        <BOOKMARK ADDRESS="ram:004000b8" TYPE="Note" CATEGORY="High memory reference" DESCRIPTION="Debugger &amp; more" />
+    </BOOKMARKS>
+    <COMMENTS>
+        <COMMENT ADDRESS="ram:00400136" TYPE="end-of-line">Absolute ROM reference
+to &lt;Open&gt;</COMMENT>
+        <COMMENT ADDRESS="ram:00100000" TYPE="plate">Outside the ROM</COMMENT>
+    </COMMENTS>
+</PROGRAM>
+"#;
+        assert_eq!(
+            parsed(xml),
+            [
+                (0xb8, " High memory reference Debugger & more".to_string()),
+                (0x136, "Absolute ROM reference\nto <Open>".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_xml() {
+        assert!(parse_markings("<COMMENT ADDRESS=\"ram:00400136\">Unterminated").is_err());
+        assert!(parse_markings("<COMMENT TYPE=\"plate\">No address</COMMENT>").is_err());
+    }
+
+    #[test]
+    fn reads_csv_with_header() {
+        let csv = "Type,Category,Description,Location
+Note,High RAM reference,\"Says \"\"hi\"\", twice\",ram:004012ab
+
+Note,8MB allocation limit,,00400200
+";
+        assert_eq!(
+            parsed(csv),
+            [
+                (0x200, "Note 8MB allocation limit  00400200".to_string()),
+                (
+                    0x12ab,
+                    "Note High RAM reference Says \"hi\", twice ram:004012ab".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_csv_without_header() {
+        // The first field that looks like an address is used, even
+        // when the text mentions an address.
+        assert_eq!(
+            parsed("Face,0x400300,Absolute ROM reference to an address\n"),
+            [(
+                0x300,
+                "Face 0x400300 Absolute ROM reference to an address".to_string()
+            )]
+        );
+        assert!(parse_markings("Note,0x400300\nNote,Nothing here\n").is_err());
+    }
+}
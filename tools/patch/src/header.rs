@@ -0,0 +1,307 @@
+//
+// ROM header
+//
+// The start of the ROM holds the reset vectors (with the ROM overlaid
+// at 0 on reset, the checksum doubles as the initial SSP), the ROM
+// version, jumps to entry points used from outside the ROM, and the
+// offsets of the resources and the trap table. This names the fields,
+// so they can be listed, changed symbolically and checked.
+//
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, ensure};
+
+use crate::asm::{self, Externals};
+use crate::{Applied, Category, ROM_SIZE};
+
+// "JMP (d16,PC)", used for the entry points.
+const JMP_PC_REL: u16 = 0x4efa;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    // Sum of the words after it. Fixed up at the end, so read-only.
+    Checksum,
+    // Absolute address in the ROM.
+    Address,
+    // A "JMP (d16,PC)" to an entry point, with the target as its value.
+    Jump,
+    // Offset from the start of the ROM.
+    Offset,
+    Word,
+    Byte,
+}
+
+struct Field {
+    name: &'static str,
+    offset: usize,
+    kind: Kind,
+    description: &'static str,
+}
+
+// The fields, up to the end of the entry point jumps.
+const FIELDS: [Field; 13] = [
+    Field {
+        name: "Checksum",
+        offset: 0x00,
+        kind: Kind::Checksum,
+        description: "ROM checksum, also the initial SSP",
+    },
+    Field {
+        name: "StartPC",
+        offset: 0x04,
+        kind: Kind::Address,
+        description: "Initial PC",
+    },
+    Field {
+        name: "ROMVersion",
+        offset: 0x08,
+        kind: Kind::Word,
+        description: "Machine and ROM version",
+    },
+    Field {
+        name: "StBoot",
+        offset: 0x0a,
+        kind: Kind::Jump,
+        description: "Restart the boot",
+    },
+    Field {
+        name: "BadDisk",
+        offset: 0x0e,
+        kind: Kind::Jump,
+        description: "Boot again after a bad boot disk",
+    },
+    Field {
+        name: "ROMRelease",
+        offset: 0x12,
+        kind: Kind::Word,
+        description: "ROM release",
+    },
+    Field {
+        name: "PatchFlags",
+        offset: 0x14,
+        kind: Kind::Byte,
+        description: "ROM patch flags",
+    },
+    Field {
+        name: "ForeignOS",
+        offset: 0x16,
+        kind: Kind::Offset,
+        description: "Foreign OS vector table, if any",
+    },
+    Field {
+        name: "RomRsrc",
+        offset: 0x1a,
+        kind: Kind::Offset,
+        description: "ROM resource header",
+    },
+    Field {
+        name: "Eject",
+        offset: 0x1e,
+        kind: Kind::Jump,
+        description: "Disk eject",
+    },
+    Field {
+        name: "DispOff",
+        offset: 0x22,
+        kind: Kind::Offset,
+        description: "Compressed trap table",
+    },
+    Field {
+        name: "Critical",
+        offset: 0x26,
+        kind: Kind::Jump,
+        description: "Critical error",
+    },
+    Field {
+        name: "ResetEntry",
+        offset: 0x2a,
+        kind: Kind::Jump,
+        description: "Reset, where StartPC points",
+    },
+];
+
+// A change to a header field. The value is an assembler expression,
+// so it can use the ROM's names and the code cave's allocations.
+// Addresses and jump targets are absolute, offsets are from the start
+// of the ROM.
+#[derive(Debug)]
+pub struct HeaderPatch<'a> {
+    pub field: &'a str,
+    pub value: &'a str,
+}
+
+fn read(data: &[u8], offset: usize, len: usize) -> u32 {
+    data[offset..offset + len]
+        .iter()
+        .fold(0u32, |acc, b| (acc << 8) | *b as u32)
+}
+
+impl Field {
+    // The bytes that hold the value.
+    fn range(&self) -> (usize, usize) {
+        match self.kind {
+            Kind::Checksum | Kind::Address | Kind::Offset => (self.offset, 4),
+            Kind::Jump => (self.offset + 2, 2),
+            Kind::Word => (self.offset, 2),
+            Kind::Byte => (self.offset, 1),
+        }
+    }
+
+    fn value(&self, data: &[u8], rom_base: usize) -> u32 {
+        let (start, len) = self.range();
+        let raw = read(data, start, len);
+        match self.kind {
+            Kind::Jump => (rom_base + start).wrapping_add(raw as i16 as usize) as u32,
+            _ => raw,
+        }
+    }
+
+    // The value as bytes, or an error if it isn't valid for the field.
+    fn encode(&self, value: i64, rom_base: usize) -> anyhow::Result<Vec<u8>> {
+        let in_rom = |addr: i64| {
+            addr & 1 == 0 && (rom_base as i64..(rom_base + ROM_SIZE) as i64).contains(&addr)
+        };
+        let (start, len) = self.range();
+        let raw = match self.kind {
+            Kind::Checksum => bail!("{} is calculated, not set", self.name),
+            Kind::Address => {
+                ensure!(
+                    in_rom(value),
+                    "{} 0x{:x} isn't an even address in the ROM at 0x{:06x}",
+                    self.name,
+                    value,
+                    rom_base
+                );
+                value
+            }
+            Kind::Jump => {
+                ensure!(
+                    in_rom(value),
+                    "{} target 0x{:x} isn't an even address in the ROM at 0x{:06x}",
+                    self.name,
+                    value,
+                    rom_base
+                );
+                let disp = value - (rom_base + start) as i64;
+                ensure!(
+                    (-0x8000..0x8000).contains(&disp),
+                    "{} target 0x{:x} is out of range of a PC-relative jump",
+                    self.name,
+                    value
+                );
+                disp & 0xffff
+            }
+            Kind::Offset => {
+                ensure!(
+                    value & 1 == 0 && (0..ROM_SIZE as i64).contains(&value),
+                    "{} 0x{:x} isn't an even offset in the ROM",
+                    self.name,
+                    value
+                );
+                value
+            }
+            Kind::Word | Kind::Byte => {
+                ensure!(
+                    (0..1 << (8 * len)).contains(&value),
+                    "{} 0x{:x} doesn't fit in {} bytes",
+                    self.name,
+                    value,
+                    len
+                );
+                value
+            }
+        };
+        Ok((0..len).rev().map(|i| (raw >> (8 * i)) as u8).collect())
+    }
+
+    // Check that the field as it stands is valid.
+    fn check(&self, data: &[u8], rom_base: usize) -> anyhow::Result<()> {
+        if self.kind == Kind::Jump {
+            ensure!(
+                read(data, self.offset, 2) as u16 == JMP_PC_REL,
+                "{} at 0x{:02x} isn't a PC-relative JMP",
+                self.name,
+                self.offset
+            );
+        }
+        if self.kind != Kind::Checksum {
+            self.encode(self.value(data, rom_base) as i64, rom_base)?;
+        }
+        Ok(())
+    }
+}
+
+fn find(name: &str) -> anyhow::Result<&'static Field> {
+    FIELDS
+        .iter()
+        .find(|f| f.name.eq_ignore_ascii_case(name))
+        .ok_or_else(|| anyhow!("No ROM header field '{}'", name))
+}
+
+// Set a header field.
+pub fn set(
+    data: &mut [u8],
+    rom_base: usize,
+    patch: &HeaderPatch,
+    externals: &Externals,
+    log: &mut Vec<Applied>,
+) -> anyhow::Result<()> {
+    let field = find(patch.field)?;
+    let (start, len) = field.range();
+    let value = asm::evaluate(patch.value, (rom_base + field.offset) as u32, externals)
+        .map_err(|e| anyhow!("{} = {}: {}", field.name, patch.value, e))?;
+    let after = field.encode(value, rom_base)?;
+    log.push(Applied {
+        addr: start,
+        category: Category::Header,
+        before: data[start..start + len].to_vec(),
+        after: after.clone(),
+    });
+    data[start..start + len].copy_from_slice(&after);
+    field.check(data, rom_base)
+}
+
+// Check that every field is valid for a ROM at 'rom_base': addresses
+// and entry points are in the ROM, and offsets are within it.
+pub fn check(data: &[u8], rom_base: usize) -> anyhow::Result<()> {
+    for field in FIELDS.iter() {
+        field.check(data, rom_base)?;
+    }
+    Ok(())
+}
+
+// Print the header, naming the addresses where possible.
+pub fn list(data: &[u8], rom_base: usize, externals: &Externals) {
+    let mut names = HashMap::new();
+    let mut symbols = externals.symbols.iter().collect::<Vec<_>>();
+    symbols.sort();
+    for (name, addr) in symbols.into_iter().rev() {
+        names.insert(*addr, name.as_str());
+    }
+
+    for field in FIELDS.iter() {
+        let value = field.value(data, rom_base);
+        let addr = match field.kind {
+            Kind::Address | Kind::Jump => Some(value),
+            Kind::Offset if value != 0 => Some(rom_base as u32 + value),
+            _ => None,
+        };
+        let name = addr.and_then(|addr| names.get(&addr)).unwrap_or(&"");
+        let digits = match field.kind {
+            Kind::Word => 4,
+            Kind::Byte => 2,
+            _ => 8,
+        };
+        println!(
+            "0x{:02x} {:<10} 0x{:0digits$x}{:pad$} {:<16} {}",
+            field.offset,
+            field.name,
+            value,
+            "",
+            name,
+            field.description,
+            pad = 8 - digits
+        );
+    }
+}
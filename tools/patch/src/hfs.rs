@@ -0,0 +1,469 @@
+//
+// HFS disk images
+//
+// Just enough of HFS to find every file on a System disk, and the
+// resources in their resource forks. Raw and DiskCopy 4.2 images are
+// supported. Forks are kept as lists of extents in the image file, so
+// that patched resources can be written back in place.
+//
+
+use std::collections::BTreeMap;
+
+use anyhow::{bail, ensure};
+
+const BLOCK_SIZE: usize = 512;
+
+// The boot blocks are the first two blocks of the volume, and the
+// Master Directory Block follows them.
+pub const BOOT_BLOCKS_SIZE: usize = 2 * BLOCK_SIZE;
+const MDB: usize = BOOT_BLOCKS_SIZE;
+const MDB_SIGNATURE: u16 = 0x4244;
+
+// MDB fields.
+const DR_AL_BLK_SIZ: usize = 0x14;
+const DR_AL_BL_ST: usize = 0x1c;
+const DR_VN: usize = 0x24;
+const DR_XT_FL_SIZE: usize = 0x82;
+const DR_XT_EXT_REC: usize = 0x86;
+const DR_CT_FL_SIZE: usize = 0x92;
+const DR_CT_EXT_REC: usize = 0x96;
+
+// The extents and catalog files' own file numbers.
+const EXTENTS_FILE_NUM: u32 = 3;
+const CATALOG_FILE_NUM: u32 = 4;
+
+// The root directory's parent's ID.
+const ROOT_PARENT_ID: u32 = 1;
+
+// The System file's file type.
+pub const SYSTEM_FILE_TYPE: &str = "ZSYS";
+
+// Catalog record types.
+const CDR_DIR_REC: u8 = 1;
+const CDR_FIL_REC: u8 = 2;
+
+// Extents key fork types.
+const DATA_FORK: u8 = 0x00;
+const RSRC_FORK: u8 = 0xff;
+
+fn read_word(data: &[u8], addr: usize) -> u16 {
+    u16::from_be_bytes(data[addr..addr + 2].try_into().unwrap())
+}
+
+fn read_long(data: &[u8], addr: usize) -> u32 {
+    u32::from_be_bytes(data[addr..addr + 4].try_into().unwrap())
+}
+
+// Four-character codes, with anything unprintable escaped.
+pub fn os_type(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&b| match b {
+            0x20..=0x7e => (b as char).to_string(),
+            _ => format!("\\x{:02x}", b),
+        })
+        .collect()
+}
+
+// Pascal strings, in MacRoman. Anything past ASCII is rare enough in
+// file names to just show as '?'.
+fn pstring(data: &[u8], addr: usize) -> String {
+    let len = data[addr] as usize;
+    data[addr + 1..addr + 1 + len]
+        .iter()
+        .map(|&b| if b.is_ascii() { b as char } else { '?' })
+        .collect()
+}
+
+// A fork's contents, as byte ranges in the image file.
+#[derive(Clone, Debug, Default)]
+pub struct Fork {
+    pub extents: Vec<(usize, usize)>,
+    pub len: usize,
+}
+
+impl Fork {
+    pub fn read(&self, image: &[u8]) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.len);
+        for (start, len) in self.extents.iter() {
+            data.extend_from_slice(&image[*start..*start + *len]);
+        }
+        data.truncate(self.len);
+        data
+    }
+
+    // Write back a fork read with 'read', the same length.
+    pub fn write(&self, image: &mut [u8], data: &[u8]) {
+        assert_eq!(data.len(), self.len, "Fork length changed");
+        let mut data = data;
+        for (start, len) in self.extents.iter() {
+            let len = (*len).min(data.len());
+            image[*start..*start + len].copy_from_slice(&data[..len]);
+            data = &data[len..];
+        }
+    }
+}
+
+pub struct File {
+    // Colon-separated, from the volume name.
+    pub path: String,
+    pub file_type: String,
+    pub rsrc: Fork,
+}
+
+pub struct Volume {
+    pub name: String,
+    // Where the volume starts in the image file.
+    pub offset: usize,
+    pub files: Vec<File>,
+}
+
+// The fixed parts of the volume used to find the files.
+struct Layout {
+    // Volume start, in the image file.
+    offset: usize,
+    // Volume size.
+    size: usize,
+    block_size: usize,
+    // Start of allocation block 0, in the image file.
+    first_block: usize,
+}
+
+impl Layout {
+    // Add an extent record's (up to three) extents to a fork, stopping
+    // once it has all the fork's bytes.
+    fn add_extents(&self, fork: &mut Fork, rec: &[u8]) -> anyhow::Result<()> {
+        for ext in rec.chunks(4).take(3) {
+            let covered: usize = fork.extents.iter().map(|(_, len)| len).sum();
+            let (block, count) = (read_word(ext, 0) as usize, read_word(ext, 2) as usize);
+            if covered >= fork.len || count == 0 {
+                break;
+            }
+            let start = self.first_block + block * self.block_size;
+            let len = count * self.block_size;
+            ensure!(
+                start + len <= self.offset + self.size,
+                "Extent at allocation block {} runs off the volume",
+                block
+            );
+            fork.extents.push((start, len));
+        }
+        Ok(())
+    }
+}
+
+// The leaf records of a B-tree file, as (key, data) pairs.
+fn leaf_records(tree: &[u8]) -> anyhow::Result<Vec<(&[u8], &[u8])>> {
+    // The header node's header record holds the node size and the
+    // first leaf node.
+    let node_size = read_word(tree, 0x20) as usize;
+    ensure!(
+        node_size >= BLOCK_SIZE && tree.len().is_multiple_of(node_size),
+        "Bad B-tree node size {}",
+        node_size
+    );
+    let mut records = Vec::new();
+    let mut node_num = read_long(tree, 0x18) as usize;
+    let mut seen = 0;
+    while node_num != 0 {
+        ensure!(
+            (node_num + 1) * node_size <= tree.len() && seen < tree.len() / node_size,
+            "Bad B-tree leaf chain at node {}",
+            node_num
+        );
+        let node = &tree[node_num * node_size..][..node_size];
+        ensure!(node[8] == 0xff, "B-tree node {} isn't a leaf", node_num);
+        let num_recs = read_word(node, 10) as usize;
+        // Record offsets are stacked back from the end of the node,
+        // with the free space offset after the last one.
+        let offsets = (0..=num_recs)
+            .map(|i| read_word(node, node_size - 2 * (i + 1)) as usize)
+            .collect::<Vec<_>>();
+        for i in 0..num_recs {
+            let rec = &node[offsets[i]..offsets[i + 1]];
+            // The key length byte doesn't count itself, and the data
+            // is word-aligned.
+            let key_len = (rec[0] as usize + 2) & !1;
+            records.push((&rec[..key_len], &rec[key_len..]));
+        }
+        node_num = read_long(node, 0) as usize;
+        seen += 1;
+    }
+    Ok(records)
+}
+
+// Read a B-tree file whose first extents are in the MDB, checking
+// the extents file for the rest.
+fn system_file(
+    image: &[u8],
+    layout: &Layout,
+    overflow: &BTreeMap<(u8, u32), Vec<&[u8]>>,
+    file_num: u32,
+    size_field: usize,
+    ext_field: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let mdb = &image[layout.offset + MDB..];
+    let mut fork = Fork {
+        extents: Vec::new(),
+        len: read_long(mdb, size_field) as usize,
+    };
+    layout.add_extents(&mut fork, &mdb[ext_field..ext_field + 12])?;
+    for rec in overflow.get(&(DATA_FORK, file_num)).into_iter().flatten() {
+        layout.add_extents(&mut fork, rec)?;
+    }
+    Ok(fork.read(image))
+}
+
+pub fn read(image: &[u8]) -> anyhow::Result<Volume> {
+    let (offset, size) = diskcopy::data(image).unwrap_or((0, image.len()));
+    ensure!(size >= MDB + BLOCK_SIZE, "Disk image is too small for HFS");
+    let mdb = &image[offset + MDB..][..BLOCK_SIZE];
+    let signature = read_word(mdb, 0);
+    if signature != MDB_SIGNATURE {
+        bail!("Not an HFS volume (signature 0x{:04x})", signature);
+    }
+    let layout = Layout {
+        offset,
+        size,
+        block_size: read_long(mdb, DR_AL_BLK_SIZ) as usize,
+        first_block: offset + read_word(mdb, DR_AL_BL_ST) as usize * BLOCK_SIZE,
+    };
+    ensure!(
+        layout.block_size != 0 && layout.block_size.is_multiple_of(BLOCK_SIZE),
+        "Bad allocation block size {}",
+        layout.block_size
+    );
+
+    // Extents past the first three for each fork, keyed by fork type
+    // and file number, in order.
+    let no_overflow = BTreeMap::new();
+    let extents = system_file(
+        image,
+        &layout,
+        &no_overflow,
+        EXTENTS_FILE_NUM,
+        DR_XT_FL_SIZE,
+        DR_XT_EXT_REC,
+    )?;
+    let mut overflow = BTreeMap::<(u8, u32), Vec<&[u8]>>::new();
+    for (key, data) in leaf_records(&extents)? {
+        overflow
+            .entry((key[1], read_long(key, 2)))
+            .or_default()
+            .push(data);
+    }
+    let catalog = system_file(
+        image,
+        &layout,
+        &overflow,
+        CATALOG_FILE_NUM,
+        DR_CT_FL_SIZE,
+        DR_CT_EXT_REC,
+    )?;
+
+    // Directory names and parents, by ID, to build the paths.
+    let records = leaf_records(&catalog)?;
+    let mut dirs = BTreeMap::new();
+    for (key, data) in records.iter() {
+        if data[0] == CDR_DIR_REC {
+            dirs.insert(read_long(data, 6), (read_long(key, 2), pstring(key, 6)));
+        }
+    }
+    let path = |mut parent: u32, name: String| {
+        let mut parts = vec![name];
+        while parent != ROOT_PARENT_ID {
+            let Some((grandparent, dir)) = dirs.get(&parent) else {
+                break;
+            };
+            parts.push(dir.clone());
+            parent = *grandparent;
+        }
+        parts.reverse();
+        parts.join(":")
+    };
+
+    let mut files = Vec::new();
+    for (key, data) in records.iter() {
+        if data[0] != CDR_FIL_REC {
+            continue;
+        }
+        // The resource fork's length and first extents are in the
+        // file record, and the rest in the extents file.
+        let mut rsrc = Fork {
+            extents: Vec::new(),
+            len: read_long(data, 36) as usize,
+        };
+        layout.add_extents(&mut rsrc, &data[86..98])?;
+        for rec in overflow
+            .get(&(RSRC_FORK, read_long(data, 20)))
+            .into_iter()
+            .flatten()
+        {
+            layout.add_extents(&mut rsrc, rec)?;
+        }
+        let covered: usize = rsrc.extents.iter().map(|(_, len)| len).sum();
+        ensure!(
+            covered >= rsrc.len,
+            "Missing extents for file {}",
+            pstring(key, 6)
+        );
+        files.push(File {
+            path: path(read_long(key, 2), pstring(key, 6)),
+            file_type: os_type(&data[4..8]),
+            rsrc,
+        });
+    }
+
+    Ok(Volume {
+        name: pstring(mdb, DR_VN),
+        offset,
+        files,
+    })
+}
+
+impl Volume {
+    // The System file, which should be the only one of its type.
+    pub fn system_file(&self) -> anyhow::Result<&File> {
+        let systems = self
+            .files
+            .iter()
+            .filter(|f| f.file_type == SYSTEM_FILE_TYPE)
+            .collect::<Vec<_>>();
+        match systems[..] {
+            [system] => Ok(system),
+            [] => bail!("No System file on \"{}\"", self.name),
+            _ => bail!(
+                "{} System files on \"{}\": {}",
+                systems.len(),
+                self.name,
+                systems
+                    .iter()
+                    .map(|f| f.path.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+
+    // The System's version, from the short version string in its
+    // 'vers' 1 resource (like "6.0.1").
+    pub fn system_version(&self, image: &[u8]) -> anyhow::Result<String> {
+        let system = self.system_file()?;
+        let fork = system.rsrc.read(image);
+        let Some(vers) = resources(&fork)?
+            .into_iter()
+            .find(|r| r.res_type == "vers" && r.id == 1)
+        else {
+            bail!("{} has no 'vers' 1 resource", system.path);
+        };
+        // The numeric version and country code come before the string.
+        let data = &fork[vers.offset..vers.offset + vers.size];
+        ensure!(
+            data.len() > 6 && 7 + data[6] as usize <= data.len(),
+            "{} has a short 'vers' 1",
+            system.path
+        );
+        Ok(pstring(data, 6))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// Resource forks.
+//
+
+pub struct Resource {
+    pub res_type: String,
+    pub id: i16,
+    pub name: Option<String>,
+    // Where the resource's data is in the fork, after its length.
+    pub offset: usize,
+    pub size: usize,
+}
+
+impl Resource {
+    pub fn label(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{} {} \"{}\"", self.res_type, self.id, name),
+            None => format!("{} {}", self.res_type, self.id),
+        }
+    }
+}
+
+// List the resources in a resource fork, in map order.
+pub fn resources(fork: &[u8]) -> anyhow::Result<Vec<Resource>> {
+    if fork.is_empty() {
+        return Ok(Vec::new());
+    }
+    ensure!(fork.len() >= 16, "Resource fork is too short");
+    let data_offset = read_long(fork, 0) as usize;
+    let map_offset = read_long(fork, 4) as usize;
+    ensure!(
+        map_offset + 30 <= fork.len(),
+        "Resource map is off the end of the fork"
+    );
+    let map = &fork[map_offset..];
+    let type_list = read_word(map, 24) as usize;
+    let name_list = read_word(map, 26) as usize;
+    ensure!(
+        type_list + 2 <= map.len(),
+        "Resource type list is off the end of the fork"
+    );
+    // Counts are stored minus one.
+    let num_types = read_word(map, type_list).wrapping_add(1) as usize;
+    ensure!(
+        type_list + 2 + num_types * 8 <= map.len(),
+        "Resource type list runs off the end of the fork"
+    );
+
+    let mut resources = Vec::new();
+    for t in 0..num_types {
+        let entry = type_list + 2 + t * 8;
+        let res_type = os_type(&map[entry..entry + 4]);
+        let count = read_word(map, entry + 4) as usize + 1;
+        let refs = type_list + read_word(map, entry + 6) as usize;
+        ensure!(
+            refs + count * 12 <= map.len(),
+            "References to '{}' run off the end of the fork",
+            res_type
+        );
+        for r in 0..count {
+            let res_ref = refs + r * 12;
+            let id = read_word(map, res_ref) as i16;
+            let name = match read_word(map, res_ref + 2) {
+                0xffff => None,
+                offset => {
+                    let addr = name_list + offset as usize;
+                    ensure!(
+                        addr < map.len() && addr + 1 + map[addr] as usize <= map.len(),
+                        "{} {}'s name is off the end of the fork",
+                        res_type,
+                        id
+                    );
+                    Some(pstring(map, addr))
+                }
+            };
+            let offset = data_offset + (read_long(map, res_ref + 4) & 0xffffff) as usize;
+            ensure!(
+                offset + 4 <= fork.len(),
+                "{} {} is off the end of the fork",
+                res_type,
+                id
+            );
+            let size = read_long(fork, offset) as usize;
+            ensure!(
+                offset + 4 + size <= fork.len(),
+                "{} {} runs off the end of the fork",
+                res_type,
+                id
+            );
+            resources.push(Resource {
+                res_type: res_type.clone(),
+                id,
+                name,
+                offset: offset + 4,
+                size,
+            });
+        }
+    }
+    Ok(resources)
+}
@@ -0,0 +1,404 @@
+//
+// High RAM references
+//
+// The debugger, the test manager and the boot and Sad Mac icons use
+// fixed addresses just under 4MB (0x3fXXXX), for the screen, the sound
+// buffer and the scratch space between them. They work before RAM is
+// sized by relying on RAM being mirrored, so that they hit the top of
+// smaller RAM. Our RAM isn't mirrored, so this rewrites them for the
+// top of the RAM the ROM is patched for.
+//
+// The sites were found with 'scan', which looks for longs in 0x3fXXXX
+// in the ROM and System resources. Each hit was reviewed: the ones
+// that aren't addresses (mostly "ANDI #$3F") are listed as such, so
+// that the scan only reports new hits.
+//
+
+use std::fs;
+
+use anyhow::{bail, ensure};
+
+use crate::screen::{ORIG_GEOMETRY, SCRATCH, SOUND_BUFFER};
+use crate::{Applied, Category, Machine, RESOURCE_PATCHES};
+
+// The memory the addresses assume.
+const ORIG_RAM_TOP: u32 = 0x400000;
+
+const HIGH_RAM: u32 = 0x3f0000;
+
+// What an address points into, going by the original layout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Area {
+    Screen,
+    Scratch,
+    Sound,
+}
+
+impl Area {
+    fn find(addr: u32) -> Option<Area> {
+        let sound = ORIG_RAM_TOP - SOUND_BUFFER;
+        let scratch = sound - SCRATCH;
+        let screen = ORIG_GEOMETRY.base(ORIG_RAM_TOP);
+        match addr {
+            _ if addr >= ORIG_RAM_TOP => None,
+            _ if addr >= sound => Some(Area::Sound),
+            _ if addr >= scratch => Some(Area::Scratch),
+            _ if addr >= screen => Some(Area::Screen),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Area::Screen => "screen buffer",
+            Area::Scratch => "scratch",
+            Area::Sound => "sound buffer",
+        }
+    }
+}
+
+// Where an address ends up. Screen addresses keep their row and byte
+// within the row, the rest keep their distance from the top of RAM.
+fn relocate(addr: u32, machine: &Machine) -> anyhow::Result<u32> {
+    let ram_top = machine.max_ram as u32;
+    let Some(area) = Area::find(addr) else {
+        bail!("0x{:06x} isn't in the high RAM layout", addr);
+    };
+    if area != Area::Screen {
+        return Ok(ram_top - (ORIG_RAM_TOP - addr));
+    }
+
+    let g = &machine.geometry;
+    let offset = addr - ORIG_GEOMETRY.base(ORIG_RAM_TOP);
+    let (row, col) = (
+        offset / ORIG_GEOMETRY.row_bytes,
+        offset % ORIG_GEOMETRY.row_bytes,
+    );
+    ensure!(
+        row < g.height && col * 8 < g.width,
+        "Screen address 0x{:06x} (row {}, byte {}) is off a {}x{} screen",
+        addr,
+        row,
+        col,
+        g.width,
+        g.height
+    );
+    Ok(g.base(ram_top) + row * g.row_bytes + col)
+}
+
+// A long holding a high RAM address.
+pub struct Site {
+    addr: usize,
+    what: &'static str,
+}
+
+// The references in an image.
+pub struct Sites {
+    sites: &'static [Site],
+    // Hits that have been reviewed, and aren't addresses.
+    not_high_ram: &'static [usize],
+}
+
+pub const ROM_SITES: Sites = Sites {
+    sites: &[
+        Site {
+            addr: 0x00f4c,
+            what: "Boot icon",
+        },
+        Site {
+            addr: 0x00f5e,
+            what: "Boot icon detail",
+        },
+        Site {
+            addr: 0x01096,
+            what: "Debugger saved registers",
+        },
+        Site {
+            addr: 0x010a2,
+            what: "Debugger screen",
+        },
+        Site {
+            addr: 0x0118a,
+            what: "Sad Mac icon",
+        },
+        Site {
+            addr: 0x01198,
+            what: "Sad Mac face",
+        },
+        Site {
+            addr: 0x012b4,
+            what: "Test manager registers, cleared",
+        },
+        Site {
+            addr: 0x012ca,
+            what: "Test manager saved registers",
+        },
+        Site {
+            addr: 0x012e0,
+            what: "Test manager argument size",
+        },
+        Site {
+            addr: 0x012f8,
+            what: "Test manager restored registers",
+        },
+        Site {
+            addr: 0x01300,
+            what: "Test manager argument size",
+        },
+        Site {
+            addr: 0x0132e,
+            what: "Test manager saved registers",
+        },
+        Site {
+            addr: 0x0134a,
+            what: "Test manager fault address",
+        },
+        Site {
+            addr: 0x01350,
+            what: "Test manager fault instruction",
+        },
+        Site {
+            addr: 0x0135c,
+            what: "Test manager saved SR",
+        },
+        Site {
+            addr: 0x01362,
+            what: "Test manager saved PC",
+        },
+        Site {
+            addr: 0x0138a,
+            what: "Test manager saved registers",
+        },
+        Site {
+            addr: 0x01390,
+            what: "Test manager saved SR",
+        },
+        Site {
+            addr: 0x01396,
+            what: "Test manager saved PC",
+        },
+        Site {
+            addr: 0x013aa,
+            what: "Test manager saved SP",
+        },
+        Site {
+            addr: 0x013e2,
+            what: "Test manager restored registers",
+        },
+        Site {
+            addr: 0x013e8,
+            what: "Test manager restored PC",
+        },
+        Site {
+            addr: 0x013ee,
+            what: "Test manager restored SR",
+        },
+        Site {
+            addr: 0x01678,
+            what: "Test manager command address",
+        },
+        Site {
+            addr: 0x0167e,
+            what: "Test manager command data",
+        },
+        Site {
+            addr: 0x01690,
+            what: "Test manager command stack",
+        },
+        Site {
+            addr: 0x017b0,
+            what: "Test manager command address",
+        },
+        Site {
+            addr: 0x017e4,
+            what: "Test manager command data",
+        },
+        Site {
+            addr: 0x01822,
+            what: "Test manager command address",
+        },
+        Site {
+            addr: 0x01848,
+            what: "Test manager data registers",
+        },
+        Site {
+            addr: 0x0184c,
+            what: "Test manager command data",
+        },
+        Site {
+            addr: 0x01856,
+            what: "Test manager data registers",
+        },
+        Site {
+            addr: 0x0185e,
+            what: "Test manager address registers",
+        },
+        Site {
+            addr: 0x01866,
+            what: "Test manager saved PC",
+        },
+        Site {
+            addr: 0x0186e,
+            what: "Test manager saved SR",
+        },
+        Site {
+            addr: 0x018ca,
+            what: "Test manager command data",
+        },
+        Site {
+            addr: 0x018d8,
+            what: "Test manager number sign",
+        },
+        Site {
+            addr: 0x01908,
+            what: "Test manager number sign",
+        },
+        Site {
+            addr: 0x01942,
+            what: "Test manager number sign",
+        },
+        Site {
+            addr: 0x0197e,
+            what: "Test manager command address",
+        },
+        Site {
+            addr: 0x0199c,
+            what: "Test manager saved PC",
+        },
+        Site {
+            addr: 0x019b4,
+            what: "Test manager address registers",
+        },
+        Site {
+            addr: 0x019e2,
+            what: "Test manager data registers",
+        },
+        Site {
+            addr: 0x029f4,
+            what: "Boot sound",
+        },
+    ],
+    not_high_ram: &[
+        0x021d8, 0x0acfa, 0x12ba2, 0x1435e, 0x14ba6, 0x14d28, 0x151bc, 0x17f32, 0x1d260, 0x1d716,
+        0x325c8, 0x358f8, 0x35914, 0x3592a, 0x3595a, 0x35966, 0x35984, 0x35990, 0x359a2, 0x364b4,
+        0x36576, 0x36b34, 0x36bb0, 0x36cdc,
+    ],
+};
+
+// Copies of the test manager's exception handler.
+pub const PTCH_117_SITES: Sites = Sites {
+    sites: &[Site {
+        addr: 0x039e6,
+        what: "Test manager saved registers",
+    }],
+    not_high_ram: &[0x00036, 0x02c60],
+};
+
+pub const PTCH_630_SITES: Sites = Sites {
+    sites: &[Site {
+        addr: 0x03266,
+        what: "Test manager saved registers",
+    }],
+    not_high_ram: &[0x00e5c, 0x02138, 0x03558],
+};
+
+pub const NO_SITES: Sites = Sites {
+    sites: &[],
+    not_high_ram: &[],
+};
+
+fn read_long(data: &[u8], addr: usize) -> u32 {
+    u32::from_be_bytes(data[addr..addr + 4].try_into().unwrap())
+}
+
+// Point the references at the top of the machine's RAM.
+pub fn patch(
+    data: &mut [u8],
+    sites: &Sites,
+    machine: &Machine,
+    log: &mut Vec<Applied>,
+) -> anyhow::Result<()> {
+    for site in sites.sites.iter() {
+        let before = read_long(data, site.addr);
+        ensure!(
+            before & 0xffff0000 == HIGH_RAM,
+            "{} at 0x{:05x} isn't a high RAM address",
+            site.what,
+            site.addr
+        );
+        let after = relocate(before, machine)?;
+        data[site.addr..site.addr + 4].copy_from_slice(&after.to_be_bytes());
+        log.push(Applied {
+            addr: site.addr,
+            category: Category::HighRam,
+            before: before.to_be_bytes().to_vec(),
+            after: after.to_be_bytes().to_vec(),
+        });
+    }
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////
+// Finding the references.
+//
+
+// ROM resources that hold code. Hits in the others are just data.
+const CODE_TYPES: [&str; 8] = [
+    "PACK", "tcsl", "SERD", "DRVR", "CDEF", "MBDF", "MDEF", "WDEF",
+];
+
+// List the hits in an image, returning how many haven't been reviewed.
+fn scan_image(
+    name: &str,
+    data: &[u8],
+    sites: &Sites,
+    skip: &dyn Fn(usize) -> Option<String>,
+) -> usize {
+    let mut unreviewed = 0;
+    for addr in (0..data.len() - 3).step_by(2) {
+        let value = read_long(data, addr);
+        if value & 0xffff0000 != HIGH_RAM {
+            continue;
+        }
+        let status = if let Some(site) = sites.sites.iter().find(|s| s.addr == addr) {
+            let area = Area::find(value).map_or("outside the layout", |a| a.name());
+            format!("{}: {}", area, site.what)
+        } else if sites.not_high_ram.contains(&addr) {
+            "not an address".to_string()
+        } else if let Some(status) = skip(addr) {
+            status
+        } else {
+            unreviewed += 1;
+            "UNREVIEWED".to_string()
+        };
+        println!("{:<8} 0x{:05x} 0x{:06x} {}", name, addr, value, status);
+    }
+    unreviewed
+}
+
+// Find every long in 0x3fXXXX in the original ROM and the System
+// resources, and check they've all been reviewed.
+pub fn scan(rom: &[u8]) -> anyhow::Result<()> {
+    let map = rom_resources::parse(rom)?;
+    let in_data = |addr: usize| {
+        map.resources
+            .iter()
+            .find(|r| (r.offset..r.offset + r.size).contains(&addr))
+            .filter(|r| !CODE_TYPES.contains(&r.res_type.as_str()))
+            .map(|r| format!("data, in {}", r.label()))
+    };
+    let mut unreviewed = scan_image("ROM", rom, &ROM_SITES, &in_data);
+
+    for res in RESOURCE_PATCHES.iter() {
+        let name = format!("{}_{}", res.res_type, res.res_id);
+        let data = fs::read(format!("../../system/6.0.1/{}", name))?;
+        unreviewed += scan_image(&name, &data, res.high_ram, &|_| None);
+    }
+
+    ensure!(
+        unreviewed == 0,
+        "{} high RAM hits haven't been reviewed",
+        unreviewed
+    );
+    Ok(())
+}
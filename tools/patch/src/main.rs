@@ -4,10 +4,32 @@
 // Applies a list of patches to a ROM, resource or disk image.
 //
 
+mod asm;
+mod cave;
+mod consistency;
+mod disk_scan;
+mod floppy;
+mod ghidra;
+mod header;
+mod hfs;
+mod highram;
+mod ram;
+mod reloc;
+mod screen;
+mod scsi;
+mod traps;
+mod via;
+
 use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, ensure, Context};
+use clap::{Args, Parser, Subcommand};
 
-use anyhow::bail;
-use clap::{Parser, Subcommand};
+use cave::{Cave, CaveCode, CaveHook};
+use header::HeaderPatch;
+use screen::Geometry;
+use traps::TrapPatch;
 
 ////////////////////////////////////////////////////////////////////////
 // Command line processing.
@@ -23,60 +45,481 @@ struct Cli {
     command: Commands,
 }
 
+// What the ROM and System are patched for.
+#[derive(Args)]
+struct MachineArgs {
+    /// Screen width, in pixels
+    #[arg(long, default_value_t = 512)]
+    width: u32,
+    /// Screen height, in pixels
+    #[arg(long, default_value_t = 342)]
+    height: u32,
+    /// Bytes per screen row [default: enough words for the width]
+    #[arg(long)]
+    row_bytes: Option<u32>,
+    /// Largest amount of RAM the ROM will take, in hex. Up to the ROM
+    /// by default
+    #[arg(long, default_value = memory_map::RAM_END_HEX,
+          value_parser = ram::parse_max_ram)]
+    max_ram: usize,
+    /// Replace the IWM floppy driver with a paravirtual one, using a
+    /// mailbox at this I/O address, in hex [default: FLOPPY_MAILBOX in
+    /// the memory map]
+    #[arg(long, num_args = 0..=1, default_missing_value = floppy::DEFAULT_MAILBOX,
+          value_parser = floppy::parse_mailbox)]
+    floppy_mailbox: Option<usize>,
+    /// Replace the SCSI Manager's 5380 access with a paravirtual block
+    /// interface, using a mailbox at this I/O address, in hex
+    /// [default: SCSI_MAILBOX in the memory map]
+    #[arg(long, num_args = 0..=1, default_missing_value = scsi::DEFAULT_MAILBOX,
+          value_parser = scsi::parse_mailbox)]
+    scsi_mailbox: Option<usize>,
+    /// Move the VBL and one-second interrupts, the clock and ADB from
+    /// the VIA to a paravirtual peripheral at this I/O address, in hex
+    /// [default: VIA_MAILBOX in the memory map]
+    #[arg(long, num_args = 0..=1, default_missing_value = via::DEFAULT_MAILBOX,
+          value_parser = via::parse_mailbox)]
+    via_mailbox: Option<usize>,
+}
+
+impl MachineArgs {
+    fn machine(&self) -> anyhow::Result<Machine> {
+        let mailboxes = [
+            ("floppy", self.floppy_mailbox, floppy::MAILBOX_SIZE),
+            ("SCSI", self.scsi_mailbox, scsi::MAILBOX_SIZE),
+            ("VIA", self.via_mailbox, via::MAILBOX_SIZE),
+        ];
+        for (i, (name_a, a, size_a)) in mailboxes.iter().enumerate() {
+            for (name_b, b, size_b) in mailboxes[i + 1..].iter() {
+                if let (Some(a), Some(b)) = (a, b) {
+                    ensure!(
+                        a + size_a <= *b || b + size_b <= *a,
+                        "The {} and {} mailboxes overlap",
+                        name_a,
+                        name_b
+                    );
+                }
+            }
+        }
+        Ok(Machine {
+            geometry: Geometry::new(self.width, self.height, self.row_bytes)?,
+            max_ram: self.max_ram,
+            floppy_mailbox: self.floppy_mailbox,
+            scsi_mailbox: self.scsi_mailbox,
+            via_mailbox: self.via_mailbox,
+        })
+    }
+}
+
+// Which System patch sets can be used.
+#[derive(Args)]
+struct PatchSetArgs {
+    /// Allow the patch sets that haven't been checked against a real
+    /// System disk yet (7.x)
+    #[arg(long)]
+    untested: bool,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Patch a ROM
-    Rom,
+    Rom {
+        #[command(flatten)]
+        machine: MachineArgs,
+    },
     // Patch an individual resource.
     Resource {
         res_type: String,
         res_id: i16,
+        /// Version of the System the resource was extracted from
+        #[arg(long, default_value = "6.0.1")]
+        system: String,
+        #[command(flatten)]
+        sets: PatchSetArgs,
+        #[command(flatten)]
+        machine: MachineArgs,
+    },
+    /// Patch a System disk, with the patches for the version of the
+    /// System on it
+    Disk {
+        image: PathBuf,
+        #[command(flatten)]
+        sets: PatchSetArgs,
+        #[command(flatten)]
+        machine: MachineArgs,
+    },
+    /// Patch the System 6.0.1 tools disk (the same as 'disk' on
+    /// ../../system/6.0.1/tools.dsk)
+    Disk601 {
+        #[command(flatten)]
+        machine: MachineArgs,
+    },
+    /// Cross-check the ROM patches against the markings in a Ghidra
+    /// comments/bookmarks export (XML or CSV).
+    Markings { export: PathBuf },
+    /// Patch for two ROM bases and check that everything that differs
+    /// is a relocated address.
+    RelocationCheck {
+        #[arg(long, default_value = memory_map::ROM_START_HEX, value_parser = parse_rom_base)]
+        base_a: usize,
+        #[arg(long, default_value = "0x800000", value_parser = parse_rom_base)]
+        base_b: usize,
+        #[command(flatten)]
+        machine: MachineArgs,
+    },
+    /// Patch the ROM and a System's resources, and check that they move
+    /// the ROM and the hardware to the same places, and that the
+    /// System only uses hardware the ROM still provides
+    Check {
+        /// Version of the System whose patches to check
+        #[arg(long, default_value = "6.0.1")]
+        system: String,
+        #[arg(long, default_value = memory_map::ROM_START_HEX,
+              value_parser = parse_rom_base)]
+        base: usize,
+        #[command(flatten)]
+        sets: PatchSetArgs,
+        #[command(flatten)]
+        machine: MachineArgs,
+    },
+    /// List the absolute ROM and hardware references in every code
+    /// resource on a System disk, and print patches for the ones the
+    /// System's resource patches don't cover
+    DiskScan {
+        #[arg(long, default_value = TOOLS_DISK_601)]
+        disk: PathBuf,
+        /// Version of the System whose patches to check the hits
+        /// against [default: the version of the System on the disk]
+        #[arg(long)]
+        system: Option<String>,
+        /// Also list the hits that aren't in instructions
+        #[arg(long)]
+        data: bool,
+        #[command(flatten)]
+        sets: PatchSetArgs,
+    },
+    /// Find the screen geometry constants in the ROM, and check that
+    /// they've all been reviewed
+    ScreenScan,
+    /// Find the references to fixed addresses under 4MB in the ROM and
+    /// System resources, and check that they've all been reviewed
+    HighRamScan,
+    /// Generate the address decoder, C header and Rust constants from
+    /// the memory map definition
+    MemoryMap {
+        /// Directory holding memory_map.txt, and the generated files
+        #[arg(long, default_value = "../../memory_map")]
+        dir: PathBuf,
+        /// Check the generated files are up to date, rather than
+        /// writing them
+        #[arg(long)]
+        check: bool,
+    },
+    /// List the ROM header and initial vectors, optionally changing
+    /// fields first (e.g. --set StartPC=<label>)
+    Header {
+        #[arg(long, default_value = "../../ROM.patched")]
+        rom: PathBuf,
+        #[arg(long, default_value = memory_map::ROM_START_HEX, value_parser = parse_rom_base)]
+        base: usize,
+        /// Field to change, as <field>=<value>. The ROM is written
+        /// back with the checksum fixed.
+        #[arg(long)]
+        set: Vec<String>,
+    },
+    /// Assemble a file of 68000 code, with the patched ROM's symbols,
+    /// and print the machine code.
+    Asm {
+        source: PathBuf,
+        #[arg(long, default_value = memory_map::ROM_START_HEX, value_parser = parse_address)]
+        origin: u32,
     },
-    // Patch a disk containing resources.
-    Disk601,
 }
 
 ////////////////////////////////////////////////////////////////////////
 // Patching
 //
 
+// Where the ROM lives on an unmodified machine.
+const ORIG_ROM_BASE: usize = memory_map::ROM_MOVED_FROM as usize;
+const ROM_SIZE: usize = memory_map::ROM_SIZE as usize;
+
+// Where the ROM ends up after patching, by default.
+const PATCHED_ROM_BASE: usize = memory_map::ROM_START as usize;
+
+// The System 6.0.1 tools disk, which the 6.0.1 patches are for.
+const TOOLS_DISK_601: &str = "../../system/6.0.1/tools.dsk";
+
+// The machine the ROM and System are patched for.
+#[derive(Clone, Copy, Debug)]
+struct Machine {
+    geometry: Geometry,
+    // RAM runs from 0 up to here.
+    max_ram: usize,
+    // Where the paravirtual floppy driver's mailbox is, if it replaces
+    // the IWM one.
+    floppy_mailbox: Option<usize>,
+    // Where the paravirtual SCSI Manager's mailbox is, if it replaces
+    // the 5380 one.
+    scsi_mailbox: Option<usize>,
+    // Where the paravirtual VIA functions' registers are, if they
+    // replace the VIA's interrupts, clock and ADB.
+    via_mailbox: Option<usize>,
+}
+
+const DEFAULT_MACHINE: Machine = Machine {
+    geometry: screen::ORIG_GEOMETRY,
+    max_ram: ram::DEFAULT_MAX_RAM,
+    floppy_mailbox: None,
+    scsi_mailbox: None,
+    via_mailbox: None,
+};
+
+// What a patch is for. Used to annotate the patches, and to
+// cross-check them against the markings in the Ghidra disassembly.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Category {
+    DebugHook,
+    RomReference,
+    Scsi,
+    SccRead,
+    SccWrite,
+    Iwm,
+    Via,
+    AllocLimit,
+    MaxMemory,
+    EasterEgg,
+    NewCode,
+    NewCodeHook,
+    TrapRedirect,
+    Header,
+    Screen,
+    HighRam,
+    Floppy,
+}
+
+impl Category {
+    // Whether patches of this kind point at hardware the memory map
+    // has moved.
+    fn moves_hardware(&self) -> bool {
+        matches!(
+            self,
+            Category::DebugHook
+                | Category::Scsi
+                | Category::SccRead
+                | Category::SccWrite
+                | Category::Iwm
+                | Category::Via
+        )
+    }
+
+    // The comment used to mark sites of this kind in the Ghidra
+    // disassembly, if they're marked.
+    fn marking(&self) -> Option<&'static str> {
+        match self {
+            Category::DebugHook => Some("High memory reference"),
+            Category::RomReference => Some("Absolute ROM reference"),
+            Category::AllocLimit => Some("8MB allocation limit"),
+            Category::HighRam => Some("High RAM reference"),
+            _ => None,
+        }
+    }
+
+    // Why patches of this kind are needed. The addresses come from the
+    // memory map, so they stay in step with it.
+    fn reason(&self) -> String {
+        use memory_map::*;
+        // Hardware is moved a page at a time.
+        let moved = |name: &str, from: u32, to: u32| {
+            format!(
+                "{} moved from 0x{:03x}xxx to 0x{:03x}xxx",
+                name,
+                from >> 12,
+                to >> 12
+            )
+        };
+        match self {
+            Category::DebugHook => format!(
+                "Debug hook moved from 0x{:02x}xxxx to 0x{:02x}xxxx to make room for the ROM",
+                DEBUG_MOVED_FROM >> 16,
+                DEBUG_START >> 16
+            ),
+            Category::RomReference => format!(
+                "Absolute ROM reference relocated from 0x{:06x}",
+                ROM_MOVED_FROM
+            ),
+            Category::Scsi => moved("SCSI", SCSI_MOVED_FROM, SCSI_START),
+            Category::SccRead => moved("SCC read", SCC_READ_MOVED_FROM, SCC_READ_START),
+            Category::SccWrite => moved("SCC write", SCC_WRITE_MOVED_FROM, SCC_WRITE_START),
+            Category::Iwm => moved("IWM", IWM_MOVED_FROM, IWM_START),
+            Category::Via => moved("VIA", VIA_MOVED_FROM, VIA_START),
+            Category::AllocLimit => "8MB allocation limit raised for more RAM".to_string(),
+            Category::MaxMemory => "Maximum installed RAM changed from 4MB".to_string(),
+            Category::EasterEgg => {
+                "Dev team pictures removed to make room for new code".to_string()
+            }
+            Category::NewCode => {
+                "New code in the space reclaimed from the easter egg and filler".to_string()
+            }
+            Category::NewCodeHook => "Hook into new code".to_string(),
+            Category::TrapRedirect => "Trap table entry pointed at new code".to_string(),
+            Category::Header => "ROM header field changed".to_string(),
+            Category::Screen => "Screen geometry changed from 512x342".to_string(),
+            Category::HighRam => "Fixed address under 4MB moved to the top of RAM".to_string(),
+            Category::Floppy => {
+                ".Sony resource pointed at the paravirtual floppy driver".to_string()
+            }
+        }
+    }
+}
+
+// Relocate the high-order bytes of an absolute ROM address, where the
+// last byte holds bits 16-23 of the address. The ROM base must have
+// been checked with parse_rom_base.
+fn relocate(before: &[u8], rom_base: usize) -> Vec<u8> {
+    let value = before
+        .iter()
+        .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+    let value = value + (rom_base >> 16) - (ORIG_ROM_BASE >> 16);
+    assert!(
+        value >> (8 * before.len()) == 0,
+        "Relocated value doesn't fit"
+    );
+    (0..before.len())
+        .rev()
+        .map(|i| (value >> (8 * i)) as u8)
+        .collect()
+}
+
+// How many of a patch's trailing bytes hold an address, and the
+// address they give, with the bits the patch doesn't cover as zero.
+// ROM references are patched through bits 16-23, in the last byte.
+// Hardware addresses are patched through their top one or two bytes
+// (bits 16-23, or 8-23).
+fn patched_address(category: Category, bytes: &[u8]) -> (usize, u32) {
+    let len = match category {
+        Category::RomReference => 1,
+        _ => bytes.len().min(2),
+    };
+    let top = &bytes[bytes.len() - len..];
+    let addr = top.iter().fold(0u32, |acc, b| (acc << 8) | *b as u32) << (8 * (3 - len));
+    (len, addr)
+}
+
+// Where a hardware address is in a region the memory map moves, the
+// patch must move it to the same place, leaving anything before it in
+// the patch the same. Values that aren't addresses, like the offset
+// from the SCC's read registers to its write ones, are left to the
+// tables.
+fn check_moved(category: Category, before: &[u8], after: &[u8]) {
+    let (len, addr) = patched_address(category, before);
+    if let Some(moved) = memory_map::MemoryMap::standard().moved(addr) {
+        let mut expected = before[..before.len() - len].to_vec();
+        expected.extend_from_slice(&moved.to_be_bytes()[1..1 + len]);
+        assert_eq!(
+            after, expected,
+            "Patch 'after' isn't where the memory map moves 0x{:06x}",
+            addr
+        );
+    }
+}
+
+// The bytes to patch in. The 'after' values in the tables are for
+// PATCHED_ROM_BASE, so ROM references are recalculated for other
+// bases. Moved hardware must agree with the memory map.
+fn patched_bytes(category: Category, before: &[u8], after: &[u8], rom_base: usize) -> Vec<u8> {
+    if category.moves_hardware() {
+        check_moved(category, before, after);
+    }
+    if category != Category::RomReference {
+        return after.to_vec();
+    }
+    assert_eq!(
+        relocate(before, PATCHED_ROM_BASE),
+        after,
+        "Patch 'after' isn't a relocated ROM address"
+    );
+    relocate(before, rom_base)
+}
+
+fn parse_address(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s.trim_start_matches("0x"), 16)
+        .map_err(|e| format!("Bad address '{}': {}", s, e))
+}
+
+// Check a ROM base is one we can relocate to: 64kB aligned, with the
+// whole ROM (and its end address) addressable in 24 bits.
+fn parse_rom_base(s: &str) -> Result<usize, String> {
+    let base = usize::from_str_radix(s.trim_start_matches("0x"), 16)
+        .map_err(|e| format!("Bad ROM base '{}': {}", s, e))?;
+    if base & 0xffff != 0 || base == 0 || base + ROM_SIZE > 0xff0000 {
+        return Err(format!("Can't relocate the ROM to 0x{:06x}", base));
+    }
+    Ok(base)
+}
+
+// Record of a patch that has been applied to an image.
+#[derive(Debug)]
+struct Applied {
+    addr: usize,
+    category: Category,
+    before: Vec<u8>,
+    after: Vec<u8>,
+}
+
 #[derive(Debug)]
 struct Patch<'a> {
+    category: Category,
     addr: usize,
     before: &'a [u8],
     after: &'a [u8],
 }
 
 impl<'a> Patch<'a> {
-    fn apply(&self, data: &mut [u8]) {
+    fn apply(&self, data: &mut [u8], rom_base: usize, log: &mut Vec<Applied>) {
+        let after = patched_bytes(self.category, self.before, self.after, rom_base);
         let target = &mut data[self.addr..];
         assert_eq!(
             self.before,
             &target[..self.before.len()],
             "Patch 'before' doesn't match ROM"
         );
-        target[..self.after.len()].copy_from_slice(self.after);
+        target[..after.len()].copy_from_slice(&after);
+        log.push(Applied {
+            addr: self.addr,
+            category: self.category,
+            before: self.before.to_vec(),
+            after,
+        });
     }
 }
 
 #[derive(Debug)]
 struct PatternPatch<'a> {
+    category: Category,
     pattern: &'a [u8],
     replacement: &'a [u8],
 }
 
 impl<'a> PatternPatch<'a> {
-    fn apply(&self, data: &mut [u8]) {
+    fn apply(&self, data: &mut [u8], rom_base: usize, log: &mut Vec<Applied>) {
         assert_eq!(
             self.pattern.len(),
             self.replacement.len(),
             "Replacement length must match pattern"
         );
+        let replacement = patched_bytes(self.category, self.pattern, self.replacement, rom_base);
         for idx in 0..(data.len() - self.pattern.len()) {
             let curr = &mut data[idx..];
             if curr.starts_with(self.pattern) {
-                curr[..self.replacement.len()].copy_from_slice(self.replacement);
+                curr[..replacement.len()].copy_from_slice(&replacement);
                 println!("Patched at 0x{:06x}", idx);
+                log.push(Applied {
+                    addr: idx,
+                    category: self.category,
+                    before: self.pattern.to_vec(),
+                    after: replacement.clone(),
+                });
             }
         }
     }
@@ -84,6 +527,7 @@ impl<'a> PatternPatch<'a> {
 
 #[derive(Debug)]
 struct ArrayPatch<'a> {
+    category: Category,
     start_addr: usize,
     // End address is inclusive.
     end_addr: usize,
@@ -93,65 +537,23 @@ struct ArrayPatch<'a> {
 }
 
 impl<'a> ArrayPatch<'a> {
-    fn apply(&self, data: &mut [u8]) {
+    fn apply(&self, data: &mut [u8], rom_base: usize, log: &mut Vec<Applied>) {
         let mut addr = self.start_addr;
         while addr <= self.end_addr {
             print!(" 0x{:06x}", addr);
             Patch {
+                category: self.category,
                 addr,
                 before: self.before,
                 after: self.after,
             }
-            .apply(data);
+            .apply(data, rom_base, log);
             addr += self.step;
         }
         println!();
     }
 }
 
-fn find_resource(prefix: &[u8], res_type: &str, res_id: i16, data: &[u8]) -> anyhow::Result<usize> {
-    assert_eq!(prefix.len(), 4);
-    assert_eq!(res_type.len(), 4);
-
-    let mut res_type_bytes = res_type.as_bytes();
-    let mut res_id = res_id;
-    if res_type == "boot" {
-        // Ugh, special case. Horrible hack.
-        res_type_bytes = &[0x00, 0x86, 0x00, 0x17];
-        res_id = 0;
-    }
-
-    let needle = [
-        prefix[0],
-        prefix[1],
-        prefix[2],
-        prefix[3],
-        res_type_bytes[0],
-        res_type_bytes[1],
-        res_type_bytes[2],
-        res_type_bytes[3],
-        (res_id >> 8) as u8,
-        (res_id & 0xff) as u8,
-    ];
-    let mut possibilities = Vec::new();
-    for idx in 0..(data.len() - 10) {
-        if data[idx..].starts_with(&needle) {
-            possibilities.push(idx);
-        }
-    }
-
-    match possibilities.len() {
-        0 => bail!("No match for resource {} {}", res_type, res_id),
-        1 => Ok(possibilities[0]),
-        _ => bail!(
-            "Multiple matches for resource {} {}: {:?}",
-            res_type,
-            res_id,
-            possibilities
-        ),
-    }
-}
-
 ////////////////////////////////////////////////////////////////////////
 // Generic immediate instruction patching.
 //
@@ -197,15 +599,16 @@ struct OwnedPatternPatch {
 }
 
 impl OwnedPatternPatch {
-    fn to_pattern_patch(&self) -> PatternPatch {
+    fn to_pattern_patch(&self) -> PatternPatch<'_> {
         PatternPatch {
+            category: Category::RomReference,
             pattern: &self.pattern,
             replacement: &self.replacement,
         }
     }
 
-    fn apply(&self, data: &mut [u8]) {
-        self.to_pattern_patch().apply(data);
+    fn apply(&self, data: &mut [u8], rom_base: usize, log: &mut Vec<Applied>) {
+        self.to_pattern_patch().apply(data, rom_base, log);
     }
 }
 
@@ -237,6 +640,7 @@ fn build_op_patches(prefixes: &[&[u8]], suffixes: &[(&[u8], &[u8])]) -> Vec<Owne
 //
 
 const BOOT_1_PATCHES: [Patch; 1] = [Patch {
+    category: Category::RomReference,
     addr: 0x2ea,
     before: &[0x00, 0x40],
     after: &[0x00, 0xf8],
@@ -244,21 +648,25 @@ const BOOT_1_PATCHES: [Patch; 1] = [Patch {
 
 const PTCH_34_PATCHES: [Patch; 4] = [
     Patch {
+        category: Category::RomReference,
         addr: 0x074e,
         before: &[0x00, 0x40],
         after: &[0x00, 0xf8],
     },
     Patch {
+        category: Category::RomReference,
         addr: 0x0756,
         before: &[0x00, 0x40],
         after: &[0x00, 0xf8],
     },
     Patch {
+        category: Category::RomReference,
         addr: 0x075e,
         before: &[0x00, 0x40],
         after: &[0x00, 0xf8],
     },
     Patch {
+        category: Category::RomReference,
         addr: 0x0766,
         before: &[0x00, 0x40],
         after: &[0x00, 0xf8],
@@ -268,12 +676,14 @@ const PTCH_34_PATCHES: [Patch; 4] = [
 const PTCH_117_PATCHES: [Patch; 2] = [
     // SCC read
     Patch {
+        category: Category::SccRead,
         addr: 0x4362 + 3,
         before: &[0x9f, 0xff],
         after: &[0xfc, 0x2f],
     },
     // SCC write
     Patch {
+        category: Category::SccWrite,
         addr: 0x4368 + 3,
         before: &[0xbf, 0xff],
         after: &[0xfc, 0x3f],
@@ -283,12 +693,14 @@ const PTCH_117_PATCHES: [Patch; 2] = [
 const PTCH_630_PATCHES: [Patch; 2] = [
     // SCC read
     Patch {
+        category: Category::SccRead,
         addr: 0x36DA + 3,
         before: &[0x9f, 0xff],
         after: &[0xfc, 0x2f],
     },
     // SCC write
     Patch {
+        category: Category::SccWrite,
         addr: 0x36E0 + 3,
         before: &[0xbf, 0xff],
         after: &[0xfc, 0x3f],
@@ -297,11 +709,13 @@ const PTCH_630_PATCHES: [Patch; 2] = [
 
 const CACH_1_PATCHES: [Patch; 2] = [
     Patch {
+        category: Category::RomReference,
         addr: 0x58,
         before: &[0x00, 0x40],
         after: &[0x00, 0xf8],
     },
     Patch {
+        category: Category::RomReference,
         addr: 0x2b6,
         before: &[0x00, 0x40],
         after: &[0x00, 0xf8],
@@ -310,52 +724,234 @@ const CACH_1_PATCHES: [Patch; 2] = [
 
 const PTCH_3_PATCHES: [Patch; 2] = [
     Patch {
+        category: Category::RomReference,
         addr: 0x19d6,
         before: &[0x00, 0x40],
         after: &[0x00, 0xf8],
     },
     Patch {
+        category: Category::RomReference,
         addr: 0x19e4,
         before: &[0x00, 0x40],
         after: &[0x00, 0xf8],
     },
 ];
 
+// The patches for one version of the System, and where its resources
+// have been extracted to.
+struct SystemPatchSet {
+    version: &'static str,
+    // The versions in a System's 'vers' resource that the set is for.
+    // Ones like "7.x" cover every version with that major version.
+    versions: &'static [&'static str],
+    dir: &'static str,
+    // Resources in the System file, patched one by one.
+    resources: &'static [ResourcePatch],
+    // Resources patched by type, in whichever of the System's files
+    // they're in.
+    type_patches: &'static [TypePatch],
+    // Hits from 'disk-scan' that aren't references to patch.
+    reviewed: &'static [disk_scan::Reviewed],
+    // Whether the set has been run against a real disk with that
+    // System on it. Ones that haven't are only used with --untested.
+    tested: bool,
+}
+
+const SYSTEM_PATCH_SETS: [SystemPatchSet; 2] = [
+    SystemPatchSet {
+        version: "6.0.1",
+        // The System on the 6.0.1 tools disk says it's 6.0.8 in its
+        // 'vers' resources. The resources are checked against the
+        // patches' lengths and prefixes before they're patched, so a
+        // System they don't fit fails rather than being mispatched.
+        versions: &["6.0.1", "6.0.8"],
+        dir: "../../system/6.0.1",
+        resources: &RESOURCE_PATCHES,
+        type_patches: &[],
+        reviewed: &disk_scan::SYSTEM_601_REVIEWED,
+        tested: true,
+    },
+    SystemPatchSet {
+        version: "7.x",
+        versions: &["7.x"],
+        dir: "../../system/7.x",
+        resources: &[],
+        type_patches: &SYSTEM_7_TYPE_PATCHES,
+        reviewed: &[],
+        // There's no System 7 disk in the repo yet.
+        tested: false,
+    },
+];
+
+impl SystemPatchSet {
+    fn matches(&self, version: &str) -> bool {
+        version == self.version
+            || self.versions.iter().any(|v| match v.strip_suffix(".x") {
+                Some(major) => version.split('.').next() == Some(major),
+                None => version == *v,
+            })
+    }
+
+    // The patches for a resource in one of the System's files.
+    fn resource_patch(
+        &self,
+        file_type: &str,
+        res_type: &str,
+        res_id: i16,
+    ) -> Option<&ResourcePatch> {
+        if file_type != hfs::SYSTEM_FILE_TYPE {
+            return None;
+        }
+        self.resources
+            .iter()
+            .find(|r| r.res_type == res_type && r.res_id == res_id)
+    }
+
+    // Patch a resource from one of the System's files, returning
+    // whether there are patches for it.
+    fn patch_resource(
+        &self,
+        file_type: &str,
+        resource: &hfs::Resource,
+        data: &mut [u8],
+        rom_base: usize,
+        machine: &Machine,
+        log: &mut Vec<Applied>,
+    ) -> anyhow::Result<bool> {
+        let res_type = resource.res_type.as_str();
+        if let Some(res) = self.resource_patch(file_type, res_type, resource.id) {
+            ensure!(
+                data.len() == res.length && data.starts_with(res.prefix),
+                "{} isn't the one the System {} patches are for",
+                resource.label(),
+                self.version
+            );
+            res.patch_data(data, rom_base, machine, log)?;
+            return Ok(true);
+        }
+        if let Some(patch) = self
+            .type_patches
+            .iter()
+            .find(|p| p.file_type == file_type && p.res_type == res_type)
+        {
+            patch.patch_data(data, rom_base, log);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
+
+fn system_patch_set(version: &str, untested: bool) -> anyhow::Result<&'static SystemPatchSet> {
+    match SYSTEM_PATCH_SETS.iter().find(|set| set.matches(version)) {
+        Some(set) if !set.tested && !untested => bail!(
+            "The System {} patches haven't been checked against a real System \
+             disk yet; use --untested to use them anyway",
+            set.version
+        ),
+        Some(set) => Ok(set),
+        None => bail!("No patches for System {}", version),
+    }
+}
+
+// Resources patched by type rather than one by one: every resource of
+// the type in files of the given type gets the generic immediate
+// operand patches.
+struct TypePatch {
+    file_type: &'static str,
+    res_type: &'static str,
+}
+
+impl TypePatch {
+    fn patch_data(&self, data: &mut [u8], rom_base: usize, log: &mut Vec<Applied>) {
+        for patch in build_op_patches(&OP_PREFIXES, &ADDR_SUFFIXES).iter() {
+            patch.apply(data, rom_base, log);
+        }
+    }
+
+    // Code with one of each instruction the patches match, each
+    // followed by a NOP, for 'check' to run them on without a System
+    // disk.
+    fn sample(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for patch in build_op_patches(&OP_PREFIXES, &ADDR_SUFFIXES).iter() {
+            data.extend_from_slice(&patch.pattern);
+            data.extend_from_slice(&[0x4e, 0x71]);
+        }
+        data
+    }
+}
+
+// System 7 patches the ROM with linked patches ('lpch') as well as
+// 'ptch' resources, in the System file and in the System Enablers
+// ("gibblies", file type 'gbly') that 7.1 loads for each machine.
+// None have been gone through by hand, so they're patched by type,
+// and 'disk-scan' shows what that leaves.
+const SYSTEM_7_TYPE_PATCHES: [TypePatch; 4] = [
+    TypePatch {
+        file_type: hfs::SYSTEM_FILE_TYPE,
+        res_type: "ptch",
+    },
+    TypePatch {
+        file_type: hfs::SYSTEM_FILE_TYPE,
+        res_type: "lpch",
+    },
+    TypePatch {
+        file_type: "gbly",
+        res_type: "ptch",
+    },
+    TypePatch {
+        file_type: "gbly",
+        res_type: "lpch",
+    },
+];
+
 struct ResourcePatch {
     res_type: &'static str,
     res_id: i16,
     patch_imm_ops: bool,
     patches: &'static [Patch<'static>],
+    // References to fixed addresses under 4MB.
+    high_ram: &'static highram::Sites,
+    // The resource's size.
     length: usize,
-    // Used for finding the ROM on-disk.
+    // Checked against the resource found on disk, to make sure it's
+    // the one the patches are for.
     prefix: &'static [u8],
 }
 
 impl ResourcePatch {
-    fn patch_file(&self) -> anyhow::Result<()> {
-        let name = format!("../../system/6.0.1/{}_{}", self.res_type, self.res_id);
+    fn patch_file(&self, dir: &str, machine: &Machine) -> anyhow::Result<()> {
+        let name = format!("{}/{}_{}", dir, self.res_type, self.res_id);
         let mut data = fs::read(&name)?;
-        self.patch_data(&mut data);
+        self.patch_data(&mut data, PATCHED_ROM_BASE, machine, &mut Vec::new())?;
         fs::write(format!("{}.patched", &name), data)?;
         Ok(())
     }
 
-    fn patch_data(&self, data: &mut [u8]) {
+    fn patch_data(
+        &self,
+        data: &mut [u8],
+        rom_base: usize,
+        machine: &Machine,
+        log: &mut Vec<Applied>,
+    ) -> anyhow::Result<()> {
         let patches = build_op_patches(&OP_PREFIXES, &ADDR_SUFFIXES);
 
         // Generic immediate operand patches.
         if self.patch_imm_ops {
             for (idx, patch) in patches.iter().enumerate() {
                 println!("Applying patch #{}: {:?}", idx, patch.to_pattern_patch());
-                patch.apply(data);
+                patch.apply(data, rom_base, log);
             }
         }
 
         // Specfic patches
         for (idx, patch) in self.patches.iter().enumerate() {
             println!("Applying patch #{}: {:?}", idx, patch);
-            patch.apply(data);
+            patch.apply(data, rom_base, log);
         }
+
+        highram::patch(data, self.high_ram, machine, log)
     }
 }
 
@@ -365,7 +961,8 @@ const RESOURCE_PATCHES: [ResourcePatch; 6] = [
         res_id: 1,
         patch_imm_ops: false,
         patches: &BOOT_1_PATCHES,
-        length: 0x404,
+        high_ram: &highram::NO_SITES,
+        length: 0x400,
         prefix: &[0x4c, 0x4b, 0x60, 0x00],
     },
     ResourcePatch {
@@ -373,7 +970,8 @@ const RESOURCE_PATCHES: [ResourcePatch; 6] = [
         res_id: 34,
         patch_imm_ops: false,
         patches: &PTCH_34_PATCHES,
-        length: 0x772,
+        high_ram: &highram::NO_SITES,
+        length: 0x76e,
         prefix: &[0x60, 0x00, 0x06, 0xe6],
     },
     ResourcePatch {
@@ -381,7 +979,8 @@ const RESOURCE_PATCHES: [ResourcePatch; 6] = [
         res_id: 117,
         patch_imm_ops: true,
         patches: &PTCH_117_PATCHES,
-        length: 0x4a48,
+        high_ram: &highram::PTCH_117_SITES,
+        length: 0x4a44,
         prefix: &[0x60, 0x00, 0x44, 0xA2],
     },
     ResourcePatch {
@@ -389,7 +988,8 @@ const RESOURCE_PATCHES: [ResourcePatch; 6] = [
         res_id: 630,
         patch_imm_ops: true,
         patches: &PTCH_630_PATCHES,
-        length: 0x41e0,
+        high_ram: &highram::PTCH_630_SITES,
+        length: 0x41dc,
         prefix: &[0x60, 0x00, 0x03c, 0xce],
     },
     ResourcePatch {
@@ -397,7 +997,8 @@ const RESOURCE_PATCHES: [ResourcePatch; 6] = [
         res_id: 1,
         patch_imm_ops: false,
         patches: &CACH_1_PATCHES,
-        length: 0xb86,
+        high_ram: &highram::NO_SITES,
+        length: 0xb82,
         prefix: &[0x60, 0x00, 0x07, 0xA4],
     },
     ResourcePatch {
@@ -405,56 +1006,83 @@ const RESOURCE_PATCHES: [ResourcePatch; 6] = [
         res_id: 3,
         patch_imm_ops: false,
         patches: &PTCH_3_PATCHES,
-        length: 0x1ab8,
+        high_ram: &highram::NO_SITES,
+        length: 0x1ab4,
         prefix: &[0x60, 0x00, 0x1A, 0xA4],
     },
 ];
 
-fn patch_resource(res_type: &str, res_id: i16) -> anyhow::Result<()> {
-    for res in RESOURCE_PATCHES.iter() {
-        if res.res_type == res_type && res.res_id == res_id {
-            res.patch_file()?;
-            return Ok(());
-        }
+fn patch_resource(
+    system: &SystemPatchSet,
+    res_type: &str,
+    res_id: i16,
+    machine: &Machine,
+) -> anyhow::Result<()> {
+    match system.resource_patch(hfs::SYSTEM_FILE_TYPE, res_type, res_id) {
+        Some(res) => res.patch_file(system.dir, machine),
+        None => bail!(
+            "Couldn't find resource {} {} in the System {} patches",
+            res_type,
+            res_id,
+            system.version
+        ),
     }
-
-    bail!("Couldn't find resource {} {}", res_type, res_id);
 }
 
 ////////////////////////////////////////////////////////////////////////
 // Disk patching.
 //
 
-fn patch_disk_601() -> anyhow::Result<()> {
-    let mut data = fs::read("../../system/6.0.1/tools.dsk")?;
+// Patch the resources in every file on a System disk, and the boot
+// blocks, with the patches for the System's version, writing the
+// result alongside as '<image>.patched'.
+fn patch_disk(path: &Path, untested: bool, machine: &Machine) -> anyhow::Result<()> {
+    let mut image = fs::read(path).with_context(|| format!("Couldn't read {}", path.display()))?;
+    let volume = hfs::read(&image)?;
+    let version = volume.system_version(&image)?;
+    let system = system_patch_set(&version, untested)?;
+    println!(
+        "\"{}\" has System {}, patching with the System {} patches",
+        volume.name, version, system.version
+    );
 
-    // The "boot" resource contents also occurs at the start of the
-    // disk (I guess it makes sense for boot resources to be placed in
-    // the boot block!), so we skip that to avoid multiple matches.
-    let patchable_data = &mut data[0x100..];
+    for file in volume.files.iter() {
+        let mut fork = file.rsrc.read(&image);
+        let mut patched = false;
+        for res in hfs::resources(&fork)?.iter() {
+            let data = &mut fork[res.offset..res.offset + res.size];
+            if system.patch_resource(
+                &file.file_type,
+                res,
+                data,
+                PATCHED_ROM_BASE,
+                machine,
+                &mut Vec::new(),
+            )? {
+                println!("Patched {} in {}", res.label(), file.path);
+                patched = true;
+            }
+        }
+        if patched {
+            file.rsrc.write(&mut image, &fork);
+        }
+    }
 
-    for patch in RESOURCE_PATCHES.iter() {
-        println!("Patching {} {}", patch.res_type, patch.res_id);
-        let idx = find_resource(patch.prefix, patch.res_type, patch.res_id, patchable_data)?;
-        patch.patch_data(&mut patchable_data[idx..][..patch.length]);
+    // The boot blocks hold the code from the System's 'boot' 1
+    // resource, so get the same patches.
+    if let Some(boot) = system.resource_patch(hfs::SYSTEM_FILE_TYPE, "boot", 1) {
+        let data = &mut image[volume.offset..volume.offset + hfs::BOOT_BLOCKS_SIZE];
+        ensure!(
+            data.starts_with(boot.prefix),
+            "The boot blocks don't hold the System {} boot code",
+            system.version
+        );
+        boot.patch_data(data, PATCHED_ROM_BASE, machine, &mut Vec::new())?;
+        println!("Patched the boot blocks");
     }
 
-    // And let's patch the boot sector while we're at it.
-    {
-        let boot_data = &mut data[..0x10000];
-        let boot_patch = &RESOURCE_PATCHES[0];
-        assert_eq!(boot_patch.res_type, "boot");
-        assert_eq!(boot_patch.res_id, 1);
-        let idx = find_resource(
-            boot_patch.prefix,
-            boot_patch.res_type,
-            boot_patch.res_id,
-            boot_data,
-        )?;
-        boot_patch.patch_data(&mut boot_data[idx..][..boot_patch.length]);
-    }
-
-    fs::write("../../system/6.0.1/tools.dsk.patched", data)?;
+    diskcopy::update_checksum(&mut image);
+    fs::write(format!("{}.patched", path.display()), image)?;
     Ok(())
 }
 
@@ -462,308 +1090,339 @@ fn patch_disk_601() -> anyhow::Result<()> {
 // ROM patching.
 //
 
-const ROM_PATCHES: [Patch; 57] = [
+const ROM_PATCHES: [Patch; 53] = [
     // Patch debug hooks from 0xf8XXXX to 0xfcXXXX, to avoid ROM
     // clash.
     Patch {
+        category: Category::DebugHook,
         addr: 0x000b8 + 5,
         before: &[0xf8],
         after: &[0xfc],
     },
     Patch {
+        category: Category::DebugHook,
         addr: 0x01bf0 + 3,
         before: &[0xf8],
         after: &[0xfc],
     },
     Patch {
+        category: Category::DebugHook,
         addr: 0x01bfa + 5,
         before: &[0xf8],
         after: &[0xfc],
     },
     // Patch references to absolute ROM addresses.
     Patch {
+        category: Category::RomReference,
         addr: 0x00004 + 1,
         before: &[0x40],
         after: &[0xf8],
     },
     Patch {
+        category: Category::RomReference,
         addr: 0x00136 + 3,
         before: &[0x41],
         after: &[0xf9],
     },
     Patch {
+        category: Category::RomReference,
         addr: 0x00262 + 3,
         before: &[0x40],
         after: &[0xf8],
     },
     Patch {
+        category: Category::RomReference,
         addr: 0x00636 + 3,
         before: &[0x41],
         after: &[0xf9],
     },
     Patch {
+        category: Category::RomReference,
         addr: 0x00642 + 3,
         before: &[0x41],
         after: &[0xf9],
     },
     Patch {
+        category: Category::RomReference,
         addr: 0x00c18 + 3,
         before: &[0x40],
         after: &[0xf8],
     },
     Patch {
+        category: Category::RomReference,
         addr: 0x00c30 + 3,
         before: &[0x40],
         after: &[0xf8],
     },
     Patch {
+        category: Category::RomReference,
         addr: 0x00c48 + 3,
         before: &[0x40],
         after: &[0xf8],
     },
     Patch {
+        category: Category::RomReference,
         addr: 0x01482 + 3,
         before: &[0x40],
         after: &[0xf8],
     },
     // 0x019ec etc. dealt with below.
     Patch {
+        category: Category::RomReference,
         addr: 0x01ca0 + 3,
         before: &[0x43],
         after: &[0xfb],
     },
     Patch {
+        category: Category::RomReference,
         addr: 0x026cc + 3,
         before: &[0x40],
         after: &[0xf8],
     },
     Patch {
+        category: Category::RomReference,
         addr: 0x0285a + 3,
         before: &[0x40],
         after: &[0xf8],
     },
     Patch {
+        category: Category::RomReference,
         addr: 0x02860 + 3,
         before: &[0x40],
         after: &[0xf8],
     },
     Patch {
+        category: Category::RomReference,
         addr: 0x0288a + 3,
         before: &[0x44],
         after: &[0xfc],
     },
     Patch {
+        category: Category::RomReference,
         addr: 0x3dd30 + 3,
         before: &[0x43],
         after: &[0xfb],
     },
     // Patch location of SCSI HW.
     Patch {
+        category: Category::Scsi,
         addr: 0x004b4 + 3,
         before: &[0x5f, 0xf0],
         after: &[0xfc, 0x10],
     },
     Patch {
+        category: Category::Scsi,
         addr: 0x01c74 + 3,
         before: &[0x5f, 0xf0],
         after: &[0xfc, 0x10],
     },
     Patch {
+        category: Category::Scsi,
         addr: 0x004bc + 3,
         before: &[0x5f, 0xf2],
         after: &[0xfc, 0x12],
     },
     Patch {
+        category: Category::Scsi,
         addr: 0x004c4 + 3,
         before: &[0x5f, 0xf2],
         after: &[0xfc, 0x12],
     },
     Patch {
+        category: Category::Scsi,
         addr: 0x004ce + 3,
         before: &[0x5f, 0xf0],
         after: &[0xfc, 0x10],
     },
     // Patches for SCC read
     Patch {
+        category: Category::SccRead,
         addr: 0x00478 + 3,
         before: &[0x9f, 0xff],
         after: &[0xfc, 0x2f],
     },
     Patch {
+        category: Category::SccRead,
         addr: 0x0056a + 3,
         before: &[0x9f, 0xff],
         after: &[0xfc, 0x2f],
     },
     Patch {
+        category: Category::SccRead,
         addr: 0x0059e + 3,
         before: &[0x9f, 0xff],
         after: &[0xfc, 0x2f],
     },
     Patch {
+        category: Category::SccRead,
         addr: 0x022f6 + 3,
         before: &[0x9f, 0xff],
         after: &[0xfc, 0x2f],
     },
     Patch {
+        category: Category::SccRead,
         addr: 0x02312 + 3,
         before: &[0x9f, 0xff],
         after: &[0xfc, 0x2f],
     },
     Patch {
+        category: Category::SccRead,
         addr: 0x02336 + 3,
         before: &[0x9f, 0xff],
         after: &[0xfc, 0x2f],
     },
     Patch {
+        category: Category::SccRead,
         addr: 0x02440 + 3,
         before: &[0x9f, 0xff],
         after: &[0xfc, 0x2f],
     },
     Patch {
+        category: Category::SccRead,
         addr: 0x0246e + 3,
         before: &[0x9f, 0xff],
         after: &[0xfc, 0x2f],
     },
     Patch {
+        category: Category::SccRead,
         addr: 0x321c6 + 3,
         before: &[0x20, 0x00],
         after: &[0x00, 0x10],
     },
     Patch {
+        category: Category::SccRead,
         addr: 0x32304 + 3,
         before: &[0x9f, 0xff],
         after: &[0xfc, 0x2f],
     },
     // Patches for SCC write
     Patch {
+        category: Category::SccWrite,
         addr: 0x00562 + 3,
         before: &[0xbf, 0xff],
         after: &[0xfc, 0x3f],
     },
     Patch {
+        category: Category::SccWrite,
         addr: 0x00598 + 3,
         before: &[0xbf, 0xff],
         after: &[0xfc, 0x3f],
     },
     Patch {
+        category: Category::SccWrite,
         addr: 0x02308 + 3,
         before: &[0xbf, 0xff],
         after: &[0xfc, 0x3f],
     },
     Patch {
+        category: Category::SccWrite,
         addr: 0x02322 + 3,
         before: &[0xbf, 0xff],
         after: &[0xfc, 0x3f],
     },
     Patch {
+        category: Category::SccWrite,
         addr: 0x02422 + 3,
         before: &[0xbf, 0xff],
         after: &[0xfc, 0x3f],
     },
     Patch {
+        category: Category::SccWrite,
         addr: 0x02432 + 3,
         before: &[0xbf, 0xff],
         after: &[0xfc, 0x3f],
     },
     Patch {
+        category: Category::SccWrite,
         addr: 0x02450 + 3,
         before: &[0xbf, 0xff],
         after: &[0xfc, 0x3f],
     },
     Patch {
+        category: Category::SccWrite,
         addr: 0x3230a + 3,
         before: &[0xbf, 0xff],
         after: &[0xfc, 0x3f],
     },
     // Patches for IWM
     Patch {
+        category: Category::Iwm,
         addr: 0x004e6 + 3,
         before: &[0xdf, 0xe1],
         after: &[0xfc, 0x41],
     },
     Patch {
+        category: Category::Iwm,
         addr: 0x004f0 + 3,
         before: &[0xdf, 0xe1],
         after: &[0xfc, 0x41],
     },
     Patch {
+        category: Category::Iwm,
         addr: 0x0109a + 3,
         before: &[0xdf, 0xf1],
         after: &[0xfc, 0x51],
     },
     Patch {
+        category: Category::Iwm,
         addr: 0x01c86 + 3,
         before: &[0xdf, 0xe1],
         after: &[0xfc, 0x41],
     },
     // Patches for VIA
     Patch {
+        category: Category::Via,
         addr: 0x00422 + 3,
         before: &[0xef, 0xe1],
         after: &[0xfc, 0x61],
     },
     Patch {
+        category: Category::Via,
         addr: 0x00520 + 3,
         before: &[0xef, 0xe1],
         after: &[0xfc, 0x61],
     },
     Patch {
+        category: Category::Via,
         addr: 0x0052a + 3,
         before: &[0xef, 0xe1],
         after: &[0xfc, 0x61],
     },
     Patch {
+        category: Category::Via,
         addr: 0x0054e + 3,
         before: &[0xef, 0xe1],
         after: &[0xfc, 0x61],
     },
     Patch {
+        category: Category::Via,
         addr: 0x0a2c4 + 3,
         before: &[0xef, 0xe1],
         after: &[0xfc, 0x61],
     },
     Patch {
+        category: Category::Via,
         addr: 0x36d2e + 3,
         before: &[0xef, 0xff],
         after: &[0xfc, 0x7f],
     },
     Patch {
+        category: Category::Via,
         addr: 0x36d42 + 3,
         before: &[0xef, 0xff],
         after: &[0xfc, 0x7f],
     },
     Patch {
+        category: Category::Via,
         addr: 0x36d6c + 5,
         before: &[0xef, 0xe1],
         after: &[0xfc, 0x61],
     },
-    // Patches to get around the 8MB limit on memory zones.
-    Patch {
-        addr: 0x0a4c0 + 3,
-        before: &[0x80],
-        after: &[0xfc],
-    },
-    Patch {
-        addr: 0x0a550 + 3,
-        before: &[0x80],
-        after: &[0xfc],
-    },
-    Patch {
-        addr: 0x0a9ae + 3,
-        before: &[0x80],
-        after: &[0xfc],
-    },
-    // Patch for maximum amount of memory that can be installed in the
-    // machine.
-    Patch {
-        addr: 0x0267e,
-        before: &[0x40],
-        after: &[0xf8],
-    },
 ];
 
 const ROM_ARRAY_PATCHES: [ArrayPatch; 3] = [
     ArrayPatch {
+        category: Category::RomReference,
         start_addr: 0x019ec + 1,
         end_addr: 0x01ae4 + 1,
         step: 4,
@@ -771,6 +1430,7 @@ const ROM_ARRAY_PATCHES: [ArrayPatch; 3] = [
         after: &[0xf8],
     },
     ArrayPatch {
+        category: Category::RomReference,
         start_addr: 0x36bc6 + 3,
         end_addr: 0x36c0e + 3,
         step: 6,
@@ -778,6 +1438,7 @@ const ROM_ARRAY_PATCHES: [ArrayPatch; 3] = [
         after: &[0xfb],
     },
     ArrayPatch {
+        category: Category::RomReference,
         start_addr: 0x3d038 + 3,
         end_addr: 0x3d08c + 3,
         step: 6,
@@ -789,31 +1450,333 @@ const ROM_ARRAY_PATCHES: [ArrayPatch; 3] = [
 const ROM_PATTERN_PATCHES: [PatternPatch; 1] = [
     // Patch LEA (0xefe1XXXX), XX for VIA
     PatternPatch {
+        category: Category::Via,
         pattern: &[0xf9, 0x00, 0xef, 0xe1],
         replacement: &[0xf9, 0x00, 0xfc, 0x61],
     },
 ];
 
-fn patch_rom() -> anyhow::Result<()> {
-    let mut data = fs::read("../../ROM.sefdhd")?;
+// New code to go in the space freed up in the ROM, and the hooks that
+// call it.
+const CAVE_CODE: [CaveCode; 0] = [];
+
+const CAVE_HOOKS: [CaveHook; 0] = [];
+
+// Replacements for trap implementations, placed in the cave.
+const TRAP_PATCHES: [TrapPatch; 0] = [];
+
+// Changes to the ROM header, which can point at new code.
+const HEADER_PATCHES: [HeaderPatch; 0] = [];
+
+// Apply all the ROM patches, returning a record of what was changed and
+// the code cave, with the new code's allocations.
+fn apply_rom_patches(
+    data: &mut [u8],
+    rom_base: usize,
+    machine: &Machine,
+) -> anyhow::Result<(Vec<Applied>, Cave)> {
+    let mut log = Vec::new();
 
     for (idx, patch) in ROM_PATCHES.iter().enumerate() {
         println!("Applying patch #{}: {:?}", idx, patch);
-        patch.apply(&mut data);
+        patch.apply(data, rom_base, &mut log);
     }
 
     for (idx, patch) in ROM_ARRAY_PATCHES.iter().enumerate() {
         println!("Applying array patch #{}: {:?}", idx, patch);
-        patch.apply(&mut data);
+        patch.apply(data, rom_base, &mut log);
     }
 
     for (idx, patch) in ROM_PATTERN_PATCHES.iter().enumerate() {
         println!("Applying pattern patch #{}: {:?}", idx, patch);
-        patch.apply(&mut data);
+        patch.apply(data, rom_base, &mut log);
+    }
+
+    ram::patch(data, rom_base, machine.max_ram, &mut log)?;
+    highram::patch(data, &highram::ROM_SITES, machine, &mut log)?;
+
+    let mut cave = Cave::reclaim(data, rom_base, &mut log)?;
+    let scsi_source = machine.scsi_mailbox.map(scsi::source);
+    let mut trap_patches = TRAP_PATCHES.to_vec();
+    if let (Some(mailbox), Some(source)) = (machine.scsi_mailbox, scsi_source.as_deref()) {
+        println!(
+            "Replacing the SCSI Manager with the paravirtual one, mailbox at 0x{:06x}",
+            mailbox
+        );
+        trap_patches.push(scsi::trap_patch(source));
+    }
+    let code = CAVE_CODE
+        .into_iter()
+        .chain(trap_patches.iter().map(TrapPatch::code))
+        .collect::<Vec<_>>();
+    cave.place(data, rom_base, &code, &CAVE_HOOKS, &mut log)?;
+    traps::redirect(data, &mut cave, &trap_patches, &mut log)?;
+    screen::patch(data, rom_base, &machine.geometry, &mut cave, &mut log)?;
+    if let Some(mailbox) = machine.floppy_mailbox {
+        floppy::patch(data, rom_base, mailbox, &mut cave, &mut log)?;
+    }
+    if let Some(mailbox) = machine.via_mailbox {
+        via::patch(data, rom_base, mailbox, &mut cave, &mut log)?;
+    }
+
+    let externals = cave.externals(data, rom_base)?;
+    for patch in HEADER_PATCHES.iter() {
+        println!("Applying header patch: {:?}", patch);
+        header::set(data, rom_base, patch, &externals, &mut log)?;
+    }
+    header::check(data, rom_base)?;
+
+    Ok((log, cave))
+}
+
+// Hardware regions in the remapped memory map: name, start and
+// (exclusive) end.
+fn io_regions() -> impl Iterator<Item = (&'static str, usize, usize)> {
+    memory_map::IO_REGIONS
+        .iter()
+        .map(|(name, start, end)| (*name, *start as usize, *end as usize))
+}
+
+// Top of the I/O space, and of the 24-bit address space.
+const IO_END: usize = memory_map::ADDRESS_SPACE_END as usize;
+
+// Parse the address of a new device's registers, which must be
+// aligned to their size, in the I/O space, and clear of the existing
+// devices.
+fn parse_io_address(s: &str, what: &str, size: usize) -> Result<usize, String> {
+    let addr = usize::from_str_radix(s.trim_start_matches("0x"), 16)
+        .map_err(|e| format!("Bad {} address '{}': {}", what, s, e))?;
+    let io_start = memory_map::IO_REGIONS[0].1 as usize;
+    if addr % size != 0 || addr < io_start || addr + size > IO_END {
+        return Err(format!(
+            "{} 0x{:06x} must be {}-byte aligned, between 0x{:06x} and 0x{:06x}",
+            what, addr, size, io_start, IO_END
+        ));
+    }
+    if let Some((name, _, _)) =
+        io_regions().find(|(_, start, end)| addr < *end && addr + size > *start)
+    {
+        return Err(format!("{} 0x{:06x} overlaps {}", what, addr, name));
     }
+    Ok(addr)
+}
+
+// The first long of the ROM is the sum of all the words after it,
+// which the startup tests check before anything else. Get it wrong
+// and all you see is a Sad Mac.
+fn fix_checksum(data: &mut [u8]) {
+    let sum = data[4..].chunks(2).fold(0u32, |acc, w| {
+        acc.wrapping_add(u16::from_be_bytes([w[0], w[1]]) as u32)
+    });
+    println!("ROM checksum: 0x{:08x}", sum);
+    data[..4].copy_from_slice(&sum.to_be_bytes());
+}
 
+fn patch_rom(machine: &Machine) -> anyhow::Result<()> {
+    let mut data = fs::read("../../ROM.sefdhd")?;
+    let (applied, cave) = apply_rom_patches(&mut data, PATCHED_ROM_BASE, machine)?;
+    for (name, offset) in cave.symbols().iter() {
+        println!("  0x{:06x} {}", PATCHED_ROM_BASE + offset, name);
+    }
+    println!(
+        "Code cave: 0x{:x} of 0x{:x} bytes free",
+        cave.free(),
+        cave.size()
+    );
+    fix_checksum(&mut data);
     fs::write("../../ROM.patched", data)?;
+    let mut io_regions = io_regions().collect::<Vec<_>>();
+    if let Some(mailbox) = machine.floppy_mailbox {
+        io_regions.push(("FLOPPY", mailbox, mailbox + floppy::MAILBOX_SIZE));
+    }
+    if let Some(mailbox) = machine.scsi_mailbox {
+        io_regions.push(("SCSI_MAILBOX", mailbox, mailbox + scsi::MAILBOX_SIZE));
+    }
+    if let Some(mailbox) = machine.via_mailbox {
+        io_regions.push(("VIA_MAILBOX", mailbox, mailbox + via::MAILBOX_SIZE));
+    }
+    ghidra::write_script(
+        Path::new("../../ROM.patched.py"),
+        PATCHED_ROM_BASE,
+        &applied,
+        cave.symbols(),
+        &io_regions,
+    )?;
+
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////
+// Cross-checking against the Ghidra disassembly.
+//
+
+// Longest 68000 instruction. A marking on an instruction covers a
+// patch anywhere in the instruction.
+const MAX_INSN_LEN: usize = 10;
+
+fn check_markings(export: &Path) -> anyhow::Result<()> {
+    let markings = ghidra::read_markings(export)?;
+
+    let mut data = fs::read("../../ROM.sefdhd")?;
+    let (mut applied, _) = apply_rom_patches(&mut data, PATCHED_ROM_BASE, &DEFAULT_MACHINE)?;
+    applied.sort_by_key(|a| a.addr);
+
+    let categories = [
+        Category::DebugHook,
+        Category::RomReference,
+        Category::AllocLimit,
+        Category::HighRam,
+    ];
+    let marked: Vec<(usize, Category)> = markings
+        .iter()
+        .filter_map(|m| {
+            categories
+                .iter()
+                .find(|c| c.marking().is_some_and(|marker| m.text.contains(marker)))
+                .map(|c| (m.addr, *c))
+        })
+        .collect();
+
+    let covers = |site: usize, patch: usize| site <= patch && patch < site + MAX_INSN_LEN;
+
+    println!();
+    println!("Marked sites with no patch:");
+    let mut unpatched = 0;
+    for (site, category) in marked.iter() {
+        if !applied
+            .iter()
+            .any(|a| a.category == *category && covers(*site, a.addr))
+        {
+            println!("  0x{:06x} {}", site, category.marking().unwrap());
+            unpatched += 1;
+        }
+    }
+
+    // Only the kinds of patch that get marked can be missing a
+    // marking. A marking of some other kind doesn't count.
+    let markable: Vec<&Applied> = applied
+        .iter()
+        .filter(|a| a.category.marking().is_some())
+        .collect();
+    println!("Patches with no marking:");
+    let mut unmarked = 0;
+    for a in markable.iter() {
+        if !marked
+            .iter()
+            .any(|(site, category)| *category == a.category && covers(*site, a.addr))
+        {
+            let other = marked
+                .iter()
+                .find(|(site, _)| covers(*site, a.addr))
+                .map(|(_, c)| format!(" (marked {:?})", c))
+                .unwrap_or_default();
+            println!(
+                "  0x{:06x} {:?} {:02x?} -> {:02x?}{}",
+                a.addr, a.category, a.before, a.after, other
+            );
+            unmarked += 1;
+        }
+    }
+
+    println!(
+        "{} marked sites ({} unpatched), {} patches ({} unmarked)",
+        marked.len(),
+        unpatched,
+        markable.len(),
+        unmarked
+    );
+    if unpatched + unmarked > 0 {
+        bail!(
+            "{} marked sites with no patch, and {} patches with no marking",
+            unpatched,
+            unmarked
+        );
+    }
+
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////
+// Trying out new code.
+//
+
+fn assemble_file(source: &Path, origin: u32) -> anyhow::Result<()> {
+    let text = fs::read_to_string(source)
+        .with_context(|| format!("Couldn't read {}", source.display()))?;
+    let rom = fs::read("../../ROM.sefdhd")?;
+    let externals = cave::rom_externals(&rom, PATCHED_ROM_BASE)?;
+    let code = asm::assemble(&text, origin, &externals)?;
+    for (idx, chunk) in code.chunks(16).enumerate() {
+        let hex = chunk
+            .chunks(2)
+            .map(|w| w.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+            .collect::<Vec<_>>();
+        println!("{:06x}: {}", origin as usize + idx * 16, hex.join(" "));
+    }
+    println!("0x{:x} bytes", code.len());
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////
+// Header editing.
+//
+
+// List the header, after making any changes to it.
+fn edit_header(path: &Path, rom_base: usize, changes: &[String]) -> anyhow::Result<()> {
+    let mut data = fs::read(path).with_context(|| format!("Couldn't read {}", path.display()))?;
+    let externals = cave::rom_externals(&data, rom_base)?;
+    if !changes.is_empty() {
+        let mut log = Vec::new();
+        for change in changes.iter() {
+            let Some((field, value)) = change.split_once('=') else {
+                bail!("Expected <field>=<value>, got '{}'", change);
+            };
+            let patch = HeaderPatch {
+                field: field.trim(),
+                value: value.trim(),
+            };
+            header::set(&mut data, rom_base, &patch, &externals, &mut log)?;
+        }
+        header::check(&data, rom_base)?;
+        fix_checksum(&mut data);
+        fs::write(path, &data)?;
+    }
+    header::check(&data, rom_base)?;
+    header::list(&data, rom_base, &externals);
+    Ok(())
+}
 
+////////////////////////////////////////////////////////////////////////
+// Memory map generation.
+//
+
+fn generate_memory_map(dir: &Path, check: bool) -> anyhow::Result<()> {
+    let path = dir.join("memory_map.txt");
+    let text =
+        fs::read_to_string(&path).with_context(|| format!("Couldn't read {}", path.display()))?;
+    let map =
+        memory_map::MemoryMap::parse(&text).with_context(|| format!("In {}", path.display()))?;
+    let mut stale = Vec::new();
+    for (name, contents) in map.generated() {
+        let path = dir.join(name);
+        if fs::read_to_string(&path).ok().as_deref() == Some(contents.as_str()) {
+            println!("{} is up to date", path.display());
+        } else if check {
+            stale.push(name);
+        } else {
+            fs::write(&path, contents)
+                .with_context(|| format!("Couldn't write {}", path.display()))?;
+            println!("Wrote {}", path.display());
+        }
+    }
+    ensure!(
+        stale.is_empty(),
+        "Out of date: {} (run 'patch memory-map')",
+        stale.join(", ")
+    );
+    if text != memory_map::DEFINITION {
+        println!("The tools were built with an older memory map: rebuild them to use this one");
+    }
     Ok(())
 }
 
@@ -825,9 +1788,54 @@ fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Rom => patch_rom()?,
-        Commands::Resource { res_type, res_id } => patch_resource(&res_type, res_id)?,
-        Commands::Disk601 => patch_disk_601()?,
+        Commands::Rom { machine } => patch_rom(&machine.machine()?)?,
+        Commands::Resource {
+            res_type,
+            res_id,
+            system,
+            sets,
+            machine,
+        } => patch_resource(
+            system_patch_set(&system, sets.untested)?,
+            &res_type,
+            res_id,
+            &machine.machine()?,
+        )?,
+        Commands::Disk {
+            image,
+            sets,
+            machine,
+        } => patch_disk(&image, sets.untested, &machine.machine()?)?,
+        Commands::Disk601 { machine } => {
+            patch_disk(Path::new(TOOLS_DISK_601), false, &machine.machine()?)?
+        }
+        Commands::Markings { export } => check_markings(&export)?,
+        Commands::RelocationCheck {
+            base_a,
+            base_b,
+            machine,
+        } => reloc::check_relocation(base_a, base_b, &machine.machine()?)?,
+        Commands::Check {
+            system,
+            base,
+            sets,
+            machine,
+        } => consistency::check(
+            base,
+            system_patch_set(&system, sets.untested)?,
+            &machine.machine()?,
+        )?,
+        Commands::DiskScan {
+            disk,
+            system,
+            data,
+            sets,
+        } => disk_scan::scan(&disk, system.as_deref(), data, sets.untested)?,
+        Commands::ScreenScan => screen::scan(&fs::read("../../ROM.sefdhd")?)?,
+        Commands::HighRamScan => highram::scan(&fs::read("../../ROM.sefdhd")?)?,
+        Commands::MemoryMap { dir, check } => generate_memory_map(&dir, check)?,
+        Commands::Asm { source, origin } => assemble_file(&source, origin)?,
+        Commands::Header { rom, base, set } => edit_header(&rom, base, &set)?,
     }
 
     Ok(())
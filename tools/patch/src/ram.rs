@@ -0,0 +1,129 @@
+//
+// Installed RAM
+//
+// The ROM sizes RAM at boot by probing for it at a list of sizes (in
+// 64kB units), and the largest in the list is taken on trust rather
+// than probed. Originally that's 4MB. Separately, the Memory Manager
+// refuses to deal with anything larger than 8MB. This sets both for
+// the largest amount of RAM we want to support.
+//
+
+use anyhow::ensure;
+
+use crate::{io_regions, Applied, Category, ROM_SIZE};
+
+// RAM runs all the way up to the ROM.
+pub const DEFAULT_MAX_RAM: usize = memory_map::RAM_END as usize;
+
+// The sizes probed for are 0.5, 1, 2 and 2.5MB, followed by the
+// assumed maximum, then a 0xff terminator.
+const MAX_SIZE: usize = 0x0267e;
+const ORIG_MAX_RAM: usize = 0x400000;
+
+// The largest probed size, which the maximum must be beyond.
+const LARGEST_PROBED: usize = 0x280000;
+
+// "MOVE.L #$800000,D0" instructions giving the largest allocation.
+const ALLOC_LIMITS: [usize; 3] = [0x0a4c0, 0x0a550, 0x0a9ae];
+const ORIG_ALLOC_LIMIT: usize = 0x800000;
+
+fn check_size(size: usize) -> Result<(), String> {
+    if size & 0xffff != 0 || size <= LARGEST_PROBED || size >= 0xff0000 {
+        return Err(format!(
+            "RAM size 0x{:06x} must be a multiple of 64kB, above 0x{:x} and below 0xff0000",
+            size, LARGEST_PROBED
+        ));
+    }
+    Ok(())
+}
+
+pub fn parse_max_ram(s: &str) -> Result<usize, String> {
+    let size = usize::from_str_radix(s.trim_start_matches("0x"), 16)
+        .map_err(|e| format!("Bad RAM size '{}': {}", s, e))?;
+    check_size(size)?;
+    Ok(size)
+}
+
+// Warn about RAM that would overlap the rest of the memory map. The
+// ROM checks the RAM it's told about, so this won't boot.
+fn check_map(max_ram: usize, rom_base: usize) {
+    if max_ram > rom_base {
+        println!(
+            "Warning: RAM up to 0x{:06x} overlaps the ROM at 0x{:06x}-0x{:06x}",
+            max_ram,
+            rom_base,
+            rom_base + ROM_SIZE
+        );
+    }
+    for (name, start, _) in io_regions().filter(|(_, start, _)| max_ram > *start) {
+        println!(
+            "Warning: RAM up to 0x{:06x} overlaps {} at 0x{:06x}",
+            max_ram, name, start
+        );
+    }
+}
+
+fn replace(
+    data: &mut [u8],
+    category: Category,
+    addr: usize,
+    before: &[u8],
+    after: &[u8],
+    log: &mut Vec<Applied>,
+) -> anyhow::Result<()> {
+    let target = &mut data[addr..addr + before.len()];
+    ensure!(
+        target == before,
+        "RAM size constant at 0x{:05x} doesn't match ROM",
+        addr
+    );
+    if target != after {
+        target.copy_from_slice(after);
+        log.push(Applied {
+            addr,
+            category,
+            before: before.to_vec(),
+            after: after.to_vec(),
+        });
+    }
+    Ok(())
+}
+
+// Patch the ROM to take up to 'max_ram' bytes of RAM.
+pub fn patch(
+    data: &mut [u8],
+    rom_base: usize,
+    max_ram: usize,
+    log: &mut Vec<Applied>,
+) -> anyhow::Result<()> {
+    check_size(max_ram).map_err(anyhow::Error::msg)?;
+    check_map(max_ram, rom_base);
+
+    replace(
+        data,
+        Category::MaxMemory,
+        MAX_SIZE,
+        &[(ORIG_MAX_RAM >> 16) as u8],
+        &[(max_ram >> 16) as u8],
+        log,
+    )?;
+
+    // Beyond 8MB, allow allocations up to the I/O region, which is as
+    // far as RAM should go.
+    if max_ram > ORIG_ALLOC_LIMIT {
+        let limit = max_ram.max(memory_map::IO_REGIONS[0].1 as usize);
+        for addr in ALLOC_LIMITS.iter() {
+            // The byte holding bits 16-23 of the immediate.
+            replace(
+                data,
+                Category::AllocLimit,
+                addr + 3,
+                &[(ORIG_ALLOC_LIMIT >> 16) as u8],
+                &[(limit >> 16) as u8],
+                log,
+            )?;
+        }
+    }
+
+    Ok(())
+}
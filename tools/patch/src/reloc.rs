@@ -0,0 +1,137 @@
+//
+// Relocation checking
+//
+// Patches the ROM and resources for two different ROM bases, and
+// checks that everything that changes between the two is a relocated
+// absolute address, rather than something that happened to match a
+// patch.
+//
+
+use std::fs;
+
+use anyhow::bail;
+
+use crate::{apply_rom_patches, Machine, ResourcePatch, RESOURCE_PATCHES, ROM_SIZE};
+
+// Classification of a byte that differs between the two images.
+#[derive(Debug, PartialEq, Eq)]
+enum Difference {
+    // High-order byte of an absolute address operand pointing at the
+    // same ROM offset in both images.
+    Relocated { rom_offset: usize },
+    // Anything else.
+    Suspicious(&'static str),
+}
+
+fn read_long(data: &[u8], addr: usize) -> usize {
+    data[addr..addr + 4]
+        .iter()
+        .fold(0usize, |acc, b| (acc << 8) | *b as usize)
+}
+
+// Work out what a differing byte at 'idx' is. Absolute addresses are
+// 24-bit values in word-aligned longs, so the byte that changes with
+// the ROM base is the second byte of the long.
+fn classify(a: &[u8], base_a: usize, b: &[u8], base_b: usize, idx: usize) -> Difference {
+    if idx & 1 == 0 {
+        return Difference::Suspicious("not the high byte of a word-aligned long");
+    }
+    let start = idx - 1;
+    if start + 4 > a.len() {
+        return Difference::Suspicious("too near the end for an address operand");
+    }
+    if a[start] != 0x00 {
+        return Difference::Suspicious("not a 24-bit address");
+    }
+    if a[start + 2..start + 4] != b[start + 2..start + 4] {
+        return Difference::Suspicious("low-order bytes differ");
+    }
+
+    let (long_a, long_b) = (read_long(a, start), read_long(b, start));
+    if long_a < base_a || long_b < base_b {
+        return Difference::Suspicious("not a ROM address");
+    }
+    let rom_offset = long_a - base_a;
+    // The end of the ROM is a legitimate address to refer to.
+    if rom_offset != long_b - base_b || rom_offset > ROM_SIZE {
+        return Difference::Suspicious("not a ROM address");
+    }
+    Difference::Relocated { rom_offset }
+}
+
+// Diff two patched images, printing the differences. Returns the
+// number of suspicious differences.
+fn diff_images(name: &str, a: &[u8], base_a: usize, b: &[u8], base_b: usize) -> usize {
+    assert_eq!(a.len(), b.len());
+
+    let mut relocated = 0;
+    let mut suspicious = 0;
+    for idx in (0..a.len()).filter(|idx| a[*idx] != b[*idx]) {
+        match classify(a, base_a, b, base_b, idx) {
+            Difference::Relocated { rom_offset } => {
+                println!(
+                    "  {} 0x{:06x}: {:02x} vs {:02x} expected (ROM offset 0x{:05x})",
+                    name, idx, a[idx], b[idx], rom_offset
+                );
+                relocated += 1;
+            }
+            Difference::Suspicious(why) => {
+                println!(
+                    "  {} 0x{:06x}: {:02x} vs {:02x} SUSPICIOUS ({})",
+                    name, idx, a[idx], b[idx], why
+                );
+                suspicious += 1;
+            }
+        }
+    }
+    println!(
+        "{}: {} relocated operands, {} suspicious bytes",
+        name, relocated, suspicious
+    );
+    suspicious
+}
+
+fn check_resource(
+    res: &ResourcePatch,
+    base_a: usize,
+    base_b: usize,
+    machine: &Machine,
+) -> anyhow::Result<usize> {
+    let name = format!("{}_{}", res.res_type, res.res_id);
+    let orig = fs::read(format!("../../system/6.0.1/{}", name))?;
+
+    let mut a = orig.clone();
+    res.patch_data(&mut a, base_a, machine, &mut Vec::new())?;
+    let mut b = orig;
+    res.patch_data(&mut b, base_b, machine, &mut Vec::new())?;
+
+    Ok(diff_images(&name, &a, base_a, &b, base_b))
+}
+
+pub fn check_relocation(base_a: usize, base_b: usize, machine: &Machine) -> anyhow::Result<()> {
+    if base_a == base_b {
+        bail!("Need two different ROM bases to compare");
+    }
+
+    let orig = fs::read("../../ROM.sefdhd")?;
+    let mut a = orig.clone();
+    apply_rom_patches(&mut a, base_a, machine)?;
+    let mut b = orig;
+    apply_rom_patches(&mut b, base_b, machine)?;
+
+    println!();
+    println!(
+        "Comparing ROM based at 0x{:06x} and 0x{:06x}:",
+        base_a, base_b
+    );
+    let mut suspicious = diff_images("ROM", &a, base_a, &b, base_b);
+
+    for res in RESOURCE_PATCHES.iter() {
+        suspicious += check_resource(res, base_a, base_b, machine)?;
+    }
+
+    if suspicious != 0 {
+        bail!("{} suspicious differences found", suspicious);
+    }
+    Ok(())
+}
@@ -0,0 +1,522 @@
+//
+// Screen geometry
+//
+// The ROM assumes the built-in 512x342 1-bit display, with 64 bytes a
+// row, and a screen buffer 0x5900 below the top of memory, just under
+// the sound buffer. This rewrites the constants for a different
+// geometry.
+//
+// The constants were found with 'scan', which looks for immediate
+// operands with the geometry's values. Each hit was reviewed: the
+// screen ones are patched here, and the rest (disk block sizes, trap
+// number masks, stack frames and the like) are listed as not screen,
+// so that the scan only reports new hits.
+//
+
+use anyhow::{bail, ensure};
+
+use crate::cave::{Cave, CaveCode, CaveHook};
+use crate::{Applied, Category};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Geometry {
+    pub width: u32,
+    pub height: u32,
+    pub row_bytes: u32,
+}
+
+// What the ROM was written for.
+pub const ORIG_GEOMETRY: Geometry = Geometry {
+    width: 512,
+    height: 342,
+    row_bytes: 64,
+};
+
+// The sound buffer sits between the screen and the top of memory.
+pub const SOUND_BUFFER: u32 = 0x300;
+
+// The test manager keeps its registers in the space left between the
+// end of the screen and the sound buffer.
+pub const SCRATCH: u32 = 0x80;
+
+impl Geometry {
+    pub fn new(width: u32, height: u32, row_bytes: Option<u32>) -> anyhow::Result<Geometry> {
+        // Rows are whole words.
+        let row_bytes = row_bytes.unwrap_or(width.div_ceil(16) * 2);
+        let g = Geometry {
+            width,
+            height,
+            row_bytes,
+        };
+        // The cursor code works on 16-pixel-aligned words, and keeps
+        // a 32-pixel strip under the cursor.
+        ensure!(
+            width >= 32 && width.is_multiple_of(16),
+            "Screen width must be a multiple of 16, at least 32"
+        );
+        ensure!(height >= 16, "Screen height must be at least 16");
+        ensure!(
+            row_bytes.is_multiple_of(2) && row_bytes * 8 >= width,
+            "Row bytes must be even, and cover the width"
+        );
+        Ok(g)
+    }
+
+    fn size(&self) -> u32 {
+        self.row_bytes * self.height
+    }
+
+    // Space taken by the screen and the scratch space, below the sound
+    // buffer.
+    fn buffer(&self) -> u32 {
+        (self.size() + SCRATCH + 0xff) & !0xff
+    }
+
+    // Where the screen starts, for RAM ending at 'ram_top'.
+    pub fn base(&self, ram_top: u32) -> u32 {
+        ram_top - SOUND_BUFFER - self.buffer()
+    }
+}
+
+// How a constant is encoded.
+#[derive(Clone, Copy, Debug)]
+enum Operand {
+    // MOVEQ, with the value in the low byte of the opcode.
+    Moveq,
+    // A word or long immediate after the opcode.
+    Word,
+    Long,
+}
+
+impl Operand {
+    fn encode(&self, value: u32) -> Option<Vec<u8>> {
+        match self {
+            Operand::Moveq => (value < 0x80).then(|| vec![value as u8]),
+            Operand::Word => (value <= 0xffff).then(|| (value as u16).to_be_bytes().to_vec()),
+            Operand::Long => Some(value.to_be_bytes().to_vec()),
+        }
+    }
+
+    // Offset of the value from the start of the instruction.
+    fn offset(&self) -> usize {
+        match self {
+            Operand::Moveq => 1,
+            Operand::Word | Operand::Long => 2,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Site {
+    // Address of the instruction.
+    addr: usize,
+    operand: Operand,
+    value: fn(&Geometry) -> u32,
+    what: &'static str,
+}
+
+const SITES: [Site; 19] = [
+    Site {
+        addr: 0x005d0,
+        operand: Operand::Word,
+        value: |g| g.row_bytes,
+        what: "ScreenRow",
+    },
+    Site {
+        addr: 0x00608,
+        operand: Operand::Word,
+        value: |g| g.height,
+        what: "CrsrPin bottom",
+    },
+    Site {
+        addr: 0x0060c,
+        operand: Operand::Word,
+        value: |g| g.width,
+        what: "CrsrPin right",
+    },
+    Site {
+        addr: 0x008ca,
+        operand: Operand::Long,
+        value: |g| g.height << 16 | g.width,
+        what: "Screen bounds bottom right, at boot",
+    },
+    Site {
+        addr: 0x010a6,
+        operand: Operand::Word,
+        value: |g| g.height,
+        what: "Debugger screen height",
+    },
+    Site {
+        addr: 0x010aa,
+        operand: Operand::Word,
+        value: |g| g.width,
+        what: "Debugger screen width",
+    },
+    Site {
+        addr: 0x010ae,
+        operand: Operand::Word,
+        value: |g| g.row_bytes,
+        what: "Debugger screen row bytes",
+    },
+    Site {
+        addr: 0x010b2,
+        operand: Operand::Long,
+        value: |g| g.size(),
+        what: "Debugger screen size",
+    },
+    Site {
+        addr: 0x011ae,
+        operand: Operand::Word,
+        value: |g| g.row_bytes,
+        what: "Debugger screen row bytes",
+    },
+    Site {
+        addr: 0x011d6,
+        operand: Operand::Word,
+        value: |g| g.row_bytes,
+        what: "Debugger screen row bytes",
+    },
+    Site {
+        addr: 0x011e8,
+        operand: Operand::Word,
+        value: |g| g.row_bytes,
+        what: "Debugger screen row bytes",
+    },
+    Site {
+        addr: 0x18e78,
+        operand: Operand::Word,
+        value: |g| g.width - 32,
+        what: "Cursor save area right limit",
+    },
+    Site {
+        addr: 0x18e7e,
+        operand: Operand::Word,
+        value: |g| g.width - 32,
+        what: "Cursor save area right limit",
+    },
+    Site {
+        addr: 0x18e9e,
+        operand: Operand::Word,
+        value: |g| g.height - 16,
+        what: "Cursor save area bottom limit",
+    },
+    Site {
+        addr: 0x18ea4,
+        operand: Operand::Word,
+        value: |g| g.height,
+        what: "Cursor save area bottom",
+    },
+    Site {
+        addr: 0x18f0a,
+        operand: Operand::Word,
+        value: |g| g.width - 16,
+        what: "Cursor shield right limit",
+    },
+    Site {
+        addr: 0x18f98,
+        operand: Operand::Word,
+        value: |g| g.height,
+        what: "ScrnSize height",
+    },
+    Site {
+        addr: 0x18f9e,
+        operand: Operand::Word,
+        value: |g| g.width,
+        what: "ScrnSize and ScrnBitMap width",
+    },
+    Site {
+        addr: 0x18fb2,
+        operand: Operand::Word,
+        value: |g| g.height,
+        what: "ScrnBitMap height",
+    },
+];
+
+// The alternate screen buffer is allocated at boot, if the boot blocks
+// ask for it, by lowering BufPtr by 0x8000, made with "MOVEQ #1,D0;
+// ROR.W #1,D0". That's replaced with "MOVEQ #n,D0; SWAP D0", so the
+// distance is in 64kB units, and covers the screen and sound buffers.
+const ALT_SCREEN: usize = 0x0093e;
+const ALT_SCREEN_BEFORE: [u8; 4] = [0x70, 0x01, 0xe2, 0x58];
+
+// Code that's hooked out to new code, as there's no room for the new
+// values: the screen buffer's distance from the top of memory only
+// fits a word for small screens, and the cursor code has the row bytes
+// in a MOVEQ.
+struct Hook {
+    addr: usize,
+    before: &'static [u8],
+    source: &'static str,
+    name: &'static str,
+    code: fn(&Geometry, usize) -> String,
+}
+
+const HOOKS: [Hook; 4] = [
+    // Memory isn't set up yet, so no JSR.
+    Hook {
+        addr: 0x0005e,
+        before: &[0x20, 0x4e, 0x90, 0xfc, 0x59, 0x00],
+        source: "    jmp ScreenTestRange\n",
+        name: "ScreenTestRange",
+        code: |g, rom_base| {
+            format!(
+                "    movea.l a6,a0\n    suba.l #${:x},a0\n    jmp ${:x}\n",
+                g.buffer() + SOUND_BUFFER,
+                rom_base + 0x00064
+            )
+        },
+    },
+    Hook {
+        addr: 0x00258,
+        before: &[0x90, 0xfc, 0x56, 0x00, 0x21, 0xc8, 0x01, 0x0c],
+        source: "    jmp ScreenAlloc\n    nop\n",
+        name: "ScreenAlloc",
+        code: |g, _| {
+            format!(
+                "    suba.l #${:x},a0\n    move.l a0,($10c).w\n    rts\n",
+                g.buffer()
+            )
+        },
+    },
+    Hook {
+        addr: 0x18dfe,
+        before: &[0x41, 0xf8, 0x08, 0x8c, 0x70, 0x40],
+        source: "    jsr CursorRestoreRow\n",
+        name: "CursorRestoreRow",
+        code: |g, _| {
+            format!(
+                "    lea ($88c).w,a0\n    move.w #${:x},d0\n    rts\n",
+                g.row_bytes
+            )
+        },
+    },
+    Hook {
+        addr: 0x18ec0,
+        before: &[0xe6, 0x48, 0xd2, 0xc0, 0x7a, 0x40],
+        source: "    jsr CursorDrawRow\n",
+        name: "CursorDrawRow",
+        code: |g, _| {
+            format!(
+                "    lsr.w #3,d0\n    adda.w d0,a1\n    move.w #${:x},d5\n    rts\n",
+                g.row_bytes
+            )
+        },
+    },
+];
+
+fn replace(
+    data: &mut [u8],
+    addr: usize,
+    before: &[u8],
+    after: &[u8],
+    log: &mut Vec<Applied>,
+) -> anyhow::Result<()> {
+    let target = &mut data[addr..addr + before.len()];
+    ensure!(
+        target == before,
+        "Screen constant at 0x{:05x} doesn't match ROM",
+        addr
+    );
+    target.copy_from_slice(after);
+    log.push(Applied {
+        addr,
+        category: Category::Screen,
+        before: before.to_vec(),
+        after: after.to_vec(),
+    });
+    Ok(())
+}
+
+// Rewrite the ROM for the given geometry. Nothing changes for the
+// original geometry.
+pub fn patch(
+    data: &mut [u8],
+    rom_base: usize,
+    geometry: &Geometry,
+    cave: &mut Cave,
+    log: &mut Vec<Applied>,
+) -> anyhow::Result<()> {
+    if *geometry == ORIG_GEOMETRY {
+        return Ok(());
+    }
+    println!(
+        "Patching for a {}x{} screen, {} bytes a row",
+        geometry.width, geometry.height, geometry.row_bytes
+    );
+
+    for site in SITES.iter() {
+        let value = (site.value)(geometry);
+        let Some(after) = site.operand.encode(value) else {
+            bail!(
+                "{} at 0x{:05x}: 0x{:x} doesn't fit a {:?} operand",
+                site.what,
+                site.addr,
+                value,
+                site.operand
+            );
+        };
+        let before = site.operand.encode((site.value)(&ORIG_GEOMETRY)).unwrap();
+        replace(
+            data,
+            site.addr + site.operand.offset(),
+            &before,
+            &after,
+            log,
+        )?;
+    }
+
+    let distance = (geometry.buffer() + SOUND_BUFFER + 0xffff) >> 16;
+    ensure!(
+        distance < 0x80,
+        "Alternate screen buffer is too far below the main one"
+    );
+    let after = [0x70, distance as u8, 0x48, 0x40];
+    replace(data, ALT_SCREEN, &ALT_SCREEN_BEFORE, &after, log)?;
+
+    let sources = HOOKS
+        .iter()
+        .map(|h| (h.code)(geometry, rom_base))
+        .collect::<Vec<_>>();
+    let code = HOOKS
+        .iter()
+        .zip(sources.iter())
+        .map(|(h, source)| CaveCode {
+            name: h.name,
+            source,
+        })
+        .collect::<Vec<_>>();
+    let hooks = HOOKS
+        .iter()
+        .map(|h| CaveHook {
+            addr: h.addr,
+            before: h.before,
+            source: h.source,
+        })
+        .collect::<Vec<_>>();
+    cave.place(data, rom_base, &code, &hooks, log)
+}
+
+////////////////////////////////////////////////////////////////////////
+// Finding the constants.
+//
+
+// Hits that have been reviewed, and aren't screen geometry.
+const NOT_SCREEN: [usize; 78] = [
+    0x006e2, 0x0070a, 0x0076e, 0x00c12, 0x0100a, 0x01d0a, 0x02ce8, 0x02d44, 0x02d68, 0x02d94,
+    0x03062, 0x0408a, 0x040ec, 0x0417e, 0x041a6, 0x04636, 0x04692, 0x047b2, 0x0488c, 0x0495c,
+    0x04968, 0x05858, 0x05872, 0x059da, 0x059e6, 0x059fc, 0x05d3c, 0x05e0e, 0x06230, 0x06a02,
+    0x06a58, 0x06ae6, 0x06b12, 0x06b5c, 0x0750e, 0x0781c, 0x087a0, 0x08ca6, 0x09754, 0x097fa,
+    0x09b40, 0x0a264, 0x0a26e, 0x0a3a4, 0x0a408, 0x0ddde, 0x0dfb6, 0x0efe8, 0x10298, 0x104ca,
+    0x129d8, 0x129ee, 0x14546, 0x14886, 0x15418, 0x15f6c, 0x198a4, 0x198dc, 0x1998e, 0x19d12,
+    0x19d9e, 0x1ceca, 0x1d912, 0x2a19c, 0x2c626, 0x2d494, 0x3203e, 0x32a72, 0x32d78, 0x35e68,
+    0x35fc0, 0x3620e, 0x36376, 0x364aa, 0x36fa6, 0x3d41a, 0x3d4f6, 0x3d75c,
+];
+
+// The values that might be screen geometry.
+fn values(g: &Geometry) -> Vec<(u32, &'static str)> {
+    vec![
+        (g.width, "width"),
+        (g.width - 1, "width - 1"),
+        (g.width - 16, "width - 16"),
+        (g.width - 32, "width - 32"),
+        (g.height, "height"),
+        (g.height - 1, "height - 1"),
+        (g.height - 16, "height - 16"),
+        (g.row_bytes, "row bytes"),
+        (g.size(), "screen size"),
+        (g.buffer(), "screen buffer size"),
+        (g.buffer() + SOUND_BUFFER, "screen buffer offset"),
+        (g.height << 16 | g.width, "height, width"),
+        (g.width << 16 | g.height, "width, height"),
+    ]
+}
+
+// The immediate operand of the instruction at 'addr', for the
+// instructions that have one straight after the opcode: MOVE, the
+// immediate ops, and the ops on address and data registers. MOVEQ's is
+// in the opcode itself.
+fn immediate(data: &[u8], addr: usize) -> Option<(Operand, u32)> {
+    let op = u16::from_be_bytes([data[addr], data[addr + 1]]);
+    let word = || u16::from_be_bytes([data[addr + 2], data[addr + 3]]) as u32;
+    let long = || {
+        u32::from_be_bytes([
+            data[addr + 2],
+            data[addr + 3],
+            data[addr + 4],
+            data[addr + 5],
+        ])
+    };
+    let mode = (op >> 3) & 7;
+    let size = (op >> 6) & 3;
+    let operand = if op & 0xf100 == 0x7000 {
+        return Some((Operand::Moveq, op as u32 & 0xff));
+    } else if op & 0xf03f == 0x303c {
+        Operand::Word
+    } else if op & 0xf03f == 0x203c {
+        Operand::Long
+    } else if [0x00, 0x02, 0x04, 0x06, 0x0a, 0x0c].contains(&(op >> 8))
+        && (size == 1 || size == 2)
+        && mode != 1
+        && op & 0x3f != 0x3c
+    {
+        // ORI, ANDI, SUBI, ADDI, EORI and CMPI.
+        if size == 1 {
+            Operand::Word
+        } else {
+            Operand::Long
+        }
+    } else {
+        match op & 0xf1ff {
+            // SUBA, ADDA, CMPA, ADD, SUB, CMP, AND, OR, MULU, MULS,
+            // DIVU and DIVS.
+            0x90fc | 0xd0fc | 0xb0fc | 0xd07c | 0x907c | 0xb07c | 0xc07c | 0x807c | 0xc0fc
+            | 0xc1fc | 0x80fc | 0x81fc => Operand::Word,
+            0x91fc | 0xd1fc | 0xb1fc | 0xd0bc | 0x90bc | 0xb0bc | 0xc0bc | 0x80bc => Operand::Long,
+            _ => return None,
+        }
+    };
+    let value = match operand {
+        Operand::Word => word(),
+        _ => long(),
+    };
+    Some((operand, value))
+}
+
+// Look for instructions with the original geometry's values as
+// immediate operands, and check they've all been reviewed.
+pub fn scan(data: &[u8]) -> anyhow::Result<()> {
+    let values = values(&ORIG_GEOMETRY);
+    let mut unreviewed = 0;
+    for addr in (0..data.len() - 6).step_by(2) {
+        let Some((_, value)) = immediate(data, addr) else {
+            continue;
+        };
+        let Some((_, name)) = values.iter().find(|(v, _)| *v == value) else {
+            continue;
+        };
+        let status = if let Some(site) = SITES.iter().find(|site| site.addr == addr) {
+            format!("patched: {}", site.what)
+        } else if let Some(hook) = HOOKS
+            .iter()
+            .find(|h| (h.addr..h.addr + h.before.len()).contains(&addr))
+        {
+            format!("hooked: {}", hook.name)
+        } else if NOT_SCREEN.contains(&addr) {
+            "not screen".to_string()
+        } else {
+            unreviewed += 1;
+            "UNREVIEWED".to_string()
+        };
+        let bytes = data[addr..addr + 6]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        println!("0x{:05x} {} {:<20} {}", addr, bytes, name, status);
+    }
+    ensure!(
+        unreviewed == 0,
+        "{} unreviewed screen geometry candidates",
+        unreviewed
+    );
+    Ok(())
+}
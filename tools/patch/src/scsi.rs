@@ -0,0 +1,35 @@
+//
+// Paravirtual SCSI Manager
+//
+// Replaces the SCSI Manager's 5380 access with a mailbox that takes
+// whole SCSI commands and their data (see scsi.s for the protocol), so
+// that the clone can put an SD card behind it instead of an NCR 5380.
+// The new code replaces _SCSIDispatch, which the ROM's SCSI boot code
+// and the disk drivers all call, and passes anything it doesn't handle
+// on to the old one.
+//
+
+use crate::traps::TrapPatch;
+
+const SOURCE: &str = include_str!("scsi.s");
+
+// Where the mailbox goes if no address is given, and its size.
+pub const DEFAULT_MAILBOX: &str = memory_map::SCSI_MAILBOX_START_HEX;
+pub const MAILBOX_SIZE: usize = memory_map::SCSI_MAILBOX_SIZE as usize;
+
+pub fn parse_mailbox(s: &str) -> Result<usize, String> {
+    crate::parse_io_address(s, "SCSI mailbox", MAILBOX_SIZE)
+}
+
+// The new SCSI Manager, for the mailbox at the given address.
+pub fn source(mailbox: usize) -> String {
+    format!("Mailbox equ ${:06x}\n{}", mailbox, SOURCE)
+}
+
+pub fn trap_patch(source: &str) -> TrapPatch<'_> {
+    TrapPatch {
+        trap: "_SCSIDispatch",
+        name: "SCSIManager",
+        source,
+    }
+}
@@ -0,0 +1,330 @@
+* Paravirtual SCSI Manager
+*
+* Replaces _SCSIDispatch, which drives the NCR 5380 phase by phase,
+* with one that hands whole SCSI commands and their data to a mailbox
+* at 'Mailbox' (defined by the patch tool). The device on the other
+* side decodes the commands, so it can be backed by an SD card:
+*
+*   +0   long  Buffer address.
+*   +4   long  Length, in bytes.
+*   +8   byte  Command, written last to start it.
+*   +9   byte  Status: 0 done, 1 busy, $80 and up for errors: $80 no
+*              such target, $81 data phase mismatch, $82 bad command
+*              or buffer, $83 out of sequence, $84 I/O error on the
+*              host.
+*   +10  byte  Target ID, for Select.
+*   +11  byte  SCSI status byte, after Complete.
+*
+* The commands are:
+*
+*   1  Select    Select the target.
+*   2  Command   Send the command descriptor block in the buffer.
+*   3  Read      Read Length bytes of the data phase into the buffer.
+*   4  Write     Write Length bytes of the data phase from the buffer.
+*   5  Complete  Finish the command, leaving the SCSI status.
+*   6  Reset     Reset the bus, dropping any command in progress.
+*
+* Reads and writes follow the transfer instruction block, so that
+* blind and polled transfers are the same. The calls this doesn't know
+* about go to the ROM's SCSI Manager.
+*
+
+CmdSelect       equ     1
+CmdCommand      equ     2
+CmdRead         equ     3
+CmdWrite        equ     4
+CmdComplete     equ     5
+CmdReset        equ     6
+
+StsBusy         equ     1
+StsPhase        equ     $81
+StsBadParms     equ     $82
+StsSequence     equ     $83
+
+MbBuffer        equ     0
+MbLength        equ     4
+MbCommand       equ     8
+MbStatus        equ     9
+MbTarget        equ     10
+MbScsiStatus    equ     11
+
+* Transfer instruction opcodes.
+scInc           equ     1
+scNoInc         equ     2
+scAdd           equ     3
+scMove          equ     4
+scLoop          equ     5
+scNop           equ     6
+scStop          equ     7
+scComp          equ     8
+
+* SCSI Manager results.
+scCommErr       equ     2
+scBadParmsErr   equ     4
+scPhaseErr      equ     5
+scCompareErr    equ     6
+scSequenceErr   equ     8
+
+MaxSelector     equ     13
+
+* Called through the trap dispatcher, with the return address, then
+* the selector, then the arguments, then space for the result on the
+* stack. Each call leaves the result in D0 and the size of its
+* arguments in D1, and goes to Return. A0 points to the arguments.
+SCSIMgr:
+        move.w  4(sp),d0
+        cmpi.w  #MaxSelector,d0
+        bhi.s   Chain
+        lea     6(sp),a0
+        add.w   d0,d0
+        move.w  Selectors(pc,d0.w),d0
+        jmp     Selectors(pc,d0.w)
+
+Selectors:
+        dc.w    Reset-Selectors         ; SCSIReset
+        dc.w    Get-Selectors           ; SCSIGet
+        dc.w    Select-Selectors        ; SCSISelect
+        dc.w    Cmd-Selectors           ; SCSICmd
+        dc.w    Complete-Selectors      ; SCSIComplete
+        dc.w    Read-Selectors          ; SCSIRead
+        dc.w    Write-Selectors         ; SCSIWrite
+        dc.w    Chain-Selectors         ; SCSIInstall
+        dc.w    Read-Selectors          ; SCSIRBlind
+        dc.w    Write-Selectors         ; SCSIWBlind
+        dc.w    Stat-Selectors          ; SCSIStat
+        dc.w    Select-Selectors        ; SCSISelAtn
+        dc.w    MsgIn-Selectors         ; SCSIMsgIn
+        dc.w    MsgOut-Selectors        ; SCSIMsgOut
+
+Chain:
+        jmp     _SCSIDispatch
+
+* Drop the selector and arguments, and store the result.
+Return:
+        movea.l (sp)+,a1
+        addq.l  #2,sp
+        adda.w  d1,sp
+        move.w  d0,(sp)
+        jmp     (a1)
+
+* Start the command in D0 on the mailbox at A1 and wait for it, leaving
+* the result in D0 and setting the flags from it.
+Command:
+        move.b  d0,MbCommand(a1)
+CommandWait:
+        moveq   #0,d0
+        move.b  MbStatus(a1),d0
+        cmpi.b  #StsBusy,d0
+        beq.s   CommandWait
+        tst.b   d0
+        beq.s   CommandDone
+        cmpi.b  #StsPhase,d0
+        beq.s   CommandPhase
+        cmpi.b  #StsBadParms,d0
+        beq.s   CommandBadParms
+        cmpi.b  #StsSequence,d0
+        beq.s   CommandSequence
+        moveq   #scCommErr,d0           ; No target, or an I/O error
+        rts
+CommandPhase:
+        moveq   #scPhaseErr,d0
+        rts
+CommandBadParms:
+        moveq   #scBadParmsErr,d0
+        rts
+CommandSequence:
+        moveq   #scSequenceErr,d0
+CommandDone:
+        rts
+
+* FUNCTION SCSIReset: OSErr
+Reset:
+        lea     Mailbox,a1
+        moveq   #CmdReset,d0
+        bsr     Command
+        moveq   #0,d1
+        bra     Return
+
+* FUNCTION SCSIGet: OSErr. There's no one else on the bus.
+Get:
+        moveq   #0,d0
+        moveq   #0,d1
+        bra     Return
+
+* FUNCTION SCSISelect(targetID: INTEGER): OSErr
+Select:
+        lea     Mailbox,a1
+        move.b  1(a0),MbTarget(a1)
+        moveq   #CmdSelect,d0
+        bsr     Command
+        moveq   #2,d1
+        bra     Return
+
+* FUNCTION SCSICmd(buffer: Ptr; count: INTEGER): OSErr
+Cmd:
+        lea     Mailbox,a1
+        move.l  2(a0),MbBuffer(a1)
+        moveq   #0,d0
+        move.w  (a0),d0
+        move.l  d0,MbLength(a1)
+        moveq   #CmdCommand,d0
+        bsr     Command
+        moveq   #6,d1
+        bra     Return
+
+* FUNCTION SCSIComplete(VAR stat, message: INTEGER; wait: LONGINT): OSErr
+Complete:
+        lea     Mailbox,a1
+        moveq   #CmdComplete,d0
+        bsr     Command
+        bne.s   CompleteDone
+        moveq   #0,d1
+        move.b  MbScsiStatus(a1),d1
+        movea.l 8(a0),a1
+        move.w  d1,(a1)
+        movea.l 4(a0),a1
+        clr.w   (a1)                    ; Command complete
+CompleteDone:
+        moveq   #12,d1
+        bra     Return
+
+* FUNCTION SCSIStat: INTEGER
+Stat:
+        moveq   #0,d0
+        moveq   #0,d1
+        bra     Return
+
+* FUNCTION SCSIMsgIn(VAR message: INTEGER): OSErr
+MsgIn:
+        movea.l (a0),a1
+        clr.w   (a1)
+        moveq   #0,d0
+        moveq   #4,d1
+        bra     Return
+
+* FUNCTION SCSIMsgOut(message: INTEGER): OSErr
+MsgOut:
+        moveq   #0,d0
+        moveq   #2,d1
+        bra     Return
+
+* FUNCTION SCSIRead(tibPtr: Ptr): OSErr, and SCSIRBlind.
+Read:
+        moveq   #CmdRead,d2
+        bra.s   Transfer
+
+* FUNCTION SCSIWrite(tibPtr: Ptr): OSErr, and SCSIWBlind.
+Write:
+        moveq   #CmdWrite,d2
+
+* Run the transfer instruction block, moving data in the direction
+* given by the command in D2.
+Transfer:
+        movem.l d3-d4/a2-a3,-(sp)
+        movea.l (a0),a2
+        lea     Mailbox,a1
+TibNext:
+        move.w  (a2),d1
+        move.l  2(a2),d3
+        move.l  6(a2),d4
+        cmpi.w  #scInc,d1
+        beq.s   TibInc
+        cmpi.w  #scNoInc,d1
+        beq.s   TibNoInc
+        cmpi.w  #scAdd,d1
+        beq.s   TibAdd
+        cmpi.w  #scMove,d1
+        beq.s   TibMove
+        cmpi.w  #scLoop,d1
+        beq.s   TibLoop
+        cmpi.w  #scNop,d1
+        beq    TibStep
+        cmpi.w  #scComp,d1
+        beq.s   TibComp
+        moveq   #0,d0
+        cmpi.w  #scStop,d1
+        beq     TibDone
+        moveq   #scBadParmsErr,d0
+        bra     TibDone
+
+* Move the bytes, and advance the buffer.
+TibInc:
+        move.l  d3,MbBuffer(a1)
+        move.l  d4,MbLength(a1)
+        move.b  d2,d0
+        bsr     Command
+        bne.s   TibDone
+        add.l   d4,2(a2)
+        bra.s   TibStep
+
+* Move the bytes one at a time, all to or from the same address.
+TibNoInc:
+        move.l  d3,MbBuffer(a1)
+        moveq   #1,d0
+        move.l  d0,MbLength(a1)
+        bra.s   NoIncNext
+NoIncLoop:
+        move.b  d2,d0
+        bsr     Command
+        bne.s   TibDone
+NoIncNext:
+        subq.l  #1,d4
+        bcc.s   NoIncLoop
+        bra.s   TibStep
+
+TibAdd:
+        movea.l d3,a3
+        add.l   d4,(a3)
+        bra.s   TibStep
+
+TibMove:
+        movea.l d3,a3
+        movea.l d4,a0
+        move.l  (a3),(a0)
+        bra.s   TibStep
+
+* Go back or forward by the offset in bytes, until the count, which
+* is kept in the block, runs out.
+TibLoop:
+        subq.l  #1,6(a2)
+        beq.s   TibStep
+        adda.l  d3,a2
+        bra    TibNext
+
+* Read the bytes one at a time, comparing them with the buffer.
+TibComp:
+        moveq   #scBadParmsErr,d0
+        cmpi.b  #CmdRead,d2
+        bne.s   TibDone
+        movea.l d3,a3
+        subq.l  #2,sp
+        move.l  sp,MbBuffer(a1)
+        moveq   #1,d0
+        move.l  d0,MbLength(a1)
+        moveq   #0,d0
+        bra.s   CompNext
+CompLoop:
+        moveq   #CmdRead,d0
+        bsr     Command
+        bne.s   CompDone
+        move.b  (sp),d1
+        cmp.b   (a3)+,d1
+        beq.s   CompNext
+        moveq   #scCompareErr,d0
+        bra.s   CompDone
+CompNext:
+        subq.l  #1,d4
+        bcc.s   CompLoop
+CompDone:
+        addq.l  #2,sp
+        tst.w   d0
+        bne.s   TibDone
+
+TibStep:
+        lea     10(a2),a2
+        bra     TibNext
+
+TibDone:
+        movem.l (sp)+,d3-d4/a2-a3
+        moveq   #4,d1
+        bra     Return
@@ -0,0 +1,133 @@
+//
+// Trap redirection
+//
+// Points trap table entries at new code in the cave, to replace the
+// ROM's implementation of a trap. The trap table is compressed, with
+// each entry mostly stored as a step from the previous one, so the
+// whole table is decoded, updated and re-encoded. If it no longer fits
+// where it was, it's moved into the cave.
+//
+
+use anyhow::{anyhow, ensure, Context};
+
+use crate::cave::{Cave, CaveCode};
+use crate::{Applied, Category};
+
+// A replacement for a trap implementation. The trap is named as in
+// trap_names.txt (e.g. "_ReadDateTime"), or by number (e.g. "A039").
+// The new code goes in the cave under 'name'. The trap's own name
+// still refers to the ROM's implementation, so the new code can chain
+// on to it.
+#[derive(Clone, Copy, Debug)]
+pub struct TrapPatch<'a> {
+    pub trap: &'a str,
+    pub name: &'a str,
+    pub source: &'a str,
+}
+
+impl<'a> TrapPatch<'a> {
+    // The replacement routine, to be placed in the cave.
+    pub fn code(&self) -> CaveCode<'a> {
+        CaveCode {
+            name: self.name,
+            source: self.source,
+        }
+    }
+
+    // Index of the trap in the trap table.
+    fn idx(&self) -> anyhow::Result<usize> {
+        let traps = extract_traps::read_traps(crate::cave::TRAP_NAMES)?;
+        if let Some((idx, _)) = traps.iter().find(|(_, name)| *name == self.trap) {
+            return Ok(*idx);
+        }
+        let digits = self.trap.trim_start_matches("0x").trim_start_matches('$');
+        let trap = u32::from_str_radix(digits, 16)
+            .ok()
+            .filter(|trap| trap & 0xf000 == 0xa000)
+            .ok_or_else(|| anyhow!("'{}' isn't a trap name or number", self.trap))?;
+        Ok(extract_traps::trap_to_idx(trap))
+    }
+}
+
+// Point the traps at their replacements, which must already have been
+// placed in the cave.
+pub fn redirect(
+    data: &mut [u8],
+    cave: &mut Cave,
+    patches: &[TrapPatch],
+    log: &mut Vec<Applied>,
+) -> anyhow::Result<()> {
+    if patches.is_empty() {
+        return Ok(());
+    }
+
+    let mut addrs = extract_traps::Decoder::new(data).collect::<Vec<_>>();
+    for p in patches.iter() {
+        let idx = p.idx()?;
+        ensure!(idx < addrs.len(), "Trap {} isn't in the trap table", p.trap);
+        let offset = cave
+            .offset(p.name)
+            .with_context(|| format!("'{}' isn't in the cave", p.name))?;
+        println!(
+            "Redirecting trap {} (0x{:04X}) from 0x{:05x} to '{}' at 0x{:05x}",
+            p.trap,
+            extract_traps::idx_to_trap(idx),
+            addrs[idx] - extract_traps::ROM_BASE,
+            p.name,
+            offset
+        );
+        // The table is based at the original ROM base, so it doesn't
+        // need relocating.
+        addrs[idx] = extract_traps::ROM_BASE + offset as u32;
+    }
+
+    let table = extract_traps::encode(&addrs);
+    let start = extract_traps::get_table_start(data);
+    let end = extract_traps::table_end(data);
+    if table.len() <= end - start {
+        // Only log the part that changed.
+        let target = &mut data[start..start + table.len()];
+        let changed = |i: &usize| target[*i] != table[*i];
+        if let (Some(first), Some(last)) = (
+            (0..table.len()).find(changed),
+            (0..table.len()).rfind(changed),
+        ) {
+            log.push(Applied {
+                addr: start + first,
+                category: Category::TrapRedirect,
+                before: target[first..=last].to_vec(),
+                after: table[first..=last].to_vec(),
+            });
+        }
+        target.copy_from_slice(&table);
+    } else {
+        let offset = cave.alloc("TrapTable", table.len())?;
+        let target = &mut data[offset..offset + table.len()];
+        log.push(Applied {
+            addr: offset,
+            category: Category::TrapRedirect,
+            before: target.to_vec(),
+            after: table.clone(),
+        });
+        target.copy_from_slice(&table);
+
+        let pointer = extract_traps::TABLE_OFFSET;
+        let after = (offset as u32).to_be_bytes();
+        log.push(Applied {
+            addr: pointer,
+            category: Category::TrapRedirect,
+            before: data[pointer..pointer + 4].to_vec(),
+            after: after.to_vec(),
+        });
+        data[pointer..pointer + 4].copy_from_slice(&after);
+    }
+
+    // Make sure the ROM will see what we meant it to.
+    let decoded = extract_traps::Decoder::new(data).collect::<Vec<_>>();
+    ensure!(
+        decoded == addrs,
+        "Re-encoded trap table doesn't decode to the intended addresses"
+    );
+
+    Ok(())
+}
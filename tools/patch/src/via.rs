@@ -0,0 +1,150 @@
+//
+// Paravirtual VIA functions
+//
+// Moves the VBL and one-second interrupts, the real-time clock and
+// parameter RAM, and ADB off the VIA's ports and shift register and
+// onto a peripheral of their own (see via.s for the registers), so
+// that the clone doesn't have to reproduce the VIA's timing or the
+// serial protocols of the clock chip and the ADB transceiver. The VIA
+// itself stays, for the sound and video page bits and for SysBeep,
+// which polls CA1 with interrupts off.
+//
+
+use anyhow::Context;
+
+use crate::cave::{Cave, CaveCode, CaveHook};
+use crate::traps::{self, TrapPatch};
+use crate::Applied;
+
+const SOURCE: &str = include_str!("via.s");
+
+// Where the registers go if no address is given, and their size.
+pub const DEFAULT_MAILBOX: &str = memory_map::VIA_MAILBOX_START_HEX;
+pub const MAILBOX_SIZE: usize = memory_map::VIA_MAILBOX_SIZE as usize;
+
+pub fn parse_mailbox(s: &str) -> Result<usize, String> {
+    crate::parse_io_address(s, "VIA mailbox", MAILBOX_SIZE)
+}
+
+// The routines in via.s that are called from outside it. Each goes in
+// the cave as a snippet of its own, running up to the next one, with
+// the definitions at the top of the file in front.
+const ROUTINES: [&str; 8] = [
+    "Level1Flags",
+    "EnableTicks",
+    "ClockXfer",
+    "AdbInit",
+    "AdbOp",
+    "AdbStart",
+    "AdbVBL",
+    "AdbInterrupt",
+];
+
+// The places in the ROM that the new code carries on from or calls.
+const ROM_ENTRIES: [(&str, usize); 4] = [
+    // InitADB, after setting up the VIA and finding the devices.
+    ("InitADBTail", 0x335a),
+    // The ADB keyboard and mouse drivers' service routines.
+    ("KeyboardService", 0x3ac0),
+    ("MouseService", 0x3a54),
+    // The level 1 VBL interrupt handler.
+    ("VBLHandler", 0x2be4),
+];
+
+const HOOKS: [CaveHook; 5] = [
+    // The level 1 interrupt handler, reading the VIA's flags.
+    CaveHook {
+        addr: 0x2b36,
+        before: &[
+            0x22, 0x78, 0x01, 0xd4, 0x10, 0x29, 0x1a, 0x00, 0xc0, 0x29, 0x1c, 0x00,
+        ],
+        source: "    jsr Level1Flags\n    nop\n    nop\n    nop\n",
+    },
+    // Start-up, enabling CA1 and CA2 on the VIA.
+    CaveHook {
+        addr: 0x55a,
+        before: &[0x11, 0x7c, 0x00, 0x83, 0x1c, 0x00],
+        source: "    jsr EnableTicks\n",
+    },
+    // ClkXfer, calling the serial transfer through $54c.
+    CaveHook {
+        addr: 0xa246,
+        before: &[0x20, 0x78, 0x05, 0x4c, 0x4e, 0xd0],
+        source: "    jmp ClockXfer\n",
+    },
+    // InitADB, setting up the VIA's shift register.
+    CaveHook {
+        addr: 0x331a,
+        before: &[0x22, 0x78, 0x01, 0xd4, 0x42, 0x29, 0x18, 0x00],
+        source: "    jmp AdbInit\n    nop\n",
+    },
+    // Where InitADB and _ADBReInit start the device search.
+    CaveHook {
+        addr: 0x3334,
+        before: &[0x08, 0xeb, 0x00, 0x02, 0x01, 0x5d],
+        source: "    jmp AdbInit\n",
+    },
+];
+
+// Split the source into the routines, each behind the definitions.
+fn routines(source: &str) -> anyhow::Result<Vec<(&'static str, String)>> {
+    let lines = source.lines().collect::<Vec<_>>();
+    let mut starts = ROUTINES
+        .iter()
+        .map(|name| {
+            let label = format!("{}:", name);
+            lines
+                .iter()
+                .position(|line| line.trim_end() == label)
+                .map(|idx| (idx, *name))
+                .with_context(|| format!("No routine '{}' in via.s", name))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    starts.sort();
+    let header = lines[..starts[0].0].join("\n");
+    Ok(starts
+        .iter()
+        .enumerate()
+        .map(|(i, (start, name))| {
+            let end = starts.get(i + 1).map_or(lines.len(), |(end, _)| *end);
+            (
+                *name,
+                format!("{}\n{}\n", header, lines[*start..end].join("\n")),
+            )
+        })
+        .collect())
+}
+
+// Put the new code in the cave, hook it in and point _ADBOp at it.
+pub fn patch(
+    data: &mut [u8],
+    rom_base: usize,
+    mailbox: usize,
+    cave: &mut Cave,
+    log: &mut Vec<Applied>,
+) -> anyhow::Result<()> {
+    println!(
+        "Moving the VIA's interrupts, clock and ADB to the paravirtual VIA, at 0x{:06x}",
+        mailbox
+    );
+    let mut prefix = format!("Mailbox equ ${:06x}\n", mailbox);
+    for (name, offset) in ROM_ENTRIES.iter() {
+        prefix += &format!("{} equ ${:06x}\n", name, rom_base + offset);
+    }
+    let sources = routines(SOURCE)?
+        .into_iter()
+        .map(|(name, source)| (name, format!("{}{}", prefix, source)))
+        .collect::<Vec<_>>();
+    let code = sources
+        .iter()
+        .map(|(name, source)| CaveCode { name, source })
+        .collect::<Vec<_>>();
+    cave.place(data, rom_base, &code, &HOOKS, log)?;
+    let adb_op = code.iter().find(|c| c.name == "AdbOp").unwrap();
+    let adb_op = TrapPatch {
+        trap: "_ADBOp",
+        name: adb_op.name,
+        source: adb_op.source,
+    };
+    traps::redirect(data, cave, &[adb_op], log)
+}
@@ -0,0 +1,438 @@
+* Paravirtual VIA functions
+*
+* The ROM takes its VBL and one-second interrupts from the VIA's CA1
+* and CA2 inputs, and drives the real-time clock and ADB bit by bit
+* through its ports and shift register. This moves them to a peripheral
+* with registers at 'Mailbox' (defined by the patch tool):
+*
+*   +0   byte  Interrupt flags: bit 0 one-second, bit 1 VBL, bit 2 ADB
+*              transaction done, as in the VIA's IFR. Bit 7 reads as
+*              set if any enabled flag is. Writing 1s clears flags.
+*   +1   byte  Interrupt enable, as in the VIA's IER: writing with bit 7
+*              set sets the bits written, otherwise it clears them.
+*              Reads with bit 7 set.
+*   +2   byte  Parameter RAM address, 0 to 255, as for XPRAM.
+*   +3   byte  Parameter RAM data, at that address.
+*   +4   long  Clock, in seconds since 1904. Counts up as the one-second
+*              flag is set.
+*   +8   byte  ADB command, written last to start a transaction.
+*   +9   byte  ADB status: bit 0 busy, bit 1 no reply, bit 2 another
+*              device asked for service.
+*   +10  byte  ADB count: bytes of data to send for Listen, or received
+*              after Talk.
+*   +16  8     ADB data.
+*
+* The VBL comes at 60.15Hz, and the one-second flag with every 60th.
+* They should still reach the VIA's CA1 and CA2 as well, as SysBeep
+* polls CA1 when interrupts are off. The level 1 interrupt handler looks
+* at these flags before the VIA's, with A1 set up so that the ROM's
+* handlers clear them here when they clear the VIA's.
+*
+* The clock is hooked at ClkXfer, which takes the clock chip's serial
+* commands and turns them into register accesses here. There's no
+* write protection.
+*
+* ADB transactions are run one at a time, each setting the ADB flag
+* when it's done. This replaces the ROM's ADB transceiver code and
+* _ADBOp, but keeps its device table, so that the rest of the ADB
+* Manager and the keyboard and mouse drivers work as before. ADB start-up
+* finds the devices with Talk R3 at each address. It doesn't move
+* devices that share an address. Each VBL polls a device with Talk R0,
+* staying with the same device until another asks for service.
+*
+* Each routine that's called from outside goes into the cave on its
+* own, with the definitions above it, so the others reach it by name.
+*
+
+* Registers.
+Flags           equ     0
+Enable          equ     1
+PramAddr        equ     2
+PramData        equ     3
+Seconds         equ     4
+AdbCommand      equ     8
+AdbStatus       equ     9
+AdbCount        equ     10
+AdbData         equ     16
+
+* Flag and status bits.
+FlagsTicks      equ     $83                     ; Set one-second and VBL
+FlagAdb         equ     $04
+EnableAdb       equ     $84
+StsBusy         equ     0
+StsNoReply      equ     1
+StsService      equ     2
+
+* Low memory.
+ADBBase         equ     $cf8
+Lvl1DT          equ     $192
+VIA             equ     $1d4
+
+* ADBBase. The device table is the ROM's: a 12-byte entry for each
+* device, with its handler ID, original and current addresses, and
+* service routine and data. The rest is used differently.
+DevOrigAddr     equ     1
+DevAddr         equ     2
+DevService      equ     4
+DevData         equ     8
+DevSize         equ     12
+Queue           equ     $c0                     ; Calls waiting to start
+QueueEnd        equ     $130
+QueueEntry      equ     14
+QueueMax        equ     8
+OpBuffer        equ     $130                    ; The call in progress
+OpCompletion    equ     $134
+OpData          equ     $138
+QueueHead       equ     $144                    ; Offsets into ADBBase
+QueueTail       equ     $146
+QueueCount      equ     $148
+OpCommand       equ     $15c
+State           equ     $15d
+StBusy          equ     0                       ; A transaction is running
+StPolling       equ     3                       ; And it's a poll
+TableSize       equ     $161                    ; Bytes of table in use
+PollEntry       equ     $162                    ; Table offset to poll
+PollBuffer      equ     $163                    ; Count, then the data,
+                                                ; which the mouse driver
+                                                ; reads from $164(a3)
+
+* The pending, enabled flags for the level 1 interrupt handler, in D0,
+* with A1 such that $1a00(a1) is the flags register. This peripheral
+* comes first, then the VIA.
+Level1Flags:
+        lea     Mailbox,a1
+        move.b  Flags(a1),d0
+        and.b   Enable(a1),d0
+        beq.s   Level1Via
+        lea     Flags-$1a00(a1),a1
+        rts
+Level1Via:
+        movea.l (VIA).w,a1
+        move.b  $1a00(a1),d0
+        and.b   $1c00(a1),d0
+        rts
+
+* Enable the one-second and VBL interrupts here instead of on the VIA.
+EnableTicks:
+        move.b  #FlagsTicks,Mailbox+Enable
+        rts
+
+* Replaces the serial transfer behind ClkXfer. The clock chip command
+* is in the low byte of D1, with the second byte of an XPRAM one in
+* the next byte up, and data to write is in D2. Data read is left in
+* D2. Goes back through A5, and may use D3-D6, A0, A1 and A6.
+ClockXfer:
+        ori     #$300,sr
+        lea     Mailbox,a1
+        move.w  d1,d3
+        andi.w  #$78,d3
+        cmpi.w  #$38,d3
+        bne.s   ClockShort
+* z0111aaa in the low byte, with the top three bits of the address,
+* then xaaaaa00 in the high byte, with the rest.
+        move.w  d1,d3
+        andi.w  #7,d3
+        lsl.w   #5,d3
+        move.w  d1,d4
+        lsr.w   #8,d4
+        lsr.w   #2,d4
+        andi.w  #$1f,d4
+        or.w    d4,d3
+        move.b  d3,PramAddr(a1)
+        tst.b   d1
+        bmi.s   ClockRead
+        bra.s   ClockWrite
+ClockShort:
+        move.b  d1,d3
+        lsr.b   #2,d3
+        btst    #6,d1
+        bne.s   ClockPramHigh
+        btst    #5,d1
+        beq.s   ClockSeconds
+        btst    #4,d1
+        bne.s   ClockDone               ; Test and write-protect
+* z010aa01: XPRAM 8-11.
+        andi.b  #3,d3
+        addq.b  #8,d3
+        bra.s   ClockPram
+* z1aaaa01: XPRAM 16-31.
+ClockPramHigh:
+        andi.b  #$f,d3
+        addi.b  #$10,d3
+ClockPram:
+        move.b  d3,PramAddr(a1)
+        tst.b   d1
+        bmi.s   ClockRead
+ClockWrite:
+        move.b  d2,PramData(a1)
+        bra.s   ClockDone
+ClockRead:
+        move.b  PramData(a1),d2
+        bra.s   ClockDone
+* z00xaa01: byte aa of the seconds, least significant first.
+ClockSeconds:
+        andi.w  #3,d3
+        neg.w   d3
+        lea     Seconds+3(a1,d3.w),a0
+        tst.b   d1
+        bmi.s   ClockSecondsRead
+        move.b  d2,(a0)
+        bra.s   ClockDone
+ClockSecondsRead:
+        move.b  (a0),d2
+ClockDone:
+        jmp     (a5)
+
+* Start ADB and find the devices. Hooked in where InitADB would set up
+* the VIA, and where _ADBReInit starts again, with A3 pointing to
+* ADBBase. Carries on in InitADB, which enables interrupts and adds the
+* keyboard and mouse drivers.
+AdbInit:
+        ori     #$300,sr
+        lea     Mailbox,a1
+AdbInitWait:                                    ; For a reinit mid-poll
+        btst    #StsBusy,AdbStatus(a1)
+        bne.s   AdbInitWait
+        move.b  #FlagAdb,Flags(a1)
+        move.w  #Queue,QueueHead(a3)
+        move.w  #Queue,QueueTail(a3)
+        clr.b   QueueCount(a3)
+        clr.b   State(a3)
+        moveq   #0,d0                           ; SendReset
+        bsr     AdbSync
+        moveq   #0,d1
+        moveq   #0,d2
+AdbInitFind:
+        move.b  d2,d0
+        lsl.b   #4,d0
+        ori.b   #$f,d0                          ; Talk R3
+        bsr     AdbSync
+        bne.s   AdbInitNext
+        cmpi.b  #2,AdbCount(a1)
+        bcs.s   AdbInitNext
+        move.b  AdbData+1(a1),0(a3,d1.w)
+        move.b  d2,DevOrigAddr(a3,d1.w)
+        move.b  d2,DevAddr(a3,d1.w)
+        clr.l   DevService(a3,d1.w)
+        clr.l   DevData(a3,d1.w)
+        cmpi.b  #2,d2
+        bne.s   AdbInitMouse
+        lea     KeyboardService,a0
+        move.l  a0,DevService(a3,d1.w)
+AdbInitMouse:
+        cmpi.b  #3,d2
+        bne.s   AdbInitAdd
+        lea     MouseService,a0
+        move.l  a0,DevService(a3,d1.w)
+AdbInitAdd:
+        addi.w  #DevSize,d1
+AdbInitNext:
+        addq.b  #1,d2
+        cmpi.b  #16,d2
+        bne.s   AdbInitFind
+        move.b  d1,TableSize(a3)
+        clr.b   PollEntry(a3)
+        lea     AdbVBL,a0
+        move.l  a0,(Lvl1DT+4).w
+        lea     AdbInterrupt,a0
+        move.l  a0,(Lvl1DT+8).w
+        move.b  #EnableAdb,Enable(a1)
+        jmp     InitADBTail
+
+* Run the command in D0 and wait for it, with interrupts off. A1
+* points to the registers. Leaves the status in D0, with Z clear if
+* there was no reply.
+AdbSync:
+        move.b  d0,AdbCommand(a1)
+AdbSyncWait:
+        move.b  AdbStatus(a1),d0
+        btst    #StsBusy,d0
+        bne.s   AdbSyncWait
+        move.b  #FlagAdb,Flags(a1)
+        btst    #StsNoReply,d0
+        rts
+
+* _ADBOp. A0 points to the buffer, completion routine and data, and D0
+* has the command. Starts it, or queues it if ADB is busy, and returns
+* -1 in D0 if the queue is full.
+AdbOp:
+        movem.l d1/a0-a3,-(sp)
+        movea.l 4(a0),a1
+        movea.l 8(a0),a2
+        movea.l (a0),a0
+        movea.l (ADBBase).w,a3
+        move.w  sr,-(sp)
+        ori     #$300,sr
+        bset    #StBusy,State(a3)
+        bne.s   AdbOpQueue
+        jsr     AdbStart
+        bra.s   AdbOpDone
+AdbOpQueue:
+        cmpi.b  #QueueMax,QueueCount(a3)
+        beq.s   AdbOpFull
+        addq.b  #1,QueueCount(a3)
+        move.w  QueueTail(a3),d1
+        move.b  d0,0(a3,d1.w)
+        move.l  a0,2(a3,d1.w)
+        move.l  a1,6(a3,d1.w)
+        move.l  a2,10(a3,d1.w)
+        addi.w  #QueueEntry,d1
+        cmpi.w  #QueueEnd,d1
+        bne.s   AdbOpTail
+        move.w  #Queue,d1
+AdbOpTail:
+        move.w  d1,QueueTail(a3)
+AdbOpDone:
+        moveq   #0,d0
+        bra.s   AdbOpReturn
+AdbOpFull:
+        moveq   #-1,d0
+AdbOpReturn:
+        move.w  (sp)+,sr
+        movem.l (sp)+,d1/a0-a3
+        rts
+
+* Start the call in D0 and A0-A2, with the busy bit set. Listen sends
+* the data in the buffer, after its count byte.
+AdbStart:
+        move.b  d0,OpCommand(a3)
+        move.l  a0,OpBuffer(a3)
+        move.l  a1,OpCompletion(a3)
+        move.l  a2,OpData(a3)
+        lea     Mailbox,a1
+        move.b  d0,d1
+        andi.b  #$c,d1
+        cmpi.b  #$8,d1
+        bne.s   AdbStartGo
+        moveq   #0,d1
+        move.b  (a0)+,d1
+        cmpi.b  #8,d1
+        bls.s   AdbStartCount
+        moveq   #8,d1
+AdbStartCount:
+        move.b  d1,AdbCount(a1)
+        lea     AdbData(a1),a2
+        bra.s   AdbStartNext
+AdbStartCopy:
+        move.b  (a0)+,(a2)+
+AdbStartNext:
+        dbra    d1,AdbStartCopy
+AdbStartGo:
+        move.b  d0,AdbCommand(a1)
+        rts
+
+* Called from the VBL interrupt, to poll the current device if ADB is
+* free, before going on to the ROM's VBL handler.
+AdbVBL:
+        movea.l (ADBBase).w,a3
+        tst.b   TableSize(a3)
+        beq.s   AdbVBLDone
+        bset    #StBusy,State(a3)
+        bne.s   AdbVBLDone
+        bset    #StPolling,State(a3)
+        moveq   #0,d1
+        move.b  PollEntry(a3),d1
+        move.b  DevAddr(a3,d1.w),d0
+        lsl.b   #4,d0
+        ori.b   #$c,d0                          ; Talk R0
+        move.b  d0,Mailbox+AdbCommand
+AdbVBLDone:
+        jmp     VBLHandler
+
+* The ADB flag's interrupt handler: a transaction is done.
+AdbInterrupt:
+        lea     Mailbox,a1
+        move.b  #FlagAdb,Flags(a1)
+        movea.l (ADBBase).w,a3
+        move.b  AdbStatus(a1),d2
+        moveq   #0,d1
+        btst    #StsNoReply,d2
+        bne.s   AdbGotCount
+        move.b  AdbCount(a1),d1
+AdbGotCount:
+        lea     AdbData(a1),a2
+        bclr    #StPolling,State(a3)
+        bne.s   AdbPolled
+
+* A call: Talk fills in its buffer. The completion routine gets the
+* buffer, command and data.
+        movea.l OpBuffer(a3),a0
+        move.b  OpCommand(a3),d0
+        move.b  d0,d3
+        andi.b  #$c,d3
+        cmpi.b  #$c,d3
+        bne.s   AdbCalled
+        move.b  d1,(a0)+
+        bra.s   AdbCallNext
+AdbCallCopy:
+        move.b  (a2)+,(a0)+
+AdbCallNext:
+        dbra    d1,AdbCallCopy
+AdbCalled:
+        movea.l OpBuffer(a3),a0
+        move.l  OpCompletion(a3),d1
+        beq.s   AdbDone
+        movea.l d1,a1
+        movea.l OpData(a3),a2
+        jsr     (a1)
+        bra.s   AdbDone
+
+* A poll: anything it got goes to the device's service routine, which
+* gets the same as a completion routine, plus A3 pointing to ADBBase.
+* If another device wants service, poll that one next time.
+AdbPolled:
+        moveq   #0,d3
+        move.b  PollEntry(a3),d3
+        tst.b   d1
+        beq.s   AdbPollNext
+        lea     PollBuffer(a3),a0
+        move.b  d1,(a0)+
+        bra.s   AdbPollCount
+AdbPollCopy:
+        move.b  (a2)+,(a0)+
+AdbPollCount:
+        dbra    d1,AdbPollCopy
+        move.l  DevService(a3,d3.w),d1
+        beq.s   AdbPollNext
+        movea.l d1,a1
+        lea     PollBuffer(a3),a0
+        move.b  DevAddr(a3,d3.w),d0
+        lsl.b   #4,d0
+        ori.b   #$c,d0
+        movea.l DevData(a3,d3.w),a2
+        movem.l d2-d3,-(sp)
+        jsr     (a1)
+        movem.l (sp)+,d2-d3
+        movea.l (ADBBase).w,a3
+AdbPollNext:
+        btst    #StsService,d2
+        beq.s   AdbDone
+        addi.b  #DevSize,d3
+        cmp.b   TableSize(a3),d3
+        bcs.s   AdbPollStep
+        moveq   #0,d3
+AdbPollStep:
+        move.b  d3,PollEntry(a3)
+
+* Start the next queued call, if there is one.
+AdbDone:
+        movea.l (ADBBase).w,a3
+        bclr    #StBusy,State(a3)
+        tst.b   QueueCount(a3)
+        beq.s   AdbNextNone
+        subq.b  #1,QueueCount(a3)
+        bset    #StBusy,State(a3)
+        move.w  QueueHead(a3),d1
+        move.b  0(a3,d1.w),d0
+        movea.l 2(a3,d1.w),a0
+        movea.l 6(a3,d1.w),a1
+        movea.l 10(a3,d1.w),a2
+        addi.w  #QueueEntry,d1
+        cmpi.w  #QueueEnd,d1
+        bne.s   AdbNextHead
+        move.w  #Queue,d1
+AdbNextHead:
+        move.w  d1,QueueHead(a3)
+        jmp     AdbStart
+AdbNextNone:
+        rts
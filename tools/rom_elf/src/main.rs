@@ -0,0 +1,358 @@
+//
+// ROM ELF wrapper
+//
+// Wraps a ROM image in an m68k ELF executable loaded at the ROM base,
+// with the trap entry points and ROM resources as symbols, so that
+// binutils and GDB can be used on it with our names.
+//
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use clap::Parser;
+
+use rom_resources::ResourceMap;
+
+const ROM_SIZE: usize = memory_map::ROM_SIZE as usize;
+
+// Offset of the reset PC in the ROM header.
+const RESET_PC_OFFSET: usize = 4;
+
+////////////////////////////////////////////////////////////////////////
+// Command line processing.
+//
+
+#[derive(Parser)]
+#[command(name = "ROM ELF")]
+#[command(author = "Simon Frankau <sgf@arbitrary.name")]
+#[command(version = "0.1")]
+#[command(about = "Wraps Apple 68k ROM images in ELF files with symbols.", long_about = None)]
+struct Cli {
+    #[arg(long, default_value = "../../ROM.patched")]
+    rom: PathBuf,
+    /// Where to write the ELF file. Defaults to the ROM with ".elf"
+    /// appended.
+    #[arg(long)]
+    output: Option<PathBuf>,
+    /// Load address. Defaults to the base implied by the reset PC, so
+    /// both original and relocated ROMs load in the right place.
+    #[arg(long, value_parser = parse_hex)]
+    base: Option<u32>,
+}
+
+fn parse_hex(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s.trim_start_matches("0x"), 16)
+        .map_err(|e| format!("Bad address '{}': {}", s, e))
+}
+
+fn read_long(mem: &[u8], addr: usize) -> u32 {
+    u32::from_be_bytes(mem[addr..addr + 4].try_into().unwrap())
+}
+
+////////////////////////////////////////////////////////////////////////
+// ELF writing.
+//
+// Just enough of ELF32 (big-endian, EM_68K) for an executable with one
+// loadable segment holding the ROM, a few sections splitting it into
+// code, resources, filler and more code, and a symbol table.
+//
+
+const EM_68K: u16 = 4;
+const ET_EXEC: u16 = 2;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_R: u32 = 4;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHF_ALLOC: u32 = 2;
+const SHF_EXECINSTR: u32 = 4;
+
+const STB_GLOBAL: u8 = 1;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+const EHDR_SIZE: usize = 52;
+const PHDR_SIZE: usize = 32;
+const SHDR_SIZE: usize = 40;
+const SYM_SIZE: usize = 16;
+
+// Where the ROM goes in the file.
+const ROM_FILE_OFFSET: usize = 0x100;
+
+#[derive(Default)]
+struct Section {
+    name: u32,
+    kind: u32,
+    flags: u32,
+    addr: u32,
+    offset: u32,
+    size: u32,
+    link: u32,
+    info: u32,
+    align: u32,
+    entsize: u32,
+}
+
+struct Symbol {
+    name: String,
+    value: u32,
+    size: u32,
+    kind: u8,
+    section: u16,
+}
+
+// A string table, starting with the empty string.
+struct StringTable(Vec<u8>);
+
+impl StringTable {
+    fn new() -> StringTable {
+        StringTable(vec![0])
+    }
+
+    fn add(&mut self, s: &str) -> u32 {
+        let idx = self.0.len() as u32;
+        self.0.extend_from_slice(s.as_bytes());
+        self.0.push(0);
+        idx
+    }
+}
+
+fn push_u16(out: &mut Vec<u8>, v: u16) {
+    out.extend_from_slice(&v.to_be_bytes());
+}
+
+fn push_u32(out: &mut Vec<u8>, v: u32) {
+    out.extend_from_slice(&v.to_be_bytes());
+}
+
+fn align(out: &mut Vec<u8>, to: usize) {
+    while !out.len().is_multiple_of(to) {
+        out.push(0);
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// Building the ELF file.
+//
+
+// Indices of the sections covering the ROM.
+const SEC_TEXT: u16 = 1;
+const SEC_RSRC: u16 = 2;
+const SEC_FILL: u16 = 3;
+const SEC_TAIL: u16 = 4;
+
+fn build_elf(rom: &[u8], base: u32, resources: &ResourceMap, symbols: &[Symbol]) -> Vec<u8> {
+    let mut shstrtab = StringTable::new();
+    let mut strtab = StringTable::new();
+
+    // The ROM, split into the code before the resources, the resources
+    // (including their map), the filler after them, and the rest of
+    // the ROM, which starts with the disk code on the SE FDHD.
+    let fill_end = rom_resources::free_limit(rom, resources);
+    let rom_parts = [
+        (".text", 0, resources.header, SHF_EXECINSTR),
+        (".rsrc", resources.header, resources.end(), SHF_EXECINSTR),
+        (".fill", resources.end(), fill_end, 0),
+        (".tail", fill_end, ROM_SIZE, SHF_EXECINSTR),
+    ];
+    let mut sections = vec![Section::default()];
+    for (name, start, end, flags) in rom_parts.iter() {
+        sections.push(Section {
+            name: shstrtab.add(name),
+            kind: SHT_PROGBITS,
+            flags: SHF_ALLOC | flags,
+            addr: base + *start as u32,
+            offset: (ROM_FILE_OFFSET + start) as u32,
+            size: (end - start) as u32,
+            align: 2,
+            ..Section::default()
+        });
+    }
+
+    let mut out = vec![0; ROM_FILE_OFFSET];
+    out.extend_from_slice(rom);
+
+    // Symbol table, all global, after the null symbol.
+    let symtab_offset = out.len();
+    out.extend_from_slice(&[0; SYM_SIZE]);
+    for sym in symbols.iter() {
+        push_u32(&mut out, strtab.add(&sym.name));
+        push_u32(&mut out, sym.value);
+        push_u32(&mut out, sym.size);
+        out.push((STB_GLOBAL << 4) | sym.kind);
+        out.push(0);
+        push_u16(&mut out, sym.section);
+    }
+    let symtab_size = out.len() - symtab_offset;
+    let strtab_idx = sections.len() as u32 + 1;
+    sections.push(Section {
+        name: shstrtab.add(".symtab"),
+        kind: SHT_SYMTAB,
+        offset: symtab_offset as u32,
+        size: symtab_size as u32,
+        link: strtab_idx,
+        // First non-local symbol.
+        info: 1,
+        align: 4,
+        entsize: SYM_SIZE as u32,
+        ..Section::default()
+    });
+
+    let strtab_offset = out.len();
+    out.extend_from_slice(&strtab.0);
+    sections.push(Section {
+        name: shstrtab.add(".strtab"),
+        kind: SHT_STRTAB,
+        offset: strtab_offset as u32,
+        size: strtab.0.len() as u32,
+        align: 1,
+        ..Section::default()
+    });
+
+    let shstrtab_name = shstrtab.add(".shstrtab");
+    let shstrtab_offset = out.len();
+    out.extend_from_slice(&shstrtab.0);
+    sections.push(Section {
+        name: shstrtab_name,
+        kind: SHT_STRTAB,
+        offset: shstrtab_offset as u32,
+        size: shstrtab.0.len() as u32,
+        align: 1,
+        ..Section::default()
+    });
+
+    align(&mut out, 4);
+    let shdr_offset = out.len();
+    for s in sections.iter() {
+        for v in [
+            s.name, s.kind, s.flags, s.addr, s.offset, s.size, s.link, s.info, s.align, s.entsize,
+        ] {
+            push_u32(&mut out, v);
+        }
+    }
+
+    // ELF header.
+    let mut header = Vec::new();
+    header.extend_from_slice(b"\x7fELF");
+    // 32-bit, big-endian, version 1, System V ABI.
+    header.extend_from_slice(&[1, 2, 1, 0]);
+    header.extend_from_slice(&[0; 8]);
+    push_u16(&mut header, ET_EXEC);
+    push_u16(&mut header, EM_68K);
+    push_u32(&mut header, 1);
+    push_u32(
+        &mut header,
+        base + (read_long(rom, RESET_PC_OFFSET) & (ROM_SIZE as u32 - 1)),
+    );
+    push_u32(&mut header, EHDR_SIZE as u32);
+    push_u32(&mut header, shdr_offset as u32);
+    push_u32(&mut header, 0);
+    push_u16(&mut header, EHDR_SIZE as u16);
+    push_u16(&mut header, PHDR_SIZE as u16);
+    push_u16(&mut header, 1);
+    push_u16(&mut header, SHDR_SIZE as u16);
+    push_u16(&mut header, sections.len() as u16);
+    push_u16(&mut header, sections.len() as u16 - 1);
+
+    // One loadable, read-only segment for the ROM.
+    for v in [
+        PT_LOAD,
+        ROM_FILE_OFFSET as u32,
+        base,
+        base,
+        ROM_SIZE as u32,
+        ROM_SIZE as u32,
+        PF_R | PF_X,
+        4,
+    ] {
+        push_u32(&mut header, v);
+    }
+
+    out[..header.len()].copy_from_slice(&header);
+    out
+}
+
+fn collect_symbols(rom: &[u8], base: u32, resources: &ResourceMap) -> anyhow::Result<Vec<Symbol>> {
+    let fill_end = rom_resources::free_limit(rom, resources);
+    let section_of = |offset: usize| {
+        if offset < resources.header {
+            SEC_TEXT
+        } else if offset < resources.end() {
+            SEC_RSRC
+        } else if offset < fill_end {
+            SEC_FILL
+        } else {
+            SEC_TAIL
+        }
+    };
+
+    let traps = extract_traps::read_traps("../extract_traps/trap_names.txt")?;
+    let mut symbols = Vec::new();
+    for label in extract_traps::labels(rom, &traps) {
+        let offset = (label.addr - extract_traps::ROM_BASE) as usize;
+        symbols.push(Symbol {
+            name: label.name,
+            value: base + offset as u32,
+            size: 0,
+            kind: STT_FUNC,
+            section: section_of(offset),
+        });
+    }
+
+    symbols.push(Symbol {
+        name: "rsrc_map".to_string(),
+        value: base + resources.map as u32,
+        size: resources.map_len as u32,
+        kind: STT_OBJECT,
+        section: SEC_RSRC,
+    });
+    for r in resources.resources.iter() {
+        symbols.push(Symbol {
+            name: r.label(),
+            value: base + r.offset as u32,
+            size: r.size as u32,
+            kind: STT_OBJECT,
+            section: SEC_RSRC,
+        });
+    }
+
+    Ok(symbols)
+}
+
+fn wrap_rom(rom_path: &Path, output: &Path, base: Option<u32>) -> anyhow::Result<()> {
+    let rom =
+        fs::read(rom_path).with_context(|| format!("Couldn't read {}", rom_path.display()))?;
+    if rom.len() != ROM_SIZE {
+        bail!("{} is not a 256kB ROM image", rom_path.display());
+    }
+    let base = base.unwrap_or(read_long(&rom, RESET_PC_OFFSET) & !(ROM_SIZE as u32 - 1));
+    let resources = rom_resources::parse(&rom).context("Couldn't parse ROM resources")?;
+    let symbols = collect_symbols(&rom, base, &resources)?;
+
+    let elf = build_elf(&rom, base, &resources, &symbols);
+    fs::write(output, elf).with_context(|| format!("Couldn't write {}", output.display()))?;
+    println!(
+        "Wrote {} at 0x{:06x} with {} symbols",
+        output.display(),
+        base,
+        symbols.len()
+    );
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////
+// Main entry point.
+//
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let output = cli.output.clone().unwrap_or_else(|| {
+        let mut path = cli.rom.clone().into_os_string();
+        path.push(".elf");
+        PathBuf::from(path)
+    });
+    wrap_rom(&cli.rom, &output, cli.base)
+}
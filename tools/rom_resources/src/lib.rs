@@ -0,0 +1,630 @@
+//
+// ROM resources
+//
+// Parses the resource map in the ROM. The ROM header points (at offset
+// 0x1a) to a short header giving the number of resources and the size
+// of the map, followed by a normal resource map, except that the data
+// offsets in the reference list are offsets from the start of the ROM.
+// Each resource's data is preceded by a Memory Manager block header,
+// as the ROM resources are presented as if they were in a heap.
+//
+
+use std::collections::HashMap;
+
+use anyhow::{bail, ensure};
+
+// Offset from start of ROM where the offset of the resources is.
+const HEADER_OFFSET: usize = 0x1a;
+
+// Size of the count and length words before the map.
+const PREFIX_SIZE: usize = 4;
+
+// Offsets within the map of the type and name list offsets.
+const TYPE_LIST_OFFSET: usize = 0x18;
+const NAME_LIST_OFFSET: usize = 0x1a;
+
+const TYPE_ENTRY_SIZE: usize = 8;
+const REF_ENTRY_SIZE: usize = 12;
+
+// Block headers are 8 bytes: a tag byte (with the size correction in
+// the low nibble) and 24-bit physical size, then a relative handle.
+pub const BLOCK_HEADER_SIZE: usize = 8;
+
+// The reference list uses this for "no name".
+const NO_NAME: u16 = 0xffff;
+
+// Where the map header's copy of the map length is.
+const MAP_LEN_COPY_OFFSET: usize = 12;
+
+// Absolute references from outside the resources into resource data,
+// as offsets of the addresses. On the SE FDHD, the disk code after the
+// resources jumps back into the .Sony driver.
+const EXTERNAL_REFS: [usize; 16] = [
+    0x3d03a, 0x3d040, 0x3d046, 0x3d04c, 0x3d052, 0x3d058, 0x3d05e, 0x3d064, 0x3d06a, 0x3d070,
+    0x3d076, 0x3d07c, 0x3d082, 0x3d088, 0x3d08e, 0x3dd32,
+];
+
+// Longest repeating pattern we look for in the filler after the
+// resources.
+const MAX_FILLER_PERIOD: usize = 16;
+
+fn read_word(mem: &[u8], addr: usize) -> u16 {
+    ((mem[addr] as u16) << 8) | (mem[addr + 1] as u16)
+}
+
+fn read_long(mem: &[u8], addr: usize) -> u32 {
+    ((read_word(mem, addr) as u32) << 16) | (read_word(mem, addr + 2) as u32)
+}
+
+fn write_word(mem: &mut [u8], addr: usize, value: u16) {
+    mem[addr..addr + 2].copy_from_slice(&value.to_be_bytes());
+}
+
+fn write_long(mem: &mut [u8], addr: usize, value: u32) {
+    mem[addr..addr + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+#[derive(Clone, Debug)]
+pub struct Resource {
+    pub res_type: String,
+    pub id: i16,
+    pub name: Option<String>,
+    pub attrs: u8,
+    // Offset of the data from the start of the ROM.
+    pub offset: usize,
+    // Logical size of the data.
+    pub size: usize,
+    // Physical size of the block, including header and padding.
+    pub block_size: usize,
+    // Offset of the reference list entry from the start of the ROM.
+    pub ref_entry: usize,
+    // Offset of the name in the name list, if it has one.
+    name_offset: Option<usize>,
+}
+
+impl Resource {
+    // A name for the resource, usable as a file name or symbol, like
+    // "DRVR_4_.Sony" or "PACK_5".
+    pub fn label(&self) -> String {
+        let label = format!("{}_{}", self.res_type.trim_end(), self.id);
+        match &self.name {
+            Some(name) => format!("{}_{}", label, name),
+            None => label,
+        }
+        .replace(['/', ' '], "_")
+    }
+
+    pub fn data<'a>(&self, rom: &'a [u8]) -> &'a [u8] {
+        &rom[self.offset..self.offset + self.size]
+    }
+
+    // Offset of the block header, which is where the resource's space
+    // starts.
+    pub fn block_start(&self) -> usize {
+        self.offset - BLOCK_HEADER_SIZE
+    }
+}
+
+#[derive(Debug)]
+pub struct ResourceMap {
+    // Offset of the count and map length that precede the map.
+    pub header: usize,
+    // Offset and length of the map itself.
+    pub map: usize,
+    pub map_len: usize,
+    // In reference list order.
+    pub resources: Vec<Resource>,
+}
+
+impl ResourceMap {
+    // Offset of the end of the last resource block.
+    pub fn end(&self) -> usize {
+        self.resources
+            .iter()
+            .map(|r| r.block_start() + r.block_size)
+            .max()
+            .unwrap_or(self.map + self.map_len)
+    }
+}
+
+fn read_name(rom: &[u8], offset: usize) -> anyhow::Result<String> {
+    ensure!(
+        offset < rom.len(),
+        "Resource name at 0x{:x} outside ROM",
+        offset
+    );
+    let len = rom[offset] as usize;
+    ensure!(
+        offset + 1 + len <= rom.len(),
+        "Resource name at 0x{:x} runs off end of ROM",
+        offset
+    );
+    Ok(rom[offset + 1..offset + 1 + len]
+        .iter()
+        .map(|b| *b as char)
+        .collect())
+}
+
+pub fn parse(rom: &[u8]) -> anyhow::Result<ResourceMap> {
+    let header = read_long(rom, HEADER_OFFSET) as usize;
+    if header + PREFIX_SIZE > rom.len() {
+        bail!("Resource header offset 0x{:x} is outside the ROM", header);
+    }
+    let count = read_word(rom, header) as usize;
+    let map_len = read_word(rom, header + 2) as usize;
+    let map = header + PREFIX_SIZE;
+    ensure!(
+        map + map_len <= rom.len(),
+        "Resource map at 0x{:x} runs off end of ROM",
+        map
+    );
+
+    let type_list = map + read_word(rom, map + TYPE_LIST_OFFSET) as usize;
+    let name_list = map + read_word(rom, map + NAME_LIST_OFFSET) as usize;
+    let num_types = read_word(rom, type_list).wrapping_add(1) as usize;
+
+    let mut resources = Vec::new();
+    for type_idx in 0..num_types {
+        let entry = type_list + 2 + type_idx * TYPE_ENTRY_SIZE;
+        let res_type = rom[entry..entry + 4]
+            .iter()
+            .map(|b| *b as char)
+            .collect::<String>();
+        let num_refs = read_word(rom, entry + 4) as usize + 1;
+        let ref_list = type_list + read_word(rom, entry + 6) as usize;
+
+        for ref_idx in 0..num_refs {
+            let ref_entry = ref_list + ref_idx * REF_ENTRY_SIZE;
+            ensure!(
+                ref_entry + REF_ENTRY_SIZE <= map + map_len,
+                "Reference list entry for {} at 0x{:x} outside map",
+                res_type,
+                ref_entry
+            );
+            let id = read_word(rom, ref_entry) as i16;
+            let name_offset = read_word(rom, ref_entry + 2);
+            let name = match name_offset {
+                NO_NAME => None,
+                offset => Some(read_name(rom, name_list + offset as usize)?),
+            };
+            let attrs_offset = read_long(rom, ref_entry + 4);
+            let attrs = (attrs_offset >> 24) as u8;
+            let offset = (attrs_offset & 0xffffff) as usize;
+            ensure!(
+                offset >= BLOCK_HEADER_SIZE && offset <= rom.len(),
+                "Resource {} {} data at 0x{:x} outside ROM",
+                res_type,
+                id,
+                offset
+            );
+
+            let block = read_long(rom, offset - BLOCK_HEADER_SIZE);
+            let correction = ((block >> 24) & 0xf) as usize;
+            let physical = (block & 0xffffff) as usize;
+            ensure!(
+                physical >= BLOCK_HEADER_SIZE + correction
+                    && offset - BLOCK_HEADER_SIZE + physical <= rom.len(),
+                "Bad block header for resource {} {} at 0x{:x}",
+                res_type,
+                id,
+                offset - BLOCK_HEADER_SIZE
+            );
+
+            resources.push(Resource {
+                res_type: res_type.clone(),
+                id,
+                name,
+                attrs,
+                offset,
+                size: physical - BLOCK_HEADER_SIZE - correction,
+                block_size: physical,
+                ref_entry,
+                name_offset: (name_offset != NO_NAME).then_some(name_offset as usize),
+            });
+        }
+    }
+
+    ensure!(
+        resources.len() == count,
+        "Resource header says {} resources, map has {}",
+        count,
+        resources.len()
+    );
+
+    Ok(ResourceMap {
+        header,
+        map,
+        map_len,
+        resources,
+    })
+}
+
+////////////////////////////////////////////////////////////////////////
+// Rebuilding.
+//
+// Lays the map and resources out afresh from the start of the map,
+// packing the resources in their original order, so that resources
+// can be replaced with bigger or smaller ones, or removed. The space
+// available runs up to the end of the filler after the original
+// resources.
+//
+
+pub enum Change {
+    Replace(Vec<u8>),
+    Remove,
+}
+
+pub struct Rebuilt {
+    // Offset of the end of the last resource block.
+    pub end: usize,
+    // Offset of the end of the space available for resources.
+    pub limit: usize,
+    // New offsets of the remaining resources, in the original order.
+    pub offsets: Vec<(String, usize)>,
+    pub warnings: Vec<String>,
+}
+
+impl Rebuilt {
+    pub fn free(&self) -> usize {
+        self.limit - self.end
+    }
+}
+
+// The end of the repeating filler that follows the resources, which
+// is free for resources to grow into.
+pub fn free_limit(rom: &[u8], map: &ResourceMap) -> usize {
+    let start = map.end();
+    (1..=MAX_FILLER_PERIOD)
+        .map(|period| {
+            let mut end = (start + period).min(rom.len());
+            while end < rom.len() && rom[end] == rom[end - period] {
+                end += 1;
+            }
+            // A run shorter than two periods isn't filler.
+            if end - start >= 2 * period {
+                end
+            } else {
+                start
+            }
+        })
+        .max()
+        .unwrap_or(start)
+}
+
+// The period of the filler that follows the resources, or 1 if there
+// isn't any.
+fn filler_period(rom: &[u8], start: usize, limit: usize) -> usize {
+    (1..=MAX_FILLER_PERIOD)
+        .find(|p| (start + p..limit).all(|i| rom[i] == rom[i - p]))
+        .unwrap_or(1)
+}
+
+// Check that the space the resources will be laid out in again holds
+// nothing but the map, the listed resources, filler (in any phase), and
+// the zeroes left at the end of a map that has shrunk. Anything else is
+// in use by something the map doesn't know about, like new code put in
+// an unlisted resource's space, or data that code outside the
+// resources still refers to, and would be overwritten.
+fn check_unused(rom: &[u8], map: &ResourceMap, limit: usize) -> anyhow::Result<()> {
+    let old_end = map.end();
+    let period = filler_period(rom, old_end, limit);
+    let pattern = &rom[old_end..(old_end + period).min(limit)];
+    let is_filler = |start: usize, end: usize| {
+        pattern.len() == period
+            && (0..period)
+                .any(|phase| (start..end).all(|i| rom[i] == pattern[(phase + i - start) % period]))
+    };
+
+    let mut used = map
+        .resources
+        .iter()
+        .map(|r| (r.block_start(), r.block_start() + r.block_size))
+        .collect::<Vec<_>>();
+    used.push((map.header, map.map + map.map_len));
+    used.sort();
+
+    let mut pos = map.header;
+    for (start, end) in used.iter().chain([(limit, limit)].iter()) {
+        if *start > pos {
+            let gap = &rom[pos..*start];
+            ensure!(
+                gap.iter().all(|b| *b == 0) || is_filler(pos, *start),
+                "0x{:x}-0x{:x} isn't in any listed resource, and isn't filler, so \
+                 something else (like new code from 'patch') may be using it",
+                pos,
+                start
+            );
+        }
+        pos = pos.max(*end);
+    }
+    Ok(())
+}
+
+fn block_header(tag: u8, size: usize, handle: u32) -> ([u8; BLOCK_HEADER_SIZE], usize) {
+    // Blocks must stay word-aligned.
+    let correction = size % 2;
+    let physical = BLOCK_HEADER_SIZE + size + correction;
+    let mut header = [0; BLOCK_HEADER_SIZE];
+    write_long(
+        &mut header,
+        0,
+        ((tag as u32 | correction as u32) << 24) | physical as u32,
+    );
+    write_long(&mut header, 4, handle);
+    (header, physical)
+}
+
+// A new map for a subset of the resources.
+struct MapLayout {
+    // Types in their original order, each with the positions of its
+    // resources.
+    types: Vec<(String, Vec<usize>)>,
+    name_list: Vec<u8>,
+    // Offsets into the name list, by position.
+    name_offsets: HashMap<usize, usize>,
+    type_list_offset: usize,
+    name_list_offset: usize,
+    len: usize,
+}
+
+impl MapLayout {
+    fn new(rom: &[u8], map: &ResourceMap, kept: &[&Resource]) -> MapLayout {
+        let mut types: Vec<(String, Vec<usize>)> = Vec::new();
+        for (pos, r) in kept.iter().enumerate() {
+            match types.iter_mut().find(|(t, _)| *t == r.res_type) {
+                Some((_, refs)) => refs.push(pos),
+                None => types.push((r.res_type.clone(), vec![pos])),
+            }
+        }
+
+        // Names in their original order.
+        let mut named = kept
+            .iter()
+            .enumerate()
+            .filter_map(|(pos, r)| r.name_offset.map(|offset| (offset, pos)))
+            .collect::<Vec<_>>();
+        named.sort();
+        let mut name_list = Vec::new();
+        let mut name_offsets = HashMap::new();
+        for (_, pos) in named.iter() {
+            let name = kept[*pos].name.as_ref().unwrap();
+            name_offsets.insert(*pos, name_list.len());
+            name_list.push(name.len() as u8);
+            name_list.extend(name.chars().map(|c| c as u8));
+        }
+
+        // The old header, then the type list, reference lists and name
+        // list.
+        let type_list_offset = read_word(rom, map.map + TYPE_LIST_OFFSET) as usize;
+        let name_list_offset =
+            type_list_offset + 2 + types.len() * TYPE_ENTRY_SIZE + kept.len() * REF_ENTRY_SIZE;
+        let len = name_list_offset + name_list.len();
+
+        MapLayout {
+            types,
+            name_list,
+            name_offsets,
+            type_list_offset,
+            name_list_offset,
+            len,
+        }
+    }
+
+    // Write the prefix and map, given the new data offset of each kept
+    // resource.
+    fn write(&self, rom: &mut [u8], map: &ResourceMap, kept: &[&Resource], offsets: &[usize]) {
+        write_word(rom, map.header, kept.len() as u16);
+        write_word(rom, map.header + 2, self.len as u16);
+        write_long(rom, map.map + MAP_LEN_COPY_OFFSET, self.len as u32);
+        write_word(
+            rom,
+            map.map + NAME_LIST_OFFSET,
+            self.name_list_offset as u16,
+        );
+        let type_list = map.map + self.type_list_offset;
+        write_word(rom, type_list, (self.types.len() as u16).wrapping_sub(1));
+        let mut ref_entry = type_list + 2 + self.types.len() * TYPE_ENTRY_SIZE;
+        for (type_idx, (res_type, refs)) in self.types.iter().enumerate() {
+            let entry = type_list + 2 + type_idx * TYPE_ENTRY_SIZE;
+            rom[entry..entry + 4].copy_from_slice(res_type.as_bytes());
+            write_word(rom, entry + 4, refs.len() as u16 - 1);
+            write_word(rom, entry + 6, (ref_entry - type_list) as u16);
+            for pos in refs.iter() {
+                let r = kept[*pos];
+                write_word(rom, ref_entry, r.id as u16);
+                let name = self.name_offsets.get(pos).map_or(NO_NAME, |o| *o as u16);
+                write_word(rom, ref_entry + 2, name);
+                write_long(
+                    rom,
+                    ref_entry + 4,
+                    ((r.attrs as u32) << 24) | offsets[*pos] as u32,
+                );
+                // Handle, filled in at run time.
+                write_long(rom, ref_entry + 8, 0);
+                ref_entry += REF_ENTRY_SIZE;
+            }
+        }
+        let names = map.map + self.name_list_offset;
+        rom[names..names + self.name_list.len()].copy_from_slice(&self.name_list);
+    }
+}
+
+// Remove a resource from the map, leaving all the data where it is, so
+// that the removed resource's block is no longer used by anything
+// that goes through the Resource Manager. The space freed at the end
+// of the map is zeroed.
+pub fn unlist(rom: &mut [u8], map: &ResourceMap, idx: usize) -> anyhow::Result<()> {
+    ensure!(idx < map.resources.len(), "No resource #{}", idx);
+    let kept = map
+        .resources
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != idx)
+        .map(|(_, r)| r)
+        .collect::<Vec<_>>();
+    let offsets = kept.iter().map(|r| r.offset).collect::<Vec<_>>();
+    let layout = MapLayout::new(rom, map, &kept);
+    layout.write(rom, map, &kept, &offsets);
+    rom[map.map + layout.len..map.map + map.map_len].fill(0);
+    Ok(())
+}
+
+// Point a resource at new data placed at 'block', behind a fresh block
+// header, leaving the old data where it is. The caller provides the
+// space, which must be 'block_size(data.len())' bytes.
+pub fn repoint(
+    rom: &mut [u8],
+    map: &ResourceMap,
+    idx: usize,
+    block: usize,
+    data: &[u8],
+) -> anyhow::Result<()> {
+    ensure!(idx < map.resources.len(), "No resource #{}", idx);
+    let r = &map.resources[idx];
+    let tag = rom[r.block_start()] & 0xf0;
+    let handle = read_long(rom, r.block_start() + 4);
+    let (header, physical) = block_header(tag, data.len(), handle);
+    ensure!(
+        block + physical <= rom.len(),
+        "No room for {} at 0x{:x}",
+        r.label(),
+        block
+    );
+    rom[block..block + BLOCK_HEADER_SIZE].copy_from_slice(&header);
+    let start = block + BLOCK_HEADER_SIZE;
+    rom[start..start + data.len()].copy_from_slice(data);
+    rom[start + data.len()..block + physical].fill(0);
+    write_long(
+        rom,
+        r.ref_entry + 4,
+        ((r.attrs as u32) << 24) | start as u32,
+    );
+    Ok(())
+}
+
+// Space taken by a block holding 'size' bytes of data.
+pub fn block_size(size: usize) -> usize {
+    BLOCK_HEADER_SIZE + size + size % 2
+}
+
+// Apply the changes, keyed by index into the map's resources, to the
+// ROM image.
+pub fn rebuild(
+    rom: &mut [u8],
+    map: &ResourceMap,
+    changes: &HashMap<usize, Change>,
+) -> anyhow::Result<Rebuilt> {
+    let limit = free_limit(rom, map);
+    let old_end = map.end();
+    check_unused(rom, map, limit)?;
+    let old = rom.to_vec();
+
+    // The resources to keep, with their new data.
+    let kept = map
+        .resources
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, r)| match changes.get(&idx) {
+            Some(Change::Remove) => None,
+            Some(Change::Replace(data)) => Some((idx, r, data.as_slice())),
+            None => Some((idx, r, r.data(&old))),
+        })
+        .collect::<Vec<_>>();
+    let kept_resources = kept.iter().map(|(_, r, _)| *r).collect::<Vec<_>>();
+    let layout = MapLayout::new(&old, map, &kept_resources);
+    let data_start = map.map + layout.len;
+
+    // Lay out the data, working out where everything goes before
+    // writing anything.
+    let first_handle = map
+        .resources
+        .iter()
+        .map(|r| read_long(&old, r.block_start() + 4))
+        .min()
+        .unwrap_or(0);
+    let mut blocks = Vec::new();
+    let mut end = data_start;
+    for (pos, (_, r, data)) in kept.iter().enumerate() {
+        let tag = old[r.block_start()] & 0xf0;
+        let (header, physical) = block_header(tag, data.len(), first_handle + 4 * pos as u32);
+        blocks.push((end, header, physical));
+        end += physical;
+    }
+    if end > limit {
+        bail!(
+            "Resources need 0x{:x} bytes, 0x{:x} more than the 0x{:x} available",
+            end - map.map,
+            end - limit,
+            limit - map.map
+        );
+    }
+
+    let offsets = blocks
+        .iter()
+        .map(|(block, _, _)| block + BLOCK_HEADER_SIZE)
+        .collect::<Vec<_>>();
+    layout.write(rom, map, &kept_resources, &offsets);
+
+    // Write the data.
+    for ((_, _, data), (block, header, physical)) in kept.iter().zip(blocks.iter()) {
+        rom[*block..block + BLOCK_HEADER_SIZE].copy_from_slice(header);
+        let start = block + BLOCK_HEADER_SIZE;
+        rom[start..start + data.len()].copy_from_slice(data);
+        rom[start + data.len()..block + physical].fill(0);
+    }
+
+    // Continue the filler over any freed space, in step with the
+    // original.
+    let period = filler_period(&old, old_end, limit);
+    for i in end..limit {
+        rom[i] = if i >= old_end {
+            old[i]
+        } else {
+            old[old_end + (period - (old_end - i) % period) % period]
+        };
+    }
+
+    // Fix up references into the resources from outside.
+    let mut warnings = Vec::new();
+    for site in EXTERNAL_REFS.iter() {
+        let value = read_long(&old, *site);
+        let target = value as usize & (rom.len() - 1);
+        let Some(idx) = map
+            .resources
+            .iter()
+            .position(|r| (r.offset..r.offset + r.size).contains(&target))
+        else {
+            warnings.push(format!(
+                "Expected a reference into the resources at 0x{:x}, found 0x{:08x}",
+                site, value
+            ));
+            continue;
+        };
+        let r = &map.resources[idx];
+        match kept.iter().position(|(i, _, _)| *i == idx) {
+            Some(pos) if !changes.contains_key(&idx) => {
+                let new_target = blocks[pos].0 + BLOCK_HEADER_SIZE + (target - r.offset);
+                write_long(rom, *site, value - target as u32 + new_target as u32);
+            }
+            _ => warnings.push(format!(
+                "0x{:x} refers to 0x{:08x} in {}, which has been replaced or removed",
+                site,
+                value,
+                r.label()
+            )),
+        }
+    }
+
+    let offsets = kept
+        .iter()
+        .zip(offsets.iter())
+        .map(|((_, r, _), offset)| (r.label(), *offset))
+        .collect();
+
+    Ok(Rebuilt {
+        end,
+        limit,
+        offsets,
+        warnings,
+    })
+}
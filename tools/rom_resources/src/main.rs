@@ -0,0 +1,351 @@
+//
+// ROM resource tool
+//
+// Walks the resource map in the ROM, prints a table of the resources,
+// regenerates resources.md from it, and optionally extracts each
+// resource to a file. Can also rebuild the resources with some
+// replaced or removed.
+//
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context};
+use clap::{Parser, Subcommand};
+
+use rom_resources::{parse, rebuild, Change, Resource, ResourceMap};
+
+const ROM_SIZE: usize = memory_map::ROM_SIZE as usize;
+
+// Offset of the reset PC in the ROM header, which tells us where the
+// ROM expects to live.
+const RESET_PC_OFFSET: usize = 4;
+
+////////////////////////////////////////////////////////////////////////
+// Command line processing.
+//
+
+#[derive(Parser)]
+#[command(name = "ROM resources")]
+#[command(author = "Simon Frankau <sgf@arbitrary.name")]
+#[command(version = "0.1")]
+#[command(about = "Lists and extracts the resources in Apple 68k ROMs.", long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// List the resources, regenerating the documentation, and
+    /// optionally extract them.
+    List {
+        #[arg(long, default_value = "../../ROM.sefdhd")]
+        rom: PathBuf,
+        /// Where to write the resource documentation.
+        #[arg(long, default_value = "../../resources.md")]
+        markdown: PathBuf,
+        /// Directory to extract the resources into, one file each.
+        #[arg(long)]
+        extract: Option<PathBuf>,
+    },
+    /// Rebuild the resources with some replaced or removed, reporting
+    /// the space left.
+    Rebuild {
+        #[arg(long, default_value = "../../ROM.patched")]
+        rom: PathBuf,
+        #[arg(long, default_value = "../../ROM.rebuilt")]
+        output: PathBuf,
+        /// Replace a resource, named as in "list", like
+        /// "DRVR_4_.Sony=driver.bin".
+        #[arg(long, value_parser = parse_replacement)]
+        replace: Vec<(String, PathBuf)>,
+        /// Remove a resource, named as in "list", like "bbmc_0".
+        #[arg(long)]
+        remove: Vec<String>,
+    },
+}
+
+fn parse_replacement(s: &str) -> Result<(String, PathBuf), String> {
+    match s.split_once('=') {
+        Some((label, path)) => Ok((label.to_string(), PathBuf::from(path))),
+        None => Err(format!("Expected <resource>=<file>, got '{}'", s)),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// What we know about the SE FDHD resources.
+//
+// Ghidra symbols and descriptions, which can't be derived from the
+// ROM. Resources not listed here get blank entries.
+//
+
+const KNOWN: [(&str, i16, &str, &str); 28] = [
+    ("PACK", 5, "pack_5_Elems68K", "Transcendental functions"),
+    ("PACK", 4, "pack_4_FP68K", "Floating-point arithmetic"),
+    ("PACK", 7, "pack_7_BCD", "Binary-decimal conversion"),
+    ("tcsl", 0, "tcsl", "Code to display `bbmc`"),
+    ("bbmc", 0, "bbmc", "Pictures of dev team"),
+    ("SERD", 0, "serd", "RAM serial driver"),
+    (
+        "DRVR",
+        10,
+        "drvr_atp",
+        "Appletalk Transaction Protocol driver",
+    ),
+    ("DRVR", 9, "drvr_mpp", "Low-level network driver"),
+    ("DRVR", 4, "drvr_sony", "3 1/2\" Disk driver"),
+    ("DRVR", 3, "drvr_sound", "Sound driver"),
+    (
+        "DRVR",
+        40,
+        "drvr_xpp",
+        "Appletalk Filing Protocol & Zone Information Protocol",
+    ),
+    (
+        "CDEF",
+        0,
+        "cdef_0_button",
+        "Push button/check box/radio button",
+    ),
+    ("CDEF", 1, "cdef_1_scrollbar", "Scroll bar"),
+    ("KCHR", 0, "kchr", "Keyboard layout"),
+    ("KMAP", 0, "kmap", "Keyboard mapping"),
+    ("MBDF", 0, "mbdf", "Draws the menu bar"),
+    ("MDEF", 0, "mdef", "Draws menus"),
+    ("WDEF", 1, "wdef_1", "Rounded-corner window"),
+    ("WDEF", 0, "wdef_0", "Regular window"),
+    ("CURS", 4, "image_wristwatch_16x16", "Wristwatch cursor"),
+    ("CURS", 1, "image_ibar_16x16", "Insertion point cursor"),
+    (
+        "CURS",
+        3,
+        "image_spreadsheet_plus_16x16",
+        "Spreadsheet cursor",
+    ),
+    ("CURS", 2, "image_drawing_plus_16x16", "Crosshair curson"),
+    ("FONT", 12, "font_c", ""),
+    ("FONT", 0, "font_0_chicago", "Empty!"),
+    ("FONT", 393, "font_189", ""),
+    ("FONT", 396, "font_18c", ""),
+    ("FONT", 521, "font_209", ""),
+];
+
+fn known(r: &Resource) -> (&'static str, &'static str) {
+    KNOWN
+        .iter()
+        .find(|(res_type, id, _, _)| *res_type == r.res_type && *id == r.id)
+        .map(|(_, _, symbol, description)| (*symbol, *description))
+        .unwrap_or(("", ""))
+}
+
+////////////////////////////////////////////////////////////////////////
+// Output.
+//
+
+const HEADINGS: [&str; 7] = [
+    "Res type",
+    "Res id",
+    "Name",
+    "Attributes",
+    "ROM Offset",
+    "Symbol",
+    "Description",
+];
+
+const NOTES: &str = "## Notes
+
+ * A full 80kB of the 256kB ROM is taken up with pictures of the dev
+   team! `patch` takes them out of the map, disables `tcsl` and uses
+   the space for new code.
+
+## TODO
+
+ * Work out the fonts.
+";
+
+fn rows(map: &ResourceMap) -> Vec<[String; 7]> {
+    map.resources
+        .iter()
+        .map(|r| {
+            let (symbol, description) = known(r);
+            [
+                r.res_type.clone(),
+                format!("0x{:X}", r.id),
+                r.name.clone().unwrap_or_default(),
+                format!("0x{:X}", r.attrs),
+                format!("0x{:X}", r.offset),
+                if symbol.is_empty() {
+                    String::new()
+                } else {
+                    format!("`{}`", symbol)
+                },
+                description.to_string(),
+            ]
+        })
+        .collect()
+}
+
+// A Markdown table with the columns padded to line up.
+fn table(rows: &[[String; 7]]) -> String {
+    let mut widths = HEADINGS.map(|h| h.len());
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let line = |cells: &[String]| {
+        let cells = cells
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!(" {:<width$} ", cell, width = width))
+            .collect::<Vec<_>>();
+        format!("|{}|\n", cells.join("|"))
+    };
+
+    let mut out = line(&HEADINGS.map(String::from));
+    let rule = widths.map(|w| "-".repeat(w + 2));
+    writeln!(out, "|{}|", rule.join("|")).unwrap();
+    for row in rows.iter() {
+        out.push_str(&line(row));
+    }
+    out
+}
+
+fn markdown(map: &ResourceMap, base: u32, rows: &[[String; 7]]) -> String {
+    format!(
+        "# ROM resources\n\nThe ROM contains a set of resources from 0x{:X} to 0x{:X}.\n\n{}\n{}",
+        base as usize + map.map,
+        base as usize + map.end(),
+        table(rows),
+        NOTES
+    )
+}
+
+fn extract(dir: &Path, rom: &[u8], map: &ResourceMap) -> anyhow::Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("Couldn't create {}", dir.display()))?;
+    for r in map.resources.iter() {
+        let path = dir.join(r.label());
+        fs::write(&path, r.data(rom))
+            .with_context(|| format!("Couldn't write {}", path.display()))?;
+    }
+    println!(
+        "Extracted {} resources to {}",
+        map.resources.len(),
+        dir.display()
+    );
+    Ok(())
+}
+
+fn read_rom(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let rom = fs::read(path).with_context(|| format!("Couldn't read {}", path.display()))?;
+    if rom.len() != ROM_SIZE {
+        bail!("{} is not a 256kB ROM image", path.display());
+    }
+    Ok(rom)
+}
+
+fn list(rom_path: &Path, markdown_path: &Path, extract_dir: Option<&Path>) -> anyhow::Result<()> {
+    let rom = read_rom(rom_path)?;
+    let base = u32::from_be_bytes(
+        rom[RESET_PC_OFFSET..RESET_PC_OFFSET + 4]
+            .try_into()
+            .unwrap(),
+    ) & !(ROM_SIZE as u32 - 1);
+    let map = parse(&rom)?;
+
+    let rows = rows(&map);
+    print!("{}", table(&rows));
+    fs::write(markdown_path, markdown(&map, base, &rows))
+        .with_context(|| format!("Couldn't write {}", markdown_path.display()))?;
+
+    if let Some(dir) = extract_dir {
+        extract(dir, &rom, &map)?;
+    }
+
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////
+// Rebuilding.
+//
+
+// Same as the "patch" tool: the first long of the ROM is the sum of
+// all the words after it, which the startup tests check.
+fn fix_checksum(data: &mut [u8]) {
+    let sum = data[4..].chunks(2).fold(0u32, |acc, w| {
+        acc.wrapping_add(u16::from_be_bytes([w[0], w[1]]) as u32)
+    });
+    println!("ROM checksum: 0x{:08x}", sum);
+    data[..4].copy_from_slice(&sum.to_be_bytes());
+}
+
+fn rebuild_rom(
+    rom_path: &Path,
+    output: &Path,
+    replace: &[(String, PathBuf)],
+    remove: &[String],
+) -> anyhow::Result<()> {
+    let mut rom = read_rom(rom_path)?;
+    let map = parse(&rom)?;
+
+    let find = |label: &str| {
+        map.resources
+            .iter()
+            .position(|r| r.label() == label)
+            .ok_or_else(|| anyhow!("No resource '{}'", label))
+    };
+    let mut changes = HashMap::new();
+    for (label, path) in replace.iter() {
+        let data = fs::read(path).with_context(|| format!("Couldn't read {}", path.display()))?;
+        changes.insert(find(label)?, Change::Replace(data));
+    }
+    for label in remove.iter() {
+        changes.insert(find(label)?, Change::Remove);
+    }
+
+    let rebuilt = rebuild(&mut rom, &map, &changes)?;
+    for (label, offset) in rebuilt.offsets.iter() {
+        println!("  0x{:05X} {}", offset, label);
+    }
+    for warning in rebuilt.warnings.iter() {
+        println!("Warning: {}", warning);
+    }
+    println!(
+        "Resources end at 0x{:05X}, leaving 0x{:X} bytes free before 0x{:05X}",
+        rebuilt.end,
+        rebuilt.free(),
+        rebuilt.limit
+    );
+
+    fix_checksum(&mut rom);
+    fs::write(output, rom).with_context(|| format!("Couldn't write {}", output.display()))?;
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////
+// Main entry point.
+//
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    match cli.command {
+        Commands::List {
+            rom,
+            markdown,
+            extract,
+        } => list(&rom, &markdown, extract.as_deref())?,
+        Commands::Rebuild {
+            rom,
+            output,
+            replace,
+            remove,
+        } => rebuild_rom(&rom, &output, &replace, &remove)?,
+    }
+
+    Ok(())
+}